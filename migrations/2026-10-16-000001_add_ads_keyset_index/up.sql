@@ -0,0 +1,2 @@
+-- Backs keyset pagination on (created_at, id), see AdRepo::get_page_after
+CREATE INDEX idx_ads_created_at_id ON ads(created_at DESC, id DESC);
@@ -0,0 +1,4 @@
+-- Backs full-text search over title and description, see AdFilter::search
+ALTER TABLE ads ADD COLUMN search_vector tsvector
+    GENERATED ALWAYS AS (to_tsvector('english', title || ' ' || description)) STORED;
+CREATE INDEX idx_ads_search_vector ON ads USING GIN (search_vector);
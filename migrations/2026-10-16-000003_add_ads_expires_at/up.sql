@@ -0,0 +1,4 @@
+-- Listings expire AD_LIFETIME_DAYS after creation, see AdRepo::mark_expired
+ALTER TABLE ads ADD COLUMN expires_at TIMESTAMP;
+UPDATE ads SET expires_at = created_at + INTERVAL '30 days';
+CREATE INDEX idx_ads_expires_at ON ads(expires_at);
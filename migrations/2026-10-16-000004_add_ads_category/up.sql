@@ -0,0 +1,3 @@
+-- Values are AdCategory's snake_case names, see models::ad::AdCategory
+ALTER TABLE ads ADD COLUMN category VARCHAR(50) NOT NULL DEFAULT 'other';
+CREATE INDEX idx_ads_category ON ads(category);
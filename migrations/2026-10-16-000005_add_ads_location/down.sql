@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_ads_location;
+ALTER TABLE ads DROP CONSTRAINT IF EXISTS ads_location_range;
+ALTER TABLE ads DROP COLUMN IF EXISTS longitude;
+ALTER TABLE ads DROP COLUMN IF EXISTS latitude;
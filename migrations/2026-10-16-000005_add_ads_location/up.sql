@@ -0,0 +1,7 @@
+-- Optional ad location in degrees (WGS 84), searched by Haversine distance
+ALTER TABLE ads ADD COLUMN latitude DOUBLE PRECISION;
+ALTER TABLE ads ADD COLUMN longitude DOUBLE PRECISION;
+ALTER TABLE ads ADD CONSTRAINT ads_location_range CHECK (
+    latitude BETWEEN -90 AND 90 AND longitude BETWEEN -180 AND 180
+);
+CREATE INDEX idx_ads_location ON ads(latitude, longitude);
@@ -0,0 +1,9 @@
+-- Ads a user has saved; removed together with the ad
+CREATE TABLE favorites (
+    user_email VARCHAR(255) NOT NULL,
+    ad_id INTEGER NOT NULL REFERENCES ads(id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_email, ad_id)
+);
+
+CREATE INDEX idx_favorites_ad_id ON favorites(ad_id);
@@ -0,0 +1,2 @@
+-- Times the ad's detail page was fetched
+ALTER TABLE ads ADD COLUMN view_count BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+-- `Idempotency-Key`s already used to create an ad, per user; purged after a TTL
+CREATE TABLE idempotency_keys (
+    user_email VARCHAR(255) NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    ad_id INTEGER NOT NULL REFERENCES ads(id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_email, key)
+);
+
+CREATE INDEX idx_idempotency_keys_created_at ON idempotency_keys(created_at);
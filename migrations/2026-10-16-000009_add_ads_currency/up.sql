@@ -0,0 +1,3 @@
+-- ISO 4217 code of `price`, see models::ad::Currency; existing ads were priced in EUR
+ALTER TABLE ads ADD COLUMN currency VARCHAR(3) NOT NULL DEFAULT 'EUR';
+CREATE INDEX idx_ads_currency ON ads(currency);
@@ -0,0 +1,2 @@
+-- Set when the owner deletes the ad; the row stays until an admin purges it
+ALTER TABLE ads ADD COLUMN deleted_at TIMESTAMP;
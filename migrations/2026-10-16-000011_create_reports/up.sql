@@ -0,0 +1,9 @@
+-- Abuse reports filed against ads; removed together with the ad
+CREATE TABLE reports (
+    id SERIAL PRIMARY KEY,
+    ad_id INTEGER NOT NULL REFERENCES ads(id) ON DELETE CASCADE,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_reports_ad_id ON reports(ad_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS ad_slugs;
+DROP INDEX IF EXISTS idx_ads_slug;
+ALTER TABLE ads DROP COLUMN IF EXISTS slug;
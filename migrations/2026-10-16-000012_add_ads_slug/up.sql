@@ -0,0 +1,17 @@
+-- URL slug of the ad, its title slugified plus the id, see models::ad::ad_slug
+ALTER TABLE ads ADD COLUMN slug VARCHAR(100);
+UPDATE ads SET slug = concat_ws(
+    '-',
+    NULLIF(trim(BOTH '-' FROM left(trim(BOTH '-' FROM regexp_replace(lower(title), '[^a-z0-9]+', '-', 'g')), 80)), ''),
+    id
+);
+ALTER TABLE ads ALTER COLUMN slug SET NOT NULL;
+CREATE UNIQUE INDEX idx_ads_slug ON ads(slug);
+
+-- Slugs an ad had before its title changed, so old links still resolve
+CREATE TABLE ad_slugs (
+    slug VARCHAR(100) PRIMARY KEY,
+    ad_id INTEGER NOT NULL REFERENCES ads(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_ad_slugs_ad_id ON ad_slugs(ad_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_ads_top_ad_price_desc;
+DROP INDEX IF EXISTS idx_ads_top_ad_price_asc;
+DROP INDEX IF EXISTS idx_ads_top_ad_created_at;
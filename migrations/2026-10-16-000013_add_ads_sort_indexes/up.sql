@@ -0,0 +1,5 @@
+-- Back the feed's ORDER BY top_ad DESC, then the chosen sort, see apply_sort, so a page
+-- is read off an index instead of sorting every matching ad first.
+CREATE INDEX idx_ads_top_ad_created_at ON ads(top_ad DESC, created_at DESC);
+CREATE INDEX idx_ads_top_ad_price_asc ON ads(top_ad DESC, price ASC);
+CREATE INDEX idx_ads_top_ad_price_desc ON ads(top_ad DESC, price DESC);
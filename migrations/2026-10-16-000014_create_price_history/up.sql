@@ -0,0 +1,10 @@
+-- Prices an ad was changed to, one row per change, see AdRepo::price_history
+CREATE TABLE price_history (
+    id SERIAL PRIMARY KEY,
+    ad_id INTEGER NOT NULL REFERENCES ads(id) ON DELETE CASCADE,
+    price DECIMAL(10,2) NOT NULL,
+    currency VARCHAR(3) NOT NULL,
+    changed_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_price_history_ad_id ON price_history(ad_id, changed_at);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_ads_top_ad_until;
+ALTER TABLE ads DROP COLUMN IF EXISTS top_ad_until;
@@ -0,0 +1,4 @@
+-- When a promotion bought through POST /ads/:id/promote ends; NULL for ads promoted
+-- without an end date. The expiry job clears top_ad once it has passed.
+ALTER TABLE ads ADD COLUMN top_ad_until TIMESTAMPTZ;
+CREATE INDEX idx_ads_top_ad_until ON ads(top_ad_until) WHERE top_ad_until IS NOT NULL;
@@ -0,0 +1,2 @@
+-- Backs AdRepo::get_by_user, a seller's ads newest first, and the other per-owner lookups.
+CREATE INDEX idx_ads_user_email_created_at ON ads(user_email, created_at DESC);
@@ -0,0 +1,2 @@
+-- Whether the seller takes offers below the asking price ("or best offer")
+ALTER TABLE ads ADD COLUMN negotiable BOOLEAN NOT NULL DEFAULT false;
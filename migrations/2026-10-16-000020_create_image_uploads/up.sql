@@ -0,0 +1,12 @@
+-- Bytes each user has stored, per image, to enforce ImageLimits::quota_bytes. Images
+-- uploaded before this table existed aren't counted.
+CREATE TABLE image_uploads (
+    user_email VARCHAR(255) NOT NULL,
+    image_id VARCHAR(255) NOT NULL,
+    bytes BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (user_email, image_id)
+);
+
+-- Rows are removed by image when the image is deleted.
+CREATE INDEX idx_image_uploads_image_id ON image_uploads(image_id);
@@ -0,0 +1,3 @@
+-- Language the ad is written in, see models::ad::Locale; existing ads were written in English
+ALTER TABLE ads ADD COLUMN locale VARCHAR(5) NOT NULL DEFAULT 'en';
+CREATE INDEX idx_ads_locale ON ads(locale);
@@ -0,0 +1,2 @@
+-- Backs the admin-only user_phone_eq filter; user_email_eq uses idx_ads_user_email_created_at.
+CREATE INDEX idx_ads_user_phone ON ads(user_phone);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS phone_codes;
+ALTER TABLE ads DROP COLUMN IF EXISTS phone_verified;
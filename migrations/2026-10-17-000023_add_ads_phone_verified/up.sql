@@ -0,0 +1,13 @@
+-- Set once the seller confirms a code sent to user_phone, see AdRepo::verify_phone
+ALTER TABLE ads ADD COLUMN phone_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- The pending code of each ad, for the number it was sent to. Expired rows are ignored
+-- and cleared whenever a new code is started.
+CREATE TABLE phone_codes (
+    ad_id INTEGER PRIMARY KEY REFERENCES ads(id) ON DELETE CASCADE,
+    code VARCHAR(6) NOT NULL,
+    user_phone VARCHAR(50) NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX idx_phone_codes_expires_at ON phone_codes(expires_at);
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_ads_top_ad_price_desc;
+DROP INDEX IF EXISTS idx_ads_top_ad_price_asc;
+DROP INDEX IF EXISTS idx_ads_top_ad_created_at;
+CREATE INDEX idx_ads_top_ad_created_at ON ads(top_ad DESC, created_at DESC);
+CREATE INDEX idx_ads_top_ad_price_asc ON ads(top_ad DESC, price ASC);
+CREATE INDEX idx_ads_top_ad_price_desc ON ads(top_ad DESC, price DESC);
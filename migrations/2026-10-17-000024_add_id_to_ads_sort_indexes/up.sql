@@ -0,0 +1,8 @@
+-- apply_sort ends every order with id DESC so ads sharing a sort key page stably; the
+-- feed indexes end with it too, so pages are still read off them without a sort.
+DROP INDEX IF EXISTS idx_ads_top_ad_created_at;
+DROP INDEX IF EXISTS idx_ads_top_ad_price_asc;
+DROP INDEX IF EXISTS idx_ads_top_ad_price_desc;
+CREATE INDEX idx_ads_top_ad_created_at ON ads(top_ad DESC, created_at DESC, id DESC);
+CREATE INDEX idx_ads_top_ad_price_asc ON ads(top_ad DESC, price ASC, id DESC);
+CREATE INDEX idx_ads_top_ad_price_desc ON ads(top_ad DESC, price DESC, id DESC);
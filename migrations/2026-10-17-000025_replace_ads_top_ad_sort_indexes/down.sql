@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS idx_ads_price_desc_id;
+DROP INDEX IF EXISTS idx_ads_price_asc_id;
+CREATE INDEX idx_ads_top_ad_created_at ON ads(top_ad DESC, created_at DESC, id DESC);
+CREATE INDEX idx_ads_top_ad_price_asc ON ads(top_ad DESC, price ASC, id DESC);
+CREATE INDEX idx_ads_top_ad_price_desc ON ads(top_ad DESC, price DESC, id DESC);
@@ -0,0 +1,7 @@
+-- Whether a promotion is running depends on the time, which no index can hold, so
+-- get_page reads promoted ads apart from the rest and the rest off these indexes instead.
+DROP INDEX IF EXISTS idx_ads_top_ad_created_at;
+DROP INDEX IF EXISTS idx_ads_top_ad_price_asc;
+DROP INDEX IF EXISTS idx_ads_top_ad_price_desc;
+CREATE INDEX idx_ads_price_asc_id ON ads(price ASC, id DESC);
+CREATE INDEX idx_ads_price_desc_id ON ads(price DESC, id DESC);
@@ -0,0 +1,196 @@
+use std::sync::Arc;
+
+use anyhow::Error;
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::repos::error::ErrorResponse;
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    /// Allowed the admin-only routes behind `require_admin`: moderation, restoring and
+    /// purging deleted ads.
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Claims {
+    /// The authenticated user's email.
+    pub sub: String,
+    pub exp: usize,
+    #[serde(default)]
+    pub role: Role,
+    /// How tokens issued before `role` grant admin rights; still honoured.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl Claims {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin || self.admin
+    }
+}
+
+/// Inserted into request extensions by `require_auth`.
+#[derive(Clone, Debug)]
+pub struct AuthUser {
+    pub email: String,
+    pub is_admin: bool,
+}
+
+pub struct JwtKeys {
+    decoding: DecodingKey,
+}
+
+impl JwtKeys {
+    pub fn from_secret(secret: &[u8]) -> Arc<JwtKeys> {
+        Arc::new(JwtKeys {
+            decoding: DecodingKey::from_secret(secret),
+        })
+    }
+
+    /// Validates an HS256 token and returns its claims.
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        let data = decode::<Claims>(token, &self.decoding, &Validation::new(Algorithm::HS256))?;
+        Ok(data.claims)
+    }
+}
+
+/// The user behind the `Authorization: Bearer` header: `None` without the header,
+/// `Err` if the token is malformed or fails verification.
+fn authenticate(keys: &JwtKeys, req: &Request) -> Option<Result<AuthUser, ErrorResponse>> {
+    let value = req.headers().get(header::AUTHORIZATION)?;
+    let user = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| keys.verify(token).ok())
+        .map(|claims| AuthUser {
+            is_admin: claims.is_admin(),
+            email: claims.sub,
+        })
+        .ok_or_else(|| unauthorized("invalid bearer token"));
+
+    Some(user)
+}
+
+fn unauthorized(message: &str) -> ErrorResponse {
+    ErrorResponse::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+}
+
+pub async fn require_auth(
+    State(keys): State<Arc<JwtKeys>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ErrorResponse> {
+    let user = authenticate(&keys, &req)
+        .unwrap_or_else(|| Err(unauthorized("a bearer token is required")))?;
+
+    req.extensions_mut().insert(user);
+    Ok(next.run(req).await)
+}
+
+/// Rejects users without the admin role with 403. Layered inside `require_auth`, which
+/// supplies the `AuthUser`.
+pub async fn require_admin(req: Request, next: Next) -> Result<Response, ErrorResponse> {
+    match req.extensions().get::<AuthUser>() {
+        Some(user) if user.is_admin => Ok(next.run(req).await),
+        Some(_) => Err(ErrorResponse::new(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "the admin role is required",
+        )),
+        None => Err(unauthorized("a bearer token is required")),
+    }
+}
+
+/// Like `require_auth`, but lets anonymous requests through without an `AuthUser`, for
+/// public routes that show more to signed-in users. A bad token is still rejected.
+pub async fn optional_auth(
+    State(keys): State<Arc<JwtKeys>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ErrorResponse> {
+    if let Some(user) = authenticate(&keys, &req) {
+        req.extensions_mut().insert(user?);
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod test {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    use super::{Claims, JwtKeys, Role};
+
+    fn token(secret: &[u8], sub: &str, exp: usize) -> String {
+        encode(
+            &Header::default(),
+            &Claims {
+                sub: sub.to_string(),
+                exp,
+                role: Role::User,
+                admin: false,
+            },
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let keys = JwtKeys::from_secret(b"secret");
+        let exp = (chrono::Utc::now().timestamp() + 60) as usize;
+
+        assert_eq!(
+            keys.verify(&token(b"secret", "test@test.com", exp))
+                .unwrap()
+                .sub,
+            "test@test.com"
+        );
+        assert!(keys.verify(&token(b"other", "test@test.com", exp)).is_err());
+        assert!(keys.verify(&token(b"secret", "test@test.com", 1)).is_err());
+        assert!(keys.verify("not a token").is_err());
+    }
+
+    #[test]
+    fn test_role_claim() {
+        let keys = JwtKeys::from_secret(b"secret");
+        let exp = (chrono::Utc::now().timestamp() + 60) as usize;
+        let claims = |payload: serde_json::Value| {
+            let token = encode(
+                &Header::default(),
+                &payload,
+                &EncodingKey::from_secret(b"secret"),
+            )
+            .unwrap();
+            keys.verify(&token)
+        };
+
+        let user = claims(serde_json::json!({ "sub": "a@test.com", "exp": exp })).unwrap();
+        assert_eq!(user.role, Role::User);
+        assert!(!user.is_admin());
+
+        let admin = claims(serde_json::json!({ "sub": "a@test.com", "exp": exp, "role": "admin" }))
+            .unwrap();
+        assert!(admin.is_admin());
+
+        // Tokens from before roles existed keep working.
+        let legacy =
+            claims(serde_json::json!({ "sub": "a@test.com", "exp": exp, "admin": true })).unwrap();
+        assert!(legacy.is_admin());
+
+        assert!(
+            claims(serde_json::json!({ "sub": "a@test.com", "exp": exp, "role": "root" })).is_err()
+        );
+    }
+}
@@ -1,194 +1,7568 @@
-use std::{env, io::Read, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     async_trait,
-    body::Body,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{delete, get, post, put},
-    Json, Router,
+    body::{Body, Bytes},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, OriginalUri, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{delete, get, patch, post, put},
+    Extension, Json, Router,
 };
-use axum_typed_multipart::TypedMultipart;
+use axum_typed_multipart::{FieldData, TypedMultipart};
 use bazaars::{
-    db,
-    models::ad::{Ad, AdContent, AdRequest},
+    auth::{optional_auth, require_admin, require_auth, AuthUser, JwtKeys},
+    config::{AppConfig, ImageBackend},
+    cors::CorsConfig,
+    db, exif,
+    feed::AdFeed,
+    graphql, jobs,
+    maintenance::{self, Maintenance},
+    models::{
+        ad::{
+            parse_etag, parse_price, Ad, AdCategory, AdContent, AdId, AdPatch, AdRequest, AdStatus,
+            Currency, Locale, PublicAd,
+        },
+        contact::ContactRequest,
+        export::ExportFormat,
+        image::{
+            signed_url_ttl, ByteRange, ImageDimensions, ImageLimits, ImageLinks, ImagesRequest,
+            RepairOutcome, ReplaceImagesRequest, SignedUrl,
+        },
+        import::{csv_records, json_records, ImportRecord, MAX_IMPORT_ROWS},
+        price_history::PriceChange,
+        report::{ReportRequest, ReportedAd},
+        rss::{rss_feed, RSS_FEED_SIZE},
+        stats::{AdFacets, AdStats},
+    },
+    notify::{ContactMessage, LogNotifier, Notifier, SmtpNotifier},
+    rate_limit::{self, RateLimitConfig, RateLimits},
     repos::{
-        ad_repo::{AdFilter, AdRepo, PostgresAdRepo},
-        image_repo::{ImageRepo, LocalImageRepo},
+        ad_query::parse_ad_query,
+        ad_repo::{
+            AdFilter, AdKeyset, AdRepo, AdSort, CursorPage, DuplicateCheck, DuplicatePolicy,
+            PageLimits, PostgresAdRepo, MAX_IDEMPOTENCY_KEY_LEN, RENEW_COOLDOWN_HOURS,
+        },
+        error::{ErrorResponse, FieldError, RepoError},
+        favorite_repo::{FavoriteRepo, PostgresFavoriteRepo},
+        image_repo::{ImageRepo, LocalImageRepo, S3ImageRepo},
+        image_usage_repo::{ImageUsageRepo, PostgresImageUsageRepo},
+        outbox_repo::{OutboxRepo, PostgresOutboxRepo},
+        report_repo::{PostgresReportRepo, ReportRepo, DEFAULT_REPORT_THRESHOLD},
     },
+    request_log, telemetry,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::io::ReaderStream;
+use tower_http::{
+    compression::CompressionLayer,
+    normalize_path::NormalizePath,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
 };
+use utoipa_swagger_ui::SwaggerUi;
 
 #[derive(Clone)]
 struct AppState {
+    db_manager: db::DbManager,
     ad_repo: Arc<dyn AdRepo>,
+    favorite_repo: Arc<dyn FavoriteRepo>,
+    report_repo: Arc<dyn ReportRepo>,
     image_repo: Arc<dyn ImageRepo>,
+    /// Bytes each user has stored, against `ImageLimits::quota_bytes`.
+    image_usage_repo: Arc<dyn ImageUsageRepo>,
+    image_limits: ImageLimits,
+    rate_limits: RateLimitConfig,
+    page_limits: PageLimits,
+    cors: CorsConfig,
+    metrics: PrometheusHandle,
+    /// Currency of ads created without one.
+    base_currency: Currency,
+    /// Locale of ads created without one.
+    default_locale: Locale,
+    /// Contact messages go here and are sent by `jobs::run_outbox`.
+    outbox_repo: Arc<dyn OutboxRepo>,
+    ad_feed: AdFeed,
+    /// Serve the GraphiQL playground at `/graphiql`.
+    graphiql: bool,
+    /// Trim trailing slashes from request paths before routing.
+    trim_trailing_slash: bool,
+    /// Indent JSON responses unless the request says `?pretty=false`, see `pretty_json`.
+    pretty_json: bool,
+    /// Prefix of the links to ads in the RSS feed, see `AppConfig::public_base_url`.
+    public_base_url: String,
+    /// Prefix of the image links in `?expand=images` and exports, see
+    /// `AppConfig::image_base_url`.
+    image_base_url: String,
+    /// Whether writes are turned away, see `maintenance::reject_writes`.
+    maintenance: Maintenance,
+    /// What `create_ad` does about reposts.
+    duplicates: DuplicateCheck,
 }
 
 #[tokio::main]
 async fn main() {
-    let db_manager = db::DbManager::new(
-        env::var("DATABASE_URL")
-            .expect("DATABASE_URL must be set")
-            .as_str(),
-    );
+    // A `.env` file is optional; variables already set in the environment take precedence.
+    let _ = dotenvy::dotenv();
 
-    let ad_repo = PostgresAdRepo::new(db_manager);
-    let image_repo = LocalImageRepo::new("images".to_string());
-
-    let app: Router = Router::new()
-        .route("/ads", get(get_ads))
-        .route("/ads/:id", get(get_ad))
-        .route("/images/:id", get(get_image))
-        .route("/ads", post(create_ad))
-        .route("/ads/:id", put(update_ad))
-        .route("/ads/:id", delete(delete_ad))
-        .with_state(AppState {
-            ad_repo,
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+
+    let config = match AppConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if config.run_migrations {
+        match db::migrations::run_pending(&config.database_url) {
+            Ok(applied) if applied.is_empty() => tracing::info!("No pending migrations"),
+            Ok(applied) => {
+                for version in applied {
+                    tracing::info!("Applied migration {}", version);
+                }
+            }
+            Err(e) => {
+                tracing::error!("Failed to run migrations: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let db_manager = match db::DbManager::new(
+        &config.database_url,
+        config.database_read_url.as_deref(),
+        &config.pool,
+    ) {
+        Ok(db_manager) => db_manager,
+        Err(e) => {
+            tracing::error!("Failed to set up database pool: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let ad_repo: Arc<dyn AdRepo> =
+        PostgresAdRepo::with_max_open_cursors(db_manager.clone(), config.max_open_cursors);
+    let favorite_repo: Arc<dyn FavoriteRepo> = PostgresFavoriteRepo::new(db_manager.clone());
+    let report_repo: Arc<dyn ReportRepo> = PostgresReportRepo::new(db_manager.clone());
+    let outbox_repo: Arc<dyn OutboxRepo> = PostgresOutboxRepo::new(db_manager.clone());
+    let image_usage_repo: Arc<dyn ImageUsageRepo> = PostgresImageUsageRepo::new(db_manager.clone());
+    let image_repo: Arc<dyn ImageRepo> = match config.image_backend {
+        ImageBackend::S3 { bucket, prefix } => {
+            let aws_config = aws_config::load_from_env().await;
+            S3ImageRepo::new(
+                aws_sdk_s3::Client::new(&aws_config),
+                bucket,
+                prefix,
+                config.image_dedup,
+            )
+        }
+        ImageBackend::Local {
+            image_dir,
+            sniff_content_type,
+        } => match LocalImageRepo::new(image_dir, config.image_dedup, sniff_content_type) {
+            Ok(image_repo) => image_repo,
+            Err(e) => {
+                tracing::error!("Invalid image storage: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let notifier: Arc<dyn Notifier> = match config.smtp {
+        Some(smtp) => match SmtpNotifier::new(smtp) {
+            Ok(notifier) => notifier,
+            Err(e) => {
+                tracing::error!("Invalid SMTP configuration: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            tracing::warn!("SMTP_URL is not set, contact messages will not be delivered");
+            Arc::new(LogNotifier)
+        }
+    };
+    let jwt_keys = JwtKeys::from_secret(config.jwt_secret.as_bytes());
+    let shutdown_timeout = config.shutdown_timeout;
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut drain_rx = shutdown_tx.subscribe();
+    let expiry_job = tokio::spawn(jobs::run_expiry(
+        ad_repo.clone(),
+        config.expiry,
+        shutdown_rx.clone(),
+    ));
+    let outbox_job = tokio::spawn(jobs::run_outbox(
+        outbox_repo.clone(),
+        notifier,
+        config.outbox,
+        shutdown_rx,
+    ));
+
+    let app = app(
+        AppState {
+            db_manager,
+            ad_repo: ad_repo.clone(),
+            favorite_repo,
+            report_repo,
             image_repo,
-        });
+            image_usage_repo,
+            image_limits: config.image_limits,
+            rate_limits: config.rate_limits,
+            page_limits: config.page_limits,
+            cors: config.cors,
+            metrics: telemetry::prometheus_handle(),
+            base_currency: config.base_currency,
+            default_locale: config.default_locale,
+            outbox_repo,
+            ad_feed: AdFeed::default(),
+            graphiql: config.graphiql,
+            trim_trailing_slash: config.trim_trailing_slash,
+            pretty_json: config.pretty_json,
+            public_base_url: config.public_base_url,
+            image_base_url: config.image_base_url,
+            duplicates: config.duplicates,
+            maintenance: Maintenance::new(&config.maintenance),
+        },
+        jwt_keys,
+    );
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
-        .await
-        .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let listener = match tokio::net::TcpListener::bind(config.bind_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Failed to bind {}: {}", config.bind_addr, e);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("Listening on {}", config.bind_addr);
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    // In-flight requests get `shutdown_timeout` to finish once the signal arrives.
+    tokio::select! {
+        res = server => {
+            if let Err(e) = res {
+                tracing::error!("Server error: {}", e);
+            }
+        }
+        _ = async {
+            let _ = drain_rx.changed().await;
+            tokio::time::sleep(shutdown_timeout).await;
+        } => {
+            tracing::warn!("In-flight requests did not finish within {:?}", shutdown_timeout);
+        }
+    }
+
+    if let Err(e) = expiry_job.await {
+        tracing::error!("Expiry job panicked: {}", e);
+    }
+    if let Err(e) = outbox_job.await {
+        tracing::error!("Outbox job panicked: {}", e);
+    }
+
+    match ad_repo.close_stale_cursors(Duration::ZERO).await {
+        Ok(closed) => tracing::info!(closed, "closed open cursors"),
+        Err(e) => tracing::warn!(error = %e, "failed to close open cursors"),
+    }
+
+    let _ = std::io::Write::flush(&mut std::io::stdout());
+}
+
+/// Resolves on SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("shutting down");
+}
+
+/// The OpenAPI description of the REST routes, served at `/api-docs/openapi.json` and
+/// browsable at `/swagger-ui`. `/graphql` and `/graphiql` describe themselves through
+/// introspection, and `/ws/ads` is a WebSocket, which OpenAPI has no way to describe.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        get_ads,
+        seek_ads,
+        search_ads,
+        count_ads,
+        ads_rss_feed,
+        ad_facets,
+        get_ads_batch,
+        get_ad,
+        head_ad,
+        get_ad_by_slug,
+        get_price_history,
+        get_categories,
+        fetch_cursor,
+        close_cursor,
+        create_ad,
+        update_ad,
+        patch_ad,
+        delete_ad,
+        import_ads,
+        publish_ad,
+        promote_ad,
+        renew_ad,
+        verify_phone,
+        contact_seller,
+        report_ad,
+        get_image,
+        head_image,
+        get_thumbnail,
+        get_ad_image,
+        upload_images,
+        add_images,
+        replace_images,
+        reorder_images,
+        remove_image,
+        add_favorite,
+        remove_favorite,
+        get_favorites,
+        get_user_ads,
+        delete_user_ads,
+        export_user_ads,
+        restore_ad,
+        purge_ad,
+        set_ad_status,
+        get_reports,
+        get_stats,
+        repair_image,
+        get_maintenance,
+        set_maintenance,
+        health,
+        ready,
+        get_metrics,
+    ),
+    components(schemas(AdStatus, AdSort, Currency, ExportFormat, ImageLinks)),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "ads", description = "Listing, searching and managing ads"),
+        (name = "images", description = "Image uploads and downloads"),
+        (name = "favorites", description = "Ads saved by the signed-in user"),
+        (name = "users", description = "A seller's ads as a whole"),
+        (name = "admin", description = "Moderation and operations, for admins only"),
+        (name = "probes", description = "Liveness, readiness and metrics"),
+    )
+)]
+struct ApiDoc;
+
+/// Declares the `bearer` scheme the paths' `security` refers to: the JWT `require_auth`
+/// checks.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+fn app(state: AppState, jwt_keys: Arc<JwtKeys>) -> Router {
+    let viewer = middleware::from_fn_with_state(jwt_keys.clone(), optional_auth);
+    let auth = middleware::from_fn_with_state(jwt_keys, require_auth);
+    // Always layered inside `auth`, which it relies on for the user.
+    let admin = middleware::from_fn(require_admin);
+    let rate_limits = RateLimits::new(&state.rate_limits);
+    let contact_rate_limit =
+        middleware::from_fn_with_state(rate_limits.clone(), rate_limit::contact_rate_limit);
+    let report_rate_limit =
+        middleware::from_fn_with_state(rate_limits.clone(), rate_limit::report_rate_limit);
+    let rate_limit = middleware::from_fn_with_state(rate_limits, rate_limit::rate_limit);
+    // Only uploads get more than axum's default 2 MB.
+    let upload_limit = DefaultBodyLimit::max(state.image_limits.max_body_bytes);
+    let cors = state.cors.layer();
+    let schema = graphql::schema(
+        state.ad_repo.clone(),
+        state.image_repo.clone(),
+        state.ad_feed.clone(),
+        state.base_currency,
+        state.default_locale,
+        state.maintenance.clone(),
+        state.page_limits,
+    );
+    let trim_trailing_slash = state.trim_trailing_slash;
+    let pretty = middleware::from_fn_with_state(state.pretty_json, pretty_json);
+    // Layered outside `auth` on every route that writes, so nothing is read or checked
+    // while in maintenance mode. GraphQL mutations check it themselves.
+    let writes =
+        middleware::from_fn_with_state(state.maintenance.clone(), maintenance::reject_writes);
+
+    let mut router = Router::new();
+    if state.graphiql {
+        router = router.route("/graphiql", get(graphiql));
+    }
+
+    let router = router
+        .route("/ads", get(get_ads).layer(viewer.clone()))
+        .route("/ads/seek", get(seek_ads).layer(viewer.clone()))
+        .route("/ads/search", get(search_ads).layer(viewer.clone()))
+        .route("/ads/count", get(count_ads).layer(viewer.clone()))
+        .route("/ads/feed.xml", get(ads_rss_feed).layer(viewer.clone()))
+        .route("/ads/facets", get(ad_facets).layer(viewer.clone()))
+        .route("/ads/batch", post(get_ads_batch).layer(viewer.clone()))
+        .route("/ads/:id", get(get_ad).head(head_ad).layer(viewer.clone()))
+        .route("/ads/slug/:slug", get(get_ad_by_slug).layer(viewer.clone()))
+        .route(
+            "/ads/:id/price-history",
+            get(get_price_history).layer(viewer.clone()),
+        )
+        .route("/ws/ads", get(new_ads_feed).layer(viewer.clone()))
+        .route(
+            "/ads/:id/contact",
+            post(contact_seller)
+                .layer(contact_rate_limit)
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/report",
+            post(report_ad)
+                .layer(report_rate_limit)
+                .layer(writes.clone()),
+        )
+        .route("/categories", get(get_categories))
+        .route("/cursors", post(fetch_cursor).layer(viewer.clone()))
+        .route("/cursors/:name", delete(close_cursor))
+        .route("/images/:id", get(get_image).head(head_image))
+        .route("/images/:id/thumbnail", get(get_thumbnail))
+        .route("/ads/:id/image", get(get_ad_image).layer(viewer.clone()))
+        .route(
+            "/images",
+            post(upload_images)
+                .layer(upload_limit)
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads",
+            post(create_ad)
+                .layer(upload_limit)
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id",
+            put(update_ad)
+                .layer(upload_limit)
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/import",
+            post(import_ads).layer(auth.clone()).layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id",
+            patch(patch_ad)
+                .delete(delete_ad)
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/images",
+            post(add_images)
+                .put(replace_images)
+                .layer(upload_limit)
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/publish",
+            post(publish_ad).layer(auth.clone()).layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/promote",
+            post(promote_ad).layer(auth.clone()).layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/renew",
+            post(renew_ad).layer(auth.clone()).layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/verify-phone",
+            post(verify_phone).layer(auth.clone()).layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/images/order",
+            put(reorder_images)
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/images/:image_id",
+            delete(remove_image)
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/restore",
+            post(restore_ad)
+                .layer(admin.clone())
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/purge",
+            delete(purge_ad)
+                .layer(admin.clone())
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/ads/:id/status",
+            put(set_ad_status)
+                .layer(admin.clone())
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/reports",
+            get(get_reports).layer(admin.clone()).layer(auth.clone()),
+        )
+        .route(
+            "/stats",
+            get(get_stats).layer(admin.clone()).layer(auth.clone()),
+        )
+        .route(
+            "/images/:id/repair",
+            post(repair_image)
+                .layer(admin.clone())
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        // Not behind `writes`, or maintenance mode couldn't be switched off again.
+        .route(
+            "/maintenance",
+            get(get_maintenance)
+                .put(set_maintenance)
+                .layer(admin)
+                .layer(auth.clone()),
+        )
+        .route("/users/:email/ads", get(get_user_ads).layer(viewer.clone()))
+        .route(
+            "/users/:email/ads",
+            delete(delete_user_ads)
+                .layer(auth.clone())
+                .layer(writes.clone()),
+        )
+        .route(
+            "/users/:email/export",
+            get(export_user_ads).layer(auth.clone()),
+        )
+        .route(
+            "/ads/:id/favorite",
+            post(add_favorite)
+                .delete(remove_favorite)
+                .layer(auth.clone())
+                .layer(writes),
+        )
+        .route("/favorites", get(get_favorites).layer(auth))
+        .route(
+            "/graphql",
+            post(graphql_query).layer(Extension(schema)).layer(viewer),
+        )
+        .layer(rate_limit)
+        // Outside the rate limit, so throttled requests are counted too.
+        .route_layer(middleware::from_fn(telemetry::track_requests))
+        // Probes and metrics are added last so they are never throttled or measured.
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/metrics", get(get_metrics))
+        .fallback(route_not_found)
+        // Covers extractor rejections, oversized bodies and unsupported methods alike.
+        .layer(middleware::map_response(json_error_body))
+        // Outside `json_error_body`, so errors are indented too.
+        .layer(pretty)
+        // Gzip or brotli, as the client accepts. The default predicate skips `image/*`
+        // responses, which are already compressed, and bodies too small to benefit.
+        .layer(CompressionLayer::new())
+        // Outside auth and rate limiting, so preflights are answered first.
+        .layer(cors)
+        .layer(PropagateRequestIdLayer::new(request_log::REQUEST_ID_HEADER))
+        // Body chunks are never logged, so image bytes stay out of the logs.
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(request_log::make_span)
+                .on_request(())
+                .on_response(request_log::on_response)
+                .on_body_chunk(())
+                .on_eos(())
+                .on_failure(()),
+        )
+        // Outermost: keeps a client-supplied `X-Request-Id`, or generates one.
+        .layer(SetRequestIdLayer::new(
+            request_log::REQUEST_ID_HEADER,
+            MakeRequestUuid,
+        ))
+        .with_state(state);
+
+    // Swagger UI redirects `/swagger-ui` to `/swagger-ui/`, which trimming would send
+    // straight back, so the docs are matched ahead of the normalization.
+    let docs = SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi());
+
+    // `Router::layer` middleware runs after the route is matched, so the path has to be
+    // normalized by a service in front of the whole router.
+    if trim_trailing_slash {
+        Router::new()
+            .merge(docs)
+            .fallback_service(NormalizePath::trim_trailing_slash(router))
+    } else {
+        router.merge(docs)
+    }
 }
 
-// #[derive(serde::Serialize)]
-// struct CursorRes<T> {
-//     cursor: String,
-//     items: Vec<T>,
-// }
+/// Requests no route matches, answered with the same JSON error body as everything else.
+async fn route_not_found(method: Method, uri: Uri) -> ErrorResponse {
+    ErrorResponse::new(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        format!("no route for {} {}", method, uri.path()),
+    )
+}
 
-// #[derive(serde::Deserialize)]
-// struct CursorReq {
-//     cursor: Option<String>,
-//     count: u8,
-//     filters: AdFilter,
-// }
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct CursorReq {
+    /// Continues from this cursor; without one, a new cursor is opened over `filters`.
+    cursor: Option<String>,
+    /// Rows to fetch, clamped to `MAX_CURSOR_FETCH`.
+    count: u32,
+    /// With `cursor`, checked rather than applied: they must be the filters the cursor
+    /// was declared with, which can't change while paging.
+    filters: Option<AdFilter>,
+}
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, utoipa::ToSchema)]
 struct PaginatedRes<T> {
     page: u32,
+    total: u64,
     items: Vec<T>,
+    /// Whether there are items after this page, i.e. `next` is set.
+    has_more: bool,
+    /// The request's URL moved one page on, or null on the last page.
+    next: Option<String>,
+    /// The request's URL moved one page back, or null on the first page. From past the
+    /// end, the last page with items.
+    prev: Option<String>,
+}
+
+impl<T> PaginatedRes<T> {
+    /// The page at `offset` of `total` items, linked to its neighbours through `uri`.
+    fn new(items: Vec<T>, total: u64, uri: &Uri, offset: u32, per_page: u32) -> Self {
+        let seen = u64::from(offset) + items.len() as u64;
+        let next = (items.len() == per_page as usize && seen < total)
+            .then(|| page_link(uri, offset.saturating_add(per_page), per_page));
+        let mut prev_offset = offset.saturating_sub(per_page);
+        if u64::from(offset) >= total {
+            let last_page = total.saturating_sub(1) / u64::from(per_page) * u64::from(per_page);
+            prev_offset = prev_offset.min(u32::try_from(last_page).unwrap_or(u32::MAX));
+        }
+        let prev = (offset > 0).then(|| page_link(uri, prev_offset, per_page));
+
+        PaginatedRes {
+            items,
+            total,
+            // Checked, although `per_page` is at least 1 and `offset` bounded by now.
+            page: offset
+                .checked_div(per_page)
+                .and_then(|page| page.checked_add(1))
+                .unwrap_or(1),
+            has_more: next.is_some(),
+            next,
+            prev,
+        }
+    }
 }
 
-#[derive(serde::Deserialize, Clone)]
+/// `uri` with its `offset` and `per_page` replaced, keeping every other parameter. Links
+/// are built from the query string, so clients still paging with a JSON body have to
+/// carry the offset over themselves.
+fn page_link(uri: &Uri, offset: u32, per_page: u32) -> String {
+    let mut params: Vec<&str> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !param.is_empty() && name != "offset" && name != "per_page"
+        })
+        .collect();
+    let paging = format!("offset={}&per_page={}", offset, per_page);
+    params.push(&paging);
+
+    format!("{}?{}", uri.path(), params.join("&"))
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema, Clone)]
 struct PaginatedReq {
     per_page: Option<u32>,
     offset: Option<u32>,
     filters: Option<AdFilter>,
 }
 
+/// Paging half of the `/ads` query string; the filter half is parsed as `AdFilter`.
+#[derive(serde::Deserialize, utoipa::IntoParams, Clone)]
+#[into_params(parameter_in = Query)]
+struct PageParams {
+    /// Ads per page, 10 when omitted.
+    per_page: Option<u32>,
+    /// Ads to skip, a multiple of `per_page` for whole pages.
+    offset: Option<u32>,
+}
+
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "probes",
+    responses((status = 200, description = "The process is up"))
+)]
+async fn health() -> StatusCode {
+    StatusCode::OK
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ReadyRes {
+    ready: bool,
+    database: bool,
+    image_storage: bool,
+    idle_connections: u32,
+    in_use_connections: u32,
+}
+
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "probes",
+    responses(
+        (status = 200, description = "The database is reachable", body = ReadyRes),
+        (status = 503, description = "The database is down", body = ReadyRes),
+    )
+)]
+async fn ready(State(state): State<AppState>) -> (StatusCode, Json<ReadyRes>) {
+    let database = state.db_manager.ping(READY_CHECK_TIMEOUT).is_ok();
+    let pool_state = state.db_manager.get_write_pool().state();
+    let image_storage = match state.image_repo.health_check().await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(error = %e, "image storage is not writable");
+            false
+        }
+    };
+
+    let ready = database && image_storage;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(ReadyRes {
+            ready,
+            database,
+            image_storage,
+            idle_connections: pool_state.idle_connections,
+            in_use_connections: pool_state.connections - pool_state.idle_connections,
+        }),
+    )
+}
+
+/// Prometheus text exposition of everything recorded so far.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "probes",
+    responses((status = 200, description = "Prometheus text exposition", content_type = "text/plain", body = String))
+)]
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    telemetry::record_pool_state(&state.db_manager);
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+/// Rejections from axum's extractors and unmatched routes come back as plain text or
+/// empty; rewraps them in the `ErrorResponse` JSON the handlers use, keeping the status
+/// and headers such as `Allow`.
+async fn json_error_body(response: Response) -> Response {
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !(status.is_client_error() || status.is_server_error()) || is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let message = axum::body::to_bytes(body, MAX_ERROR_MESSAGE_LEN)
+        .await
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .unwrap_or_default();
+    let error = ErrorResponse::from_status(status, message).into_response();
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.extend(error.headers().clone());
+    Response::from_parts(parts, error.into_body())
+}
+
+/// Rejection messages are a line or two; anything longer isn't worth echoing back.
+const MAX_ERROR_MESSAGE_LEN: usize = 4096;
+
+#[derive(serde::Deserialize)]
+struct PrettyParams {
+    pretty: Option<bool>,
+}
+
+/// Re-serializes JSON responses with `serde_json::to_string_pretty` when the request
+/// asks with `?pretty=true`, or by default with `PRETTY_JSON`, in which case
+/// `?pretty=false` opts out. Only whitespace changes: `serde_json` is built with
+/// `preserve_order`, so object keys keep their order. Downloads such as exports are
+/// streamed and left as they are.
+async fn pretty_json(State(default): State<bool>, request: Request, next: Next) -> Response {
+    let pretty = Query::<PrettyParams>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(params)| params.pretty)
+        .unwrap_or(default);
+    let response = next.run(request).await;
+
+    let headers = response.headers();
+    let is_json = headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    if !pretty || !is_json || headers.contains_key(header::CONTENT_DISPOSITION) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return RepoError::Internal(anyhow::Error::new(e)).into_response(),
+    };
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+    {
+        Ok(pretty) => Body::from(pretty),
+        Err(_) => Body::from(bytes),
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, body)
+}
+
+/// The optional `Idempotency-Key` header, which must be 1 to `MAX_IDEMPOTENCY_KEY_LEN`
+/// printable ASCII characters.
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, RepoError> {
+    let Some(value) = headers.get("idempotency-key") else {
+        return Ok(None);
+    };
+
+    value
+        .to_str()
+        .ok()
+        .filter(|key| {
+            !key.is_empty()
+                && key.len() <= MAX_IDEMPOTENCY_KEY_LEN
+                && key.chars().all(|c| c.is_ascii_graphic())
+        })
+        .map(|key| Some(key.to_string()))
+        .ok_or_else(|| {
+            RepoError::Validation(format!(
+                "Idempotency-Key must be 1 to {} printable ASCII characters",
+                MAX_IDEMPOTENCY_KEY_LEN
+            ))
+        })
+}
+
+/// Validates `filters` and turns away the ones only admins may use, see
+/// `AdFilter::needs_admin`.
+fn check_filters(
+    filters: &AdFilter,
+    viewer: Option<&Extension<AuthUser>>,
+) -> Result<(), RepoError> {
+    filters.validate().map_err(RepoError::InvalidFields)?;
+    if filters.needs_admin() && !viewer.is_some_and(|Extension(user)| user.is_admin) {
+        return Err(RepoError::Forbidden);
+    }
+    Ok(())
+}
+
+/// Masks each ad's contact details unless `viewer` owns it or is an admin.
+fn public_ads(ads: Vec<Ad>, viewer: Option<&Extension<AuthUser>>) -> Vec<PublicAd> {
+    let viewer = viewer.map(|Extension(user)| user);
+    ads.into_iter()
+        .filter(|ad| PublicAd::is_visible_to(ad, viewer))
+        .map(|ad| PublicAd::for_viewer(ad, viewer))
+        .collect()
+}
+
+#[utoipa::path(
+    get,
+    path = "/ads",
+    tag = "ads",
+    params(PageParams, AdFilter),
+    request_body(
+        content = Option<PaginatedReq>,
+        description = "Paging and filters as JSON, for older clients; wins over the query string"
+    ),
+    responses(
+        (status = 200, description = "A page of ads", body = PaginatedRes<PublicAd>),
+        (status = 400, description = "Invalid paging or filters", body = ErrorResponse),
+    )
+)]
 async fn get_ads(
     State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    OriginalUri(uri): OriginalUri,
+    Query(page): Query<PageParams>,
+    Query(filters): Query<AdFilter>,
     payload: Option<Json<PaginatedReq>>,
-) -> Result<Json<PaginatedRes<Ad>>, StatusCode> {
-    // Stub implementation
+) -> Result<Json<PaginatedRes<PublicAd>>, RepoError> {
+    // A JSON body is still accepted for older clients and wins over the query string.
     let params = match payload {
         Some(Json(payload)) => payload,
         None => PaginatedReq {
-            per_page: Some(10),
-            offset: Some(0),
-            filters: None,
+            per_page: page.per_page,
+            offset: page.offset,
+            filters: Some(filters),
         },
     };
-    let per_page = params.per_page.unwrap_or(10);
+    let filters = params.filters.unwrap_or_default();
+    check_filters(&filters, viewer.as_ref())?;
 
-    let offset = params.offset.unwrap_or(0);
+    ads_page(
+        &state,
+        viewer,
+        &uri,
+        params.per_page,
+        params.offset,
+        filters,
+    )
+    .await
+}
 
-    match state
-        .ad_repo
-        .get_page(offset, per_page, params.filters.unwrap_or_default())
-        .await
-    {
-        Ok(items) => Ok(Json(PaginatedRes {
-            items,
-            page: offset / per_page + 1,
-        })),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-    }
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchParams {
+    /// E.g. `price<500 title:bike`, see `parse_ad_query`.
+    q: String,
 }
 
-async fn get_image(State(state): State<AppState>, Path(id): Path<String>) -> impl IntoResponse {
-    match state.image_repo.get_image(&id).await {
-        Ok(image) => {
-            let content_type = image.mime_type;
-            let bytes = image.bytes;
-            let body = Body::from(bytes);
-            let response = axum::http::Response::builder()
-                .header("Content-Type", content_type)
-                .body(body)
-                .unwrap();
-            Ok(response)
+/// `get_ads` with the filter given as one `q` in the language of `parse_ad_query`, e.g.
+/// `q=price<500 title:bike`.
+#[utoipa::path(
+    get,
+    path = "/ads/search",
+    tag = "ads",
+    params(
+        PageParams,
+        SearchParams,
+    ),
+    responses(
+        (status = 200, description = "A page of ads", body = PaginatedRes<PublicAd>),
+        (status = 400, description = "Invalid paging or query", body = ErrorResponse),
+    )
+)]
+async fn search_ads(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    OriginalUri(uri): OriginalUri,
+    Query(page): Query<PageParams>,
+    Query(search): Query<SearchParams>,
+) -> Result<Json<PaginatedRes<PublicAd>>, RepoError> {
+    let filters = parse_ad_query(&search.q).map_err(RepoError::InvalidFields)?;
+
+    ads_page(&state, viewer, &uri, page.per_page, page.offset, filters).await
+}
+
+/// A page of ads matching the already validated `filters`, linked to its neighbours
+/// through `uri`.
+async fn ads_page(
+    state: &AppState,
+    viewer: Option<Extension<AuthUser>>,
+    uri: &Uri,
+    per_page: Option<u32>,
+    offset: Option<u32>,
+    filters: AdFilter,
+) -> Result<Json<PaginatedRes<PublicAd>>, RepoError> {
+    let per_page = state.page_limits.per_page(per_page)?;
+    let offset = state.page_limits.offset(offset)?;
+
+    // Counted first, so an offset past the end needn't fetch a page to come back empty.
+    let total = state.ad_repo.count(filters.clone()).await?;
+    state.page_limits.check_offset(offset, total)?;
+    let items = if u64::from(offset) < total {
+        state.ad_repo.get_page(offset, per_page, filters).await?
+    } else {
+        Vec::new()
+    };
+
+    Ok(Json(PaginatedRes::new(
+        public_ads(items, viewer.as_ref()),
+        total,
+        uri,
+        offset,
+        per_page,
+    )))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct CountRes {
+    total: u64,
+}
+
+/// The `total` that `get_ads` would report for the same filters, without fetching any ads,
+/// so a client can warn about a broad search before running it.
+#[utoipa::path(
+    get,
+    path = "/ads/count",
+    tag = "ads",
+    params(AdFilter),
+    responses(
+        (status = 200, description = "How many ads match", body = CountRes),
+        (status = 400, description = "Invalid filters", body = ErrorResponse),
+    )
+)]
+async fn count_ads(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Query(filters): Query<AdFilter>,
+) -> Result<Json<CountRes>, RepoError> {
+    check_filters(&filters, viewer.as_ref())?;
+    let total = state.ad_repo.count(filters).await?;
+
+    Ok(Json(CountRes { total }))
+}
+
+/// The newest active ads matching the same filters as `get_ads`, as an RSS feed for feed
+/// readers and integrations.
+#[utoipa::path(
+    get,
+    path = "/ads/feed.xml",
+    tag = "ads",
+    params(AdFilter),
+    responses(
+        (status = 200, description = "The newest matching ads", content_type = "application/rss+xml", body = String),
+        (status = 400, description = "Invalid filters", body = ErrorResponse),
+    )
+)]
+async fn ads_rss_feed(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Query(filters): Query<AdFilter>,
+) -> Result<impl IntoResponse, RepoError> {
+    check_filters(&filters, viewer.as_ref())?;
+    let ads = state.ad_repo.get_page(0, RSS_FEED_SIZE, filters).await?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")],
+        rss_feed(&ads, &state.public_base_url),
+    ))
+}
+
+/// Category and price range counts over the ads `get_ads` would list for the same
+/// filters, for a search's filter sidebar.
+#[utoipa::path(
+    get,
+    path = "/ads/facets",
+    tag = "ads",
+    params(AdFilter),
+    responses(
+        (status = 200, description = "Counts per category and price range", body = AdFacets),
+        (status = 400, description = "Invalid filters", body = ErrorResponse),
+    )
+)]
+async fn ad_facets(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Query(filters): Query<AdFilter>,
+) -> Result<Json<AdFacets>, RepoError> {
+    check_filters(&filters, viewer.as_ref())?;
+    let facets = state.ad_repo.facets(filters).await?;
+
+    Ok(Json(facets))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct KeysetRes<T> {
+    items: Vec<T>,
+    /// Pass back as `after_created_at`/`after_id` to fetch the next page; `None` at the end.
+    next: Option<AdKeyset>,
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams, Clone)]
+#[into_params(parameter_in = Query)]
+struct KeysetParams {
+    per_page: Option<u32>,
+    after_created_at: Option<chrono::DateTime<chrono::Utc>>,
+    after_id: Option<AdId>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/ads/seek",
+    tag = "ads",
+    params(KeysetParams, AdFilter),
+    responses(
+        (status = 200, description = "The ads after the given position", body = KeysetRes<PublicAd>),
+        (status = 400, description = "Invalid position or filters", body = ErrorResponse),
+    )
+)]
+async fn seek_ads(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Query(params): Query<KeysetParams>,
+    Query(filters): Query<AdFilter>,
+) -> Result<Json<KeysetRes<PublicAd>>, RepoError> {
+    check_filters(&filters, viewer.as_ref())?;
+    let per_page = state.page_limits.per_page(params.per_page)?;
+    let after = match (params.after_created_at, params.after_id) {
+        (Some(created_at), Some(id)) => Some(AdKeyset { created_at, id }),
+        (None, None) => None,
+        _ => {
+            return Err(RepoError::Validation(
+                "after_created_at and after_id must be given together".to_string(),
+            ))
         }
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-    }
+    };
+
+    let items = state
+        .ad_repo
+        .get_page_after(after, per_page, filters)
+        .await?;
+    let next = if items.len() as u32 == per_page {
+        items.last().map(AdKeyset::from)
+    } else {
+        None
+    };
+
+    Ok(Json(KeysetRes {
+        items: public_ads(items, viewer.as_ref()),
+        next,
+    }))
 }
 
-#[axum::debug_handler]
-async fn get_ad(
+/// Runs a GraphQL query or mutation. Mutations need a bearer token, like their REST
+/// counterparts.
+async fn graphql_query(
+    Extension(schema): Extension<graphql::AdSchema>,
+    viewer: Option<Extension<AuthUser>>,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    let request = request.data(graphql::Viewer(viewer.map(|Extension(user)| user)));
+    Json(schema.execute(request).await)
+}
+
+async fn graphiql() -> Html<String> {
+    Html(
+        async_graphql::http::GraphiQLSource::build()
+            .endpoint("/graphql")
+            .finish(),
+    )
+}
+
+/// Upgrades to a WebSocket that receives each newly created ad matching the filter in
+/// the query string, as a JSON text message. Contacts are masked as in `GET /ads`.
+async fn new_ads_feed(
     State(state): State<AppState>,
-    Path(id): Path<String>,
-) -> Result<Json<Ad>, StatusCode> {
-    let id = id.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    viewer: Option<Extension<AuthUser>>,
+    Query(filter): Query<AdFilter>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, RepoError> {
+    check_filters(&filter, viewer.as_ref())?;
+    // Subscribed before the upgrade, so no ad created after the handshake is missed.
+    let ads = state.ad_feed.subscribe();
+    let viewer = viewer.map(|Extension(user)| user);
 
-    match state.ad_repo.get_by_id(id).await {
-        Ok(Some(ad)) => Ok(Json(ad)),
-        Ok(None) => Err(StatusCode::NOT_FOUND),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    Ok(ws.on_upgrade(move |socket| stream_new_ads(socket, ads, filter, viewer)))
+}
+
+async fn stream_new_ads(
+    mut socket: WebSocket,
+    mut ads: broadcast::Receiver<Arc<Ad>>,
+    filter: AdFilter,
+    viewer: Option<AuthUser>,
+) {
+    loop {
+        tokio::select! {
+            received = ads.recv() => match received {
+                Ok(ad) if filter.matches(&ad) => {
+                    let ad = PublicAd::for_viewer(Ad::clone(&ad), viewer.as_ref());
+                    let Ok(json) = serde_json::to_string(&ad) else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                // A slow client skips what it missed rather than holding up the feed.
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "ad feed subscriber lagged behind");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // Incoming messages are ignored; the socket is only read to notice a close.
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
     }
 }
 
-#[axum::debug_handler]
-async fn create_ad(
+#[utoipa::path(
+    get,
+    path = "/categories",
+    tag = "ads",
+    responses((status = 200, description = "Every category's name", body = Vec<AdCategory>))
+)]
+async fn get_categories() -> Json<Vec<&'static str>> {
+    Json(AdCategory::ALL.iter().map(AdCategory::as_str).collect())
+}
+
+/// The next `count` ads from a cursor, closed once it runs out. 410 Gone if the cursor
+/// is no longer open, in which case the client starts over without one.
+#[utoipa::path(
+    post,
+    path = "/cursors",
+    tag = "ads",
+    request_body = CursorReq,
+    responses(
+        (status = 200, description = "The next ads and the cursor to continue from", body = CursorPage<PublicAd>),
+        (status = 400, description = "Invalid filters, or not the cursor's", body = ErrorResponse),
+        (status = 410, description = "The cursor is no longer open", body = ErrorResponse),
+    )
+)]
+async fn fetch_cursor(
     State(state): State<AppState>,
-    TypedMultipart(payload): TypedMultipart<AdRequest>,
-) -> Result<String, StatusCode> {
-    let ad = AdContent {
-        title: payload.title,
-        description: payload.description,
-        price: payload.price,
-        user_email: payload.user_email,
-        user_phone: payload.user_phone,
-        top_ad: payload.top_ad,
+    viewer: Option<Extension<AuthUser>>,
+    Json(req): Json<CursorReq>,
+) -> Result<Json<CursorPage<PublicAd>>, RepoError> {
+    let (cursor, filters) = match req.cursor {
+        Some(cursor) => (cursor, req.filters),
+        None => {
+            let filters = req.filters.unwrap_or_default();
+            check_filters(&filters, viewer.as_ref())?;
+            (state.ad_repo.new_cursor(filters).await?, None)
+        }
     };
 
-    let mut image_ids = Vec::new();
+    let page = state
+        .ad_repo
+        .fetch_from_cursor(cursor, req.count, true, filters.as_ref())
+        .await?;
+    Ok(Json(page.map(|ads| public_ads(ads, viewer.as_ref()))))
+}
 
-    for image in payload.images {
-        let image_data: Vec<u8> = image.contents.bytes().filter_map(Result::ok).collect();
-        let image_id = state
-            .image_repo
-            .create_image(
-                image.metadata.file_name.unwrap(),
-                image_data,
-                image.metadata.content_type.unwrap(),
-            )
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        image_ids.push(image_id);
+#[utoipa::path(
+    delete,
+    path = "/cursors/{name}",
+    tag = "ads",
+    params(("name" = String, Path, description = "The cursor's name")),
+    responses(
+        (status = 204, description = "Closed, or already gone"),
+        (status = 400, description = "Not a valid cursor name", body = ErrorResponse),
+    )
+)]
+async fn close_cursor(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, RepoError> {
+    state.ad_repo.close_cursor(name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stored images are never modified, so the id alone identifies the content.
+fn image_etag(id: &str) -> String {
+    format!("\"{}\"", id)
+}
+
+/// Weak comparison against each entry of an `If-None-Match` list, per RFC 9110.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/images/{id}",
+    tag = "images",
+    params(
+        ("id" = String, Path, description = "The image's id"),
+        ("Range" = Option<String>, Header, description = "A single byte range, e.g. `bytes=0-1023`"),
+    ),
+    responses(
+        (status = 200, description = "The image", content_type = "image/*", body = Vec<u8>),
+        (status = 206, description = "The requested range", content_type = "image/*", body = Vec<u8>),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 404, description = "No such image", body = ErrorResponse),
+        (status = 416, description = "The range is past the end", body = ErrorResponse),
+    )
+)]
+async fn get_image(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, RepoError> {
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ByteRange::parse);
+
+    // Streamed, so a large download costs a small read buffer instead of the whole file.
+    let image = state.image_repo.open_image_stream(&id, range).await?;
+    let etag = image_etag(&id);
+
+    let response = axum::http::Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable");
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| etag_matches(value, &etag));
+    if not_modified {
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
     }
 
-    let ad = state
-        .ad_repo
-        .create(ad, image_ids)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let response = match image.range {
+        Some((first, last)) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", first, last, image.len),
+            )
+            .header(header::CONTENT_LENGTH, last - first + 1),
+        None => response.header(header::CONTENT_LENGTH, image.len),
+    };
 
-    Ok(ad.id.to_string())
+    Ok(response
+        .header(header::CONTENT_TYPE, image.mime_type)
+        .body(Body::from_stream(ReaderStream::new(image.reader)))
+        .unwrap())
 }
 
-async fn update_ad(
+/// `get_image`'s headers without its bytes: the stream is opened for its size and type
+/// and dropped unread.
+#[utoipa::path(
+    head,
+    path = "/images/{id}",
+    tag = "images",
+    params(("id" = String, Path, description = "The image's id")),
+    responses(
+        (status = 200, description = "The image's headers", headers(("Content-Length" = u64), ("ETag" = String))),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 404, description = "No such image", body = ErrorResponse),
+    )
+)]
+async fn head_image(
+    state: State<AppState>,
+    id: Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, RepoError> {
+    let (parts, _) = get_image(state, id, headers)
+        .await?
+        .into_response()
+        .into_parts();
+    Ok(Response::from_parts(parts, Body::empty()))
+}
+
+const DEFAULT_THUMBNAIL_DIM: u32 = 256;
+const MAX_THUMBNAIL_DIM: u32 = 1024;
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ThumbnailReq {
+    /// Longest side in pixels, 256 when omitted and at most 1024.
+    max_dim: Option<u32>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/images/{id}/thumbnail",
+    tag = "images",
+    params(("id" = String, Path, description = "The image's id"), ThumbnailReq),
+    responses(
+        (status = 200, description = "The image scaled down", content_type = "image/*", body = Vec<u8>),
+        (status = 404, description = "No such image", body = ErrorResponse),
+    )
+)]
+async fn get_thumbnail(
+    State(state): State<AppState>,
     Path(id): Path<String>,
+    Query(params): Query<ThumbnailReq>,
+) -> Result<impl IntoResponse, RepoError> {
+    // Clamped so clients can't fill the cache with arbitrary sizes.
+    let max_dim = params
+        .max_dim
+        .unwrap_or(DEFAULT_THUMBNAIL_DIM)
+        .clamp(1, MAX_THUMBNAIL_DIM);
+
+    let image = state.image_repo.get_thumbnail(&id, max_dim).await?;
+    let response = axum::http::Response::builder()
+        .header("Content-Type", image.mime_type)
+        .body(Body::from(image.bytes))
+        .unwrap();
+    Ok(response)
+}
+
+/// Takes a JSON array of ids and returns the ads that exist, in the same order.
+#[utoipa::path(
+    post,
+    path = "/ads/batch",
+    tag = "ads",
+    request_body = Vec<i32>,
+    responses((status = 200, description = "The ads found, in request order", body = Vec<PublicAd>))
+)]
+async fn get_ads_batch(
     State(state): State<AppState>,
-    TypedMultipart(payload): TypedMultipart<AdRequest>,
-) -> StatusCode {
-    // Stub implementation
-    StatusCode::OK
+    viewer: Option<Extension<AuthUser>>,
+    Json(ids): Json<Vec<AdId>>,
+) -> Result<Json<Vec<PublicAd>>, RepoError> {
+    let ads = state.ad_repo.get_by_ids(&ids).await?;
+    Ok(Json(public_ads(ads, viewer.as_ref())))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AdImageParams {
+    /// Serve the thumbnail instead, given as a bare `?thumb` or `?thumb=true`.
+    #[serde(default, deserialize_with = "query_flag")]
+    thumb: bool,
+    /// Longest side of the thumbnail, as for `/images/:id/thumbnail`.
+    max_dim: Option<u32>,
+}
+
+/// A boolean query parameter that also counts as set when given without a value.
+fn query_flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match <String as serde::Deserialize>::deserialize(deserializer)?.as_str() {
+        "" | "true" => Ok(true),
+        "false" => Ok(false),
+        other => Err(serde::de::Error::invalid_value(
+            serde::de::Unexpected::Str(other),
+            &"true, false or no value",
+        )),
+    }
+}
+
+/// The ad's first image, or its thumbnail, so a grid of ads needn't fetch each ad to
+/// learn its image ids. 404 if the viewer can't see the ad or it has no images.
+#[utoipa::path(
+    get,
+    path = "/ads/{id}/image",
+    tag = "images",
+    params(
+        ("id" = i32, Path, description = "The ad's id"),
+        AdImageParams,
+    ),
+    responses(
+        (status = 200, description = "The ad's first image or its thumbnail", content_type = "image/*", body = Vec<u8>),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 404, description = "No such ad, or it has no images", body = ErrorResponse),
+    )
+)]
+async fn get_ad_image(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Path(id): Path<String>,
+    Query(params): Query<AdImageParams>,
+    headers: HeaderMap,
+) -> Result<Response, RepoError> {
+    let id: AdId = id.parse()?;
+    let viewer = viewer.as_ref().map(|Extension(user)| user);
+
+    let image_id = match state.ad_repo.get_by_id(id, false).await? {
+        Some(ad) if PublicAd::is_visible_to(&ad, viewer) => ad.images.into_iter().next(),
+        _ => None,
+    }
+    .ok_or(RepoError::NotFound)?;
+
+    let mut response = if params.thumb {
+        let thumbnail = ThumbnailReq {
+            max_dim: params.max_dim,
+        };
+        get_thumbnail(State(state), Path(image_id), Query(thumbnail))
+            .await?
+            .into_response()
+    } else {
+        get_image(State(state), Path(image_id), headers)
+            .await?
+            .into_response()
+    };
+    // Unlike `/images/:id`, which image this is changes when the ad's images are
+    // reordered, so clients revalidate against the image's `ETag` each time.
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GetAdParams {
+    /// Fetch without counting a view, e.g. for the owner's or an admin's preview.
+    #[serde(default)]
+    preview: bool,
+    expand: Option<Expand>,
+    /// Seconds the URLs of `?expand=signed` stay valid, see `signed_url_ttl`.
+    ttl: Option<u64>,
+}
+
+/// What `?expand=` resolves in a single ad.
+#[derive(serde::Deserialize, utoipa::ToSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Expand {
+    /// Image links instead of bare ids, see `PublicAd::with_image_links`.
+    Images,
+    /// Like `Images`, with URLs signed to fetch the images straight from storage where
+    /// the image repo supports that, see `ImageRepo::presigned_url`.
+    Signed,
+}
+
+/// The body of `get_ad` and `get_ad_by_slug`, tagged with the ad's `ETag`.
+async fn ad_response(
+    state: &AppState,
+    ad: Ad,
+    viewer: Option<&AuthUser>,
+    params: &GetAdParams,
+) -> Result<Response, RepoError> {
+    let ttl = signed_url_ttl(params.ttl).map_err(|e| RepoError::InvalidFields(vec![e]))?;
+    let etag = [(header::ETAG, ad.etag())];
+    let dimensions = match params.expand {
+        Some(_) => image_dimensions(state, &ad.images).await,
+        None => HashMap::new(),
+    };
+    let signed = match params.expand {
+        Some(Expand::Signed) => signed_image_urls(state, &ad.images, ttl).await,
+        _ => HashMap::new(),
+    };
+    let ad = PublicAd::for_viewer(ad, viewer);
+
+    Ok(match params.expand {
+        Some(_) => {
+            let ad = ad.with_signed_image_links(&state.image_base_url, &dimensions, &signed)?;
+            (etag, Json(ad)).into_response()
+        }
+        None => (etag, Json(ad)).into_response(),
+    })
 }
 
-async fn delete_ad(Path(id): Path<String>, State(state): State<AppState>) -> StatusCode {
-    // Stub implementation
-    StatusCode::NO_CONTENT
+/// Signed URLs of the images, by id. Images that can't be signed, or whose signing
+/// fails, are left out and keep their link through the API.
+async fn signed_image_urls(
+    state: &AppState,
+    image_ids: &[String],
+    ttl: std::time::Duration,
+) -> HashMap<String, SignedUrl> {
+    let mut signed = HashMap::new();
+    for image_id in image_ids {
+        match state.image_repo.presigned_url(image_id, ttl).await {
+            Ok(Some(url)) => {
+                signed.insert(image_id.clone(), url);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!(image_id = %image_id, error = %e, "failed to sign image url")
+            }
+        }
+    }
+    signed
+}
+
+/// The recorded dimensions of the images, by id. Images without any, or whose
+/// dimensions can't be read, are left out rather than failing the response.
+async fn image_dimensions(
+    state: &AppState,
+    image_ids: &[String],
+) -> HashMap<String, ImageDimensions> {
+    let mut dimensions = HashMap::new();
+    for image_id in image_ids {
+        match state.image_repo.get_dimensions(image_id).await {
+            Ok(Some(found)) => {
+                dimensions.insert(image_id.clone(), found);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!(image_id = %image_id, error = %e, "failed to read image dimensions")
+            }
+        }
+    }
+    dimensions
+}
+
+#[axum::debug_handler]
+#[utoipa::path(
+    get,
+    path = "/ads/{id}",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id"), GetAdParams),
+    responses(
+        (status = 200, description = "The ad", body = PublicAd, headers(("ETag" = String))),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+    )
+)]
+async fn get_ad(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Path(id): Path<String>,
+    Query(params): Query<GetAdParams>,
+) -> Result<impl IntoResponse, RepoError> {
+    let id: AdId = id.parse()?;
+    let viewer = viewer.as_ref().map(|Extension(user)| user);
+
+    match state.ad_repo.get_by_id(id, !params.preview).await? {
+        Some(ad) if PublicAd::is_visible_to(&ad, viewer) => {
+            ad_response(&state, ad, viewer, &params).await
+        }
+        _ => Err(RepoError::NotFound),
+    }
+}
+
+/// Whether the viewer can see the ad, with its `ETag`, without counting a view.
+#[utoipa::path(
+    head,
+    path = "/ads/{id}",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    responses(
+        (status = 200, description = "The viewer can see the ad", headers(("ETag" = String))),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+    )
+)]
+async fn head_ad(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, RepoError> {
+    let id: AdId = id.parse()?;
+    let viewer = viewer.as_ref().map(|Extension(user)| user);
+
+    match state.ad_repo.get_by_id(id, false).await? {
+        Some(ad) if PublicAd::is_visible_to(&ad, viewer) => Ok([(header::ETAG, ad.etag())]),
+        _ => Err(RepoError::NotFound),
+    }
+}
+
+/// Like `get_ad`, by slug. A slug the ad had before its title changed redirects to the
+/// current one.
+#[utoipa::path(
+    get,
+    path = "/ads/slug/{slug}",
+    tag = "ads",
+    params(
+        ("slug" = String, Path, description = "The ad's current or an earlier slug"),
+        GetAdParams,
+    ),
+    responses(
+        (status = 200, description = "The ad, as for `GET /ads/{id}`", body = PublicAd, headers(("ETag" = String))),
+        (status = 308, description = "An earlier slug; `Location` has the current one", headers(("Location" = String))),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+    )
+)]
+async fn get_ad_by_slug(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Path(slug): Path<String>,
+    Query(params): Query<GetAdParams>,
+) -> Result<Response, RepoError> {
+    let viewer = viewer.as_ref().map(|Extension(user)| user);
+
+    let ad = match state.ad_repo.get_by_slug(&slug).await? {
+        Some(ad) if PublicAd::is_visible_to(&ad, viewer) => ad,
+        _ => return Err(RepoError::NotFound),
+    };
+    if ad.slug != slug {
+        return Ok(Redirect::permanent(&format!("/ads/slug/{}", ad.slug)).into_response());
+    }
+
+    let ad = if params.preview {
+        ad
+    } else {
+        state
+            .ad_repo
+            .get_by_id(ad.id, true)
+            .await?
+            .ok_or(RepoError::NotFound)?
+    };
+    ad_response(&state, ad, viewer, &params).await
+}
+
+/// Prices the ad was changed to, oldest first, for ads the viewer can see.
+#[utoipa::path(
+    get,
+    path = "/ads/{id}/price-history",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    responses(
+        (status = 200, description = "Price changes, oldest first", body = Vec<PriceChange>),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+    )
+)]
+async fn get_price_history(
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<PriceChange>>, RepoError> {
+    let id: AdId = id.parse()?;
+    let viewer = viewer.as_ref().map(|Extension(user)| user);
+
+    match state.ad_repo.get_by_id(id, false).await? {
+        Some(ad) if PublicAd::is_visible_to(&ad, viewer) => {
+            Ok(Json(state.ad_repo.price_history(id).await?))
+        }
+        _ => Err(RepoError::NotFound),
+    }
+}
+
+#[axum::debug_handler]
+#[utoipa::path(
+    post,
+    path = "/ads",
+    tag = "ads",
+    request_body(content = AdRequest, content_type = "multipart/form-data"),
+    security(("bearer" = [])),
+    responses(
+        (status = 201, description = "The new ad", body = CreatedAd, headers(("Location" = String), ("ETag" = String))),
+        (status = 400, description = "Invalid fields or images", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 409, description = "A repost refused by `DUPLICATE_ADS=block`", body = ErrorResponse),
+        (status = 413, description = "Over the uploader's storage quota, or the body is too large", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn create_ad(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    headers: HeaderMap,
+    TypedMultipart(mut payload): TypedMultipart<AdRequest>,
+) -> Result<impl IntoResponse, RepoError> {
+    let idempotency_key = idempotency_key(&headers)?;
+
+    // A retry of an ad that was already created is answered without storing its images again.
+    if let Some(key) = &idempotency_key {
+        if let Some(ad) = state
+            .ad_repo
+            .get_by_idempotency_key(&user.email, key)
+            .await?
+        {
+            return Ok(ad_created(ad, Vec::new(), None));
+        }
+    }
+
+    let image_ids = std::mem::take(&mut payload.image_ids);
+    let uploads = std::mem::take(&mut payload.images);
+    let partial_ok = payload.partial_ok;
+    let ad = ad_content(&state, user.email, payload)?;
+
+    let duplicate_of = match state.duplicates.policy {
+        DuplicatePolicy::Off => None,
+        policy => {
+            let similar = state
+                .ad_repo
+                .find_similar(
+                    &ad.user_email,
+                    &ad.title,
+                    &ad.price,
+                    state.duplicates.since(),
+                )
+                .await?;
+            match similar {
+                Some(similar) if policy == DuplicatePolicy::Block => {
+                    return Err(RepoError::Conflict(format!(
+                        "you already posted this ad as {}",
+                        similar.id
+                    )))
+                }
+                similar => similar.map(|similar| similar.id),
+            }
+        }
+    };
+    if let Some(id) = duplicate_of {
+        metrics::counter!("duplicate_ads_total").increment(1);
+        tracing::info!(user_email = %ad.user_email, duplicate_of = %id, "ad looks like a repost");
+    }
+
+    state
+        .image_limits
+        .validate_count(image_ids.len() + uploads.len())
+        .map_err(RepoError::Validation)?;
+    check_stored_images(&state, &image_ids).await?;
+    let (images, rejected) = if partial_ok {
+        check_images(&state, uploads)
+    } else {
+        (read_images(&state, uploads)?, Vec::new())
+    };
+    let images = dedup_images(images);
+
+    let ad =
+        store_ad_with_images(&state, ad, image_ids, images, idempotency_key.as_deref()).await?;
+
+    Ok(ad_created(ad, rejected, duplicate_of))
+}
+
+/// The ad `payload` describes, for `create_ad` and `update_ad`. Its images are left to
+/// the caller.
+fn ad_content(
+    state: &AppState,
+    user_email: String,
+    payload: AdRequest,
+) -> Result<AdContent, RepoError> {
+    // Every bad field is reported at once, so a form can highlight them all.
+    let mut errors = Vec::new();
+    let ad = AdContent {
+        title: payload.title,
+        description: payload.description,
+        price: parsed(parse_price(&payload.price), &mut errors).unwrap_or_default(),
+        currency: payload
+            .currency
+            .and_then(|currency| parsed(currency.parse(), &mut errors))
+            .unwrap_or(state.base_currency),
+        user_email,
+        user_phone: payload.user_phone,
+        negotiable: payload.negotiable,
+        locale: payload
+            .locale
+            .and_then(|locale| parsed(locale.parse(), &mut errors))
+            .unwrap_or(state.default_locale),
+        category: payload
+            .category
+            .and_then(|category| parsed(category.parse(), &mut errors))
+            .unwrap_or_default(),
+        latitude: payload.latitude,
+        longitude: payload.longitude,
+        draft: payload.draft,
+    };
+    if let Err(content_errors) = ad.validate() {
+        errors.extend(content_errors);
+    }
+    if !errors.is_empty() {
+        return Err(RepoError::InvalidFields(errors));
+    }
+
+    Ok(ad)
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ImportQuery {
+    /// Create nothing unless every record is valid. Off by default.
+    #[serde(default)]
+    strict: bool,
+}
+
+/// What became of one record of an import.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ImportedRow {
+    /// Position among the records, counting from 0.
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<AdId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct ImportReport {
+    created: usize,
+    rejected: usize,
+    rows: Vec<ImportedRow>,
+}
+
+/// Creates the caller's ads from a JSON array or CSV file of `ImportRecord`s, all with a
+/// single `create_many`. Invalid records are reported and skipped, or with `strict` the
+/// whole import is rejected with the report as `details`.
+#[utoipa::path(
+    post,
+    path = "/ads/import",
+    tag = "ads",
+    params(ImportQuery),
+    request_body(content = Vec<ImportRecord>, description = "A JSON array, or a CSV file sent as `text/csv`"),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "What became of each record", body = ImportReport),
+        (status = 400, description = "Unreadable body, or with `strict`, invalid records", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 415, description = "Neither JSON nor CSV", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn import_ads(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, RepoError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or_default()
+        .trim();
+    let records = match content_type {
+        "application/json" => json_records(&body),
+        "text/csv" => std::str::from_utf8(&body)
+            .map_err(|_| "CSV must be UTF-8".to_string())
+            .and_then(csv_records),
+        _ => {
+            return Ok(ErrorResponse::from_status(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "expected application/json or text/csv",
+            )
+            .into_response())
+        }
+    }
+    .map_err(RepoError::Validation)?;
+    if records.len() > MAX_IMPORT_ROWS {
+        return Err(RepoError::Validation(format!(
+            "at most {} ads can be imported at once",
+            MAX_IMPORT_ROWS
+        )));
+    }
+
+    let mut valid = Vec::new();
+    let mut rows = Vec::new();
+    for (index, record) in records.into_iter().enumerate() {
+        let errors = match record.and_then(|record| {
+            record.into_content(&user.email, state.base_currency, state.default_locale)
+        }) {
+            Ok((ad, image_ids)) => {
+                let mut errors = Vec::new();
+                if let Err(message) = state.image_limits.validate_count(image_ids.len()) {
+                    errors.push(FieldError {
+                        field: "image_ids",
+                        message,
+                    });
+                }
+                errors.extend(stored_image_errors(&state, &image_ids).await?);
+                if errors.is_empty() {
+                    valid.push((index, (ad, image_ids)));
+                    continue;
+                }
+                errors
+            }
+            Err(errors) => errors,
+        };
+        rows.push(ImportedRow {
+            index,
+            id: None,
+            errors,
+        });
+    }
+
+    if query.strict && !rows.is_empty() {
+        return Ok(ErrorResponse::new(
+            StatusCode::BAD_REQUEST,
+            "import_rejected",
+            "one or more records are invalid, nothing was imported",
+        )
+        .with_details(serde_json::json!(rows))
+        .into_response());
+    }
+
+    let rejected = rows.len();
+    let (indices, ads): (Vec<_>, Vec<_>) = valid.into_iter().unzip();
+    let created = state.ad_repo.create_many(ads).await?;
+    for (index, ad) in indices.into_iter().zip(&created) {
+        state.ad_feed.publish(ad);
+        rows.push(ImportedRow {
+            index,
+            id: Some(ad.id),
+            errors: Vec::new(),
+        });
+    }
+    rows.sort_by_key(|row| row.index);
+
+    Ok(Json(ImportReport {
+        created: created.len(),
+        rejected,
+        rows,
+    })
+    .into_response())
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct CreatedAd {
+    #[serde(flatten)]
+    ad: Ad,
+    /// Only ever filled with `partial_ok`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    rejected_images: Vec<RejectedImage>,
+    /// A recent ad of the seller's this one repeats, see `DuplicateCheck`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<AdId>,
+}
+
+/// An upload that failed the image checks and was left out of the ad.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct RejectedImage {
+    /// Position among the request's `images`, counting from 0.
+    index: usize,
+    file_name: String,
+    reason: String,
+}
+
+fn ad_created(
+    ad: Ad,
+    rejected_images: Vec<RejectedImage>,
+    duplicate_of: Option<AdId>,
+) -> impl IntoResponse {
+    (
+        StatusCode::CREATED,
+        [
+            (header::LOCATION, format!("/ads/{}", ad.id)),
+            (header::ETAG, ad.etag()),
+        ],
+        Json(CreatedAd {
+            ad,
+            rejected_images,
+            duplicate_of,
+        }),
+    )
+}
+
+/// Checks that each of `image_ids` names a stored image, listed once, so an ad can reuse
+/// images uploaded before without sending them again.
+async fn check_stored_images(state: &AppState, image_ids: &[String]) -> Result<(), RepoError> {
+    let errors = stored_image_errors(state, image_ids).await?;
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(RepoError::InvalidFields(errors))
+    }
+}
+
+/// The ids of `image_ids` that name no stored image or are listed more than once.
+async fn stored_image_errors(
+    state: &AppState,
+    image_ids: &[String],
+) -> Result<Vec<FieldError>, RepoError> {
+    let mut errors = Vec::new();
+
+    for (index, id) in image_ids.iter().enumerate() {
+        let message = if image_ids[..index].contains(id) {
+            format!("image listed more than once: {}", id)
+        } else if !state.image_repo.exists(id).await? {
+            format!("unknown image: {}", id)
+        } else {
+            continue;
+        };
+        errors.push(FieldError {
+            field: "image_ids",
+            message,
+        });
+    }
+
+    Ok(errors)
+}
+
+/// An uploaded image's file name, bytes and detected MIME type.
+type UploadedImage = (String, Vec<u8>, &'static str);
+
+/// Reads each upload, checks it against the image limits and strips its metadata, before
+/// anything is stored. Fails on the first upload that doesn't pass.
+fn read_images(
+    state: &AppState,
+    uploads: Vec<FieldData<NamedTempFile>>,
+) -> Result<Vec<UploadedImage>, RepoError> {
+    match check_images(state, uploads) {
+        (_, rejected) if !rejected.is_empty() => {
+            Err(RepoError::Validation(rejected[0].reason.clone()))
+        }
+        (images, _) => Ok(images),
+    }
+}
+
+/// Like `read_images`, but carries on past uploads that don't pass and returns those
+/// separately, with the reason.
+fn check_images(
+    state: &AppState,
+    uploads: Vec<FieldData<NamedTempFile>>,
+) -> (Vec<UploadedImage>, Vec<RejectedImage>) {
+    let mut images = Vec::new();
+    let mut rejected = Vec::new();
+
+    for (index, image) in uploads.into_iter().enumerate() {
+        let file_name = image.metadata.file_name.unwrap_or_default();
+        let image_data: Vec<u8> = image.contents.bytes().filter_map(Result::ok).collect();
+        let checked = state
+            .image_limits
+            .validate(&file_name, &image_data)
+            .and_then(|mime_type| Ok((mime_type, exif::strip_metadata(mime_type, image_data)?)));
+
+        match checked {
+            Ok((mime_type, image_data)) => images.push((file_name, image_data, mime_type)),
+            Err(reason) => rejected.push(RejectedImage {
+                index,
+                file_name,
+                reason,
+            }),
+        }
+    }
+
+    (images, rejected)
+}
+
+/// Drops uploads whose bytes, once stripped of metadata, match an earlier upload of the
+/// same request, e.g. a photo picked twice, so the ad lists it once.
+fn dedup_images(images: Vec<UploadedImage>) -> Vec<UploadedImage> {
+    let uploaded = images.len();
+    let mut seen = HashSet::new();
+    let images: Vec<_> = images
+        .into_iter()
+        .filter(|(_, image_data, _)| seen.insert(Sha256::digest(image_data)))
+        .collect();
+
+    let collapsed = uploaded - images.len();
+    if collapsed > 0 {
+        tracing::info!(collapsed, "collapsed duplicate uploads");
+    }
+    images
+}
+
+/// Stores the images for `user_email`, returning their ids. Fails with `QuotaExceeded`
+/// before storing any if they would take the user past `ImageLimits::quota_bytes`; two
+/// uploads checked at the same time may together end up slightly over it. If one fails,
+/// those already stored are deleted again.
+async fn store_images(
+    state: &AppState,
+    user_email: &str,
+    images: Vec<UploadedImage>,
+) -> Result<Vec<String>, RepoError> {
+    if images.is_empty() {
+        return Ok(Vec::new());
+    }
+    let adding: u64 = images.iter().map(|(_, data, _)| data.len() as u64).sum();
+    let used = state.image_usage_repo.used_bytes(user_email).await?;
+    state
+        .image_limits
+        .validate_quota(used, adding)
+        .map_err(RepoError::QuotaExceeded)?;
+
+    let mut image_ids = Vec::new();
+
+    for (file_name, image_data, mime_type) in images {
+        let bytes = image_data.len() as u64;
+        let stored = match state
+            .image_repo
+            .create_image(file_name, image_data, mime_type.to_string())
+            .await
+        {
+            Ok(image_id) => {
+                image_ids.push(image_id.clone());
+                state
+                    .image_usage_repo
+                    .record(user_email, &image_id, bytes)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            discard_images(state, &image_ids).await;
+            return Err(e);
+        }
+    }
+
+    Ok(image_ids)
+}
+
+/// Stores the images, then the ad row, which lists `stored_ids` ahead of them. If any step
+/// fails, the images stored for this request are deleted again so nothing is left
+/// orphaned. With an `idempotency_key` the owner already used, the earlier ad is returned
+/// and this request's images discarded. Only a newly created ad is published to the feed.
+async fn store_ad_with_images(
+    state: &AppState,
+    ad: AdContent,
+    stored_ids: Vec<String>,
+    images: Vec<UploadedImage>,
+    idempotency_key: Option<&str>,
+) -> Result<Ad, RepoError> {
+    let image_ids = store_images(state, &ad.user_email, images).await?;
+    let all_ids: Vec<String> = stored_ids.into_iter().chain(image_ids.clone()).collect();
+
+    let created = match idempotency_key {
+        Some(key) => state.ad_repo.create_idempotent(ad, all_ids, key).await,
+        None => state.ad_repo.create(ad, all_ids).await.map(|ad| (ad, true)),
+    };
+
+    match created {
+        Ok((ad, true)) => {
+            // Drafts reach the feed once they are published.
+            if ad.status != AdStatus::Draft.as_str() {
+                state.ad_feed.publish(&ad);
+            }
+            Ok(ad)
+        }
+        Ok((ad, false)) => {
+            discard_images(state, &image_ids).await;
+            Ok(ad)
+        }
+        Err(e) => {
+            discard_images(state, &image_ids).await;
+            Err(e)
+        }
+    }
+}
+
+/// Deletes stored images no ad lists anymore. Another ad may still list one, either as a
+/// deduplicated upload or through `image_ids`, and those are kept.
+async fn discard_images(state: &AppState, image_ids: &[String]) {
+    let in_use = match state.ad_repo.images_in_use(image_ids).await {
+        Ok(in_use) => in_use,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to check which images are in use, keeping them");
+            return;
+        }
+    };
+
+    let unused: Vec<&str> = image_ids
+        .iter()
+        .filter(|id| !in_use.contains(id))
+        .map(String::as_str)
+        .collect();
+    let results = match state.image_repo.delete_images(&unused).await {
+        Ok(results) => results,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to discard images");
+            return;
+        }
+    };
+
+    let mut deleted = Vec::new();
+    for (image_id, result) in results {
+        match result {
+            Ok(()) => deleted.push(image_id),
+            Err(e) => tracing::warn!(image_id = %image_id, error = %e, "failed to discard image"),
+        }
+    }
+
+    // Deleted images no longer count against their uploaders' quota.
+    if let Err(e) = state.image_usage_repo.forget(&deleted).await {
+        tracing::warn!(error = %e, "failed to forget the usage of discarded images");
+    }
+}
+
+/// Replaces the ad's fields with the form's, checked as in `create_ad`. The status and
+/// promotion are left as they are, and images are replaced with `PUT /ads/:id/images`.
+#[utoipa::path(
+    put,
+    path = "/ads/{id}",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body(content = AdRequest, content_type = "multipart/form-data", description = "Every field of the ad; images are left out"),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The updated ad", body = Ad, headers(("ETag" = String))),
+        (status = 400, description = "Invalid id or fields, or images sent", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn update_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    TypedMultipart(payload): TypedMultipart<AdRequest>,
+) -> Result<impl IntoResponse, RepoError> {
+    let id: AdId = id.parse()?;
+    if !payload.images.is_empty() || !payload.image_ids.is_empty() {
+        return Err(RepoError::Validation(
+            "images are replaced with PUT /ads/:id/images".to_string(),
+        ));
+    }
+    let ad = ad_content(&state, user.email.clone(), payload)?;
+
+    match state.ad_repo.update(id, &user.email, ad).await? {
+        Some(ad) => Ok(([(header::ETAG, ad.etag())], Json(ad))),
+        None => Err(not_owned_error(&state, id).await?),
+    }
+}
+
+/// Requires `If-Match` with the `ETag` the ad was fetched with, so an edit made in the
+/// meantime isn't overwritten; `*` skips the check.
+#[utoipa::path(
+    patch,
+    path = "/ads/{id}",
+    tag = "ads",
+    params(
+        ("id" = i32, Path, description = "The ad's id"),
+        ("If-Match" = String, Header, description = "The ad's `ETag`, or `*` to skip the check"),
+    ),
+    request_body = AdPatch,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The updated ad", body = Ad, headers(("ETag" = String))),
+        (status = 400, description = "Invalid id or fields", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 412, description = "The ad changed since the `If-Match` version", body = ErrorResponse),
+        (status = 428, description = "No `If-Match` header", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn patch_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    headers: HeaderMap,
+    Json(changes): Json<AdPatch>,
+) -> Result<impl IntoResponse, RepoError> {
+    let id: AdId = id.parse()?;
+    let expected_updated_at = if_match(&headers)?;
+    changes.validate().map_err(RepoError::InvalidFields)?;
+
+    match state
+        .ad_repo
+        .patch(id, &user.email, changes, expected_updated_at)
+        .await?
+    {
+        Some(ad) => Ok(([(header::ETAG, ad.etag())], Json(ad))),
+        None => Err(match state.ad_repo.get_by_id(id, false).await? {
+            Some(ad)
+                if ad.user_email == user.email
+                    && ad.status != AdStatus::Hidden.as_str()
+                    && expected_updated_at.is_some_and(|at| at != ad.updated_at) =>
+            {
+                RepoError::PreconditionFailed
+            }
+            Some(_) => RepoError::Forbidden,
+            None => RepoError::NotFound,
+        }),
+    }
+}
+
+/// Publishes the caller's draft as if it had just been created. Someone else's draft is
+/// as invisible here as anywhere else.
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/publish",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The published ad", body = Ad, headers(("ETag" = String))),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 404, description = "No such draft", body = ErrorResponse),
+        (status = 409, description = "Not a draft", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn publish_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<impl IntoResponse, RepoError> {
+    let id: AdId = id.parse()?;
+
+    match state.ad_repo.publish(id, &user.email).await? {
+        Some(ad) => {
+            state.ad_feed.publish(&ad);
+            Ok(([(header::ETAG, ad.etag())], Json(ad)))
+        }
+        None => Err(match state.ad_repo.get_by_id(id, false).await? {
+            Some(ad) if ad.user_email == user.email => {
+                RepoError::Conflict("ad is not a draft".to_string())
+            }
+            Some(ad) if PublicAd::is_visible_to(&ad, Some(&user)) => RepoError::Forbidden,
+            _ => RepoError::NotFound,
+        }),
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct PromoteRequest {
+    until: chrono::DateTime<chrono::Utc>,
+}
+
+/// Promotes the caller's active ad until `until`, after which the expiry job drops it
+/// back among the other ads.
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/promote",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body = PromoteRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The promoted ad", body = Ad, headers(("ETag" = String))),
+        (status = 400, description = "Invalid id, or `until` is not in the future", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 409, description = "The ad is not active", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn promote_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(request): Json<PromoteRequest>,
+) -> Result<impl IntoResponse, RepoError> {
+    let id: AdId = id.parse()?;
+    if request.until <= chrono::Utc::now() {
+        return Err(RepoError::Validation(
+            "until must be in the future".to_string(),
+        ));
+    }
+
+    match state
+        .ad_repo
+        .promote(id, &user.email, request.until)
+        .await?
+    {
+        Some(ad) => Ok(([(header::ETAG, ad.etag())], Json(ad))),
+        None => Err(match state.ad_repo.get_by_id(id, false).await? {
+            Some(ad) if ad.user_email == user.email => {
+                RepoError::Conflict("only active ads can be promoted".to_string())
+            }
+            Some(ad) if PublicAd::is_visible_to(&ad, Some(&user)) => RepoError::Forbidden,
+            _ => RepoError::NotFound,
+        }),
+    }
+}
+
+/// Bumps the caller's active ad to the top of the newest ads without editing it, at most
+/// once per `RENEW_COOLDOWN_HOURS`.
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/renew",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The renewed ad", body = Ad, headers(("ETag" = String))),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 409, description = "The ad is not active", body = ErrorResponse),
+        (status = 429, description = "Renewed too recently", body = ErrorResponse, headers(("Retry-After" = u64))),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn renew_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<impl IntoResponse, RepoError> {
+    let id: AdId = id.parse()?;
+
+    match state.ad_repo.renew(id, &user.email).await? {
+        Some(ad) => Ok(([(header::ETAG, ad.etag())], Json(ad))),
+        None => Err(match state.ad_repo.get_by_id(id, false).await? {
+            Some(ad) if ad.user_email == user.email && ad.status == AdStatus::Active.as_str() => {
+                let renewable_at = ad.created_at + chrono::Duration::hours(RENEW_COOLDOWN_HOURS);
+                let wait = (renewable_at - chrono::Utc::now()).num_seconds();
+                RepoError::TooSoon(u64::try_from(wait).unwrap_or_default().max(1))
+            }
+            Some(ad) if ad.user_email == user.email => {
+                RepoError::Conflict("only active ads can be renewed".to_string())
+            }
+            Some(ad) if PublicAd::is_visible_to(&ad, Some(&user)) => RepoError::Forbidden,
+            _ => RepoError::NotFound,
+        }),
+    }
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct VerifyPhoneRequest {
+    code: String,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct PhoneCodeSent {
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Verifies the phone of the caller's ad in two steps. Without a body, a new six-digit
+/// code is made for the ad's number and 202 is returned with when it expires; with
+/// `{"code": ...}`, a matching code marks the phone verified and returns the ad.
+///
+/// Codes aren't sent by SMS yet, only logged, so this is a stub until a provider is
+/// wired in.
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/verify-phone",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body(content = Option<VerifyPhoneRequest>, description = "The code, or nothing to have a new one made"),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The code matched", body = Ad, headers(("ETag" = String))),
+        (status = 202, description = "A new code was made", body = PhoneCodeSent),
+        (status = 400, description = "Invalid id, or the code is wrong or expired", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn verify_phone(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    payload: Option<Json<VerifyPhoneRequest>>,
+) -> Result<Response, RepoError> {
+    let id: AdId = id.parse()?;
+
+    let Some(Json(request)) = payload else {
+        let code = format!("{:06}", rand::rng().random_range(0..1_000_000));
+        return match state
+            .ad_repo
+            .start_phone_verification(id, &user.email, &code)
+            .await?
+        {
+            Some(expires_at) => {
+                tracing::info!(ad_id = id.0, code = %code, "SMS is not configured, logging code");
+                Ok((StatusCode::ACCEPTED, Json(PhoneCodeSent { expires_at })).into_response())
+            }
+            None => Err(match state.ad_repo.get_by_id(id, false).await? {
+                Some(ad) if PublicAd::is_visible_to(&ad, Some(&user)) => RepoError::Forbidden,
+                _ => RepoError::NotFound,
+            }),
+        };
+    };
+
+    match state
+        .ad_repo
+        .verify_phone(id, &user.email, request.code.trim())
+        .await?
+    {
+        Some(ad) => Ok(([(header::ETAG, ad.etag())], Json(ad)).into_response()),
+        None => Err(match state.ad_repo.get_by_id(id, false).await? {
+            Some(ad) if ad.user_email == user.email => {
+                RepoError::Validation("code is wrong or has expired".to_string())
+            }
+            Some(ad) if PublicAd::is_visible_to(&ad, Some(&user)) => RepoError::Forbidden,
+            _ => RepoError::NotFound,
+        }),
+    }
+}
+
+/// The parsed field, or `None` with its failure added to `errors`.
+fn parsed<T>(result: Result<T, FieldError>, errors: &mut Vec<FieldError>) -> Option<T> {
+    result.map_err(|e| errors.push(e)).ok()
+}
+
+/// The version `If-Match` asks for, or `None` for `*`. An `ETag` that isn't one of ours
+/// can't match any version.
+fn if_match(headers: &HeaderMap) -> Result<Option<chrono::DateTime<chrono::Utc>>, RepoError> {
+    let value = headers
+        .get(header::IF_MATCH)
+        .ok_or(RepoError::PreconditionRequired)?
+        .to_str()
+        .map_err(|_| RepoError::PreconditionFailed)?
+        .trim();
+    if value == "*" {
+        return Ok(None);
+    }
+    parse_etag(value)
+        .map(Some)
+        .ok_or(RepoError::PreconditionFailed)
+}
+
+/// Takes the ad's image ids as a JSON array in their new order; the first is the
+/// primary image.
+#[utoipa::path(
+    put,
+    path = "/ads/{id}/images/order",
+    tag = "images",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body(content = Vec<String>, description = "Every image id of the ad, in the new order"),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The reordered ad", body = Ad),
+        (status = 400, description = "Invalid id, or not exactly the ad's images", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn reorder_images(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(image_ids): Json<Vec<String>>,
+) -> Result<Json<Ad>, RepoError> {
+    let id: AdId = id.parse()?;
+
+    match state
+        .ad_repo
+        .reorder_images(id, &user.email, image_ids)
+        .await?
+    {
+        Some(ad) => Ok(Json(ad)),
+        None => Err(not_owned_error(&state, id).await?),
+    }
+}
+
+/// Ids of images stored by `POST /images`, to list as `image_ids` when creating an ad.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct UploadedImages {
+    image_ids: Vec<String>,
+}
+
+/// Stores images ahead of the ad that will list them, checked like uploads sent with the
+/// ad. They count against the uploader's quota, though no ad lists them yet.
+#[utoipa::path(
+    post,
+    path = "/images",
+    tag = "images",
+    request_body(content = ImagesRequest, content_type = "multipart/form-data"),
+    security(("bearer" = [])),
+    responses(
+        (status = 201, description = "The stored images' ids", body = UploadedImages),
+        (status = 400, description = "No images, or invalid ones", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 413, description = "Over the uploader's storage quota, or the body is too large", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn upload_images(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    TypedMultipart(payload): TypedMultipart<ImagesRequest>,
+) -> Result<impl IntoResponse, RepoError> {
+    if payload.images.is_empty() {
+        return Err(RepoError::Validation(
+            "at least one image is required".to_string(),
+        ));
+    }
+    state
+        .image_limits
+        .validate_count(payload.images.len())
+        .map_err(RepoError::Validation)?;
+    let images = read_images(&state, payload.images)?;
+
+    let image_ids = store_images(&state, &user.email, images).await?;
+
+    Ok((StatusCode::CREATED, Json(UploadedImages { image_ids })))
+}
+
+/// Appends the uploaded `images` to the ad. The owner is checked before anything is
+/// stored; the count limit is checked again when the ad row is updated.
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/images",
+    tag = "images",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body(content = ImagesRequest, content_type = "multipart/form-data"),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The ad with the images appended", body = Ad),
+        (status = 400, description = "Invalid id or images, or too many", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 413, description = "Over the uploader's storage quota, or the body is too large", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn add_images(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    TypedMultipart(payload): TypedMultipart<ImagesRequest>,
+) -> Result<Json<Ad>, RepoError> {
+    let id: AdId = id.parse()?;
+
+    match state.ad_repo.get_owner(id).await? {
+        Some(owner) if owner != user.email => return Err(RepoError::Forbidden),
+        Some(_) => {}
+        None => return Err(RepoError::NotFound),
+    };
+    if payload.images.is_empty() {
+        return Err(RepoError::Validation(
+            "at least one image is required".to_string(),
+        ));
+    }
+    state
+        .image_limits
+        .validate_count(payload.images.len())
+        .map_err(RepoError::Validation)?;
+    let images = read_images(&state, payload.images)?;
+
+    let image_ids = store_images(&state, &user.email, images).await?;
+    let added = state
+        .ad_repo
+        .add_images(
+            id,
+            &user.email,
+            image_ids.clone(),
+            state.image_limits.max_count,
+        )
+        .await;
+
+    match added {
+        Ok(Some(ad)) => Ok(Json(ad)),
+        Ok(None) => {
+            discard_images(&state, &image_ids).await;
+            Err(not_owned_error(&state, id).await?)
+        }
+        Err(e) => {
+            discard_images(&state, &image_ids).await;
+            Err(e)
+        }
+    }
+}
+
+/// Replaces the ad's images in one request: the `keep` fields name current images to hold
+/// on to, in order, and the uploaded `images` follow them. Images left out are deleted
+/// once the ad no longer lists them.
+#[utoipa::path(
+    put,
+    path = "/ads/{id}/images",
+    tag = "images",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body(content = ReplaceImagesRequest, content_type = "multipart/form-data"),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The ad with its new images", body = Ad),
+        (status = 400, description = "Invalid id or images, or too many", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 413, description = "Over the uploader's storage quota, or the body is too large", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn replace_images(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    TypedMultipart(payload): TypedMultipart<ReplaceImagesRequest>,
+) -> Result<Json<Ad>, RepoError> {
+    let id: AdId = id.parse()?;
+
+    match state.ad_repo.get_owner(id).await? {
+        Some(owner) if owner != user.email => return Err(RepoError::Forbidden),
+        Some(_) => {}
+        None => return Err(RepoError::NotFound),
+    };
+    state
+        .image_limits
+        .validate_count(payload.keep.len() + payload.images.len())
+        .map_err(RepoError::Validation)?;
+    let images = read_images(&state, payload.images)?;
+
+    let image_ids = store_images(&state, &user.email, images).await?;
+    let replaced = state
+        .ad_repo
+        .set_images(
+            id,
+            &user.email,
+            payload.keep,
+            image_ids.clone(),
+            state.image_limits.max_count,
+        )
+        .await;
+
+    match replaced {
+        Ok(Some((ad, dropped))) => {
+            discard_images(&state, &dropped).await;
+            Ok(Json(ad))
+        }
+        Ok(None) => {
+            discard_images(&state, &image_ids).await;
+            Err(not_owned_error(&state, id).await?)
+        }
+        Err(e) => {
+            discard_images(&state, &image_ids).await;
+            Err(e)
+        }
+    }
+}
+
+/// Removes one image from the ad, then deletes the stored file.
+#[utoipa::path(
+    delete,
+    path = "/ads/{id}/images/{image_id}",
+    tag = "images",
+    params(
+        ("id" = i32, Path, description = "The ad's id"),
+        ("image_id" = String, Path, description = "The image's id"),
+    ),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The ad without the image", body = Ad),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad or image", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn remove_image(
+    Path((id, image_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Json<Ad>, RepoError> {
+    let id: AdId = id.parse()?;
+
+    let Some(ad) = state
+        .ad_repo
+        .remove_image(id, &user.email, &image_id)
+        .await?
+    else {
+        return Err(not_owned_error(&state, id).await?);
+    };
+    discard_images(&state, &[image_id]).await;
+
+    Ok(Json(ad))
+}
+
+/// Why a write filtered on the owner matched no row: someone else's ad, or no such ad.
+/// Only checked after the write, so the common case costs a single query.
+async fn not_owned_error(state: &AppState, id: AdId) -> Result<RepoError, RepoError> {
+    Ok(if state.ad_repo.exists(id).await? {
+        RepoError::Forbidden
+    } else {
+        RepoError::NotFound
+    })
+}
+
+#[utoipa::path(
+    delete,
+    path = "/ads/{id}",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "Deleted; an admin can still restore it"),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Someone else's ad", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn delete_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<StatusCode, RepoError> {
+    let id: AdId = id.parse()?;
+
+    // Only soft-deleted; the images stay so the ad can be restored until it is purged.
+    match state.ad_repo.delete(id, &user.email).await? {
+        0 => Err(not_owned_error(&state, id).await?),
+        _ => Ok(StatusCode::NO_CONTENT),
+    }
+}
+
+/// Undoes an owner's delete. Admin only.
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/restore",
+    tag = "admin",
+    params(("id" = i32, Path, description = "The ad's id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The restored ad", body = Ad),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such deleted ad", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn restore_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<Ad>, RepoError> {
+    let id: AdId = id.parse()?;
+
+    state
+        .ad_repo
+        .restore(id)
+        .await?
+        .map(Json)
+        .ok_or(RepoError::NotFound)
+}
+
+/// Removes a soft-deleted ad for good, then its images. Admin only.
+#[utoipa::path(
+    delete,
+    path = "/ads/{id}/purge",
+    tag = "admin",
+    params(("id" = i32, Path, description = "The ad's id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "Removed for good"),
+        (status = 400, description = "Invalid id", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such deleted ad", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn purge_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, RepoError> {
+    let id: AdId = id.parse()?;
+
+    let ad = state.ad_repo.purge(id).await?.ok_or(RepoError::NotFound)?;
+    // The row is already gone, so a failed image delete is logged rather than retried.
+    discard_images(&state, &ad.images).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct DeletedRes {
+    deleted: usize,
+}
+
+/// A seller's ads for their profile page, newest first. The seller themselves and admins
+/// also see drafts and ads that are no longer listed.
+#[utoipa::path(
+    get,
+    path = "/users/{email}/ads",
+    tag = "users",
+    params(
+        ("email" = String, Path, description = "The seller's email"),
+        PageParams,
+    ),
+    responses(
+        (status = 200, description = "A page of the seller's ads", body = PaginatedRes<PublicAd>),
+        (status = 400, description = "Invalid paging", body = ErrorResponse),
+    )
+)]
+async fn get_user_ads(
+    Path(email): Path<String>,
+    State(state): State<AppState>,
+    viewer: Option<Extension<AuthUser>>,
+    OriginalUri(uri): OriginalUri,
+    Query(page): Query<PageParams>,
+) -> Result<Json<PaginatedRes<PublicAd>>, RepoError> {
+    let per_page = state.page_limits.per_page(page.per_page)?;
+    let offset = state.page_limits.offset(page.offset)?;
+    let include_unlisted = viewer
+        .as_ref()
+        .is_some_and(|viewer| viewer.email == email || viewer.is_admin);
+
+    let (items, total) = state
+        .ad_repo
+        .get_by_user(&email, include_unlisted, offset, per_page)
+        .await?;
+    state.page_limits.check_offset(offset, total)?;
+
+    Ok(Json(PaginatedRes::new(
+        public_ads(items, viewer.as_ref()),
+        total,
+        &uri,
+        offset,
+        per_page,
+    )))
+}
+
+/// Removes all of a user's ads for good, e.g. when they close their account. Allowed for
+/// the user themselves and for admins.
+#[utoipa::path(
+    delete,
+    path = "/users/{email}/ads",
+    tag = "users",
+    params(("email" = String, Path, description = "The seller's email")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "How many ads were removed", body = DeletedRes),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Neither the user nor an admin", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn delete_user_ads(
+    Path(email): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<Json<DeletedRes>, RepoError> {
+    if user.email != email && !user.is_admin {
+        return Err(RepoError::Forbidden);
+    }
+
+    let ads = state.ad_repo.delete_by_user(&email).await?;
+    let mut image_ids = Vec::new();
+    for ad in &ads {
+        image_ids.extend(ad.images.iter().cloned());
+    }
+
+    // Images are only discarded once the rows are gone for good, so a failed delete never
+    // leaves an ad pointing at missing images; a failed image delete is logged instead.
+    discard_images(&state, &image_ids).await;
+
+    Ok(Json(DeletedRes { deleted: ads.len() }))
+}
+
+/// Ads fetched per query while an export is written.
+const EXPORT_PAGE_SIZE: u32 = 100;
+
+/// Bytes an export is written ahead of the client reading them.
+const EXPORT_BUFFER_BYTES: usize = 64 * 1024;
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+/// All of a user's ads, drafts and hidden ones included, as a JSON or CSV download for
+/// the user themselves or an admin. The body is written a page of ads at a time while it
+/// is sent, so a large account is never held in memory at once.
+#[utoipa::path(
+    get,
+    path = "/users/{email}/export",
+    tag = "users",
+    params(
+        ("email" = String, Path, description = "The seller's email"),
+        ExportQuery,
+    ),
+    security(("bearer" = [])),
+    responses(
+        (
+            status = 200,
+            description = "Every ad of the user's as a download, with image links as for `?expand=images`",
+            content((Vec<Ad> = "application/json"), (String = "text/csv")),
+            headers(("Content-Disposition" = String))
+        ),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Neither the user nor an admin", body = ErrorResponse),
+    )
+)]
+async fn export_user_ads(
+    Path(email): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, RepoError> {
+    if user.email != email && !user.is_admin {
+        return Err(RepoError::Forbidden);
+    }
+
+    // Fetched before the response starts, so a failure here still gets an error status.
+    let (first_page, _) = state
+        .ad_repo
+        .get_by_user(&email, true, 0, EXPORT_PAGE_SIZE)
+        .await?;
+    let format = query.format;
+    let (mut writer, reader) = tokio::io::duplex(EXPORT_BUFFER_BYTES);
+    tokio::spawn(async move {
+        let written = write_export(&state, &email, &user, format, first_page, &mut writer).await;
+        // The response is already under way, so it just ends early.
+        if let Err(e) = written {
+            tracing::warn!(email = %email, error = %e, "failed to write export");
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", format.file_name()),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+/// Writes `email`'s ads to `writer`, starting with `page`, the first of them. Stops with
+/// an error once the client goes away.
+async fn write_export(
+    state: &AppState,
+    email: &str,
+    viewer: &AuthUser,
+    format: ExportFormat,
+    mut page: Vec<Ad>,
+    writer: &mut DuplexStream,
+) -> Result<(), RepoError> {
+    writer.write_all(format.start().as_bytes()).await?;
+
+    let mut offset = 0;
+    let mut first = true;
+    loop {
+        let fetched = page.len();
+        for ad in page {
+            // Only JSON lists the images with their dimensions.
+            let dimensions = match format {
+                ExportFormat::Json => image_dimensions(state, &ad.images).await,
+                ExportFormat::Csv => HashMap::new(),
+            };
+            let record = format.record(ad, viewer, &state.image_base_url, &dimensions, first)?;
+            writer.write_all(record.as_bytes()).await?;
+            first = false;
+        }
+        if fetched < EXPORT_PAGE_SIZE as usize {
+            break;
+        }
+        offset += EXPORT_PAGE_SIZE;
+        (page, _) = state
+            .ad_repo
+            .get_by_user(email, true, offset, EXPORT_PAGE_SIZE)
+            .await?;
+    }
+
+    writer.write_all(format.end().as_bytes()).await?;
+    Ok(())
+}
+
+/// Emails the ad's owner on a buyer's behalf, through the outbox, so the message is
+/// accepted even while the mail server is down. The owner's address stays private: the
+/// response is empty and the owner answers via `reply_to`.
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/contact",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body = ContactRequest,
+    responses(
+        (status = 202, description = "The message is queued for the seller"),
+        (status = 400, description = "Invalid id or fields", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 429, description = "Too many messages from this address", body = ErrorResponse, headers(("Retry-After" = u64))),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn contact_seller(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(request): Json<ContactRequest>,
+) -> Result<StatusCode, RepoError> {
+    let id: AdId = id.parse()?;
+    request.validate().map_err(RepoError::InvalidFields)?;
+    let ad = state
+        .ad_repo
+        .get_by_id(id, false)
+        .await?
+        .filter(|ad| PublicAd::is_visible_to(ad, None))
+        .ok_or(RepoError::NotFound)?;
+
+    state
+        .outbox_repo
+        .enqueue(ContactMessage {
+            to: ad.user_email,
+            subject: format!("Re: {}", ad.title),
+            body: format!(
+                "A buyer sent a message about your ad \"{}\":\n\n{}\n\n\
+                 Reply to this email to answer them.",
+                ad.title, request.message
+            ),
+            reply_to: request.reply_to,
+        })
+        .await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Files an abuse report for the moderation queue. Open to anonymous users, so it has
+/// its own per-IP rate limit.
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/report",
+    tag = "ads",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body = ReportRequest,
+    responses(
+        (status = 202, description = "The report is filed"),
+        (status = 400, description = "Invalid id or reason", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 429, description = "Too many reports from this address", body = ErrorResponse, headers(("Retry-After" = u64))),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn report_ad(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(request): Json<ReportRequest>,
+) -> Result<StatusCode, RepoError> {
+    let id: AdId = id.parse()?;
+    request.validate().map_err(RepoError::InvalidFields)?;
+    state.report_repo.add(id, request.reason.trim()).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ReportsParams {
+    /// Leave out ads with fewer reports. Defaults to `DEFAULT_REPORT_THRESHOLD`.
+    min_reports: Option<u32>,
+}
+
+/// The moderation queue: reported ads, most reported first. Admin only.
+#[utoipa::path(
+    get,
+    path = "/reports",
+    tag = "admin",
+    params(ReportsParams),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Reported ads, most reported first", body = Vec<ReportedAd>),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    )
+)]
+async fn get_reports(
+    State(state): State<AppState>,
+    Query(params): Query<ReportsParams>,
+) -> Result<Json<Vec<ReportedAd>>, RepoError> {
+    let min_reports = params.min_reports.unwrap_or(DEFAULT_REPORT_THRESHOLD);
+    Ok(Json(state.report_repo.list_reported(min_reports).await?))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct RepairRes {
+    id: String,
+    outcome: RepairOutcome,
+}
+
+/// Rebuilds an image's lost or corrupted metadata from its bytes, so it can be served
+/// again. Admin only.
+#[utoipa::path(
+    post,
+    path = "/images/{id}/repair",
+    tag = "admin",
+    params(("id" = String, Path, description = "The image's id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "What was wrong and whether it was fixed", body = RepairRes),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such image", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn repair_image(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<RepairRes>, RepoError> {
+    let outcome = state.image_repo.repair(&id).await?;
+    if outcome != RepairOutcome::Intact {
+        tracing::info!(image_id = %id, outcome = ?outcome, "repaired image metadata");
+    }
+
+    Ok(Json(RepairRes { id, outcome }))
+}
+
+/// Figures for the admin dashboard. Admin only.
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "admin",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Figures for the dashboard", body = AdStats),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    )
+)]
+async fn get_stats(State(state): State<AppState>) -> Result<Json<AdStats>, RepoError> {
+    Ok(Json(state.ad_repo.stats().await?))
+}
+
+#[derive(serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+struct MaintenanceMode {
+    enabled: bool,
+}
+
+/// Whether writes are currently turned away. Admin only.
+#[utoipa::path(
+    get,
+    path = "/maintenance",
+    tag = "admin",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Whether maintenance mode is on", body = MaintenanceMode),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    )
+)]
+async fn get_maintenance(State(state): State<AppState>) -> Json<MaintenanceMode> {
+    Json(MaintenanceMode {
+        enabled: state.maintenance.is_enabled(),
+    })
+}
+
+/// Switches maintenance mode on or off, e.g. around a migration. Admin only. The flag
+/// lives in this process, so with several instances each has to be switched, or started
+/// with `MAINTENANCE_MODE` instead.
+#[utoipa::path(
+    put,
+    path = "/maintenance",
+    tag = "admin",
+    request_body = MaintenanceMode,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Maintenance mode as it now is", body = MaintenanceMode),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+    )
+)]
+async fn set_maintenance(
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+    Json(mode): Json<MaintenanceMode>,
+) -> Json<MaintenanceMode> {
+    state.maintenance.set_enabled(mode.enabled);
+    tracing::warn!(admin = %user.email, enabled = mode.enabled, "maintenance mode switched");
+
+    Json(mode)
+}
+
+#[derive(serde::Deserialize, utoipa::ToSchema)]
+struct StatusRequest {
+    status: AdStatus,
+}
+
+/// Sets any ad's status, e.g. `hidden` to take down an offending ad or `active` to bring
+/// it back. Admin only.
+#[utoipa::path(
+    put,
+    path = "/ads/{id}/status",
+    tag = "admin",
+    params(("id" = i32, Path, description = "The ad's id")),
+    request_body = StatusRequest,
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The ad with its new status", body = Ad),
+        (status = 400, description = "Invalid id or status", body = ErrorResponse),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 403, description = "Not an admin", body = ErrorResponse),
+        (status = 404, description = "No such ad", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn set_ad_status(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(request): Json<StatusRequest>,
+) -> Result<Json<Ad>, RepoError> {
+    let id: AdId = id.parse()?;
+
+    state
+        .ad_repo
+        .set_status(id, request.status)
+        .await?
+        .map(Json)
+        .ok_or(RepoError::NotFound)
+}
+
+#[utoipa::path(
+    post,
+    path = "/ads/{id}/favorite",
+    tag = "favorites",
+    params(("id" = i32, Path, description = "The ad's id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "Saved, or already saved"),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 404, description = "No such ad, or not one the caller can see", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn add_favorite(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<StatusCode, RepoError> {
+    let id: AdId = id.parse()?;
+    match state.ad_repo.get_by_id(id, false).await? {
+        Some(ad) if PublicAd::is_visible_to(&ad, Some(&user)) => {
+            state.favorite_repo.add(&user.email, id).await?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        _ => Err(RepoError::NotFound),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/ads/{id}/favorite",
+    tag = "favorites",
+    params(("id" = i32, Path, description = "The ad's id")),
+    security(("bearer" = [])),
+    responses(
+        (status = 204, description = "No longer saved"),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+        (status = 503, description = "Down for maintenance", body = ErrorResponse, headers(("Retry-After" = u64))),
+    )
+)]
+async fn remove_favorite(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Extension(user): Extension<AuthUser>,
+) -> Result<StatusCode, RepoError> {
+    let id: AdId = id.parse()?;
+    state.favorite_repo.remove(&user.email, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/favorites",
+    tag = "favorites",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The caller's saved ads, with other sellers' contact details masked", body = Vec<PublicAd>),
+        (status = 401, description = "Not signed in", body = ErrorResponse),
+    )
+)]
+async fn get_favorites(
+    State(state): State<AppState>,
+    user: Extension<AuthUser>,
+) -> Result<Json<Vec<PublicAd>>, RepoError> {
+    let ads = state.favorite_repo.list_for_user(&user.email).await?;
+    Ok(Json(public_ads(ads, Some(&user))))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env,
+        future::IntoFuture,
+        io::Read,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use axum::{
+        async_trait,
+        body::{to_bytes, Body},
+        http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    };
+    use bazaars::{
+        auth::{Claims, JwtKeys, Role},
+        cors::CorsConfig,
+        db, exif,
+        feed::AdFeed,
+        jobs::{self, OutboxConfig},
+        maintenance::Maintenance,
+        models::{
+            ad::{AdCategory, AdContent, AdId, AdPatch, Currency, Locale},
+            image::{ImageDimensions, ImageLimits},
+        },
+        notify::{ContactMessage, Notifier},
+        rate_limit::RateLimitConfig,
+        repos::{
+            ad_repo::{
+                AdFilter, AdRepo, DuplicateCheck, DuplicatePolicy, PageLimits, PostgresAdRepo,
+                MAX_OFFSET,
+            },
+            error::RepoError,
+            favorite_repo::PostgresFavoriteRepo,
+            image_repo::{ImageRepo, LocalImageRepo},
+            image_usage_repo::PostgresImageUsageRepo,
+            mock::{InMemoryAdRepo, InMemoryImageRepo, InMemoryImageUsageRepo, InMemoryOutboxRepo},
+            outbox_repo::PostgresOutboxRepo,
+            report_repo::PostgresReportRepo,
+        },
+        telemetry,
+    };
+    use futures_util::StreamExt;
+    use tower::ServiceExt;
+
+    use super::{app, idempotency_key, pretty_json, store_ad_with_images, AppState};
+
+    const ALLOWED_ORIGIN: &str = "http://localhost:5173";
+
+    fn test_state(image_dir: &str) -> AppState {
+        let db_manager = db::DbManager::new(
+            env::var("DATABASE_URL")
+                .expect("DATABASE_URL must be set")
+                .as_str(),
+            None,
+            &db::PoolConfig::default(),
+        )
+        .expect("Failed to create pool");
+
+        AppState {
+            ad_repo: PostgresAdRepo::new(db_manager.clone()),
+            favorite_repo: PostgresFavoriteRepo::new(db_manager.clone()),
+            report_repo: PostgresReportRepo::new(db_manager.clone()),
+            outbox_repo: PostgresOutboxRepo::new(db_manager.clone()),
+            image_usage_repo: PostgresImageUsageRepo::new(db_manager.clone()),
+            db_manager,
+            image_repo: LocalImageRepo::new(image_dir.to_string(), false, false).unwrap(),
+            image_limits: ImageLimits::default(),
+            rate_limits: RateLimitConfig::default(),
+            page_limits: PageLimits::default(),
+            duplicates: DuplicateCheck::default(),
+            maintenance: Maintenance::default(),
+            cors: CorsConfig {
+                allowed_origins: vec![HeaderValue::from_static(ALLOWED_ORIGIN)],
+                ..CorsConfig::default()
+            },
+            metrics: telemetry::prometheus_handle(),
+            base_currency: Currency::default(),
+            default_locale: Locale::default(),
+            ad_feed: AdFeed::default(),
+            graphiql: false,
+            trim_trailing_slash: true,
+            pretty_json: false,
+            public_base_url: String::new(),
+            image_base_url: String::new(),
+        }
+    }
+
+    fn test_app() -> axum::Router {
+        app(
+            test_state(&env::temp_dir().display().to_string()),
+            JwtKeys::from_secret(b"test"),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_get_ads_query_params() {
+        let response = test_app()
+            .oneshot(
+                Request::get("/ads?per_page=5&offset=10&title_contains=bike")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["page"], 3);
+        assert!(body["items"].as_array().unwrap().len() <= 5);
+    }
+
+    #[tokio::test]
+    async fn test_openapi_spec() {
+        let response = test_app()
+            .oneshot(
+                Request::get("/api-docs/openapi.json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let list = &spec["paths"]["/ads"]["get"];
+        let params: Vec<&str> = list["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|param| param["name"].as_str().unwrap())
+            .collect();
+        for name in ["per_page", "offset", "price_lt", "category_eq", "sort_by"] {
+            assert!(params.contains(&name), "{}", name);
+        }
+        assert!(list["responses"]["400"].is_object());
+
+        let create = &spec["paths"]["/ads"]["post"];
+        let form = &create["requestBody"]["content"]["multipart/form-data"]["schema"];
+        assert!(form.is_object());
+        for status in ["201", "400", "401", "409", "503"] {
+            assert!(create["responses"][status].is_object(), "{}", status);
+        }
+        assert!(create["responses"]["409"]["description"]
+            .as_str()
+            .unwrap()
+            .contains("DUPLICATE_ADS"));
+        for (path, method) in [
+            ("/ads/{id}", "get"),
+            ("/ads/{id}", "head"),
+            ("/ads/{id}", "put"),
+            ("/ads/count", "get"),
+            ("/ads/facets", "get"),
+            ("/ads/slug/{slug}", "get"),
+            ("/cursors", "post"),
+            ("/ads/{id}/images/{image_id}", "delete"),
+            ("/users/{email}/export", "get"),
+            ("/ads/{id}/purge", "delete"),
+            ("/maintenance", "put"),
+            ("/metrics", "get"),
+        ] {
+            assert!(
+                spec["paths"][path][method].is_object(),
+                "{} {}",
+                method,
+                path
+            );
+        }
+        assert_eq!(
+            spec["paths"]["/ads/{id}"]["put"]["responses"]["403"]["content"]["application/json"]
+                ["schema"]["$ref"],
+            "#/components/schemas/ErrorResponse"
+        );
+        assert!(spec["components"]["schemas"]["Ad"].is_object());
+        assert!(spec["components"]["securitySchemes"]["bearer"].is_object());
+
+        let response = test_app()
+            .oneshot(Request::get("/swagger-ui/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_get_ads_rejects_malformed_query_params() {
+        for uri in ["/ads?per_page=five", "/ads?currency_eq=XYZ"] {
+            let response = test_app()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::options("/ads")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_allowed_origin() {
+        let response = test_app().oneshot(preflight(ALLOWED_ORIGIN)).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], ALLOWED_ORIGIN);
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_unknown_origin() {
+        let response = test_app()
+            .oneshot(preflight("https://evil.example"))
+            .await
+            .unwrap();
+
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let response = test_app()
+            .oneshot(Request::get("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let generated = response.headers()["x-request-id"].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(generated).is_ok());
+
+        let response = test_app()
+            .oneshot(
+                Request::get("/health")
+                    .header("x-request-id", "upstream-123")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-request-id"], "upstream-123");
+    }
+
+    #[tokio::test]
+    async fn test_ready_checks_image_storage() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let ready = |state| async move {
+            let response = app(state, JwtKeys::from_secret(b"test"))
+                .oneshot(Request::get("/ready").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            (response.status(), json_body(response).await)
+        };
+
+        let (status, body) = ready(test_state(&image_dir.path().display().to_string())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["image_storage"], true);
+        // The probe doesn't stay behind.
+        assert_eq!(std::fs::read_dir(image_dir.path()).unwrap().count(), 0);
+
+        // Gone after startup created it.
+        let removed = image_dir.path().join("removed");
+        let state = test_state(&removed.display().to_string());
+        std::fs::remove_dir(&removed).unwrap();
+        let (status, body) = ready(state).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["database"], true);
+        assert_eq!(body["image_storage"], false);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let app = test_app();
+        for _ in 0..3 {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/categories").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(response.headers()[header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with("text/plain"));
+
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains(
+                "http_requests_total{method=\"GET\",path=\"/categories\",status=\"200\"}"
+            ));
+            assert!(body.contains("http_request_duration_seconds_bucket"));
+            assert!(body.contains("db_pool_connections{pool=\"write\"}"));
+            assert!(!body.contains("path=\"/metrics\""));
+        }
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("idempotency-key", HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        assert_eq!(idempotency_key(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(
+            idempotency_key(&headers("retry-42")).unwrap().as_deref(),
+            Some("retry-42")
+        );
+        for value in ["", "has space", &"k".repeat(256)] {
+            assert!(
+                matches!(
+                    idempotency_key(&headers(value)),
+                    Err(RepoError::Validation(_))
+                ),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    /// An `Authorization` header value accepted by `test_app`.
+    fn bearer(email: &str, admin: bool) -> String {
+        let claims = Claims {
+            sub: email.to_string(),
+            exp: (chrono::Utc::now().timestamp() + 60) as usize,
+            role: if admin { Role::Admin } else { Role::User },
+            admin: false,
+        };
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"test"),
+        )
+        .unwrap();
+        format!("Bearer {}", token)
+    }
+
+    #[tokio::test]
+    async fn test_delete_then_restore() {
+        let state = test_state(&env::temp_dir().display().to_string());
+        let ad = state
+            .ad_repo
+            .create(
+                AdContent {
+                    title: "Restorable".to_string(),
+                    description: "Test Description".to_string(),
+                    price: 100.into(),
+                    currency: Currency::default(),
+                    user_email: "owner@test.com".to_string(),
+                    user_phone: "1234567890".to_string(),
+                    negotiable: false,
+                    locale: Locale::default(),
+                    category: AdCategory::default(),
+                    latitude: None,
+                    longitude: None,
+                    draft: false,
+                },
+                vec![],
+            )
+            .await
+            .unwrap();
+        let app = app(state, JwtKeys::from_secret(b"test"));
+        let send = |method: &str, uri: String, auth: Option<String>| {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let ad_uri = format!("/ads/{}", ad.id);
+        let restore_uri = format!("/ads/{}/restore", ad.id);
+
+        let response = send(
+            "DELETE",
+            ad_uri.clone(),
+            Some(bearer("intruder@test.com", false)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = send(
+            "DELETE",
+            ad_uri.clone(),
+            Some(bearer("owner@test.com", false)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Already deleted, so there is nothing left to delete.
+        let response = send(
+            "DELETE",
+            ad_uri.clone(),
+            Some(bearer("owner@test.com", false)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send("GET", ad_uri.clone(), None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Restoring is admin only, even for the owner.
+        let response = send(
+            "POST",
+            restore_uri.clone(),
+            Some(bearer("owner@test.com", false)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = send("POST", restore_uri, Some(bearer("admin@test.com", true)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send("GET", ad_uri, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_purge_requires_admin() {
+        let state = test_state(&env::temp_dir().display().to_string());
+        let ad = state
+            .ad_repo
+            .create(
+                AdContent {
+                    title: "Purgeable".to_string(),
+                    description: "Test Description".to_string(),
+                    price: 100.into(),
+                    currency: Currency::default(),
+                    user_email: "owner@test.com".to_string(),
+                    user_phone: "1234567890".to_string(),
+                    negotiable: false,
+                    locale: Locale::default(),
+                    category: AdCategory::default(),
+                    latitude: None,
+                    longitude: None,
+                    draft: false,
+                },
+                vec![],
+            )
+            .await
+            .unwrap();
+        state.ad_repo.delete(ad.id, &ad.user_email).await.unwrap();
+        let app = app(state, JwtKeys::from_secret(b"test"));
+        let purge = |auth: Option<String>| {
+            let mut request = Request::delete(format!("/ads/{}/purge", ad.id));
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = purge(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = purge(Some(bearer("owner@test.com", false))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(json_body(response).await["code"], "forbidden");
+
+        let response = purge(Some(bearer("admin@test.com", true))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = purge(Some(bearer("admin@test.com", true))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_favorites_mask_other_sellers() {
+        let state = test_state(&env::temp_dir().display().to_string());
+        let seller = format!("{}@test.com", uuid::Uuid::new_v4());
+        let buyer = format!("{}@test.com", uuid::Uuid::new_v4());
+        let mut ads = Vec::new();
+        for draft in [false, true] {
+            let ad = state
+                .ad_repo
+                .create(
+                    AdContent {
+                        title: "Favorite".to_string(),
+                        description: "Test Description".to_string(),
+                        price: 100.into(),
+                        currency: Currency::default(),
+                        user_email: seller.clone(),
+                        user_phone: "1234567890".to_string(),
+                        negotiable: false,
+                        locale: Locale::default(),
+                        category: AdCategory::default(),
+                        latitude: None,
+                        longitude: None,
+                        draft,
+                    },
+                    vec![],
+                )
+                .await
+                .unwrap();
+            ads.push(ad);
+        }
+        let app = app(state, JwtKeys::from_secret(b"test"));
+        let send = |method: &str, uri: String, email: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header(header::AUTHORIZATION, bearer(email, false))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = send("POST", format!("/ads/{}/favorite", ads[0].id), &buyer)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send("GET", "/favorites".to_string(), &buyer).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body[0]["id"], serde_json::json!(ads[0].id));
+        assert_ne!(body[0]["user_email"], seller.as_str());
+        assert_eq!(body[0]["user_phone"], "*******890");
+
+        // Someone else's draft can't be saved, as if it didn't exist.
+        let response = send("POST", format!("/ads/{}/favorite", ads[1].id), &buyer)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = send("POST", format!("/ads/{}/favorite", ads[1].id), &seller)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = send("POST", format!("/ads/{}/favorite", ads[0].id), &seller)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let body = json_body(
+            send("GET", "/favorites".to_string(), &seller)
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(body[0]["user_email"], seller.as_str());
+        assert_eq!(body[0]["user_phone"], "1234567890");
+    }
+
+    #[tokio::test]
+    async fn test_report_and_hide() {
+        let state = test_state(&env::temp_dir().display().to_string());
+        let mut ads = Vec::new();
+        for title in ["Reported", "Reported once"] {
+            let ad = state
+                .ad_repo
+                .create(
+                    AdContent {
+                        title: title.to_string(),
+                        description: "Test Description".to_string(),
+                        price: 100.into(),
+                        currency: Currency::default(),
+                        user_email: "owner@test.com".to_string(),
+                        user_phone: "1234567890".to_string(),
+                        negotiable: false,
+                        locale: Locale::default(),
+                        category: AdCategory::default(),
+                        latitude: None,
+                        longitude: None,
+                        draft: false,
+                    },
+                    vec![],
+                )
+                .await
+                .unwrap();
+            ads.push(ad);
+        }
+        let app = app(
+            AppState {
+                rate_limits: RateLimitConfig {
+                    report_per_minute: 4,
+                    ..RateLimitConfig::default()
+                },
+                page_limits: PageLimits::default(),
+                ..state
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |method: &str, uri: String, auth: Option<String>, body: serde_json::Value| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                // Nothing else edits these ads, so edits needn't name a version.
+                .header(header::IF_MATCH, "*");
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            app.clone()
+                .oneshot(request.body(Body::from(body.to_string())).unwrap())
+        };
+        let report = |id: AdId, reason: &str| {
+            send(
+                "POST",
+                format!("/ads/{}/report", id),
+                None,
+                serde_json::json!({ "reason": reason }),
+            )
+        };
+
+        let response = report(ads[0].id, " ").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(response).await["details"][0]["field"], "reason");
+
+        for _ in 0..2 {
+            let response = report(ads[0].id, "scam").await.unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+        let response = report(ads[1].id, "spam").await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        // The fifth report from the same address within a minute is throttled.
+        let response = report(ads[1].id, "spam").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let reports_uri = "/reports?min_reports=2".to_string();
+        let response = send(
+            "GET",
+            reports_uri.clone(),
+            Some(bearer("owner@test.com", false)),
+            serde_json::Value::Null,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = send(
+            "GET",
+            reports_uri,
+            Some(bearer("admin@test.com", true)),
+            serde_json::Value::Null,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        let queued = |id: AdId| {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .find(|reported| reported["id"] == id.0)
+                .cloned()
+        };
+        assert_eq!(queued(ads[0].id).unwrap()["report_count"], 2);
+        assert!(queued(ads[1].id).is_none());
+
+        let status_uri = format!("/ads/{}/status", ads[0].id);
+        let hide = serde_json::json!({ "status": "hidden" });
+        let response = send(
+            "PUT",
+            status_uri.clone(),
+            Some(bearer("owner@test.com", false)),
+            hide.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = send(
+            "PUT",
+            status_uri,
+            Some(bearer("admin@test.com", true)),
+            hide,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["status"], "hidden");
+
+        // Hidden from the public, but still visible to the owner, who can no longer edit it.
+        let ad_uri = format!("/ads/{}", ads[0].id);
+        let response = send("GET", ad_uri.clone(), None, serde_json::Value::Null)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send(
+            "GET",
+            ad_uri.clone(),
+            Some(bearer("owner@test.com", false)),
+            serde_json::Value::Null,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = send(
+            "PATCH",
+            ad_uri,
+            Some(bearer("owner@test.com", false)),
+            serde_json::json!({ "status": "active" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    /// Keeps sent messages so tests can check what would have been emailed.
+    #[derive(Default)]
+    struct RecordingNotifier {
+        sent: Mutex<Vec<ContactMessage>>,
+    }
+
+    #[async_trait]
+    impl Notifier for RecordingNotifier {
+        async fn send(&self, message: ContactMessage) -> Result<(), anyhow::Error> {
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    /// Like `test_state`, but on the in-memory repos, so no database is needed.
+    fn mock_state(ad_repo: Arc<InMemoryAdRepo>, image_repo: Arc<InMemoryImageRepo>) -> AppState {
+        let db_manager = db::DbManager::new_lazy("postgres://localhost/unused");
+
+        AppState {
+            ad_repo,
+            favorite_repo: PostgresFavoriteRepo::new(db_manager.clone()),
+            report_repo: PostgresReportRepo::new(db_manager.clone()),
+            db_manager,
+            image_repo,
+            image_usage_repo: InMemoryImageUsageRepo::new(),
+            image_limits: ImageLimits::default(),
+            rate_limits: RateLimitConfig::default(),
+            page_limits: PageLimits::default(),
+            duplicates: DuplicateCheck::default(),
+            maintenance: Maintenance::default(),
+            cors: CorsConfig::default(),
+            metrics: telemetry::prometheus_handle(),
+            base_currency: Currency::default(),
+            default_locale: Locale::default(),
+            outbox_repo: InMemoryOutboxRepo::new(),
+            ad_feed: AdFeed::default(),
+            graphiql: false,
+            trim_trailing_slash: true,
+            pretty_json: false,
+            public_base_url: String::new(),
+            image_base_url: String::new(),
+        }
+    }
+
+    async fn json_body(response: axum::response::Response) -> serde_json::Value {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn seed_mock_ads(ad_repo: &InMemoryAdRepo, titles: &[&str]) {
+        for title in titles {
+            ad_repo
+                .create(
+                    AdContent {
+                        title: title.to_string(),
+                        description: "Test Description".to_string(),
+                        price: 100.into(),
+                        currency: Currency::default(),
+                        user_email: "seller@test.com".to_string(),
+                        user_phone: "1234567890".to_string(),
+                        negotiable: false,
+                        locale: Locale::default(),
+                        category: AdCategory::default(),
+                        latitude: None,
+                        longitude: None,
+                        draft: false,
+                    },
+                    vec![],
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_ads_without_db() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["First", "Second", "Third"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+
+        let response = app
+            .oneshot(
+                Request::get("/ads?per_page=2&offset=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = json_body(response).await;
+        assert_eq!(body["page"], 2);
+        assert_eq!(body["total"], 3);
+        let items = body["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["title"], "First");
+        assert_eq!(items[0]["price"], "100.00");
+        assert_eq!(items[0]["currency"], "EUR");
+    }
+
+    #[tokio::test]
+    async fn test_search_ads() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Road bike", "Bike lock", "Sofa"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        // `q` as a client would send it, percent-encoded.
+        let search = |q: &str| {
+            let uri = format!("/ads/search?q={}&per_page=1", q);
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = search("title%3Abike%20price%3C500").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["items"][0]["title"], "Bike lock");
+        assert_eq!(
+            body["next"],
+            "/ads/search?q=title%3Abike%20price%3C500&offset=1&per_page=1"
+        );
+
+        let response = search("title%3Abike%20price%3E100").await.unwrap();
+        assert_eq!(json_body(response).await["total"], 0);
+
+        let response = search("title%3Abike%20price%3C").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(response).await;
+        assert_eq!(body["details"][0]["field"], "q");
+        assert_eq!(body["details"][0]["message"], "`price<` needs a value");
+    }
+
+    #[tokio::test]
+    async fn test_get_user_ads() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike", "Lamp"]).await;
+        let content = |user_email: &str, title: &str, draft: bool| AdContent {
+            title: title.to_string(),
+            description: "Test Description".to_string(),
+            price: 100.into(),
+            currency: Currency::default(),
+            user_email: user_email.to_string(),
+            user_phone: "1234567890".to_string(),
+            negotiable: false,
+            locale: Locale::default(),
+            category: AdCategory::default(),
+            latitude: None,
+            longitude: None,
+            draft,
+        };
+        ad_repo
+            .create(content("seller@test.com", "Sofa", true), vec![])
+            .await
+            .unwrap();
+        ad_repo
+            .create(content("other@test.com", "Desk", false), vec![])
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str, email: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(email) = email {
+                request = request.header(header::AUTHORIZATION, bearer(email, false));
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let titles = |body: &serde_json::Value| {
+            body["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|ad| ad["title"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let response = get("/users/seller@test.com/ads", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(titles(&body), ["Lamp", "Bike"]);
+        assert_eq!(body["total"], 2);
+        assert_ne!(body["items"][0]["user_email"], "seller@test.com");
+
+        let response = get("/users/seller@test.com/ads", Some("other@test.com"))
+            .await
+            .unwrap();
+        assert_eq!(titles(&json_body(response).await), ["Lamp", "Bike"]);
+
+        let response = get(
+            "/users/seller@test.com/ads?per_page=2",
+            Some("seller@test.com"),
+        )
+        .await
+        .unwrap();
+        let body = json_body(response).await;
+        assert_eq!(titles(&body), ["Sofa", "Lamp"]);
+        assert_eq!(body["total"], 3);
+        assert_eq!(body["items"][0]["user_email"], "seller@test.com");
+        assert_eq!(
+            body["next"],
+            "/users/seller@test.com/ads?offset=2&per_page=2"
+        );
+
+        let response = get("/users/other@test.com/ads", None).await.unwrap();
+        assert_eq!(titles(&json_body(response).await), ["Desk"]);
+    }
+
+    #[tokio::test]
+    async fn test_get_ad_by_slug() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Mountain bike"]).await;
+        let app = app(
+            mock_state(ad_repo.clone(), InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/ads/slug/mountain-bike-1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["id"], 1);
+        assert_eq!(body["view_count"], 1);
+
+        let changes = AdPatch {
+            title: Some("Road bike".to_string()),
+            ..Default::default()
+        };
+        ad_repo
+            .patch(AdId(1), "seller@test.com", changes, None)
+            .await
+            .unwrap();
+
+        let response = get("/ads/slug/mountain-bike-1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "/ads/slug/road-bike-1"
+        );
+        let response = get("/ads/slug/road-bike-1").await.unwrap();
+        assert_eq!(json_body(response).await["title"], "Road bike");
+
+        let response = get("/ads/slug/road-bike-2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_cursor() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["First", "Second", "Third"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let fetch = |req: serde_json::Value| {
+            app.clone().oneshot(
+                Request::post("/cursors")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(req.to_string()))
+                    .unwrap(),
+            )
+        };
+
+        let response = fetch(serde_json::json!({ "count": 2 })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let page = json_body(response).await;
+        assert_eq!(page["items"].as_array().unwrap().len(), 2);
+        assert_eq!(page["has_more"], true);
+        let cursor = page["cursor"].as_str().unwrap().to_string();
+
+        let response = fetch(serde_json::json!({ "cursor": cursor, "count": 2 }))
+            .await
+            .unwrap();
+        let page = json_body(response).await;
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert_eq!(page["has_more"], false);
+        assert_eq!(page["cursor"], serde_json::Value::Null);
+
+        // Exhausted, and so closed: the client has to start over.
+        let response = fetch(serde_json::json!({ "cursor": cursor, "count": 2 }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+        assert_eq!(json_body(response).await["code"], "cursor_expired");
+
+        // Counts past `MAX_CURSOR_FETCH` are clamped rather than rejected.
+        let response = fetch(serde_json::json!({ "count": 1000 })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let page = json_body(response).await;
+        assert_eq!(page["items"].as_array().unwrap().len(), 3);
+        assert_eq!(page["cursor"], serde_json::Value::Null);
+
+        // Filters sent along with a cursor must be the ones it was declared with.
+        let filters = serde_json::json!({ "title_contains": "ir" });
+        let response = fetch(serde_json::json!({ "count": 1, "filters": filters }))
+            .await
+            .unwrap();
+        let page = json_body(response).await;
+        assert_eq!(page["items"][0]["title"], "Third");
+        let cursor = page["cursor"].as_str().unwrap().to_string();
+
+        let response = fetch(serde_json::json!({
+            "cursor": cursor,
+            "count": 1,
+            "filters": { "title_contains": "Sec" },
+        }))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(response).await;
+        assert_eq!(body["code"], "invalid_fields");
+        assert_eq!(body["details"][0]["field"], "filters");
+
+        // The rejected fetch didn't move the cursor.
+        let response =
+            fetch(serde_json::json!({ "cursor": cursor, "count": 1, "filters": filters }))
+                .await
+                .unwrap();
+        assert_eq!(json_body(response).await["items"][0]["title"], "First");
+
+        // Closing is idempotent, so a client retrying it isn't told the cursor is missing.
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::delete(format!("/cursors/{}", cursor))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+        let response = fetch(serde_json::json!({ "cursor": cursor, "count": 1 }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn test_get_ad_expands_images() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        ad_repo
+            .add_images(AdId(1), "seller@test.com", vec![id.to_string()], 10)
+            .await
+            .unwrap();
+        let app = app(
+            AppState {
+                public_base_url: "https://api.example.com".to_string(),
+                image_base_url: "https://cdn.example.com".to_string(),
+                ..mock_state(ad_repo, InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/ads/1").await.unwrap();
+        assert_eq!(json_body(response).await["images"], serde_json::json!([id]));
+
+        let response = get("/ads/1?expand=images").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(header::ETAG));
+        let ad = json_body(response).await;
+        assert_eq!(ad["title"], "Bike");
+        assert_eq!(ad["user_email"], "s***@test.com");
+        let image = &ad["images"][0];
+        assert_eq!(image["id"], id);
+        assert!(image.get("width").is_none());
+        for (field, path) in [
+            ("url", format!("/images/{}", id)),
+            ("thumbnail_url", format!("/images/{}/thumbnail", id)),
+        ] {
+            let url: axum::http::Uri = image[field].as_str().unwrap().parse().unwrap();
+            assert_eq!(url.scheme_str(), Some("https"));
+            // Images are linked through the CDN rather than the API.
+            assert_eq!(url.host(), Some("cdn.example.com"));
+            assert_eq!(url.path(), path);
+        }
+
+        let response = get("/ads/slug/bike-1?expand=images").await.unwrap();
+        assert_eq!(json_body(response).await["images"][0]["id"], id);
+
+        let response = get("/ads/1?expand=seller").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_get_ad_expands_signed_images() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        ad_repo
+            .add_images(AdId(1), "seller@test.com", vec![id.to_string()], 10)
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        // Images the repo can't sign keep their link through the API.
+        let response = get("/ads/1?expand=signed&ttl=300").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let image = &json_body(response).await["images"][0];
+        assert_eq!(image["url"], format!("/images/{}", id));
+        assert!(image.get("expires_at").is_none());
+
+        for ttl in ["0", "604801"] {
+            let response = get(&format!("/ads/1?expand=signed&ttl={}", ttl))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(json_body(response).await["details"][0]["field"], "ttl");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let image_repo = InMemoryImageRepo::new();
+        let image_id = image_repo
+            .create_image(
+                "bike.png".to_string(),
+                tagged_png(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        let stored = image_repo.get_image(&image_id).await.unwrap().bytes;
+        let state = mock_state(ad_repo, image_repo);
+        let exact = app(
+            AppState {
+                trim_trailing_slash: false,
+                ..state.clone()
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let app = app(state, JwtKeys::from_secret(b"test"));
+        let get = |uri: String| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        for uri in [
+            "/ads",
+            "/ads/",
+            "/ads/1",
+            "/ads/1/",
+            "/health/",
+            "/ads/?per_page=1",
+        ] {
+            let response = get(uri.to_string()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        }
+        let response = get("/ads/?per_page=1".to_string()).await.unwrap();
+        assert_eq!(json_body(response).await["items"][0]["title"], "Bike");
+
+        // Image bytes are served the same either way.
+        for uri in [
+            format!("/images/{}", image_id),
+            format!("/images/{}/", image_id),
+        ] {
+            let response = get(uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body, stored);
+        }
+
+        let response = get("/adverts/".to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error = json_body(response).await;
+        assert_eq!(error["code"], "not_found");
+        assert_eq!(error["message"], "no route for GET /adverts");
+
+        // Turned off, only the exact path matches.
+        let response = exact
+            .oneshot(Request::get("/ads/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            json_body(response).await["message"],
+            "no route for GET /ads/"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_image_dimensions() {
+        let image_repo = InMemoryImageRepo::new();
+        let app = app(
+            mock_state(InMemoryAdRepo::new(), image_repo.clone()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(3, 2)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let (content_type, body) = ad_form_with(&[], &[("wide.png", png.get_ref())]);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/ads")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .oneshot(
+                Request::get("/ads/1?expand=images")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let image = &json_body(response).await["images"][0];
+        assert_eq!(image["width"], 3);
+        assert_eq!(image["height"], 2);
+
+        let image_id = image["id"].as_str().unwrap();
+        assert_eq!(
+            image_repo.get_image(image_id).await.unwrap().dimensions,
+            Some(ImageDimensions {
+                width: 3,
+                height: 2
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_price_history() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let patch = |changes: serde_json::Value| {
+            app.clone().oneshot(
+                Request::patch("/ads/1")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .header(header::IF_MATCH, "*")
+                    .body(Body::from(changes.to_string()))
+                    .unwrap(),
+            )
+        };
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        patch(serde_json::json!({ "price": "90" })).await.unwrap();
+        patch(serde_json::json!({ "title": "Bike, still 90" }))
+            .await
+            .unwrap();
+        patch(serde_json::json!({ "price": "75" })).await.unwrap();
+
+        let response = get("/ads/1/price-history").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let prices: Vec<_> = json_body(response)
+            .await
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| change["price"].clone())
+            .collect();
+        assert_eq!(prices, ["90.00", "75.00"]);
+
+        let response = get("/ads/2/price-history").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_patch_requires_current_etag() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let patch = |if_match: Option<&str>, price: &str| {
+            let mut request = Request::patch("/ads/1")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false));
+            if let Some(if_match) = if_match {
+                request = request.header(header::IF_MATCH, if_match);
+            }
+            let body = serde_json::json!({ "price": price }).to_string();
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/ads/1").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let response = patch(None, "90").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_REQUIRED);
+
+        // Two editors start from the same version; the second would overwrite the first.
+        let response = patch(Some(&etag), "90").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let new_etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_ne!(new_etag, etag);
+
+        let response = patch(Some(&etag), "80").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(json_body(response).await["code"], "precondition_failed");
+
+        let response = patch(Some(&new_etag), "80").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["price"], "80.00");
+
+        let response = patch(Some("\"stale\""), "70").await.unwrap();
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        let response = patch(Some("*"), "70").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_update_ad() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let put = |uri: &str, email: &str, (content_type, body): (String, Vec<u8>)| {
+            app.clone().oneshot(
+                Request::put(uri)
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer(email, false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let form = || ad_form_with(&[("title", "Road bike"), ("price", "90")], &[]);
+
+        let response = put("/ads/1", "seller@test.com", form()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(header::ETAG));
+        let ad = json_body(response).await;
+        assert_eq!(ad["title"], "Road bike");
+        assert_eq!(ad["price"], "90.00");
+        assert_eq!(ad["slug"], "road-bike-1");
+        assert_eq!(ad["status"], "active");
+
+        let response = put("/ads/1", "other@test.com", form()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = put("/ads/2", "seller@test.com", form()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = put(
+            "/ads/1",
+            "seller@test.com",
+            ad_form_with(&[("price", "-1")], &[]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = put(
+            "/ads/1",
+            "seller@test.com",
+            ad_form_with(&[], &[("bike.png", &tagged_png())]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/ads/1").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(json_body(response).await["title"], "Road bike");
+    }
+
+    #[tokio::test]
+    async fn test_delete_user_ads() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike", "Lamp"]).await;
+        let other = ad_repo
+            .create(
+                AdContent {
+                    title: "Chair".to_string(),
+                    description: "Test Description".to_string(),
+                    price: 100.into(),
+                    currency: Currency::default(),
+                    user_email: "other@test.com".to_string(),
+                    user_phone: "1234567890".to_string(),
+                    negotiable: false,
+                    locale: Locale::default(),
+                    category: AdCategory::default(),
+                    latitude: None,
+                    longitude: None,
+                    draft: false,
+                },
+                vec![],
+            )
+            .await
+            .unwrap();
+        let image_repo = InMemoryImageRepo::new();
+        let image_id = image_repo
+            .create_image(
+                "pixel.png".to_string(),
+                vec![0; 16],
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        ad_repo
+            .add_images(AdId(1), "seller@test.com", vec![image_id.clone()], 10)
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo.clone(), image_repo.clone()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let delete = |auth: String| {
+            app.clone().oneshot(
+                Request::delete("/users/seller@test.com/ads")
+                    .header(header::AUTHORIZATION, auth)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = delete(bearer("other@test.com", false)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = delete(bearer("seller@test.com", false)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["deleted"], 2);
+        assert!(ad_repo.get_by_id(AdId(1), false).await.unwrap().is_none());
+        assert!(image_repo.get_image(&image_id).await.is_err());
+        assert!(ad_repo.exists(other.id).await.unwrap());
+
+        let response = delete(bearer("admin@test.com", true)).await.unwrap();
+        assert_eq!(json_body(response).await["deleted"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_count_ads() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(
+            &ad_repo,
+            &["Road bike", "Mountain bike", "Lamp", "Bike helmet"],
+        )
+        .await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        for filter in [
+            "title_contains=bike",
+            "title_contains=lamp",
+            "price_gt=500",
+            "",
+        ] {
+            let response = get(&format!("/ads/count?{}", filter)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let total = json_body(response).await["total"].as_u64().unwrap();
+
+            let body =
+                json_body(get(&format!("/ads?per_page=100&{}", filter)).await.unwrap()).await;
+            assert_eq!(total, body["items"].as_array().unwrap().len() as u64);
+            assert_eq!(total, body["total"]);
+        }
+
+        let response = get("/ads/count?currency_eq=XYZ").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_ad_facets() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Road bike", "Mountain bike", "Lamp"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/ads/facets?title_contains=bike").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        // The mock ads are all priced 100.
+        assert_eq!(body["categories"], serde_json::json!({ "other": 2 }));
+        assert_eq!(
+            body["price_ranges"][2],
+            serde_json::json!({ "min": 100, "max": 500, "count": 2 })
+        );
+        assert_eq!(body["price_ranges"][5]["max"], serde_json::Value::Null);
+        let total: i64 = body["price_ranges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|range| range["count"].as_i64().unwrap())
+            .sum();
+        assert_eq!(total, 2);
+
+        let response = get("/ads/facets?currency_eq=XYZ").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_page_links() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["First", "Second", "Third", "Fourth", "Fifth"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let links = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let body = json_body(response).await;
+                (body["next"].clone(), body["prev"].clone())
+            }
+        };
+
+        let (next, prev) = links("/ads?per_page=2&sort_by=price_asc").await;
+        assert_eq!(next, "/ads?sort_by=price_asc&offset=2&per_page=2");
+        assert!(prev.is_null());
+
+        let (next, prev) = links("/ads?offset=2&per_page=2&sort_by=price_asc").await;
+        assert_eq!(next, "/ads?sort_by=price_asc&offset=4&per_page=2");
+        assert_eq!(prev, "/ads?sort_by=price_asc&offset=0&per_page=2");
+
+        // Only one ad is left for a page of two, so there is nothing after it.
+        let (next, prev) = links("/ads?offset=4&per_page=2").await;
+        assert!(next.is_null());
+        assert_eq!(prev, "/ads?offset=2&per_page=2");
+
+        // A full last page doesn't link to an empty one either.
+        let (next, _) = links("/ads?offset=3&per_page=2").await;
+        assert!(next.is_null());
+
+        let (next, prev) = links("/ads?offset=1&per_page=2").await;
+        assert_eq!(next, "/ads?offset=3&per_page=2");
+        assert_eq!(prev, "/ads?offset=0&per_page=2");
+    }
+
+    #[tokio::test]
+    async fn test_offset_past_the_end() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Road bike", "Lamp", "Mountain bike", "Desk"]).await;
+        let lenient = app(
+            mock_state(ad_repo.clone(), InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let strict = app(
+            AppState {
+                page_limits: PageLimits {
+                    strict: true,
+                    ..PageLimits::default()
+                },
+                ..mock_state(ad_repo, InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |app: &axum::Router, uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        // Two ads match the filter, so the page at offset 6 doesn't exist.
+        let uri = "/ads?title_contains=bike&offset=6&per_page=2";
+        let response = get(&lenient, uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let page = json_body(response).await;
+        assert_eq!(page["items"], serde_json::json!([]));
+        assert_eq!(page["page"], 4);
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["has_more"], false);
+        assert!(page["next"].is_null());
+        assert_eq!(page["prev"], "/ads?title_contains=bike&offset=0&per_page=2");
+
+        let response = get(&strict, uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Pages that exist, and the first page when nothing matches, are unaffected.
+        let page = json_body(
+            get(&strict, "/ads?title_contains=bike&per_page=1")
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(page["has_more"], true);
+        let response = get(&strict, "/ads?title_contains=piano").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_per_page_is_capped() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["First", "Second", "Third", "Fourth", "Fifth"]).await;
+        let app = app(
+            AppState {
+                page_limits: PageLimits {
+                    default_per_page: 2,
+                    max_per_page: 3,
+                    ..PageLimits::default()
+                },
+                ..mock_state(ad_repo, InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let item_count = |request: Request<Body>| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                json_body(response).await["items"].as_array().unwrap().len()
+            }
+        };
+
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        assert_eq!(item_count(get("/ads?per_page=1000000")).await, 3);
+        assert_eq!(item_count(get("/ads")).await, 2);
+        assert_eq!(item_count(get("/ads/seek?per_page=1000000")).await, 3);
+
+        let request = Request::get("/ads")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"per_page": 1000000}"#))
+            .unwrap();
+        assert_eq!(item_count(request).await, 3);
+
+        // GraphQL pages the same way.
+        for (page, expected) in [("", 2), ("(page: {perPage: 4000000000})", 3)] {
+            let query = format!("{{ ads{} {{ items {{ title }} }} }}", page);
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::post("/graphql")
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(
+                            serde_json::json!({ "query": query }).to_string(),
+                        ))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = json_body(response).await;
+            let items = body["data"]["ads"]["items"].as_array().unwrap();
+            assert_eq!(items.len(), expected, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_paging_is_rejected() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["First", "Second"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| {
+            let app = app.clone();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+        let get = |uri: String| Request::get(uri).body(Body::empty()).unwrap();
+
+        for request in [
+            get("/ads?per_page=0".to_string()),
+            get("/ads/seek?per_page=0".to_string()),
+            Request::get("/ads")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"per_page": 0}"#))
+                .unwrap(),
+        ] {
+            let response = send(request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(json_body(response).await["details"][0]["field"], "per_page");
+        }
+
+        let response = send(get(format!("/ads?offset={}", MAX_OFFSET + 1))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(response).await["details"][0]["field"], "offset");
+
+        let response = send(get(format!("/ads?offset={}&per_page=1", MAX_OFFSET))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["page"], MAX_OFFSET + 1);
+        assert!(body["items"].as_array().unwrap().is_empty());
+
+        // GraphQL checks paging the same way.
+        let graphql = |page: String| {
+            let query = format!("{{ ads(page: {}) {{ page items {{ title }} }} }}", page);
+            Request::post("/graphql")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "query": query }).to_string(),
+                ))
+                .unwrap()
+        };
+        for (page, field) in [
+            ("{perPage: 0}".to_string(), "per_page"),
+            (format!("{{offset: {}}}", MAX_OFFSET + 1), "offset"),
+            ("{offset: 4000000000}".to_string(), "offset"),
+        ] {
+            let body = json_body(send(graphql(page.clone())).await).await;
+            assert_eq!(body["data"], serde_json::Value::Null, "{}", page);
+            let extensions = &body["errors"][0]["extensions"];
+            assert_eq!(extensions["code"], "BAD_REQUEST", "{}", page);
+            assert_eq!(extensions["fields"][0]["field"], field, "{}", page);
+        }
+        let page = format!("{{offset: {}, perPage: 1}}", MAX_OFFSET);
+        let body = json_body(send(graphql(page)).await).await;
+        assert_eq!(body["data"]["ads"]["page"], MAX_OFFSET + 1);
+        assert!(body["data"]["ads"]["items"].as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_responses() {
+        let ad_repo = InMemoryAdRepo::new();
+        let titles: Vec<String> = (0..50).map(|n| format!("Ad {}", n)).collect();
+        seed_mock_ads(
+            &ad_repo,
+            &titles.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+        .await;
+        let image_repo = InMemoryImageRepo::new();
+        let image_id = image_repo
+            .create_image(
+                "pixel.png".to_string(),
+                vec![0; 4096],
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo, image_repo),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: String| {
+            app.clone().oneshot(
+                Request::get(uri)
+                    .header(header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("/ads?per_page=50".to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(body["items"].as_array().unwrap().len(), 50);
+
+        let response = get(format!("/images/{}", image_id)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), 4096);
+    }
+
+    #[tokio::test]
+    async fn test_stats_without_db() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["First", "Second"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |admin: bool| {
+            app.clone().oneshot(
+                Request::get("/stats")
+                    .header(header::AUTHORIZATION, bearer("someone@test.com", admin))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get(false).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = get(true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["total_active"], 2);
+        assert_eq!(body["per_category"]["other"], 2);
+        assert_eq!(body["average_price"]["EUR"], "100.00");
+        let days = body["created_per_day"].as_array().unwrap();
+        assert_eq!(days.len(), 7);
+        assert_eq!(days[6]["count"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["First"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let toggle = |admin: bool, enabled: bool| {
+            app.clone().oneshot(
+                Request::put("/maintenance")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(header::AUTHORIZATION, bearer("someone@test.com", admin))
+                    .body(Body::from(
+                        serde_json::json!({ "enabled": enabled }).to_string(),
+                    ))
+                    .unwrap(),
+            )
+        };
+        let create = || {
+            let png = tagged_png();
+            let (content_type, body) = ad_form_with(&[], &[("front.png", &png)]);
+            app.clone().oneshot(
+                Request::post("/ads")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = toggle(false, true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = toggle(true, true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["enabled"], true);
+
+        let response = create().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "300");
+        assert_eq!(json_body(response).await["code"], "maintenance");
+
+        // Reads and health checks stay up.
+        for uri in ["/ads", "/categories", "/health"] {
+            let response = get(uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        }
+
+        let response = toggle(true, false).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = create().await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_repair_image() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let image_repo =
+            LocalImageRepo::new(image_dir.path().display().to_string(), false, false).unwrap();
+        let image_id = image_repo
+            .create_image(
+                "bike.png".to_string(),
+                tagged_png(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        std::fs::remove_file(image_dir.path().join(format!("{}.meta", image_id))).unwrap();
+        let app = app(
+            AppState {
+                image_repo,
+                ..mock_state(InMemoryAdRepo::new(), InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let repair = |id: &str, admin: bool| {
+            send(
+                Request::post(format!("/images/{}/repair", id))
+                    .header(header::AUTHORIZATION, bearer("someone@test.com", admin))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        let get_image = || {
+            send(
+                Request::get(format!("/images/{}", image_id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        assert_ne!(get_image().await.unwrap().status(), StatusCode::OK);
+
+        let response = repair(&image_id, false).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = repair(&image_id, true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(response).await,
+            serde_json::json!({ "id": image_id, "outcome": "repaired" })
+        );
+        let response = get_image().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+
+        let response = repair(&image_id, true).await.unwrap();
+        assert_eq!(json_body(response).await["outcome"], "intact");
+
+        let missing = uuid::Uuid::new_v4().to_string();
+        let response = repair(&missing, true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_ads_price_ranges() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["First", "Second"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        // Seeded ads cost 100; `price_between` includes its bounds.
+        let response = get("/ads?price_between=50,100").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["total"], 2);
+
+        let response = get("/ads?price_between=100.01,200").await.unwrap();
+        assert_eq!(json_body(response).await["total"], 0);
+
+        let response = get("/ads?price_between=50").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = get("/ads?price_gt=200&price_lt=50").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(response).await["details"][0]["field"], "price_gt");
+    }
+
+    #[tokio::test]
+    async fn test_get_ad_without_db() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/ads/1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["id"], 1);
+        assert_eq!(body["title"], "Bike");
+
+        let response = get("/ads/999").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json_body(response).await["code"], "not_found");
+
+        let response = get("/ads/not-a-number").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(response).await;
+        assert_eq!(body["code"], "invalid_id");
+        assert_eq!(body["message"], "invalid ad id: not-a-number");
+    }
+
+    #[tokio::test]
+    async fn test_ad_contacts_masked_for_non_owners() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str, auth: Option<String>| {
+            let mut request = Request::get(uri);
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let body = json_body(get("/ads/1", None).await.unwrap()).await;
+        assert_eq!(body["user_email"], "s***@test.com");
+        assert_eq!(body["user_phone"], "*******890");
+
+        let stranger = Some(bearer("buyer@test.com", false));
+        let body = json_body(get("/ads?per_page=1", stranger).await.unwrap()).await;
+        assert_eq!(body["items"][0]["user_email"], "s***@test.com");
+
+        let owner = Some(bearer("seller@test.com", false));
+        let body = json_body(get("/ads/1", owner.clone()).await.unwrap()).await;
+        assert_eq!(body["user_email"], "seller@test.com");
+        assert_eq!(body["user_phone"], "1234567890");
+        let body = json_body(get("/ads?per_page=1", owner).await.unwrap()).await;
+        assert_eq!(body["items"][0]["user_phone"], "1234567890");
+
+        let response = get("/ads/1", Some("Bearer nonsense".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_contact_seller() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let outbox_repo = InMemoryOutboxRepo::new();
+        let app = app(
+            AppState {
+                outbox_repo: outbox_repo.clone(),
+                rate_limits: RateLimitConfig {
+                    contact_per_minute: 3,
+                    ..RateLimitConfig::default()
+                },
+                page_limits: PageLimits::default(),
+                ..mock_state(ad_repo, InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let contact = |id: &str, reply_to: &str| {
+            let body =
+                serde_json::json!({ "message": "Is it still available?", "reply_to": reply_to });
+            app.clone().oneshot(
+                Request::post(format!("/ads/{}/contact", id))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+        };
+
+        let response = contact("1", "buyer@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        // Nothing about the seller comes back to the buyer.
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+
+        // Queued for the outbox job, which sends it on its next run.
+        assert_eq!(outbox_repo.messages().len(), 1);
+        let notifier = RecordingNotifier::default();
+        jobs::deliver_outbox(&*outbox_repo, &notifier, &OutboxConfig::default())
+            .await
+            .unwrap();
+        let sent = notifier.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "seller@test.com");
+        assert_eq!(sent[0].reply_to, "buyer@test.com");
+        assert!(sent[0].body.contains("Is it still available?"));
+
+        let response = contact("1", "not an address").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(response).await["details"][0]["field"], "reply_to");
+
+        let response = contact("999", "buyer@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // The contact budget of 3 is spent, even by the rejected attempts.
+        let response = contact("1", "buyer@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(outbox_repo.messages().len(), 1);
+    }
+
+    /// A `multipart/form-data` body for `POST /ads` with one PNG image.
+    fn ad_form(price: &str) -> (String, Vec<u8>) {
+        ad_form_with(&[("price", price)], &[("bike.png", &tagged_png())])
+    }
+
+    /// A `multipart/form-data` body for `POST /ads` with the given `images`; `fields` are
+    /// added to, or override, placeholder ones.
+    fn ad_form_with(fields: &[(&str, &str)], images: &[(&str, &[u8])]) -> (String, Vec<u8>) {
+        const BOUNDARY: &str = "test-boundary";
+        let mut body = Vec::new();
+        let mut all_fields = vec![
+            ("title", "Bike"),
+            ("description", "Barely used"),
+            ("price", "100"),
+            ("user_phone", "+421 900 123 456"),
+        ];
+        all_fields.retain(|(name, _)| fields.iter().all(|(given, _)| given != name));
+        all_fields.extend_from_slice(fields);
+        for (name, value) in all_fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    BOUNDARY, name, value
+                )
+                .as_bytes(),
+            );
+        }
+        for (file_name, bytes) in images {
+            push_image(&mut body, BOUNDARY, file_name, bytes);
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+        (format!("multipart/form-data; boundary={}", BOUNDARY), body)
+    }
+
+    /// A `multipart/form-data` body for `POST /ads/:id/images` with `count` PNG images.
+    fn images_form(count: usize) -> (String, Vec<u8>) {
+        const BOUNDARY: &str = "test-boundary";
+        let mut body = Vec::new();
+        for _ in 0..count {
+            push_png(&mut body, BOUNDARY);
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+        (format!("multipart/form-data; boundary={}", BOUNDARY), body)
+    }
+
+    fn push_png(body: &mut Vec<u8>, boundary: &str) {
+        push_image(body, boundary, "bike.png", &tagged_png());
+    }
+
+    fn push_image(body: &mut Vec<u8>, boundary: &str, file_name: &str, bytes: &[u8]) {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"images\"; filename=\"{}\"\r\n\
+                 Content-Type: image/png\r\n\r\n",
+                boundary, file_name
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(bytes);
+        body.extend_from_slice(b"\r\n");
+    }
+
+    const PNG_COMMENT: &[u8] = b"Comment\0taken at 48.1486 N, 17.1077 E";
+
+    /// A 1x1 PNG with a text chunk after IHDR, as cameras and editors leave them.
+    fn tagged_png() -> Vec<u8> {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(1, 1)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let mut png = png.into_inner();
+        let mut chunk = (PNG_COMMENT.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"tEXt");
+        chunk.extend_from_slice(PNG_COMMENT);
+        // Not checked, the chunk is stripped on upload.
+        chunk.extend_from_slice(&[0; 4]);
+        // Signature and IHDR: 8 + 12 + 13 bytes.
+        png.splice(33..33, chunk);
+        png
+    }
+
+    /// A red pixel, for a second image that isn't a duplicate of `tagged_png`.
+    fn red_png() -> Vec<u8> {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(1, 1, image::Rgb([255, 0, 0]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_shared_image_outlives_one_ad() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let image_dir_path = image_dir.path().display().to_string();
+        let app = app(
+            AppState {
+                image_repo: LocalImageRepo::new(image_dir_path.clone(), true, false).unwrap(),
+                ..test_state(&image_dir_path)
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        // Unique to the run, as ads from earlier runs still list their images.
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::GrayImage::from_raw(16, 1, uuid::Uuid::new_v4().as_bytes().to_vec())
+            .unwrap()
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let stored = || std::fs::read_dir(image_dir.path()).unwrap().count();
+
+        let mut ads = Vec::new();
+        for _ in 0..2 {
+            let (content_type, body) = ad_form_with(&[], &[("bike.png", &png)]);
+            let response = send(
+                Request::post("/ads")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            ads.push(json_body(response).await);
+        }
+        let image_id = ads[0]["images"][0].as_str().unwrap();
+        assert_eq!(ads[1]["images"][0], image_id);
+        // The image and its metadata, stored once.
+        assert_eq!(stored(), 2);
+
+        let remove = |ad: &serde_json::Value| {
+            Request::delete(format!("/ads/{}/images/{}", ad["id"], image_id))
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = send(remove(&ads[0])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(stored(), 2);
+
+        let response = send(remove(&ads[1])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(stored(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_upload_body_limit() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let image_repo = InMemoryImageRepo::new();
+        let app = app(
+            AppState {
+                image_limits: ImageLimits {
+                    max_body_bytes: 4096,
+                    ..ImageLimits::default()
+                },
+                ..mock_state(ad_repo, image_repo.clone())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let post = |uri: &str, (content_type, body): (String, Vec<u8>)| {
+            app.clone().oneshot(
+                Request::post(uri)
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let response = post("/ads", ad_form("100")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(image_repo.len(), 1);
+
+        // Each image is within the limit, together they are not.
+        let padding = vec![0; 1500];
+        let images = [
+            ("front.png", &padding[..]),
+            ("back.png", &padding[..]),
+            ("side.png", &padding[..]),
+        ];
+        let response = post("/ads", ad_form_with(&[], &images)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(json_body(response).await["code"], "payload_too_large");
+
+        let mut body = Vec::new();
+        for (file_name, bytes) in images {
+            push_image(&mut body, "test-boundary", file_name, bytes);
+        }
+        body.extend_from_slice(b"--test-boundary--\r\n");
+        let content_type = "multipart/form-data; boundary=test-boundary".to_string();
+        let response = post("/ads/1/images", (content_type, body)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(image_repo.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replace_images() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let image_repo = InMemoryImageRepo::new();
+        let app = app(
+            AppState {
+                image_limits: ImageLimits {
+                    max_count: 2,
+                    ..ImageLimits::default()
+                },
+                ..mock_state(ad_repo, image_repo.clone())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |method: &str, email: &str, keep: &[&str], uploads: usize| {
+            let mut body = Vec::new();
+            for id in keep {
+                body.extend_from_slice(
+                    format!(
+                        "--test-boundary\r\nContent-Disposition: form-data; name=\"keep\"\r\n\r\n{}\r\n",
+                        id
+                    )
+                    .as_bytes(),
+                );
+            }
+            for _ in 0..uploads {
+                push_png(&mut body, "test-boundary");
+            }
+            body.extend_from_slice(b"--test-boundary--\r\n");
+            app.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri("/ads/1/images")
+                    .header(
+                        header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=test-boundary",
+                    )
+                    .header(header::AUTHORIZATION, bearer(email, false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let image_ids = |body: serde_json::Value| -> Vec<String> {
+            serde_json::from_value(body["images"].clone()).unwrap()
+        };
+
+        let response = send("POST", "seller@test.com", &[], 2).await.unwrap();
+        let old = image_ids(json_body(response).await);
+
+        // A different pair of images replaces the old one, whose files are deleted.
+        let response = send("PUT", "seller@test.com", &[], 2).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let new = image_ids(json_body(response).await);
+        assert_eq!(new.len(), 2);
+        assert!(new.iter().all(|id| !old.contains(id)));
+        assert_eq!(image_repo.len(), 2);
+        for id in &old {
+            assert!(matches!(
+                image_repo.get_image(id).await,
+                Err(RepoError::NotFound)
+            ));
+        }
+
+        // Kept images stay, in the order given, ahead of the uploads.
+        let response = send("PUT", "seller@test.com", &[&new[1]], 1).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let images = image_ids(json_body(response).await);
+        assert_eq!(images[0], new[1]);
+        assert_eq!(image_repo.len(), 2);
+        assert!(image_repo.get_image(&new[1]).await.is_ok());
+        assert!(matches!(
+            image_repo.get_image(&new[0]).await,
+            Err(RepoError::NotFound)
+        ));
+
+        // Rejected replacements leave everything as it was, uploads included.
+        for (email, keep, uploads, status) in [
+            (
+                "seller@test.com",
+                &[old[0].as_str()][..],
+                0,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "seller@test.com",
+                &[images[0].as_str()][..],
+                2,
+                StatusCode::BAD_REQUEST,
+            ),
+            ("buyer@test.com", &[][..], 1, StatusCode::FORBIDDEN),
+        ] {
+            let response = send("PUT", email, keep, uploads).await.unwrap();
+            assert_eq!(response.status(), status);
+            assert_eq!(image_repo.len(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_images() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let image_repo = InMemoryImageRepo::new();
+        let app = app(
+            AppState {
+                image_limits: ImageLimits {
+                    max_count: 2,
+                    ..ImageLimits::default()
+                },
+                ..mock_state(ad_repo, image_repo.clone())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let upload = |count: usize, email: &str| {
+            let (content_type, body) = images_form(count);
+            app.clone().oneshot(
+                Request::post("/ads/1/images")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer(email, false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+        let remove = |image_id: &str| {
+            app.clone().oneshot(
+                Request::delete(format!("/ads/1/images/{}", image_id))
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = upload(2, "seller@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let images = json_body(response).await["images"].clone();
+        assert_eq!(images.as_array().unwrap().len(), 2);
+        assert_eq!(image_repo.len(), 2);
+
+        // A third image would exceed the limit; the upload isn't kept.
+        let response = upload(1, "seller@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(image_repo.len(), 2);
+
+        let response = upload(1, "buyer@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(image_repo.len(), 2);
+
+        let removed = images[0].as_str().unwrap();
+        let response = remove(removed).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(response).await["images"],
+            serde_json::json!([images[1]])
+        );
+        assert_eq!(image_repo.len(), 1);
+        assert!(matches!(
+            image_repo.get_image(removed).await,
+            Err(RepoError::NotFound)
+        ));
+
+        let response = remove(removed).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_draft_is_left_to_its_owner() {
+        let ad_repo = InMemoryAdRepo::new();
+        let app = app(
+            mock_state(ad_repo.clone(), InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let get = |uri: &str, email: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(email) = email {
+                request = request.header(header::AUTHORIZATION, bearer(email, false));
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let publish = |uri: &str, email: &str| {
+            Request::post(uri)
+                .header(header::AUTHORIZATION, bearer(email, false))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (content_type, body) = ad_form_with(&[("draft", "true")], &[]);
+        let response = send(
+            Request::post("/ads")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let draft = json_body(response).await;
+        assert_eq!(draft["status"], "draft");
+        let uri = format!("/ads/{}", draft["id"]);
+
+        let response = send(get(&uri, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = send(get(&uri, Some("buyer@test.com"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = send(get(&uri, Some("seller@test.com"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(get("/ads", None)).await.unwrap();
+        assert_eq!(json_body(response).await["total"], 0);
+        let response = send(get("/ads?status_eq=draft", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let publish_uri = format!("{}/publish", uri);
+        let response = send(publish(&publish_uri, "buyer@test.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send(publish(&publish_uri, "seller@test.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let published = json_body(response).await;
+        assert_eq!(published["status"], "active");
+        assert!(published["created_at"].as_str() > draft["created_at"].as_str());
+
+        let response = send(get(&uri, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(get("/ads", None)).await.unwrap();
+        assert_eq!(json_body(response).await["total"], 1);
+
+        let response = send(publish(&publish_uri, "seller@test.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = send(publish(&publish_uri, "buyer@test.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_negotiable_ads() {
+        let app = app(
+            mock_state(InMemoryAdRepo::new(), InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let create = |fields: &[(&str, &str)]| {
+            let (content_type, body) = ad_form_with(fields, &[]);
+            Request::post("/ads")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = send(create(&[("title", "Fixed")])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(json_body(response).await["negotiable"], false);
+        let response = send(create(&[("title", "Offers"), ("negotiable", "true")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(json_body(response).await["negotiable"], true);
+
+        for (query, expected) in [("true", "Offers"), ("false", "Fixed")] {
+            let response = send(
+                Request::get(format!("/ads?negotiable_eq={}", query))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+            let body = json_body(response).await;
+            assert_eq!(body["total"], 1, "{}", query);
+            assert_eq!(body["items"][0]["title"], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_ad_with_stored_images() {
+        let image_repo = InMemoryImageRepo::new();
+        let stored = image_repo
+            .create_image(
+                "old.png".to_string(),
+                b"old".to_vec(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(InMemoryAdRepo::new(), image_repo.clone()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let create = |fields: &[(&str, &str)]| {
+            let (content_type, body) = ad_form_with(fields, &[("photo.png", &tagged_png())]);
+            Request::post("/ads")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = send(create(&[("image_ids", &stored)])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let images = json_body(response).await["images"].clone();
+        assert_eq!(images.as_array().unwrap().len(), 2);
+        assert_eq!(images[0], stored.as_str());
+        assert!(image_repo
+            .exists(images[1].as_str().unwrap())
+            .await
+            .unwrap());
+
+        let unknown = uuid::Uuid::new_v4().to_string();
+        for fields in [
+            vec![("image_ids", unknown.as_str())],
+            vec![
+                ("image_ids", stored.as_str()),
+                ("image_ids", stored.as_str()),
+            ],
+        ] {
+            let response = send(create(&fields)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                json_body(response).await["details"][0]["field"],
+                "image_ids"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_images_then_create_ad() {
+        let image_repo = InMemoryImageRepo::new();
+        let app = app(
+            mock_state(InMemoryAdRepo::new(), image_repo.clone()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let upload = |(content_type, body): (String, Vec<u8>)| {
+            Request::post("/images")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = send(upload(images_form(2))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let image_ids = json_body(response).await["image_ids"].clone();
+        let image_ids: Vec<&str> = image_ids
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|id| id.as_str().unwrap())
+            .collect();
+        assert_eq!(image_ids.len(), 2);
+        assert_eq!(image_repo.len(), 2);
+
+        let (content_type, body) = ad_form_with(
+            &[("image_ids", image_ids[0]), ("image_ids", image_ids[1])],
+            &[],
+        );
+        let response = send(
+            Request::post("/ads")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            json_body(response).await["images"],
+            serde_json::json!(image_ids)
+        );
+
+        // Checked like inline uploads.
+        let mut body = Vec::new();
+        push_image(&mut body, "test-boundary", "notes.txt", b"not an image");
+        body.extend_from_slice(b"--test-boundary--\r\n");
+        let content_type = "multipart/form-data; boundary=test-boundary".to_string();
+        let response = send(upload((content_type, body))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(upload(images_form(0))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(image_repo.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_image_quota() {
+        let image_repo = InMemoryImageRepo::new();
+        // Room for three images as stored, with their metadata stripped.
+        let image_bytes = exif::strip_metadata("image/png", tagged_png())
+            .unwrap()
+            .len() as u64;
+        let app = app(
+            AppState {
+                image_limits: ImageLimits {
+                    quota_bytes: 3 * image_bytes,
+                    ..ImageLimits::default()
+                },
+                ..mock_state(InMemoryAdRepo::new(), image_repo.clone())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let upload = |email: &str, (content_type, body): (String, Vec<u8>)| {
+            Request::post("/images")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer(email, false))
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let create = |email: &str, (content_type, body): (String, Vec<u8>)| {
+            Request::post("/ads")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer(email, false))
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let png = tagged_png();
+
+        let response = send(upload("seller@test.com", images_form(2)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Two more would go past the quota, with the ad or on their own.
+        let form = ad_form_with(&[], &[("bike.png", &png), ("red.png", &red_png())]);
+        let response = send(create("seller@test.com", form)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(json_body(response).await["code"], "quota_exceeded");
+        let response = send(upload("seller@test.com", images_form(2)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(image_repo.len(), 2);
+
+        // One still fits, and another user has a quota of their own.
+        let form = ad_form_with(&[], &[("bike.png", &png)]);
+        let response = send(create("seller@test.com", form)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = send(upload("other@test.com", images_form(3)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = send(upload("seller@test.com", images_form(1)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(image_repo.len(), 6);
+    }
+
+    /// Text of every `<item><title>` in an RSS feed, checking on the way that the feed is
+    /// well-formed XML rooted at `<rss>`.
+    fn rss_item_titles(xml: &str) -> Vec<String> {
+        use xmlparser::{ElementEnd, Token};
+
+        let mut open: Vec<&str> = Vec::new();
+        let mut titles = Vec::new();
+        for token in xmlparser::Tokenizer::from(xml) {
+            match token.unwrap() {
+                Token::ElementStart { local, .. } => open.push(local.as_str()),
+                Token::ElementEnd { end, .. } => match end {
+                    ElementEnd::Open => {}
+                    ElementEnd::Close(_, local) => {
+                        assert_eq!(open.pop(), Some(local.as_str()))
+                    }
+                    ElementEnd::Empty => {
+                        open.pop();
+                    }
+                },
+                Token::Text { text } if open.ends_with(&["item", "title"]) => {
+                    titles.push(
+                        text.as_str()
+                            .replace("&lt;", "<")
+                            .replace("&gt;", ">")
+                            .replace("&amp;", "&"),
+                    );
+                }
+                _ => {}
+            }
+        }
+        assert!(open.is_empty());
+        assert!(xml.contains("<rss version=\"2.0\">"));
+        titles
+    }
+
+    #[tokio::test]
+    async fn test_ads_rss_feed() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(
+            &ad_repo,
+            &["Road bike", "Lamp & <brass> shade", "Mountain bike"],
+        )
+        .await;
+        let app = app(
+            AppState {
+                public_base_url: "https://api.example.com".to_string(),
+                ..mock_state(ad_repo, InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/ads/feed.xml").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/rss+xml; charset=utf-8"
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let xml = String::from_utf8(body.to_vec()).unwrap();
+        // Newest first.
+        assert_eq!(
+            rss_item_titles(&xml),
+            ["Mountain bike", "Lamp & <brass> shade", "Road bike"]
+        );
+        assert!(xml.contains("<link>https://api.example.com/ads/1</link>"));
+        assert!(xml.contains("<description>100.00 EUR"));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/ads/feed.xml?title_contains=bike")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let xml = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(rss_item_titles(&xml), ["Mountain bike", "Road bike"]);
+
+        let response = app
+            .oneshot(
+                Request::get("/ads/feed.xml?price_gt=500&price_lt=100")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_import_ads() {
+        let ad_repo = InMemoryAdRepo::new();
+        let image_repo = InMemoryImageRepo::new();
+        let stored = image_repo
+            .create_image(
+                "old.png".to_string(),
+                b"old".to_vec(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo.clone(), image_repo),
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let import = |query: &str, content_type: &str, body: String| {
+            Request::post(format!("/ads/import{}", query))
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let csv = format!(
+            "title,price,user_phone,category,image_ids\n\
+             Bike,100,+421 900 123 456,vehicles,{}\n\
+             Lamp,cheap,+421 900 123 456,,\n\
+             \"Chair, oak\",25.50,+421 900 123 456,,\n",
+            stored
+        );
+
+        let response = send(import("?strict=true", "text/csv", csv.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(response).await;
+        assert_eq!(body["code"], "import_rejected");
+        assert_eq!(body["details"][0]["index"], 1);
+        assert_eq!(ad_repo.count(AdFilter::default()).await.unwrap(), 0);
+
+        let response = send(import("", "text/csv; charset=utf-8", csv))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        assert_eq!(body["created"], 2);
+        assert_eq!(body["rejected"], 1);
+        assert!(body["rows"][0]["id"].is_number());
+        assert_eq!(body["rows"][1]["errors"][0]["field"], "price");
+        assert!(body["rows"][2]["id"].is_number());
+        let bike = ad_repo
+            .get_by_id(AdId(body["rows"][0]["id"].as_i64().unwrap() as i32), false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bike.user_email, "seller@test.com");
+        assert_eq!(bike.images, vec![stored]);
+        assert_eq!(bike.category, "vehicles");
+
+        let json = r#"[{"title": "Desk", "price": 40, "user_phone": "+421 900 123 456",
+                        "image_ids": ["00000000-0000-0000-0000-000000000000"]}]"#;
+        let response = send(import("", "application/json", json.to_string()))
+            .await
+            .unwrap();
+        let body = json_body(response).await;
+        assert_eq!(body["created"], 0);
+        assert_eq!(body["rows"][0]["errors"][0]["field"], "image_ids");
+
+        let response = send(import("", "text/plain", "Bike".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_export_user_ads() {
+        let ad_repo = InMemoryAdRepo::new();
+        // More than one page of the export.
+        let titles: Vec<String> = (0..105).map(|n| format!("Ad {}", n)).collect();
+        seed_mock_ads(
+            &ad_repo,
+            &titles.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+        .await;
+        ad_repo
+            .add_images(AdId(1), "seller@test.com", vec!["image-1".to_string()], 10)
+            .await
+            .unwrap();
+        ad_repo
+            .patch(
+                AdId(2),
+                "seller@test.com",
+                AdPatch {
+                    description: Some("Oak, \"solid\"".to_string()),
+                    ..AdPatch::default()
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        let app = app(
+            AppState {
+                image_base_url: "https://cdn.example.com".to_string(),
+                ..mock_state(ad_repo, InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let export = |email: &str, format: &str| {
+            app.clone().oneshot(
+                Request::get(format!("/users/seller@test.com/export{}", format))
+                    .header(
+                        header::AUTHORIZATION,
+                        bearer(email, email == "admin@test.com"),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = export("seller@test.com", "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"ads.json\""
+        );
+        let ads = json_body(response).await;
+        let ads = ads.as_array().unwrap();
+        assert_eq!(ads.len(), 105);
+        let first = ads.iter().find(|ad| ad["id"] == 1).unwrap();
+        assert_eq!(first["user_email"], "seller@test.com");
+        assert_eq!(
+            first["images"][0]["url"],
+            "https://cdn.example.com/images/image-1"
+        );
+
+        let response = export("admin@test.com", "?format=csv").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let csv = String::from_utf8(body.to_vec()).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 106);
+        assert!(lines[0].starts_with("id,status,title,description,price"));
+        assert!(lines.iter().any(|line| line.starts_with("1,active,Ad 0,")
+            && line.ends_with(",https://cdn.example.com/images/image-1")));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("2,active,Ad 1,\"Oak, \"\"solid\"\"\",")));
+
+        let response = export("other@test.com", "").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = export("seller@test.com", "?format=xml").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_ads() {
+        let ad_repo = InMemoryAdRepo::new();
+        let warn = app(
+            mock_state(ad_repo.clone(), InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let block = app(
+            AppState {
+                duplicates: DuplicateCheck {
+                    policy: DuplicatePolicy::Block,
+                    ..DuplicateCheck::default()
+                },
+                ..mock_state(ad_repo.clone(), InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let create = |app: &axum::Router, fields: &[(&str, &str)]| {
+            let (content_type, body) = ad_form_with(fields, &[]);
+            app.clone().oneshot(
+                Request::post("/ads")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let response = create(&warn, &[]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let first = json_body(response).await;
+        assert!(first.get("duplicate_of").is_none());
+
+        let response = create(&warn, &[("title", " bike "), ("price", "100.00")])
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(json_body(response).await["duplicate_of"], first["id"]);
+
+        let response = create(&warn, &[("price", "120")]).await.unwrap();
+        assert!(json_body(response).await.get("duplicate_of").is_none());
+
+        let response = create(&block, &[]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(ad_repo.count(AdFilter::default()).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_ad_locales() {
+        let app = app(
+            AppState {
+                default_locale: Locale::Sk,
+                ..mock_state(InMemoryAdRepo::new(), InMemoryImageRepo::new())
+            },
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let create = |fields: &[(&str, &str)]| {
+            let (content_type, body) = ad_form_with(fields, &[]);
+            Request::post("/ads")
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = send(create(&[("title", "Bicykel")])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(json_body(response).await["locale"], "sk");
+        let response = send(create(&[("title", "Kolo"), ("locale", "cs")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(json_body(response).await["locale"], "cs");
+        let response = send(create(&[("title", "Bike"), ("locale", "en-US")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(response).await["details"][0]["field"], "locale");
+
+        for (query, expected) in [("sk", "Bicykel"), ("cs", "Kolo")] {
+            let response = send(
+                Request::get(format!("/ads?locale_eq={}", query))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+            let body = json_body(response).await;
+            assert_eq!(body["total"], 1, "{}", query);
+            assert_eq!(body["items"][0]["title"], expected);
+        }
+        let response = send(
+            Request::get("/ads?locale_eq=xx")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_user_needs_admin() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        ad_repo
+            .create(
+                AdContent {
+                    title: "Kolo".to_string(),
+                    description: "Test Description".to_string(),
+                    price: 100.into(),
+                    currency: Currency::default(),
+                    user_email: "other@test.com".to_string(),
+                    user_phone: "0987654321".to_string(),
+                    negotiable: false,
+                    locale: Locale::default(),
+                    category: AdCategory::default(),
+                    latitude: None,
+                    longitude: None,
+                    draft: false,
+                },
+                vec![],
+            )
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str, token: Option<String>| {
+            let mut request = Request::get(uri);
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, token);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        for uri in [
+            "/ads?user_email_eq=other@test.com",
+            "/ads/count?user_email_eq=other@test.com",
+            "/ads/facets?user_phone_eq=1234567890",
+        ] {
+            let response = get(uri, None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+            let response = get(uri, Some(bearer("other@test.com", false)))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", uri);
+        }
+
+        let admin = || Some(bearer("admin@test.com", true));
+        let response = get("/ads?user_email_eq=other@test.com", admin())
+            .await
+            .unwrap();
+        let body = json_body(response).await;
+        assert_eq!(body["total"], 1);
+        assert_eq!(body["items"][0]["title"], "Kolo");
+        let response = get("/ads/count?user_email_eq=OTHER@test.com", admin())
+            .await
+            .unwrap();
+        assert_eq!(json_body(response).await["total"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_promote_ad() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Older", "Newer"]).await;
+        let app = app(
+            mock_state(ad_repo.clone(), InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+        let promote = |email: &str, until: chrono::DateTime<chrono::Utc>| {
+            Request::post("/ads/1/promote")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::AUTHORIZATION, bearer(email, false))
+                .body(Body::from(
+                    serde_json::json!({ "until": until.to_rfc3339() }).to_string(),
+                ))
+                .unwrap()
+        };
+        let titles = || async {
+            let response = send(Request::get("/ads").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            json_body(response).await["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|ad| ad["title"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(titles().await, ["Newer", "Older"]);
+
+        // Promoting is the only way to the top; a patch can't set `top_ad`.
+        let response = send(
+            Request::patch("/ads/1")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                .header(header::IF_MATCH, "*")
+                .body(Body::from(r#"{"top_ad": true}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(titles().await, ["Newer", "Older"]);
+
+        let until = chrono::Utc::now() + chrono::Duration::days(7);
+        let response = send(promote("buyer@test.com", until)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let past = chrono::Utc::now() - chrono::Duration::days(1);
+        let response = send(promote("seller@test.com", past)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(promote("seller@test.com", until)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let promoted = json_body(response).await;
+        assert_eq!(promoted["top_ad"], true);
+        assert_eq!(
+            promoted["top_ad_until"]
+                .as_str()
+                .and_then(|until| until.parse::<chrono::DateTime<chrono::Utc>>().ok()),
+            Some(until)
+        );
+        assert_eq!(titles().await, ["Older", "Newer"]);
+        assert_eq!(ad_repo.end_promotions().await.unwrap(), 0);
+        assert_eq!(titles().await, ["Older", "Newer"]);
+    }
+
+    #[tokio::test]
+    async fn test_renew_ad() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let renew = |email: &str| {
+            app.clone().oneshot(
+                Request::post("/ads/1/renew")
+                    .header(header::AUTHORIZATION, bearer(email, false))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = renew("buyer@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // The ad was just posted, which starts the cooldown like a renewal does.
+        let response = renew("seller@test.com").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: i64 = response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after > 0 && retry_after <= 24 * 60 * 60);
+        assert_eq!(json_body(response).await["code"], "too_soon");
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post("/ads/7/renew")
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_verify_phone() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike", "Lamp"]).await;
+        let app = app(
+            mock_state(ad_repo.clone(), InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let verify = |email: &str, code: Option<&str>| {
+            let request = Request::post("/ads/1/verify-phone")
+                .header(header::AUTHORIZATION, bearer(email, false));
+            app.clone().oneshot(match code {
+                Some(code) => request
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(serde_json::json!({ "code": code }).to_string()))
+                    .unwrap(),
+                None => request.body(Body::empty()).unwrap(),
+            })
+        };
+
+        let response = verify("buyer@test.com", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = verify("seller@test.com", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(json_body(response).await["expires_at"].is_string());
+
+        // The code is only logged, so start over with a known one.
+        ad_repo
+            .start_phone_verification(AdId(1), "seller@test.com", "123456")
+            .await
+            .unwrap()
+            .unwrap();
+        let response = verify("seller@test.com", Some("654321")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = verify("seller@test.com", Some("123456")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["phone_verified"], true);
+
+        // The code is used up.
+        let response = verify("seller@test.com", Some("123456")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        for (query, expected) in [("true", "Bike"), ("false", "Lamp")] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::get(format!("/ads?phone_verified_eq={}", query))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = json_body(response).await;
+            let titles: Vec<_> = body["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|ad| ad["title"].as_str().unwrap())
+                .collect();
+            assert_eq!(titles, [expected], "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_ad_collapses_duplicate_uploads() {
+        let ad_repo = InMemoryAdRepo::new();
+        let image_repo = InMemoryImageRepo::new();
+        let app = app(
+            mock_state(ad_repo.clone(), image_repo.clone()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let png = tagged_png();
+        let (content_type, body) = ad_form_with(&[], &[("front.png", &png), ("again.png", &png)]);
+
+        let response = app
+            .oneshot(
+                Request::post("/ads")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let images = json_body(response).await["images"].clone();
+        assert_eq!(images.as_array().unwrap().len(), 1);
+
+        // Only the first is stored.
+        let image = image_repo
+            .get_image(images[0].as_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(image.file_name, "front.png");
+    }
+
+    #[tokio::test]
+    async fn test_create_ad_with_broken_image() {
+        let ad_repo = InMemoryAdRepo::new();
+        let image_repo = InMemoryImageRepo::new();
+        let app = app(
+            mock_state(ad_repo.clone(), image_repo.clone()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let png = tagged_png();
+        let red = red_png();
+        let images: [(&str, &[u8]); 3] = [
+            ("front.png", &png),
+            ("broken.png", b"not an image"),
+            ("back.png", &red),
+        ];
+        let create = |partial_ok: &str| {
+            let (content_type, body) = ad_form_with(&[("partial_ok", partial_ok)], &images);
+            app.clone().oneshot(
+                Request::post("/ads")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        // All or nothing by default: no ad, and nothing stored.
+        let response = create("false").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(json_body(response).await["message"]
+            .as_str()
+            .unwrap()
+            .contains("broken.png"));
+        assert_eq!(ad_repo.count(AdFilter::default()).await.unwrap(), 0);
+
+        let response = create("true").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = json_body(response).await;
+        assert_eq!(body["images"].as_array().unwrap().len(), 2);
+        let rejected = body["rejected_images"].as_array().unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0]["index"], 1);
+        assert_eq!(rejected[0]["file_name"], "broken.png");
+        assert!(rejected[0]["reason"]
+            .as_str()
+            .unwrap()
+            .contains("not a supported type"));
+
+        let image_id = body["images"][0].as_str().unwrap();
+        assert!(image_repo.get_image(image_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_new_ads_feed() {
+        let ad_repo = InMemoryAdRepo::new();
+        let app = app(
+            mock_state(ad_repo, InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, app.clone()).into_future());
+
+        let (mut feed, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}/ws/ads?price_lt=50", addr))
+                .await
+                .unwrap();
+        let create = |price: &str| {
+            let (content_type, body) = ad_form(price);
+            app.clone().oneshot(
+                Request::post("/ads")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("seller@test.com", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let response = create("99").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = create("19.99").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Published in order, so the expensive ad would have arrived first.
+        let message = tokio::time::timeout(Duration::from_secs(5), feed.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let ad: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(ad["id"], 2);
+        assert_eq!(ad["price"], "19.99");
+        assert_eq!(ad["user_email"], "s***@test.com");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), feed.next())
+                .await
+                .is_err()
+        );
+
+        let response = app
+            .oneshot(
+                Request::get("/ws/ads?radius_km=5")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_graphql() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike", "Car"]).await;
+        let image_repo = InMemoryImageRepo::new();
+        let image_id = image_repo
+            .create_image(
+                "bike.png".to_string(),
+                tagged_png(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        ad_repo
+            .add_images(AdId(1), "seller@test.com", vec![image_id.clone()], 10)
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo, image_repo),
+            JwtKeys::from_secret(b"test"),
+        );
+        let graphql = |query: &str, email: Option<&str>| {
+            let mut request =
+                Request::post("/graphql").header(header::CONTENT_TYPE, "application/json");
+            if let Some(email) = email {
+                request = request.header(header::AUTHORIZATION, bearer(email, false));
+            }
+            let body = serde_json::json!({ "query": query }).to_string();
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+
+        let response = graphql(
+            "{ ads(filter: {sortBy: CREATED_AT_ASC}, page: {perPage: 1}) {
+                 total page items { title userEmail images { url mimeType } }
+             } }",
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(response).await["data"]["ads"],
+            serde_json::json!({
+                "total": 2,
+                "page": 1,
+                "items": [{
+                    "title": "Bike",
+                    "userEmail": "s***@test.com",
+                    "images": [{
+                        "url": format!("/images/{}", image_id),
+                        "mimeType": "image/png",
+                    }],
+                }],
+            })
+        );
+
+        let response = graphql("{ ad(id: 2) { title userPhone } }", Some("seller@test.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            json_body(response).await["data"]["ad"],
+            serde_json::json!({ "title": "Car", "userPhone": "1234567890" })
+        );
+
+        let create = r#"mutation {
+            createAd(input: {title: "Lamp", description: "Brass", price: "12.50", userPhone: "0900123456"}) {
+                id price currency userEmail images { id }
+            }
+        }"#;
+        let response = graphql(create, None).await.unwrap();
+        let body = json_body(response).await;
+        assert_eq!(body["errors"][0]["extensions"]["code"], "UNAUTHENTICATED");
+
+        let response = graphql(create, Some("buyer@test.com")).await.unwrap();
+        assert_eq!(
+            json_body(response).await["data"]["createAd"],
+            serde_json::json!({
+                "id": 3,
+                "price": "12.50",
+                "currency": "EUR",
+                "userEmail": "buyer@test.com",
+                "images": [],
+            })
+        );
+
+        let response = graphql(
+            r#"mutation { updateAd(id: 3, input: {price: "cheap"}) { price } }"#,
+            Some("buyer@test.com"),
+        )
+        .await
+        .unwrap();
+        let body = json_body(response).await;
+        assert_eq!(body["errors"][0]["extensions"]["code"], "BAD_REQUEST");
+        assert_eq!(
+            body["errors"][0]["extensions"]["fields"][0]["field"],
+            "price"
+        );
+
+        let response = graphql(
+            r#"mutation { updateAd(id: 3, input: {title: "Brass lamp"}) { title } }"#,
+            Some("buyer@test.com"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            json_body(response).await["data"]["updateAd"]["title"],
+            "Brass lamp"
+        );
+
+        let response = graphql("mutation { deleteAd(id: 3) }", Some("seller@test.com"))
+            .await
+            .unwrap();
+        let body = json_body(response).await;
+        assert_eq!(body["errors"][0]["extensions"]["code"], "FORBIDDEN");
+
+        let response = graphql("mutation { deleteAd(id: 3) }", Some("buyer@test.com"))
+            .await
+            .unwrap();
+        assert_eq!(json_body(response).await["data"]["deleteAd"], true);
+
+        let response = graphql("{ ad(id: 3) { title } }", None).await.unwrap();
+        assert_eq!(
+            json_body(response).await["data"]["ad"],
+            serde_json::Value::Null
+        );
+
+        // The playground is opt-in.
+        let response = app
+            .clone()
+            .oneshot(Request::get("/graphiql").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_create_ad_without_db() {
+        let ad_repo = InMemoryAdRepo::new();
+        let image_repo = InMemoryImageRepo::new();
+        let app = app(
+            mock_state(ad_repo.clone(), image_repo.clone()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let post = |price: &str, auth: Option<String>| {
+            let (content_type, body) = ad_form(price);
+            let mut request = Request::post("/ads").header(header::CONTENT_TYPE, content_type);
+            if let Some(auth) = auth {
+                request = request.header(header::AUTHORIZATION, auth);
+            }
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+
+        let response = post("19.99", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(json_body(response).await["code"], "unauthorized");
+
+        let response = post("cheap", Some(bearer("seller@test.com", false)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(response).await;
+        assert_eq!(body["code"], "invalid_fields");
+        assert_eq!(body["details"][0]["field"], "price");
+        assert_eq!(ad_repo.count(AdFilter::default()).await.unwrap(), 0);
+        assert!(image_repo.is_empty());
+
+        let response = post("19.99", Some(bearer("seller@test.com", false)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()[header::LOCATION], "/ads/1");
+        let body = json_body(response).await;
+        assert_eq!(body["title"], "Bike");
+        assert_eq!(body["price"], "19.99");
+        assert_eq!(body["user_email"], "seller@test.com");
+        let image_id = body["images"][0].as_str().unwrap();
+        let image = image_repo.get_image(image_id).await.unwrap();
+        assert_eq!(image.mime_type, "image/png");
+        assert!(!image
+            .bytes
+            .windows(PNG_COMMENT.len())
+            .any(|window| window == PNG_COMMENT));
+        assert_eq!(image_repo.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_ad_reports_every_invalid_field() {
+        let ad_repo = InMemoryAdRepo::new();
+        let app = app(
+            mock_state(ad_repo.clone(), InMemoryImageRepo::new()),
+            JwtKeys::from_secret(b"test"),
+        );
+        let (content_type, body) = ad_form_with(&[("title", " "), ("price", "-5")], &[]);
+
+        let response = app
+            .oneshot(
+                Request::post("/ads")
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::AUTHORIZATION, bearer("not-an-email", false))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = json_body(response).await;
+        assert_eq!(body["code"], "invalid_fields");
+        let mut fields: Vec<_> = body["details"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| error["field"].as_str().unwrap())
+            .collect();
+        fields.sort_unstable();
+        assert_eq!(fields, ["price", "title", "user_email"]);
+        assert_eq!(ad_repo.count(AdFilter::default()).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_get_ad_image() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bare", "Bike"]).await;
+        let image_repo = InMemoryImageRepo::new();
+        let mut image_ids = Vec::new();
+        for name in ["front.png", "back.png"] {
+            let image_id = image_repo
+                .create_image(name.to_string(), tagged_png(), "image/png".to_string())
+                .await
+                .unwrap();
+            image_ids.push(image_id);
+        }
+        ad_repo
+            .add_images(AdId(2), "seller@test.com", image_ids.clone(), 10)
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo.clone(), image_repo),
+            JwtKeys::from_secret(b"test"),
+        );
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        for uri in ["/ads/1/image", "/ads/1/image?thumb", "/ads/99/image"] {
+            let response = get(uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+
+        let response = get("/ads/2/image").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+        assert_eq!(headers[header::ETAG], format!("\"{}\"", image_ids[0]));
+        assert_eq!(headers[header::CACHE_CONTROL], "no-cache");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, tagged_png());
+
+        for uri in ["/ads/2/image?thumb", "/ads/2/image?thumb=true&max_dim=1"] {
+            let response = get(uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert!(image::load_from_memory(&body).is_ok(), "{}", uri);
+        }
+
+        // Not a view of the ad.
+        let ad = ad_repo.get_by_id(AdId(2), false).await.unwrap().unwrap();
+        assert_eq!(ad.view_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_pretty_json() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let state = mock_state(ad_repo, InMemoryImageRepo::new());
+        let body_of = |pretty_json: bool, uri: &'static str| {
+            let app = app(
+                AppState {
+                    pretty_json,
+                    ..state.clone()
+                },
+                JwtKeys::from_secret(b"test"),
+            );
+            async move {
+                let response = app
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let compact = body_of(false, "/ads/1?preview=true").await;
+        assert!(!compact.contains('\n'));
+        let pretty = body_of(false, "/ads/1?preview=true&pretty=true").await;
+        assert!(pretty.contains("\n  \"title\": \"Bike\""), "{}", pretty);
+        // Only whitespace is added; the keys keep their order.
+        let unindented: String = pretty.lines().map(str::trim_start).collect();
+        assert_eq!(unindented.replace("\": ", "\":"), compact);
+
+        // Errors too.
+        let error = body_of(false, "/ads/nope?pretty=true").await;
+        assert!(error.contains("\n  \"code\": "), "{}", error);
+
+        // `PRETTY_JSON` makes it the default, which a request can turn off.
+        assert!(body_of(true, "/ads/count")
+            .await
+            .contains("\n  \"total\": 1"));
+        assert_eq!(
+            body_of(true, "/ads/count?pretty=false").await,
+            "{\"total\":1}"
+        );
+
+        // A body that can't be read is reported, not replaced with an empty one.
+        let broken = axum::Router::new()
+            .route(
+                "/broken",
+                axum::routing::get(|| async {
+                    let chunks = futures_util::stream::iter([
+                        Ok(axum::body::Bytes::from_static(b"{\"total\":")),
+                        Err(std::io::Error::other("connection reset")),
+                    ]);
+                    (
+                        [(header::CONTENT_TYPE, "application/json")],
+                        Body::from_stream(chunks),
+                    )
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(true, pretty_json));
+        let response = broken
+            .oneshot(Request::get("/broken").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(json_body(response).await["code"], "internal");
+    }
+
+    #[tokio::test]
+    async fn test_head_requests() {
+        let ad_repo = InMemoryAdRepo::new();
+        seed_mock_ads(&ad_repo, &["Bike"]).await;
+        let image_repo = InMemoryImageRepo::new();
+        let image_id = image_repo
+            .create_image(
+                "bike.png".to_string(),
+                tagged_png(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        let app = app(
+            mock_state(ad_repo.clone(), image_repo),
+            JwtKeys::from_secret(b"test"),
+        );
+        let head = |uri: String| {
+            app.clone()
+                .oneshot(Request::head(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = head(format!("/images/{}", image_id)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+        assert_eq!(
+            headers[header::CONTENT_LENGTH],
+            tagged_png().len().to_string()
+        );
+        assert!(headers.contains_key(header::ETAG));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+
+        let response = head(format!("/images/{}", uuid::Uuid::new_v4()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = head("/ads/1".to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let ad = ad_repo.get_by_id(AdId(1), false).await.unwrap().unwrap();
+        assert_eq!(response.headers()[header::ETAG], ad.etag());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+        // Checking on an ad isn't viewing it.
+        assert_eq!(ad.view_count, 0);
+
+        let response = head("/ads/99".to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_image_sniffs_content_type() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let image_dir = image_dir.path().display().to_string();
+        let id = LocalImageRepo::new(image_dir.clone(), false, false)
+            .unwrap()
+            .create_image(
+                "bike.gif".to_string(),
+                tagged_png(),
+                "image/gif".to_string(),
+            )
+            .await
+            .unwrap();
+        let content_type = |sniff_content_type: bool| {
+            let app = app(
+                AppState {
+                    image_repo: LocalImageRepo::new(image_dir.clone(), false, sniff_content_type)
+                        .unwrap(),
+                    ..test_state(&image_dir)
+                },
+                JwtKeys::from_secret(b"test"),
+            );
+            let uri = format!("/images/{}", id);
+            async move {
+                let response = app
+                    .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response.headers()[header::CONTENT_TYPE].clone()
+            }
+        };
+
+        assert_eq!(content_type(false).await, "image/gif");
+        assert_eq!(content_type(true).await, "image/png");
+    }
+
+    #[tokio::test]
+    async fn test_get_image_cache_headers() {
+        let image_repo =
+            LocalImageRepo::new(env::temp_dir().display().to_string(), false, false).unwrap();
+        let id = image_repo
+            .create_image(
+                "pixel.png".to_string(),
+                b"not really a png".to_vec(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+
+        let response = test_app()
+            .oneshot(
+                Request::get(format!("/images/{}", id))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_LENGTH], "16");
+        assert_eq!(
+            headers[header::CACHE_CONTROL],
+            "public, max-age=31536000, immutable"
+        );
+        let etag = headers[header::ETAG].clone();
+
+        let response = test_app()
+            .oneshot(
+                Request::get(format!("/images/{}", id))
+                    .header(header::IF_NONE_MATCH, etag)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+
+        image_repo.delete_image(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_image_range() {
+        let image_repo =
+            LocalImageRepo::new(env::temp_dir().display().to_string(), false, false).unwrap();
+        let id = image_repo
+            .create_image(
+                "digits.png".to_string(),
+                b"0123456789".to_vec(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+
+        let get = |range: &str| {
+            Request::get(format!("/images/{}", id))
+                .header(header::RANGE, range)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = test_app().oneshot(get("bytes=2-5")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "4");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"2345");
+
+        let response = test_app().oneshot(get("bytes=-3")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"789");
+
+        let response = test_app().oneshot(get("bytes=10-")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+
+        image_repo.delete_image(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_insert_leaves_no_images() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let state = test_state(image_dir.path().to_str().unwrap());
+
+        // Too long for the VARCHAR(50) column, so the insert fails after the images are stored.
+        let ad = AdContent {
+            title: "Orphan check".to_string(),
+            description: "Test Description".to_string(),
+            price: 100.into(),
+            currency: Currency::default(),
+            user_email: "test@test.com".to_string(),
+            user_phone: "1".repeat(51),
+            negotiable: false,
+            locale: Locale::default(),
+            category: AdCategory::default(),
+            latitude: None,
+            longitude: None,
+            draft: false,
+        };
+        let images = vec![
+            ("a.png".to_string(), b"first".to_vec(), "image/png"),
+            ("b.png".to_string(), b"second".to_vec(), "image/png"),
+        ];
+
+        let result = store_ad_with_images(&state, ad, Vec::new(), images, None).await;
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_dir(image_dir.path()).unwrap().count(), 0);
+    }
 }
@@ -0,0 +1,307 @@
+//! Fills the database configured for the server with fake ads for local development:
+//!
+//!     cargo run --bin seed -- [--count N] [--clear]
+//!
+//! Ads are spread over a few seed users, so `--clear` removes earlier seed ads, images
+//! included, without touching anyone else's.
+
+use std::{env, sync::Arc};
+
+use bazaars::{
+    config::{AppConfig, ImageBackend},
+    db,
+    models::{
+        ad::{AdCategory, AdContent, Currency, Locale},
+        money::Money,
+    },
+    repos::{
+        ad_repo::{AdRepo, PostgresAdRepo},
+        image_repo::{ImageRepo, LocalImageRepo, S3ImageRepo},
+    },
+};
+use bigdecimal::BigDecimal;
+use fake::{
+    faker::{lorem::en::Sentences, number::en::NumberWithFormat},
+    Fake,
+};
+use rand::{seq::IndexedRandom, Rng};
+
+const USAGE: &str = "usage: seed [--count N] [--clear]";
+
+/// Ads created when `--count` isn't given.
+const DEFAULT_COUNT: usize = 50;
+
+/// Seed ads belong to `seed-1@example.com` up to this many users.
+const SEED_USERS: usize = 5;
+
+/// Upper bound on images per seed ad, on top of the configured limit.
+const MAX_SEED_IMAGES: usize = 3;
+
+const CONDITIONS: [&str; 6] = [
+    "Barely used",
+    "Vintage",
+    "Like new",
+    "Used",
+    "New",
+    "Refurbished",
+];
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    count: usize,
+    clear: bool,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        count: DEFAULT_COUNT,
+        clear: false,
+    };
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--clear" => parsed.clear = true,
+            "--count" => {
+                let count = args.next().ok_or("--count needs a value")?;
+                parsed.count = count
+                    .parse()
+                    .map_err(|_| format!("--count has an invalid value: {}", count))?;
+            }
+            other => return Err(format!("unknown argument: {}", other)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn seed_user(n: usize) -> String {
+    format!("seed-{}@example.com", n)
+}
+
+/// Things a seller in `category` might list.
+fn items(category: AdCategory) -> &'static [&'static str] {
+    match category {
+        AdCategory::Electronics => &["laptop", "phone", "headphones", "TV", "camera"],
+        AdCategory::Vehicles => &["mountain bike", "car", "scooter", "motorbike", "trailer"],
+        AdCategory::RealEstate => &["flat", "house", "garage", "plot", "office"],
+        AdCategory::HomeAndGarden => &["sofa", "lawn mower", "dining table", "lamp", "grill"],
+        AdCategory::Fashion => &["winter jacket", "sneakers", "handbag", "dress", "watch"],
+        AdCategory::Jobs => &["barista", "developer", "driver", "cleaner", "tutor"],
+        AdCategory::Services => &["plumbing", "moving help", "piano lessons", "tax advice"],
+        AdCategory::Other => &["board game", "guitar", "aquarium", "book collection"],
+    }
+}
+
+fn fake_ad(rng: &mut impl Rng) -> AdContent {
+    let category = *AdCategory::ALL.choose(rng).unwrap();
+    let item = items(category).choose(rng).unwrap();
+    let title = match category {
+        AdCategory::Jobs => format!("Hiring a {}", item),
+        AdCategory::Services => format!("Offering {}", item),
+        _ => format!("{} {}", CONDITIONS.choose(rng).unwrap(), item),
+    };
+    let description: Vec<String> = Sentences(2..5).fake_with_rng(rng);
+    // Roughly Slovakia, for ads that have a location.
+    let location = rng
+        .random_bool(0.5)
+        .then(|| (rng.random_range(47.8..49.5), rng.random_range(17.0..22.5)));
+
+    AdContent {
+        title,
+        description: description.join(" "),
+        price: Money::new(BigDecimal::new(rng.random_range(100..500_000).into(), 2))
+            .expect("seed prices are positive"),
+        currency: if rng.random_bool(0.8) {
+            Currency::Eur
+        } else {
+            *Currency::ALL.choose(rng).unwrap()
+        },
+        user_email: seed_user(rng.random_range(1..=SEED_USERS)),
+        user_phone: NumberWithFormat("+421 9## ### ###").fake_with_rng(rng),
+        negotiable: rng.random_bool(0.3),
+        // The fake descriptions are English.
+        locale: Locale::En,
+        category,
+        latitude: location.map(|(latitude, _)| latitude),
+        longitude: location.map(|(_, longitude)| longitude),
+        draft: false,
+    }
+}
+
+/// A small PNG in a random colour.
+fn fake_image(rng: &mut impl Rng) -> Vec<u8> {
+    let color = image::Rgb([rng.random(), rng.random(), rng.random()]);
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::RgbImage::from_pixel(320, 240, color)
+        .write_to(&mut png, image::ImageFormat::Png)
+        .expect("Failed to encode image");
+    png.into_inner()
+}
+
+async fn clear(ad_repo: &dyn AdRepo, image_repo: &dyn ImageRepo) -> Result<usize, anyhow::Error> {
+    let mut deleted = 0;
+    for n in 1..=SEED_USERS {
+        for ad in ad_repo.delete_by_user(&seed_user(n)).await? {
+            for image_id in ad.images {
+                if let Err(e) = image_repo.delete_image(&image_id).await {
+                    tracing::warn!(image_id = %image_id, error = %e, "failed to discard image");
+                }
+            }
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+async fn seed(
+    ad_repo: &dyn AdRepo,
+    image_repo: &dyn ImageRepo,
+    count: usize,
+    max_images: usize,
+) -> Result<(), anyhow::Error> {
+    let mut rng = rand::rng();
+    let mut ads = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let ad = fake_ad(&mut rng);
+        if let Err(errors) = ad.validate() {
+            anyhow::bail!("generated an invalid ad: {:?}", errors);
+        }
+
+        let mut image_ids = Vec::new();
+        for n in 0..rng.random_range(0..=max_images) {
+            let image_id = image_repo
+                .create_image(
+                    format!("seed-{}.png", n),
+                    fake_image(&mut rng),
+                    "image/png".to_string(),
+                )
+                .await?;
+            image_ids.push(image_id);
+        }
+
+        ads.push((ad, image_ids));
+    }
+
+    for ad in ad_repo.create_many(ads).await? {
+        // Promoted the only way real ads are, for up to a week.
+        if rng.random_bool(0.1) {
+            let until = chrono::Utc::now() + chrono::Duration::hours(rng.random_range(1..=168));
+            ad_repo.promote(ad.id, &ad.user_email, until).await?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let _ = dotenvy::dotenv();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let config = match AppConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let db_manager = match db::DbManager::new(&config.database_url, None, &config.pool) {
+        Ok(db_manager) => db_manager,
+        Err(e) => {
+            tracing::error!("Failed to set up database pool: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let ad_repo = PostgresAdRepo::new(db_manager);
+    let image_repo: Arc<dyn ImageRepo> = match config.image_backend {
+        ImageBackend::S3 { bucket, prefix } => {
+            let aws_config = aws_config::load_from_env().await;
+            S3ImageRepo::new(
+                aws_sdk_s3::Client::new(&aws_config),
+                bucket,
+                prefix,
+                config.image_dedup,
+            )
+        }
+        ImageBackend::Local {
+            image_dir,
+            sniff_content_type,
+        } => match LocalImageRepo::new(image_dir, config.image_dedup, sniff_content_type) {
+            Ok(image_repo) => image_repo,
+            Err(e) => {
+                tracing::error!("Invalid image storage: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    if args.clear {
+        match clear(&*ad_repo, &*image_repo).await {
+            Ok(deleted) => tracing::info!("Removed {} seed ads", deleted),
+            Err(e) => {
+                tracing::error!("Failed to remove seed ads: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let max_images = MAX_SEED_IMAGES.min(config.image_limits.max_count);
+    if let Err(e) = seed(&*ad_repo, &*image_repo, args.count, max_images).await {
+        tracing::error!("Failed to seed ads: {:#}", e);
+        std::process::exit(1);
+    }
+    tracing::info!("Created {} seed ads", args.count);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fake_ad, parse_args, Args, DEFAULT_COUNT};
+
+    fn args(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            args(&[]),
+            Ok(Args {
+                count: DEFAULT_COUNT,
+                clear: false
+            })
+        );
+        assert_eq!(
+            args(&["--clear", "--count", "200"]),
+            Ok(Args {
+                count: 200,
+                clear: true
+            })
+        );
+        assert!(args(&["--count"]).is_err());
+        assert!(args(&["--count", "many"]).is_err());
+        assert!(args(&["--verbose"]).is_err());
+    }
+
+    #[test]
+    fn test_fake_ads_are_valid() {
+        let mut rng = rand::rng();
+        for _ in 0..100 {
+            assert_eq!(fake_ad(&mut rng).validate(), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    env,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::{Context, Error};
+
+use crate::cors::CorsConfig;
+use crate::db::{parse_env, PoolConfig};
+use crate::jobs::{ExpiryConfig, OutboxConfig};
+use crate::maintenance::MaintenanceConfig;
+use crate::models::{
+    ad::{Currency, Locale},
+    image::ImageLimits,
+};
+use crate::notify::SmtpConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::repos::ad_repo::{DuplicateCheck, PageLimits, DEFAULT_MAX_OPEN_CURSORS};
+
+/// Where uploaded images are kept, chosen by `IMAGE_BACKEND` (`local` or `s3`).
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImageBackend {
+    /// A directory on disk, `IMAGE_DIR`, defaulting to `images`. With
+    /// `IMAGE_SNIFF_CONTENT_TYPE`, images are served with the type their bytes show
+    /// rather than the one stored at upload.
+    Local {
+        image_dir: String,
+        sniff_content_type: bool,
+    },
+    /// An S3 bucket, `S3_BUCKET`, with keys under `S3_PREFIX`.
+    S3 { bucket: String, prefix: String },
+}
+
+impl ImageBackend {
+    fn from_env() -> Result<Self, Error> {
+        match env::var("IMAGE_BACKEND").as_deref() {
+            Ok("local") | Err(_) => Ok(ImageBackend::Local {
+                image_dir: env::var("IMAGE_DIR").unwrap_or_else(|_| "images".to_string()),
+                sniff_content_type: parse_env("IMAGE_SNIFF_CONTENT_TYPE")?.unwrap_or(false),
+            }),
+            Ok("s3") => Ok(ImageBackend::S3 {
+                bucket: required("S3_BUCKET").context("IMAGE_BACKEND is s3")?,
+                prefix: env::var("S3_PREFIX").unwrap_or_default(),
+            }),
+            Ok(other) => Err(Error::msg(format!(
+                "IMAGE_BACKEND has an invalid value: {}, expected local or s3",
+                other
+            ))),
+        }
+    }
+}
+
+/// Everything the server reads from the environment, loaded once at startup so a bad
+/// value fails before anything is served.
+#[derive(Clone, Debug)]
+pub struct AppConfig {
+    /// Address the server listens on, read from `BIND_ADDR`. Defaults to `127.0.0.1:3000`;
+    /// use `0.0.0.0:<port>` to accept connections from outside a container.
+    pub bind_addr: SocketAddr,
+    pub database_url: String,
+    /// Read replica; reads go to `database_url` without one.
+    pub database_read_url: Option<String>,
+    pub pool: PoolConfig,
+    /// Apply pending migrations on startup, read from `RUN_MIGRATIONS`. Off by default.
+    pub run_migrations: bool,
+    pub image_backend: ImageBackend,
+    pub image_limits: ImageLimits,
+    /// Store identical uploads once, under an id derived from their content, read from
+    /// `IMAGE_DEDUP`. Off by default.
+    pub image_dedup: bool,
+    pub cors: CorsConfig,
+    pub rate_limits: RateLimitConfig,
+    pub page_limits: PageLimits,
+    pub duplicates: DuplicateCheck,
+    /// Open cursors kept before the oldest are closed, read from `MAX_OPEN_CURSORS`.
+    /// Defaults to 100.
+    pub max_open_cursors: usize,
+    /// Currency of ads created without one.
+    pub base_currency: Currency,
+    /// Locale of ads created without one.
+    pub default_locale: Locale,
+    pub jwt_secret: String,
+    /// Outgoing mail for contact messages, sent through the outbox; without it they are
+    /// logged and dropped.
+    pub smtp: Option<SmtpConfig>,
+    pub expiry: ExpiryConfig,
+    pub outbox: OutboxConfig,
+    pub maintenance: MaintenanceConfig,
+    /// How long in-flight requests get to finish after a shutdown signal, read from
+    /// `SHUTDOWN_TIMEOUT_SECS`. Defaults to 30s.
+    pub shutdown_timeout: Duration,
+    /// Serve the GraphiQL playground at `/graphiql`, read from `GRAPHIQL`. Off by default.
+    pub graphiql: bool,
+    /// Route `/ads/` like `/ads`, read from `TRIM_TRAILING_SLASH`. On by default.
+    pub trim_trailing_slash: bool,
+    /// Indent JSON responses for reading, read from `PRETTY_JSON`. Off by default; a
+    /// request can also ask with `?pretty=true`.
+    pub pretty_json: bool,
+    /// The API's public URL, e.g. `https://api.example.com`, that image links are built
+    /// on, read from `PUBLIC_BASE_URL`. Empty by default, making them relative paths.
+    pub public_base_url: String,
+    /// Where image links point instead, e.g. a CDN in front of `/images`, read from
+    /// `IMAGE_PUBLIC_BASE_URL`. Falls back to `public_base_url`.
+    pub image_base_url: String,
+}
+
+impl AppConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        let public_base_url = base_url("PUBLIC_BASE_URL")?;
+        let image_base_url = match base_url("IMAGE_PUBLIC_BASE_URL")? {
+            url if url.is_empty() => public_base_url.clone(),
+            url => url,
+        };
+
+        Ok(AppConfig {
+            bind_addr: parse_env("BIND_ADDR")?.unwrap_or(DEFAULT_BIND_ADDR),
+            database_url: required("DATABASE_URL")?,
+            database_read_url: env::var("DATABASE_READ_URL").ok(),
+            pool: PoolConfig::from_env().context("invalid database pool configuration")?,
+            run_migrations: parse_env("RUN_MIGRATIONS")?.unwrap_or(false),
+            image_backend: ImageBackend::from_env()?,
+            image_limits: ImageLimits::from_env().context("invalid image limits")?,
+            image_dedup: parse_env("IMAGE_DEDUP")?.unwrap_or(false),
+            cors: CorsConfig::from_env().context("invalid CORS configuration")?,
+            rate_limits: RateLimitConfig::from_env().context("invalid rate limit configuration")?,
+            page_limits: PageLimits::from_env().context("invalid page limits")?,
+            duplicates: DuplicateCheck::from_env().context("invalid duplicate ad check")?,
+            max_open_cursors: parse_env("MAX_OPEN_CURSORS")?.unwrap_or(DEFAULT_MAX_OPEN_CURSORS),
+            base_currency: Currency::base_from_env().context("invalid base currency")?,
+            default_locale: Locale::default_from_env().context("invalid default locale")?,
+            jwt_secret: required("JWT_SECRET")?,
+            smtp: SmtpConfig::from_env().context("invalid SMTP configuration")?,
+            expiry: ExpiryConfig::from_env().context("invalid expiry job configuration")?,
+            outbox: OutboxConfig::from_env().context("invalid outbox configuration")?,
+            maintenance: MaintenanceConfig::from_env()
+                .context("invalid maintenance mode configuration")?,
+            shutdown_timeout: parse_env("SHUTDOWN_TIMEOUT_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            graphiql: parse_env("GRAPHIQL")?.unwrap_or(false),
+            trim_trailing_slash: parse_env("TRIM_TRAILING_SLASH")?.unwrap_or(true),
+            pretty_json: parse_env("PRETTY_JSON")?.unwrap_or(false),
+            public_base_url,
+            image_base_url,
+        })
+    }
+}
+
+/// An http(s) URL read from `key` without its trailing slash, or empty when unset.
+fn base_url(key: &str) -> Result<String, Error> {
+    let url = env::var(key).unwrap_or_default();
+    if !(url.is_empty() || url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(Error::msg(format!(
+            "{} has an invalid value: {}, expected an http(s) URL",
+            key, url
+        )));
+    }
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+const DEFAULT_BIND_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000));
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn required(key: &str) -> Result<String, Error> {
+    match env::var(key) {
+        Ok(value) if !value.is_empty() => Ok(value),
+        _ => Err(Error::msg(format!("{} must be set", key))),
+    }
+}
@@ -0,0 +1,89 @@
+use std::env;
+
+use anyhow::Error;
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::db::parse_env;
+
+/// CORS settings, read from `CORS_ALLOWED_ORIGINS`, `CORS_ALLOWED_METHODS`,
+/// `CORS_ALLOWED_HEADERS` (all comma-separated) and `CORS_ALLOW_CREDENTIALS`.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    /// Exact origins, e.g. `https://bazaars.example`. Empty denies every cross-origin request.
+    pub allowed_origins: Vec<HeaderValue>,
+    /// Defaults to the methods the API serves.
+    pub allowed_methods: Vec<Method>,
+    /// Defaults to `authorization` and `content-type`.
+    pub allowed_headers: Vec<HeaderName>,
+    /// Off by default. Browsers reject credentials combined with `*`, so origins,
+    /// methods and headers are always listed explicitly.
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ],
+            allowed_headers: vec![
+                axum::http::header::AUTHORIZATION,
+                axum::http::header::CONTENT_TYPE,
+            ],
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        let defaults = CorsConfig::default();
+        Ok(CorsConfig {
+            allowed_origins: parse_list("CORS_ALLOWED_ORIGINS")?
+                .unwrap_or(defaults.allowed_origins),
+            allowed_methods: parse_list("CORS_ALLOWED_METHODS")?
+                .unwrap_or(defaults.allowed_methods),
+            allowed_headers: parse_list("CORS_ALLOWED_HEADERS")?
+                .unwrap_or(defaults.allowed_headers),
+            allow_credentials: parse_env("CORS_ALLOW_CREDENTIALS")?
+                .unwrap_or(defaults.allow_credentials),
+        })
+    }
+
+    pub fn layer(&self) -> CorsLayer {
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(self.allowed_origins.clone()))
+            .allow_methods(self.allowed_methods.clone())
+            .allow_headers(self.allowed_headers.clone())
+            .allow_credentials(self.allow_credentials)
+    }
+}
+
+fn parse_list<T: std::str::FromStr>(key: &str) -> Result<Option<Vec<T>>, Error> {
+    let Ok(value) = env::var(key) else {
+        return Ok(None);
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            if item == "*" {
+                return Err(Error::msg(format!(
+                    "{} must list values explicitly, not *",
+                    key
+                )));
+            }
+            item.parse()
+                .map_err(|_| Error::msg(format!("{} has an invalid value: {}", key, item)))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
@@ -0,0 +1,67 @@
+use anyhow::Error;
+use diesel::{sql_query, sql_types::BigInt, Connection, PgConnection, RunQueryDsl};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+
+/// Everything under `migrations/`, from diesel's initial setup and the `ads` table on,
+/// compiled into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Key of the advisory lock held while migrating, so instances starting together take
+/// turns and the later ones find nothing left to run.
+const MIGRATION_LOCK_KEY: i64 = 0x0062_617a_6161_7273;
+
+/// Runs the migrations the database hasn't seen yet and returns their versions, in the
+/// order they ran.
+pub fn run_pending(database_url: &str) -> Result<Vec<String>, Error> {
+    // A connection of its own, as the lock belongs to the session: if anything fails,
+    // dropping the connection releases it.
+    let conn = &mut PgConnection::establish(database_url)?;
+    run_pending_locked(conn)
+}
+
+fn run_pending_locked(conn: &mut PgConnection) -> Result<Vec<String>, Error> {
+    sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)?;
+
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map(|versions| versions.iter().map(ToString::to_string).collect())
+        .map_err(Error::msg);
+
+    sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)?;
+
+    applied
+}
+
+#[cfg(test)]
+mod test {
+    use diesel::migration::MigrationSource;
+    use diesel::{sql_query, RunQueryDsl};
+
+    use super::{run_pending_locked, MIGRATIONS};
+    use crate::repos::fixtures::test_db;
+
+    #[test]
+    fn test_migrations_run_once() {
+        let db = test_db();
+        let conn = &mut db.write_conn().unwrap();
+        // An empty schema inside the test transaction, so the migrations start from
+        // scratch without touching the tables other tests use.
+        sql_query("CREATE SCHEMA migrations_test")
+            .execute(conn)
+            .unwrap();
+        sql_query("SET LOCAL search_path TO migrations_test")
+            .execute(conn)
+            .unwrap();
+
+        let applied = run_pending_locked(conn).unwrap();
+        let embedded = MigrationSource::<diesel::pg::Pg>::migrations(&MIGRATIONS).unwrap();
+        assert_eq!(applied.len(), embedded.len());
+        assert_eq!(applied.first().unwrap(), "00000000000000");
+
+        assert_eq!(run_pending_locked(conn).unwrap(), Vec::<String>::new());
+    }
+}
@@ -1,33 +1,432 @@
+pub mod migrations;
 pub mod schema;
 
-use std::sync::Arc;
+use std::{
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use anyhow::Error;
 use diesel::{
-    r2d2::{ConnectionManager, Pool},
-    PgConnection,
+    r2d2::{ConnectionManager, CustomizeConnection, Pool, PoolError, PooledConnection},
+    Connection, PgConnection, RunQueryDsl,
 };
 
+pub type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+/// r2d2 pool settings, read from `DB_POOL_MAX_SIZE`, `DB_POOL_MIN_IDLE`,
+/// `DB_POOL_CONNECTION_TIMEOUT_SECS`, `DB_POOL_ACQUIRE_RETRIES`,
+/// `DB_POOL_ACQUIRE_BACKOFF_MS` and `DB_STATEMENT_TIMEOUT_MS`.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Upper bound on open connections per pool. Defaults to 10, matching r2d2.
+    pub max_size: u32,
+    /// Idle connections kept warm. Defaults to 1 so a quiet instance doesn't hold
+    /// `max_size` connections open.
+    pub min_idle: u32,
+    /// How long a request waits for a free connection before failing. Defaults to 30s.
+    pub connection_timeout: Duration,
+    /// How that wait is split into attempts, see `AcquireRetry`.
+    pub acquire_retry: AcquireRetry,
+    /// Postgres cancels any statement running longer, so a runaway query can't hold its
+    /// connection. Defaults to 30s; zero turns the limit off.
+    pub statement_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 10,
+            min_idle: 1,
+            connection_timeout: Duration::from_secs(30),
+            acquire_retry: AcquireRetry::default(),
+            statement_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        let defaults = PoolConfig::default();
+        Ok(PoolConfig {
+            max_size: parse_env("DB_POOL_MAX_SIZE")?.unwrap_or(defaults.max_size),
+            min_idle: parse_env("DB_POOL_MIN_IDLE")?.unwrap_or(defaults.min_idle),
+            connection_timeout: parse_env("DB_POOL_CONNECTION_TIMEOUT_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.connection_timeout),
+            acquire_retry: AcquireRetry {
+                retries: parse_env("DB_POOL_ACQUIRE_RETRIES")?
+                    .unwrap_or(defaults.acquire_retry.retries),
+                base_delay: parse_env("DB_POOL_ACQUIRE_BACKOFF_MS")?
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.acquire_retry.base_delay),
+            },
+            statement_timeout: parse_env("DB_STATEMENT_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.statement_timeout),
+        })
+    }
+}
+
+/// How the wait for a pooled connection is split up when none is free, e.g. because a
+/// burst of requests holds every connection. The first attempts wait only briefly, twice
+/// as long each time, and log a warning when they fail, so pool pressure shows up well
+/// before requests fail; the last waits out the rest of the pool's connection timeout.
+/// The pool hands out a connection as soon as one is returned or established, so the
+/// attempts together take no longer than a single wait of the whole timeout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AcquireRetry {
+    /// Short attempts before the last. Defaults to 3; 0 waits the whole timeout at once.
+    pub retries: u32,
+    /// How long the first attempt waits. Defaults to 50ms.
+    pub base_delay: Duration,
+}
+
+/// Short attempts stop growing here, however many retries are configured.
+const MAX_ACQUIRE_DELAY: Duration = Duration::from_secs(5);
+
+impl Default for AcquireRetry {
+    fn default() -> Self {
+        AcquireRetry {
+            retries: 3,
+            base_delay: Duration::from_millis(50),
+        }
+    }
+}
+
+impl AcquireRetry {
+    /// No retries, for pools where failing fast matters more.
+    pub const NONE: AcquireRetry = AcquireRetry {
+        retries: 0,
+        base_delay: Duration::ZERO,
+    };
+
+    /// How long short attempt number `retry` waits, counting from 0.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_ACQUIRE_DELAY)
+    }
+
+    /// Calls `acquire` with how long to wait until it succeeds or `timeout` has passed in
+    /// all, never sleeping in between. Returns the last error.
+    fn run<T, E: std::fmt::Display>(
+        &self,
+        timeout: Duration,
+        mut acquire: impl FnMut(Duration) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let deadline = Instant::now() + timeout;
+        let mut retry = 0;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let wait = if retry < self.retries {
+                self.delay(retry).min(remaining)
+            } else {
+                remaining
+            };
+            match acquire(wait) {
+                Ok(conn) => return Ok(conn),
+                Err(e) if wait < remaining => {
+                    tracing::warn!(
+                        "No database connection within {:?}, still waiting: {}",
+                        wait,
+                        e
+                    );
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Sets `statement_timeout` on each new connection, see `PoolConfig::statement_timeout`.
+#[derive(Debug)]
+struct StatementTimeout(Duration);
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        diesel::sql_query(format!("SET statement_timeout = {}", self.0.as_millis()))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+pub(crate) fn parse_env<T: std::str::FromStr>(key: &str) -> Result<Option<T>, Error> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::msg(format!("{} has an invalid value: {}", key, value))),
+        Err(_) => Ok(None),
+    }
+}
+
 #[derive(Clone)]
 pub struct DbManager {
-    pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+    write_pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+    read_pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+    cursor_pool: Arc<Pool<ConnectionManager<PgConnection>>>,
+    acquire_retry: AcquireRetry,
 }
 
 impl DbManager {
-    pub fn new(connection_string: &str) -> Self {
+    /// Builds the primary pool and, when `read_connection_string` is given, a separate
+    /// pool for a read replica. Without a replica, reads go to the primary. The cursor
+    /// connection, see `cursor_conn`, is opened on the primary once first asked for.
+    pub fn new(
+        connection_string: &str,
+        read_connection_string: Option<&str>,
+        config: &PoolConfig,
+    ) -> Result<Self, Error> {
+        let write_pool = Arc::new(Self::build_pool(connection_string, config)?);
+        let read_pool = match read_connection_string {
+            Some(read_connection_string) => {
+                Arc::new(Self::build_pool(read_connection_string, config)?)
+            }
+            None => write_pool.clone(),
+        };
+        let cursor_pool = Arc::new(Self::build_cursor_pool(connection_string, config)?);
+        Ok(DbManager {
+            write_pool,
+            read_pool,
+            cursor_pool,
+            acquire_retry: config.acquire_retry,
+        })
+    }
+
+    /// A primary-only manager that doesn't connect until a connection is first asked for,
+    /// so it can be built while the database is unreachable, e.g. for handler tests that
+    /// never touch it.
+    pub fn new_lazy(connection_string: &str) -> Self {
         let manager = ConnectionManager::<PgConnection>::new(connection_string);
-        let pool = Pool::builder()
-            .build(manager)
-            .expect("Failed to create pool.");
+        let pool = Arc::new(
+            Pool::builder()
+                .min_idle(Some(0))
+                .connection_timeout(Duration::from_secs(1))
+                .build_unchecked(manager),
+        );
         DbManager {
-            pool: Arc::new(pool),
+            write_pool: pool.clone(),
+            read_pool: pool.clone(),
+            cursor_pool: pool,
+            acquire_retry: AcquireRetry::NONE,
+        }
+    }
+
+    fn build_pool(
+        connection_string: &str,
+        config: &PoolConfig,
+    ) -> Result<Pool<ConnectionManager<PgConnection>>, Error> {
+        let manager = ConnectionManager::<PgConnection>::new(connection_string);
+        Pool::builder()
+            .max_size(config.max_size)
+            .min_idle(Some(config.min_idle))
+            .connection_timeout(config.connection_timeout)
+            .connection_customizer(Box::new(StatementTimeout(config.statement_timeout)))
+            .build(manager)
+            .map_err(Error::from)
+    }
+
+    /// A pool of the one connection cursors are declared on. It is never recycled while
+    /// healthy, as closing it would close every cursor on it.
+    fn build_cursor_pool(
+        connection_string: &str,
+        config: &PoolConfig,
+    ) -> Result<Pool<ConnectionManager<PgConnection>>, Error> {
+        let manager = ConnectionManager::<PgConnection>::new(connection_string);
+        Pool::builder()
+            .max_size(1)
+            .min_idle(Some(0))
+            .max_lifetime(None)
+            .idle_timeout(None)
+            .connection_timeout(config.connection_timeout)
+            .connection_customizer(Box::new(StatementTimeout(config.statement_timeout)))
+            .build(manager)
+            .map_err(Error::from)
+    }
+
+    /// Runs `SELECT 1` on a primary connection, waiting at most `timeout` for one.
+    pub fn ping(&self, timeout: Duration) -> Result<(), Error> {
+        let conn = &mut self.write_pool.get_timeout(timeout)?;
+        diesel::sql_query("SELECT 1").execute(conn)?;
+        Ok(())
+    }
+
+    /// Runs `f` inside a transaction on a primary connection, committing if it returns
+    /// `Ok` and rolling every write back if it returns `Err`.
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut PgConnection) -> Result<T, E>,
+        E: From<diesel::result::Error> + From<PoolError>,
+    {
+        let mut conn = self.write_conn()?;
+        PgConnection::transaction(&mut conn, f)
+    }
+
+    /// A manager over a single connection that stays inside a test transaction, so
+    /// everything written through it is rolled back once it is dropped. Transactions
+    /// opened by the repos become savepoints within it, and cursors are declared on it
+    /// too. A statement that fails outside of one aborts the whole test transaction, so
+    /// such cases need a real pool.
+    #[cfg(test)]
+    pub(crate) fn for_test(connection_string: &str) -> Result<Self, Error> {
+        #[derive(Debug)]
+        struct TestTransaction;
+
+        impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for TestTransaction {
+            fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+                conn.begin_test_transaction()
+                    .map_err(diesel::r2d2::Error::QueryError)
+            }
         }
+
+        // Never recycled, or the test would lose what it wrote so far.
+        let pool = Arc::new(
+            Pool::builder()
+                .max_size(1)
+                .max_lifetime(None)
+                .idle_timeout(None)
+                .connection_customizer(Box::new(TestTransaction))
+                .build(ConnectionManager::<PgConnection>::new(connection_string))?,
+        );
+        Ok(DbManager {
+            write_pool: pool.clone(),
+            read_pool: pool.clone(),
+            cursor_pool: pool,
+            acquire_retry: AcquireRetry::NONE,
+        })
+    }
+
+    /// A primary connection, waited for as configured.
+    pub fn write_conn(&self) -> Result<DbConnection, PoolError> {
+        let pool = &self.write_pool;
+        self.acquire_retry
+            .run(pool.connection_timeout(), |wait| pool.get_timeout(wait))
+    }
+
+    /// A connection to the read replica, or the primary without one, waited for as
+    /// configured.
+    pub fn read_conn(&self) -> Result<DbConnection, PoolError> {
+        let pool = &self.read_pool;
+        self.acquire_retry
+            .run(pool.connection_timeout(), |wait| pool.get_timeout(wait))
+    }
+
+    /// The primary connection every cursor is declared, fetched from and closed on. A
+    /// `WITH HOLD` cursor only exists in the session that declared it, so any other
+    /// connection would report it missing. Cursor statements wait for each other on it.
+    pub fn cursor_conn(&self) -> Result<DbConnection, PoolError> {
+        let pool = &self.cursor_pool;
+        self.acquire_retry
+            .run(pool.connection_timeout(), |wait| pool.get_timeout(wait))
     }
 
     pub fn get_write_pool(&self) -> Arc<Pool<ConnectionManager<PgConnection>>> {
-        self.pool.clone()
+        self.write_pool.clone()
     }
 
     pub fn get_read_pool(&self) -> Arc<Pool<ConnectionManager<PgConnection>>> {
-        self.pool.clone()
+        self.read_pool.clone()
+    }
+
+    pub fn get_cursor_pool(&self) -> Arc<Pool<ConnectionManager<PgConnection>>> {
+        self.cursor_pool.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use axum::{http::StatusCode, response::IntoResponse};
+    use diesel::RunQueryDsl;
+
+    use super::{AcquireRetry, DbManager, PoolConfig};
+    use crate::repos::{error::RepoError, fixtures::database_url};
+
+    #[test]
+    fn test_acquire_backoff() {
+        let retry = AcquireRetry {
+            retries: 8,
+            base_delay: Duration::from_millis(50),
+        };
+        let delays: Vec<_> = (0..retry.retries).map(|n| retry.delay(n)).collect();
+        assert_eq!(
+            delays,
+            [50, 100, 200, 400, 800, 1600, 3200, 5000].map(Duration::from_millis)
+        );
+        assert_eq!(retry.delay(u32::MAX), Duration::from_secs(5));
+
+        // Short waits first, then the rest of the timeout, as the pool would wait.
+        let retry = AcquireRetry {
+            retries: 2,
+            base_delay: Duration::from_millis(10),
+        };
+        let timeout = Duration::from_millis(200);
+        let started = Instant::now();
+        let mut waits = Vec::new();
+        let result: Result<(), &str> = retry.run(timeout, |wait| {
+            waits.push(wait);
+            std::thread::sleep(wait);
+            Err("pool exhausted")
+        });
+        assert_eq!(result, Err("pool exhausted"));
+        assert_eq!(waits.len(), 3);
+        assert_eq!(waits[..2], [10, 20].map(Duration::from_millis));
+        assert!(waits[2] > Duration::from_millis(150), "{:?}", waits);
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed < timeout + Duration::from_millis(50),
+            "{:?}",
+            elapsed
+        );
+
+        // Retries that don't fit in the timeout are cut short rather than waited out.
+        let retry = AcquireRetry {
+            retries: 8,
+            base_delay: Duration::from_millis(50),
+        };
+        let mut waits = Vec::new();
+        let result: Result<(), &str> = retry.run(Duration::from_millis(100), |wait| {
+            waits.push(wait);
+            std::thread::sleep(wait);
+            Err("pool exhausted")
+        });
+        assert_eq!(result, Err("pool exhausted"));
+        assert_eq!(waits.len(), 2);
+        assert!(waits.iter().sum::<Duration>() <= Duration::from_millis(100));
+
+        let mut attempts = 0;
+        let result = retry.run(timeout, |_| {
+            attempts += 1;
+            if attempts < 3 {
+                Err("pool exhausted")
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result, Ok(3));
+    }
+
+    #[test]
+    fn test_statement_timeout() {
+        let config = PoolConfig {
+            statement_timeout: Duration::from_millis(100),
+            ..PoolConfig::default()
+        };
+        let db_manager = DbManager::new(&database_url(), None, &config).unwrap();
+        let conn = &mut db_manager.write_conn().unwrap();
+
+        let error = diesel::sql_query("SELECT pg_sleep(5)")
+            .execute(conn)
+            .map_err(RepoError::from)
+            .unwrap_err();
+        assert!(matches!(error, RepoError::Timeout));
+        assert_eq!(error.into_response().status(), StatusCode::GATEWAY_TIMEOUT);
+        // The connection is still usable once the statement is cancelled.
+        diesel::sql_query("SELECT 1").execute(conn).unwrap();
     }
 }
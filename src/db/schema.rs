@@ -13,9 +13,129 @@ diesel::table! {
         user_email -> Varchar,
         #[max_length = 50]
         user_phone -> Varchar,
-        created_at -> Timestamp,
-        updated_at -> Timestamp,
+        created_at -> Timestamptz,
+        updated_at -> Timestamptz,
         top_ad -> Bool,
         images -> Jsonb,
+        expires_at -> Nullable<Timestamptz>,
+        #[max_length = 50]
+        category -> Varchar,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        view_count -> Int8,
+        #[max_length = 3]
+        currency -> Varchar,
+        deleted_at -> Nullable<Timestamptz>,
+        #[max_length = 100]
+        slug -> Varchar,
+        top_ad_until -> Nullable<Timestamptz>,
+        negotiable -> Bool,
+        #[max_length = 5]
+        locale -> Varchar,
+        phone_verified -> Bool,
+    }
+}
+
+diesel::table! {
+    ad_slugs (slug) {
+        #[max_length = 100]
+        slug -> Varchar,
+        ad_id -> Int4,
+    }
+}
+
+diesel::table! {
+    favorites (user_email, ad_id) {
+        #[max_length = 255]
+        user_email -> Varchar,
+        ad_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    idempotency_keys (user_email, key) {
+        #[max_length = 255]
+        user_email -> Varchar,
+        #[max_length = 255]
+        key -> Varchar,
+        ad_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    image_uploads (user_email, image_id) {
+        #[max_length = 255]
+        user_email -> Varchar,
+        #[max_length = 255]
+        image_id -> Varchar,
+        bytes -> Int8,
+        created_at -> Timestamptz,
     }
 }
+
+diesel::table! {
+    outbox (id) {
+        id -> Int8,
+        payload -> Jsonb,
+        #[max_length = 10]
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        sent_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    phone_codes (ad_id) {
+        ad_id -> Int4,
+        #[max_length = 6]
+        code -> Varchar,
+        #[max_length = 50]
+        user_phone -> Varchar,
+        attempts -> Int4,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    price_history (id) {
+        id -> Int4,
+        ad_id -> Int4,
+        price -> Numeric,
+        #[max_length = 3]
+        currency -> Varchar,
+        changed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    reports (id) {
+        id -> Int4,
+        ad_id -> Int4,
+        reason -> Text,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::joinable!(ad_slugs -> ads (ad_id));
+diesel::joinable!(favorites -> ads (ad_id));
+diesel::joinable!(idempotency_keys -> ads (ad_id));
+diesel::joinable!(phone_codes -> ads (ad_id));
+diesel::joinable!(price_history -> ads (ad_id));
+diesel::joinable!(reports -> ads (ad_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    ad_slugs,
+    ads,
+    favorites,
+    idempotency_keys,
+    image_uploads,
+    outbox,
+    phone_codes,
+    price_history,
+    reports,
+);
@@ -0,0 +1,322 @@
+//! Removes privacy-sensitive metadata (EXIF, XMP, IPTC, comments) from uploaded images
+//! without re-encoding them. The EXIF orientation is the one tag kept, rewritten into a
+//! minimal EXIF block, so photos still display upright.
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Returns `bytes` with metadata removed. Formats other than JPEG and PNG are returned
+/// unchanged; a JPEG or PNG whose structure can't be followed is rejected, since its
+/// metadata can't be found reliably.
+pub fn strip_metadata(mime_type: &str, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    match mime_type {
+        "image/jpeg" => strip_jpeg(&bytes),
+        "image/png" => strip_png(&bytes),
+        _ => Ok(bytes),
+    }
+}
+
+fn malformed(format: &str) -> String {
+    format!("{} image is malformed", format)
+}
+
+fn strip_jpeg(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let err = || malformed("JPEG");
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return Err(err());
+    }
+
+    let mut kept = Vec::new();
+    let mut orientation = None;
+    let mut i = 2;
+
+    let rest = loop {
+        if bytes.get(i) != Some(&0xFF) {
+            return Err(err());
+        }
+        // Any number of 0xFF fill bytes may precede a marker.
+        while bytes.get(i) == Some(&0xFF) {
+            i += 1;
+        }
+        let marker = *bytes.get(i).ok_or_else(err)?;
+        i += 1;
+
+        // TEM and RSTn stand alone, without a length.
+        if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            kept.push((marker, &bytes[i..i]));
+            continue;
+        }
+        if marker == 0xD9 {
+            break &bytes[i - 2..];
+        }
+
+        let len = usize::from(u16::from_be_bytes([
+            *bytes.get(i).ok_or_else(err)?,
+            *bytes.get(i + 1).ok_or_else(err)?,
+        ]));
+        let payload = bytes.get(i + 2..i + len).ok_or_else(err)?;
+        i += len;
+
+        match marker {
+            // Start of scan: the compressed data and everything after it is copied as is.
+            0xDA => {
+                kept.push((marker, payload));
+                break &bytes[i..];
+            }
+            // APP1 holds EXIF and XMP.
+            0xE1 => {
+                if let Some(tiff) = payload.strip_prefix(EXIF_HEADER) {
+                    orientation = orientation.or_else(|| tiff_orientation(tiff));
+                }
+            }
+            // APP13 holds IPTC, COM free-text comments.
+            0xED | 0xFE => {}
+            _ => kept.push((marker, payload)),
+        }
+    };
+
+    let mut out = vec![0xFF, 0xD8];
+    // JFIF expects its APP0 first, so the rewritten EXIF goes right after any.
+    let leading_app0 = kept
+        .iter()
+        .take_while(|(marker, _)| *marker == 0xE0)
+        .count();
+    for (n, (marker, payload)) in kept.iter().enumerate() {
+        if n == leading_app0 {
+            if let Some(orientation) = orientation {
+                push_jpeg_segment(&mut out, 0xE1, &minimal_exif(orientation));
+            }
+        }
+        push_jpeg_segment(&mut out, *marker, payload);
+    }
+    out.extend_from_slice(rest);
+
+    Ok(out)
+}
+
+fn push_jpeg_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    out.extend_from_slice(&[0xFF, marker]);
+    if marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+        return;
+    }
+    out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// The orientation from IFD0 of a TIFF-structured EXIF block, unless it's the default.
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| {
+        let b = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    };
+    let u32_at = |at: usize| {
+        let b = [
+            *tiff.get(at)?,
+            *tiff.get(at + 1)?,
+            *tiff.get(at + 2)?,
+            *tiff.get(at + 3)?,
+        ];
+        Some(if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)?;
+    (0..usize::from(entries))
+        .map(|n| ifd + 2 + n * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+/// An EXIF block with IFD0 holding only the orientation.
+fn minimal_exif(orientation: u16) -> Vec<u8> {
+    let mut exif = EXIF_HEADER.to_vec();
+    exif.extend_from_slice(b"MM\0\x2a");
+    exif.extend_from_slice(&8u32.to_be_bytes());
+    exif.extend_from_slice(&1u16.to_be_bytes());
+    // Tag, type SHORT, count 1, value padded to 4 bytes.
+    exif.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    exif.extend_from_slice(&3u16.to_be_bytes());
+    exif.extend_from_slice(&1u32.to_be_bytes());
+    exif.extend_from_slice(&orientation.to_be_bytes());
+    exif.extend_from_slice(&[0, 0]);
+    // No next IFD.
+    exif.extend_from_slice(&0u32.to_be_bytes());
+    exif
+}
+
+fn strip_png(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let err = || malformed("PNG");
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return Err(err());
+    }
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut orientation = None;
+    let mut exif_at = None;
+    let mut i = PNG_SIGNATURE.len();
+
+    while i < bytes.len() {
+        let header = bytes.get(i..i + 8).ok_or_else(err)?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk_type = &header[4..8];
+        let chunk = bytes.get(i..i + 12 + len).ok_or_else(err)?;
+        i += chunk.len();
+
+        match chunk_type {
+            b"eXIf" => orientation = orientation.or_else(|| tiff_orientation(&chunk[8..8 + len])),
+            // Text chunks carry comments, XMP and raw EXIF/IPTC profiles.
+            b"tEXt" | b"zTXt" | b"iTXt" => {}
+            _ => {
+                out.extend_from_slice(chunk);
+                // eXIf has to come before the image data; right after IHDR is always safe.
+                if chunk_type == b"IHDR" {
+                    exif_at = Some(out.len());
+                }
+            }
+        }
+        if chunk_type == b"IEND" {
+            break;
+        }
+    }
+
+    if let (Some(orientation), Some(at)) = (orientation, exif_at) {
+        let exif = minimal_exif(orientation);
+        let tiff = &exif[EXIF_HEADER.len()..];
+        let mut chunk = (tiff.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"eXIf");
+        chunk.extend_from_slice(tiff);
+        chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+        out.splice(at..at, chunk);
+    }
+
+    Ok(out)
+}
+
+/// The CRC-32 PNG chunks end with, over the chunk type and data.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use image::{ImageFormat, RgbImage};
+
+    use super::{crc32, minimal_exif, strip_metadata, tiff_orientation, EXIF_HEADER};
+
+    const GPS: &[u8] = b"GPS 48.1486 N 17.1077 E";
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        RgbImage::new(4, 2).write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    /// An EXIF block with the orientation, followed by data standing in for a GPS IFD.
+    fn exif_with_gps(orientation: u16) -> Vec<u8> {
+        let mut exif = minimal_exif(orientation);
+        exif.extend_from_slice(GPS);
+        exif
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    #[test]
+    fn test_strips_jpeg_exif_keeping_orientation() {
+        let jpeg = encode(ImageFormat::Jpeg);
+        let exif = exif_with_gps(6);
+        let comment = b"shot on a phone";
+
+        let mut tagged = jpeg[..2].to_vec();
+        tagged.extend_from_slice(&[0xFF, 0xE1]);
+        tagged.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        tagged.extend_from_slice(&exif);
+        tagged.extend_from_slice(&[0xFF, 0xFE]);
+        tagged.extend_from_slice(&(comment.len() as u16 + 2).to_be_bytes());
+        tagged.extend_from_slice(comment);
+        tagged.extend_from_slice(&jpeg[2..]);
+        assert!(contains(&tagged, GPS));
+
+        let stripped = strip_metadata("image/jpeg", tagged).unwrap();
+        assert!(!contains(&stripped, GPS));
+        assert!(!contains(&stripped, comment));
+        image::load_from_memory_with_format(&stripped, ImageFormat::Jpeg).unwrap();
+
+        let at = stripped
+            .windows(EXIF_HEADER.len())
+            .position(|window| window == EXIF_HEADER)
+            .unwrap();
+        assert_eq!(
+            tiff_orientation(&stripped[at + EXIF_HEADER.len()..]),
+            Some(6)
+        );
+    }
+
+    #[test]
+    fn test_strips_png_exif_keeping_orientation() {
+        let png = encode(ImageFormat::Png);
+        // Signature and IHDR: 8 + 12 + 13 bytes.
+        let (head, tail) = png.split_at(33);
+        let chunk = |chunk_type: &[u8], data: &[u8]| {
+            let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+            chunk.extend_from_slice(chunk_type);
+            chunk.extend_from_slice(data);
+            chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+            chunk
+        };
+
+        let mut tagged = head.to_vec();
+        tagged.extend(chunk(b"eXIf", &exif_with_gps(3)[EXIF_HEADER.len()..]));
+        tagged.extend(chunk(b"tEXt", b"Comment\0shot on a phone"));
+        tagged.extend_from_slice(tail);
+        assert!(contains(&tagged, GPS));
+
+        let stripped = strip_metadata("image/png", tagged).unwrap();
+        assert!(!contains(&stripped, GPS));
+        assert!(!contains(&stripped, b"shot on a phone"));
+        // The image crate checks every chunk's CRC.
+        image::load_from_memory_with_format(&stripped, ImageFormat::Png).unwrap();
+
+        let at = stripped
+            .windows(4)
+            .position(|window| window == b"eXIf")
+            .unwrap();
+        assert_eq!(tiff_orientation(&stripped[at + 4..]), Some(3));
+    }
+
+    #[test]
+    fn test_rejects_truncated_images() {
+        assert!(strip_metadata("image/jpeg", vec![0xFF, 0xD8, 0xFF, 0xE1, 0x00]).is_err());
+        assert!(strip_metadata("image/png", b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec()).is_err());
+        assert_eq!(
+            strip_metadata("image/webp", b"RIFF".to_vec()).unwrap(),
+            b"RIFF"
+        );
+    }
+}
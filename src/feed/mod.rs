@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+
+use crate::models::ad::Ad;
+
+/// Ads a subscriber may fall behind by before it starts missing them.
+pub const FEED_CAPACITY: usize = 256;
+
+/// Newly created ads, fanned out to every `/ws/ads` connection. Each subscriber filters
+/// the ads itself.
+#[derive(Clone)]
+pub struct AdFeed {
+    sender: broadcast::Sender<Arc<Ad>>,
+}
+
+impl AdFeed {
+    pub fn new(capacity: usize) -> AdFeed {
+        let (sender, _) = broadcast::channel(capacity);
+        AdFeed { sender }
+    }
+
+    /// Never waits on subscribers: one that is more than the capacity behind skips the
+    /// oldest ads instead. Without subscribers the ad is simply dropped.
+    pub fn publish(&self, ad: &Ad) {
+        let _ = self.sender.send(Arc::new(ad.clone()));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Ad>> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for AdFeed {
+    fn default() -> Self {
+        AdFeed::new(FEED_CAPACITY)
+    }
+}
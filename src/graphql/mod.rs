@@ -0,0 +1,559 @@
+//! A GraphQL API over the same repositories as the REST routes, for clients that want to
+//! pick fields and fetch ads with their images in one request.
+
+use std::sync::Arc;
+
+use async_graphql::{
+    Context, EmptySubscription, Error, ErrorExtensions, InputObject, Object, Result, Schema,
+    SimpleObject,
+};
+
+use crate::auth::AuthUser;
+use crate::feed::AdFeed;
+use crate::maintenance::Maintenance;
+use crate::models::ad::{
+    parse_price, Ad, AdCategory, AdContent, AdId, AdPatch, AdStatus, Currency, Locale, PublicAd,
+};
+use crate::models::money::Money;
+use crate::repos::ad_repo::{AdFilter, AdRepo, AdSort, PageLimits};
+use crate::repos::error::{FieldError, RepoError};
+use crate::repos::image_repo::ImageRepo;
+
+pub type AdSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+/// Deepest nesting a query may use; the schema itself is only a few levels deep.
+const MAX_QUERY_DEPTH: usize = 8;
+
+pub fn schema(
+    ad_repo: Arc<dyn AdRepo>,
+    image_repo: Arc<dyn ImageRepo>,
+    ad_feed: AdFeed,
+    base_currency: Currency,
+    default_locale: Locale,
+    maintenance: Maintenance,
+    page_limits: PageLimits,
+) -> AdSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(ad_repo)
+        .data(image_repo)
+        .data(ad_feed)
+        .data(base_currency)
+        .data(default_locale)
+        .data(maintenance)
+        .data(page_limits)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .finish()
+}
+
+/// The user behind the request's bearer token, if any. Added to each request's data.
+pub struct Viewer(pub Option<AuthUser>);
+
+/// Maps a `RepoError` the way the REST API maps it to a status, as `extensions.code`.
+/// Server-side details are logged rather than returned.
+fn repo_error(e: RepoError) -> Error {
+    let (code, message) = match e {
+        RepoError::NotFound => ("NOT_FOUND", e.to_string()),
+        RepoError::Forbidden => ("FORBIDDEN", e.to_string()),
+        RepoError::Conflict(message) => ("CONFLICT", message),
+        RepoError::PreconditionFailed => ("PRECONDITION_FAILED", e.to_string()),
+        RepoError::PreconditionRequired => ("PRECONDITION_REQUIRED", e.to_string()),
+        RepoError::CursorExpired => ("CURSOR_EXPIRED", e.to_string()),
+        RepoError::TooSoon(_) => ("TOO_SOON", e.to_string()),
+        RepoError::Maintenance(_) => ("MAINTENANCE", e.to_string()),
+        RepoError::QuotaExceeded(message) => ("QUOTA_EXCEEDED", message),
+        RepoError::Validation(message) | RepoError::InvalidId(message) => ("BAD_REQUEST", message),
+        RepoError::InvalidFields(errors) => return invalid_fields(errors),
+        RepoError::Unavailable(_) => {
+            tracing::error!("{}", e);
+            ("UNAVAILABLE", "service unavailable".to_string())
+        }
+        RepoError::Timeout => {
+            tracing::error!("{}", e);
+            ("TIMEOUT", "the request took too long".to_string())
+        }
+        RepoError::RangeNotSatisfiable(_) | RepoError::Database(_) | RepoError::Internal(_) => {
+            tracing::error!("{}", e);
+            ("INTERNAL", "internal error".to_string())
+        }
+    };
+
+    Error::new(message).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+/// Rejected input fields, listed under `extensions.fields` like the REST error `details`.
+fn invalid_fields(errors: Vec<FieldError>) -> Error {
+    Error::new("invalid fields").extend_with(|_, extensions| {
+        extensions.set("code", "BAD_REQUEST");
+        extensions.set(
+            "fields",
+            async_graphql::to_value(&errors).unwrap_or_default(),
+        );
+    })
+}
+
+fn viewer<'a>(ctx: &Context<'a>) -> Option<&'a AuthUser> {
+    ctx.data_opt::<Viewer>()
+        .and_then(|Viewer(user)| user.as_ref())
+}
+
+fn require_viewer<'a>(ctx: &Context<'a>) -> Result<&'a AuthUser> {
+    viewer(ctx).ok_or_else(|| {
+        Error::new("authentication required")
+            .extend_with(|_, extensions| extensions.set("code", "UNAUTHENTICATED"))
+    })
+}
+
+/// Parses an optional price argument, reporting failures under `field`.
+fn price_arg(
+    field: &'static str,
+    price: Option<String>,
+    errors: &mut Vec<FieldError>,
+) -> Option<Money> {
+    match parse_price(&price?) {
+        Ok(price) => Some(price),
+        Err(e) => {
+            errors.push(FieldError {
+                field,
+                message: e.message,
+            });
+            None
+        }
+    }
+}
+
+/// Why a write filtered on the owner matched no row: someone else's ad, or no such ad.
+async fn not_owned_error(ad_repo: &Arc<dyn AdRepo>, id: AdId) -> Error {
+    match ad_repo.exists(id).await {
+        Ok(true) => repo_error(RepoError::Forbidden),
+        Ok(false) => repo_error(RepoError::NotFound),
+        Err(e) => repo_error(e),
+    }
+}
+
+/// An ad as returned to the viewer, with contact details already masked if need be.
+pub struct AdObject(Ad);
+
+impl AdObject {
+    fn for_viewer(ad: Ad, viewer: Option<&AuthUser>) -> Self {
+        AdObject(PublicAd::for_viewer(ad, viewer).into_inner())
+    }
+}
+
+#[Object(name = "Ad")]
+impl AdObject {
+    async fn id(&self) -> i32 {
+        self.0.id.0
+    }
+
+    async fn title(&self) -> &str {
+        &self.0.title
+    }
+
+    async fn description(&self) -> &str {
+        &self.0.description
+    }
+
+    /// A decimal string, so no precision is lost to floats.
+    async fn price(&self) -> String {
+        self.0.price.to_string()
+    }
+
+    async fn currency(&self) -> &str {
+        &self.0.currency
+    }
+
+    async fn status(&self) -> &str {
+        &self.0.status
+    }
+
+    async fn category(&self) -> &str {
+        &self.0.category
+    }
+
+    async fn user_email(&self) -> &str {
+        &self.0.user_email
+    }
+
+    async fn user_phone(&self) -> &str {
+        &self.0.user_phone
+    }
+
+    async fn top_ad(&self) -> bool {
+        self.0.top_ad
+    }
+
+    async fn top_ad_until(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.0.top_ad_until
+    }
+
+    async fn negotiable(&self) -> bool {
+        self.0.negotiable
+    }
+
+    async fn locale(&self) -> &str {
+        &self.0.locale
+    }
+
+    async fn phone_verified(&self) -> bool {
+        self.0.phone_verified
+    }
+
+    async fn latitude(&self) -> Option<f64> {
+        self.0.latitude
+    }
+
+    async fn longitude(&self) -> Option<f64> {
+        self.0.longitude
+    }
+
+    /// For links of the form `/ads/slug/<slug>`.
+    async fn slug(&self) -> &str {
+        &self.0.slug
+    }
+
+    async fn view_count(&self) -> i64 {
+        self.0.view_count
+    }
+
+    async fn created_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.0.created_at
+    }
+
+    async fn updated_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.0.updated_at
+    }
+
+    async fn expires_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.0.expires_at
+    }
+
+    /// In display order; the first is the primary image.
+    async fn images(&self) -> Vec<ImageObject> {
+        self.0.images.iter().cloned().map(ImageObject).collect()
+    }
+}
+
+pub struct ImageObject(String);
+
+#[Object(name = "Image")]
+impl ImageObject {
+    async fn id(&self) -> &str {
+        &self.0
+    }
+
+    /// Path of the full image, relative to the API's base URL.
+    async fn url(&self) -> String {
+        format!("/images/{}", self.0)
+    }
+
+    async fn thumbnail_url(&self) -> String {
+        format!("/images/{}/thumbnail", self.0)
+    }
+
+    /// Looked up in image storage, so only requested when needed.
+    async fn mime_type(&self, ctx: &Context<'_>) -> Result<String> {
+        let image_repo = ctx.data::<Arc<dyn ImageRepo>>()?;
+        let image = image_repo
+            .open_image_stream(&self.0, None)
+            .await
+            .map_err(repo_error)?;
+        Ok(image.mime_type)
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct AdPage {
+    page: u32,
+    total: u64,
+    items: Vec<AdObject>,
+}
+
+/// The `AdFilter` of the REST API. Prices are decimal strings.
+#[derive(InputObject, Default)]
+#[graphql(name = "AdFilter")]
+pub struct AdFilterInput {
+    search: Option<String>,
+    title_contains: Option<String>,
+    description_contains: Option<String>,
+    price_lt: Option<String>,
+    price_gt: Option<String>,
+    price_between: Option<PriceRangeInput>,
+    currency_eq: Option<Currency>,
+    updated_at_lt: Option<chrono::DateTime<chrono::Utc>>,
+    updated_at_gt: Option<chrono::DateTime<chrono::Utc>>,
+    created_at_lt: Option<chrono::DateTime<chrono::Utc>>,
+    created_at_gt: Option<chrono::DateTime<chrono::Utc>>,
+    category_eq: Option<AdCategory>,
+    near_lat: Option<f64>,
+    near_lon: Option<f64>,
+    radius_km: Option<f64>,
+    has_images: Option<bool>,
+    top_ad: Option<bool>,
+    negotiable_eq: Option<bool>,
+    locale_eq: Option<Locale>,
+    phone_verified_eq: Option<bool>,
+    #[graphql(default)]
+    include_expired: bool,
+    status_eq: Option<AdStatus>,
+    sort_by: Option<AdSort>,
+}
+
+impl AdFilterInput {
+    fn into_filter(self) -> Result<AdFilter> {
+        let mut errors = Vec::new();
+        let filter = AdFilter {
+            search: self.search,
+            title_contains: self.title_contains,
+            description_contains: self.description_contains,
+            price_lt: price_arg("price_lt", self.price_lt, &mut errors),
+            price_gt: price_arg("price_gt", self.price_gt, &mut errors),
+            price_between: self.price_between.and_then(|range| {
+                let min = price_arg("price_between", Some(range.min), &mut errors);
+                let max = price_arg("price_between", Some(range.max), &mut errors);
+                min.zip(max)
+            }),
+            currency_eq: self.currency_eq,
+            updated_at_lt: self.updated_at_lt,
+            updated_at_gt: self.updated_at_gt,
+            created_at_lt: self.created_at_lt,
+            created_at_gt: self.created_at_gt,
+            category_eq: self.category_eq,
+            near_lat: self.near_lat,
+            near_lon: self.near_lon,
+            radius_km: self.radius_km,
+            has_images: self.has_images,
+            top_ad: self.top_ad,
+            negotiable_eq: self.negotiable_eq,
+            locale_eq: self.locale_eq,
+            phone_verified_eq: self.phone_verified_eq,
+            user_email_eq: None,
+            user_phone_eq: None,
+            include_expired: self.include_expired,
+            include_deleted: false,
+            status_eq: self.status_eq,
+            sort_by: self.sort_by,
+        };
+        if let Err(filter_errors) = filter.validate() {
+            errors.extend(filter_errors);
+        }
+
+        if errors.is_empty() {
+            Ok(filter)
+        } else {
+            Err(invalid_fields(errors))
+        }
+    }
+}
+
+/// Inclusive bounds, as decimal strings.
+#[derive(InputObject)]
+#[graphql(name = "PriceRange")]
+pub struct PriceRangeInput {
+    min: String,
+    max: String,
+}
+
+#[derive(InputObject, Default)]
+#[graphql(name = "Page")]
+pub struct PageInput {
+    /// The configured default when omitted; larger pages are cut down to the configured
+    /// maximum, as with `GET /ads`.
+    per_page: Option<u32>,
+    /// At most `MAX_OFFSET`.
+    offset: Option<u32>,
+}
+
+/// A new ad, owned by the authenticated user. Images are attached afterwards with
+/// `POST /ads/:id/images`.
+#[derive(InputObject)]
+#[graphql(name = "CreateAd")]
+pub struct CreateAdInput {
+    title: String,
+    description: String,
+    price: String,
+    /// The configured base currency when omitted.
+    currency: Option<Currency>,
+    user_phone: String,
+    #[graphql(default)]
+    negotiable: bool,
+    /// The configured default locale when omitted.
+    locale: Option<Locale>,
+    #[graphql(default)]
+    category: AdCategory,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+/// Only the fields given are changed.
+#[derive(InputObject)]
+#[graphql(name = "UpdateAd")]
+pub struct UpdateAdInput {
+    title: Option<String>,
+    description: Option<String>,
+    price: Option<String>,
+    currency: Option<Currency>,
+    user_phone: Option<String>,
+    negotiable: Option<bool>,
+    locale: Option<Locale>,
+    status: Option<AdStatus>,
+    category: Option<AdCategory>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn ads(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<AdFilterInput>,
+        page: Option<PageInput>,
+    ) -> Result<AdPage> {
+        let ad_repo = ctx.data::<Arc<dyn AdRepo>>()?;
+        let page_limits = ctx.data::<PageLimits>()?;
+        let filter = filter.unwrap_or_default().into_filter()?;
+        let page = page.unwrap_or_default();
+        let per_page = page_limits.per_page(page.per_page).map_err(repo_error)?;
+        let offset = page_limits.offset(page.offset).map_err(repo_error)?;
+
+        // Counted first, so an offset past the end needn't fetch a page to come back empty.
+        let total = ad_repo.count(filter.clone()).await.map_err(repo_error)?;
+        page_limits
+            .check_offset(offset, total)
+            .map_err(repo_error)?;
+        let items = if u64::from(offset) < total {
+            ad_repo
+                .get_page(offset, per_page, filter)
+                .await
+                .map_err(repo_error)?
+        } else {
+            Vec::new()
+        };
+
+        Ok(AdPage {
+            page: offset / per_page + 1,
+            total,
+            items: items
+                .into_iter()
+                .map(|ad| AdObject::for_viewer(ad, viewer(ctx)))
+                .collect(),
+        })
+    }
+
+    /// Counts a view, like `GET /ads/:id`; `preview` skips that.
+    async fn ad(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        #[graphql(default)] preview: bool,
+    ) -> Result<Option<AdObject>> {
+        let ad_repo = ctx.data::<Arc<dyn AdRepo>>()?;
+        let ad = ad_repo
+            .get_by_id(AdId(id), !preview)
+            .await
+            .map_err(repo_error)?;
+        Ok(ad
+            .filter(|ad| PublicAd::is_visible_to(ad, viewer(ctx)))
+            .map(|ad| AdObject::for_viewer(ad, viewer(ctx))))
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_ad(&self, ctx: &Context<'_>, input: CreateAdInput) -> Result<AdObject> {
+        let user = require_viewer(ctx)?;
+        ctx.data::<Maintenance>()?.check().map_err(repo_error)?;
+        let ad_repo = ctx.data::<Arc<dyn AdRepo>>()?;
+        let base_currency = *ctx.data::<Currency>()?;
+        let default_locale = *ctx.data::<Locale>()?;
+
+        let mut errors = Vec::new();
+        let content = AdContent {
+            title: input.title,
+            description: input.description,
+            price: price_arg("price", Some(input.price), &mut errors).unwrap_or_default(),
+            currency: input.currency.unwrap_or(base_currency),
+            user_email: user.email.clone(),
+            user_phone: input.user_phone,
+            negotiable: input.negotiable,
+            locale: input.locale.unwrap_or(default_locale),
+            category: input.category,
+            latitude: input.latitude,
+            longitude: input.longitude,
+            draft: false,
+        };
+        if let Err(content_errors) = content.validate() {
+            errors.extend(content_errors);
+        }
+        if !errors.is_empty() {
+            return Err(invalid_fields(errors));
+        }
+
+        let ad = ad_repo
+            .create(content, Vec::new())
+            .await
+            .map_err(repo_error)?;
+        ctx.data::<AdFeed>()?.publish(&ad);
+        Ok(AdObject(ad))
+    }
+
+    /// Only the owner may update an ad.
+    async fn update_ad(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        input: UpdateAdInput,
+    ) -> Result<AdObject> {
+        let user = require_viewer(ctx)?;
+        ctx.data::<Maintenance>()?.check().map_err(repo_error)?;
+        let ad_repo = ctx.data::<Arc<dyn AdRepo>>()?;
+        let id = AdId(id);
+
+        let mut errors = Vec::new();
+        let changes = AdPatch {
+            title: input.title,
+            description: input.description,
+            price: price_arg("price", input.price, &mut errors),
+            currency: input.currency,
+            user_phone: input.user_phone,
+            negotiable: input.negotiable,
+            locale: input.locale,
+            status: input.status,
+            category: input.category,
+            latitude: input.latitude,
+            longitude: input.longitude,
+        };
+        if let Err(patch_errors) = changes.validate() {
+            errors.extend(patch_errors);
+        }
+        if !errors.is_empty() {
+            return Err(invalid_fields(errors));
+        }
+
+        match ad_repo
+            .patch(id, &user.email, changes, None)
+            .await
+            .map_err(repo_error)?
+        {
+            Some(ad) => Ok(AdObject(ad)),
+            None => Err(not_owned_error(ad_repo, id).await),
+        }
+    }
+
+    /// Soft-deletes the ad, like `DELETE /ads/:id`. Only the owner may delete it.
+    async fn delete_ad(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let user = require_viewer(ctx)?;
+        ctx.data::<Maintenance>()?.check().map_err(repo_error)?;
+        let ad_repo = ctx.data::<Arc<dyn AdRepo>>()?;
+        let id = AdId(id);
+
+        match ad_repo.delete(id, &user.email).await.map_err(repo_error)? {
+            0 => Err(not_owned_error(ad_repo, id).await),
+            _ => Ok(true),
+        }
+    }
+}
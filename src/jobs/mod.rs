@@ -0,0 +1,366 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Error;
+use tokio::{sync::watch, time::MissedTickBehavior};
+
+use crate::db::parse_env;
+use crate::notify::Notifier;
+use crate::repos::ad_repo::AdRepo;
+use crate::repos::outbox_repo::OutboxRepo;
+
+/// Expiry job settings, read from `EXPIRE_INTERVAL_SECS` and `CURSOR_MAX_AGE_SECS`.
+#[derive(Clone, Debug)]
+pub struct ExpiryConfig {
+    /// Time between runs. Defaults to 5 minutes.
+    pub interval: Duration,
+    /// `WITH HOLD` cursors not fetched from for this long are closed. Defaults to 1 hour.
+    pub cursor_max_age: Duration,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        ExpiryConfig {
+            interval: Duration::from_secs(5 * 60),
+            cursor_max_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl ExpiryConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        let defaults = ExpiryConfig::default();
+        let interval = parse_env("EXPIRE_INTERVAL_SECS")?
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.interval);
+        if interval.is_zero() {
+            return Err(Error::msg("EXPIRE_INTERVAL_SECS must be greater than 0"));
+        }
+
+        Ok(ExpiryConfig {
+            interval,
+            cursor_max_age: parse_env("CURSOR_MAX_AGE_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.cursor_max_age),
+        })
+    }
+}
+
+/// Periodically expires past-due ads and promotions, closes stale cursors and purges
+/// expired idempotency keys until `shutdown` flips.
+/// Failures are logged and retried on the next tick rather than ending the job.
+pub async fn run_expiry(
+    ad_repo: Arc<dyn AdRepo>,
+    config: ExpiryConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => break,
+        }
+
+        match ad_repo.mark_expired().await {
+            Ok(expired) => tracing::info!(expired, "expired ads"),
+            Err(e) => tracing::warn!(error = %e, "failed to expire ads"),
+        }
+
+        match ad_repo.end_promotions().await {
+            Ok(ended) => tracing::info!(ended, "ended promotions"),
+            Err(e) => tracing::warn!(error = %e, "failed to end promotions"),
+        }
+
+        match ad_repo.close_stale_cursors(config.cursor_max_age).await {
+            Ok(closed) => tracing::info!(closed, "closed stale cursors"),
+            Err(e) => tracing::warn!(error = %e, "failed to close stale cursors"),
+        }
+
+        match ad_repo.purge_idempotency_keys().await {
+            Ok(purged) => tracing::info!(purged, "purged idempotency keys"),
+            Err(e) => tracing::warn!(error = %e, "failed to purge idempotency keys"),
+        }
+    }
+
+    tracing::info!("expiry job stopped");
+}
+
+/// Messages claimed per outbox run.
+const OUTBOX_BATCH_SIZE: u32 = 50;
+
+/// How long claimed messages are held back from other workers; plenty for a batch to
+/// go out even with a slow mail server.
+const OUTBOX_LEASE: Duration = Duration::from_secs(5 * 60);
+
+/// Longest wait between two attempts at the same message.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Outbox worker settings, read from `OUTBOX_INTERVAL_SECS`, `OUTBOX_MAX_ATTEMPTS` and
+/// `OUTBOX_RETRY_SECS`.
+#[derive(Clone, Debug)]
+pub struct OutboxConfig {
+    /// Time between polls. Defaults to 10 seconds.
+    pub interval: Duration,
+    /// Attempts before a message is marked failed. Defaults to 8.
+    pub max_attempts: u32,
+    /// Wait after the first failure, doubled after each further one up to an hour.
+    /// Defaults to 30 seconds.
+    pub retry_delay: Duration,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            interval: Duration::from_secs(10),
+            max_attempts: 8,
+            retry_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl OutboxConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        let defaults = OutboxConfig::default();
+        let interval = parse_env("OUTBOX_INTERVAL_SECS")?
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.interval);
+        if interval.is_zero() {
+            return Err(Error::msg("OUTBOX_INTERVAL_SECS must be greater than 0"));
+        }
+        let max_attempts = parse_env("OUTBOX_MAX_ATTEMPTS")?.unwrap_or(defaults.max_attempts);
+        if max_attempts == 0 {
+            return Err(Error::msg("OUTBOX_MAX_ATTEMPTS must be greater than 0"));
+        }
+
+        Ok(OutboxConfig {
+            interval,
+            max_attempts,
+            retry_delay: parse_env("OUTBOX_RETRY_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.retry_delay),
+        })
+    }
+
+    /// Wait before the next attempt at a message that has failed `attempts` times.
+    fn backoff(&self, attempts: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+/// What one `deliver_outbox` run did.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OutboxRun {
+    pub sent: usize,
+    /// Failed, to be tried again later.
+    pub retrying: usize,
+    /// Failed for the last time and marked failed.
+    pub failed: usize,
+}
+
+/// Sends the messages that are due, one batch, marking each sent or scheduling its
+/// retry with exponential backoff.
+pub async fn deliver_outbox(
+    outbox_repo: &dyn OutboxRepo,
+    notifier: &dyn Notifier,
+    config: &OutboxConfig,
+) -> Result<OutboxRun, Error> {
+    let mut run = OutboxRun::default();
+
+    for entry in outbox_repo
+        .claim_due(OUTBOX_BATCH_SIZE, OUTBOX_LEASE)
+        .await?
+    {
+        let error = match notifier.send(entry.message).await {
+            Ok(()) => {
+                outbox_repo.mark_sent(entry.id).await?;
+                run.sent += 1;
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        let attempts = entry.attempts + 1;
+        let retry_at = (attempts < config.max_attempts).then(|| {
+            chrono::Utc::now()
+                + chrono::Duration::from_std(config.backoff(attempts)).unwrap_or_default()
+        });
+        // Only the id goes to the log, the message holds addresses.
+        tracing::warn!(id = entry.id, attempts, error = %error, "failed to send message");
+        outbox_repo
+            .mark_failed(entry.id, &format!("{:#}", error), retry_at)
+            .await?;
+        match retry_at {
+            Some(_) => run.retrying += 1,
+            None => run.failed += 1,
+        }
+    }
+
+    Ok(run)
+}
+
+/// Delivers outbox messages every `config.interval` until `shutdown` flips. Failures
+/// are logged and retried on the next tick rather than ending the job.
+pub async fn run_outbox(
+    outbox_repo: Arc<dyn OutboxRepo>,
+    notifier: Arc<dyn Notifier>,
+    config: OutboxConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => break,
+        }
+
+        match deliver_outbox(&*outbox_repo, &*notifier, &config).await {
+            Ok(run) if run == OutboxRun::default() => {}
+            Ok(run) => tracing::info!(
+                sent = run.sent,
+                retrying = run.retrying,
+                failed = run.failed,
+                "delivered outbox messages"
+            ),
+            Err(e) => tracing::warn!(error = %e, "failed to deliver outbox messages"),
+        }
+    }
+
+    tracing::info!("outbox job stopped");
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use anyhow::Error;
+    use axum::async_trait;
+
+    use super::{deliver_outbox, OutboxConfig, OutboxRun};
+    use crate::models::outbox::OutboxStatus;
+    use crate::notify::{ContactMessage, Notifier};
+    use crate::repos::{mock::InMemoryOutboxRepo, outbox_repo::OutboxRepo};
+
+    /// Fails the first `failures` sends, then records the rest.
+    struct FlakyNotifier {
+        failures: Mutex<u32>,
+        sent: Mutex<Vec<ContactMessage>>,
+    }
+
+    #[async_trait]
+    impl Notifier for FlakyNotifier {
+        async fn send(&self, message: ContactMessage) -> Result<(), Error> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(Error::msg("connection refused"));
+            }
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    fn message() -> ContactMessage {
+        ContactMessage {
+            to: "seller@test.com".to_string(),
+            reply_to: "buyer@test.com".to_string(),
+            subject: "Re: Bike".to_string(),
+            body: "Is it still available?".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_sends_are_retried() {
+        let outbox_repo = InMemoryOutboxRepo::new();
+        let notifier = FlakyNotifier {
+            failures: Mutex::new(2),
+            sent: Mutex::new(Vec::new()),
+        };
+        // Retries are due right away.
+        let config = OutboxConfig {
+            retry_delay: Duration::ZERO,
+            ..OutboxConfig::default()
+        };
+        outbox_repo.enqueue(message()).await.unwrap();
+
+        for _ in 0..2 {
+            let run = deliver_outbox(&*outbox_repo, &notifier, &config)
+                .await
+                .unwrap();
+            assert_eq!(
+                run,
+                OutboxRun {
+                    retrying: 1,
+                    ..OutboxRun::default()
+                }
+            );
+            assert_eq!(outbox_repo.messages()[0].1, OutboxStatus::Pending);
+        }
+
+        let run = deliver_outbox(&*outbox_repo, &notifier, &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            run,
+            OutboxRun {
+                sent: 1,
+                ..OutboxRun::default()
+            }
+        );
+        assert_eq!(
+            outbox_repo.messages(),
+            vec![(message(), OutboxStatus::Sent)]
+        );
+        assert_eq!(*notifier.sent.lock().unwrap(), vec![message()]);
+
+        // Nothing is left to send.
+        let run = deliver_outbox(&*outbox_repo, &notifier, &config)
+            .await
+            .unwrap();
+        assert_eq!(run, OutboxRun::default());
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let outbox_repo = InMemoryOutboxRepo::new();
+        let notifier = FlakyNotifier {
+            failures: Mutex::new(u32::MAX),
+            sent: Mutex::new(Vec::new()),
+        };
+        let config = OutboxConfig {
+            max_attempts: 2,
+            retry_delay: Duration::ZERO,
+            ..OutboxConfig::default()
+        };
+        outbox_repo.enqueue(message()).await.unwrap();
+
+        deliver_outbox(&*outbox_repo, &notifier, &config)
+            .await
+            .unwrap();
+        let run = deliver_outbox(&*outbox_repo, &notifier, &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            run,
+            OutboxRun {
+                failed: 1,
+                ..OutboxRun::default()
+            }
+        );
+        assert_eq!(outbox_repo.messages()[0].1, OutboxStatus::Failed);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_an_hour() {
+        let config = OutboxConfig::default();
+        assert_eq!(config.backoff(1), Duration::from_secs(30));
+        assert_eq!(config.backoff(2), Duration::from_secs(60));
+        assert_eq!(config.backoff(3), Duration::from_secs(120));
+        assert_eq!(config.backoff(40), Duration::from_secs(60 * 60));
+    }
+}
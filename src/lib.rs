@@ -1,3 +1,15 @@
+pub mod auth;
+pub mod config;
+pub mod cors;
 pub mod db;
+pub mod exif;
+pub mod feed;
+pub mod graphql;
+pub mod jobs;
+pub mod maintenance;
 pub mod models;
+pub mod notify;
+pub mod rate_limit;
 pub mod repos;
+pub mod request_log;
+pub mod telemetry;
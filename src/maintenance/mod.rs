@@ -0,0 +1,100 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Error;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::db::parse_env;
+use crate::repos::error::RepoError;
+
+/// Maintenance mode settings, read from `MAINTENANCE_MODE` and
+/// `MAINTENANCE_RETRY_AFTER_SECS`.
+#[derive(Clone, Debug)]
+pub struct MaintenanceConfig {
+    /// Start in maintenance mode. Off by default; admins can also switch it at runtime.
+    pub enabled: bool,
+    /// What `Retry-After` tells rejected clients. Defaults to 5 minutes.
+    pub retry_after: Duration,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig {
+            enabled: false,
+            retry_after: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        let defaults = MaintenanceConfig::default();
+        Ok(MaintenanceConfig {
+            enabled: parse_env("MAINTENANCE_MODE")?.unwrap_or(defaults.enabled),
+            retry_after: parse_env("MAINTENANCE_RETRY_AFTER_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.retry_after),
+        })
+    }
+}
+
+/// Whether writes are turned away, e.g. while a deployment migrates the database. Clones
+/// share the flag, so a toggle applies to every route at once. Reads are never affected.
+#[derive(Clone)]
+pub struct Maintenance {
+    enabled: Arc<AtomicBool>,
+    retry_after: Duration,
+}
+
+impl Maintenance {
+    pub fn new(config: &MaintenanceConfig) -> Self {
+        Maintenance {
+            enabled: Arc::new(AtomicBool::new(config.enabled)),
+            retry_after: config.retry_after,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// `Maintenance` while writes are turned away.
+    pub fn check(&self) -> Result<(), RepoError> {
+        if self.is_enabled() {
+            return Err(RepoError::Maintenance(self.retry_after.as_secs()));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance::new(&MaintenanceConfig::default())
+    }
+}
+
+/// Middleware for routes that write: answers 503 with `Retry-After` instead of running
+/// the handler while in maintenance mode.
+pub async fn reject_writes(
+    State(maintenance): State<Maintenance>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match maintenance.check() {
+        Ok(()) => next.run(request).await,
+        Err(e) => e.into_response(),
+    }
+}
@@ -1,42 +1,1055 @@
+use std::{collections::HashMap, fmt, io::Write, str::FromStr};
+
 use axum_typed_multipart::{FieldData, TryFromMultipart};
-use bigdecimal::BigDecimal;
-use diesel::{prelude::AsChangeset, Insertable, Queryable, QueryableByName, Selectable};
+use diesel::{
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::AsChangeset,
+    serialize::{self, Output, ToSql},
+    sql_types::{Integer, Jsonb},
+    Insertable, Queryable, QueryableByName, Selectable,
+};
 use serde_derive::Serialize;
 use tempfile::NamedTempFile;
 
-#[derive(Serialize, Queryable, Selectable, Insertable, AsChangeset, QueryableByName, Debug)]
+use crate::auth::AuthUser;
+use crate::models::image::{ImageDimensions, ImageLinks, SignedUrl};
+use crate::models::money::{Money, MoneyError};
+use crate::repos::error::{FieldError, RepoError};
+
+pub const MAX_TITLE_LEN: usize = 255;
+
+/// An ad's primary key, kept apart from other integers so they can't be mixed up.
+/// Stored as a plain `INTEGER` and serialized as a bare number.
+#[derive(
+    serde::Deserialize,
+    Serialize,
+    utoipa::ToSchema,
+    AsExpression,
+    FromSqlRow,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+)]
+#[serde(transparent)]
+#[diesel(sql_type = Integer)]
+pub struct AdId(pub i32);
+
+impl fmt::Display for AdId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for AdId {
+    type Err = RepoError;
+
+    /// Fails with `RepoError::InvalidId`, so a malformed id in a path is a 400 rather
+    /// than a 404.
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        id.parse()
+            .map(AdId)
+            .map_err(|_| RepoError::InvalidId(format!("invalid ad id: {}", id)))
+    }
+}
+
+impl ToSql<Integer, Pg> for AdId {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <i32 as ToSql<Integer, Pg>>::to_sql(&self.0, out)
+    }
+}
+
+impl FromSql<Integer, Pg> for AdId {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        i32::from_sql(bytes).map(AdId)
+    }
+}
+
+/// The `images` JSONB column: the ad's image ids in display order. Rows written before
+/// the column was kept to a list may hold `null`, which reads as no images; anything
+/// other than a list of strings fails to load rather than reaching clients.
+#[derive(AsExpression, FromSqlRow, Debug)]
+#[diesel(sql_type = Jsonb)]
+pub struct ImageIds(pub Vec<String>);
+
+impl From<ImageIds> for Vec<String> {
+    fn from(ids: ImageIds) -> Self {
+        ids.0
+    }
+}
+
+impl From<Vec<String>> for ImageIds {
+    fn from(ids: Vec<String>) -> Self {
+        ImageIds(ids)
+    }
+}
+
+impl ToSql<Jsonb, Pg> for ImageIds {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        // The JSONB binary format: a version byte, then the JSON text.
+        out.write_all(&[1])?;
+        serde_json::to_writer(out, &self.0)?;
+        Ok(serialize::IsNull::No)
+    }
+}
+
+impl FromSql<Jsonb, Pg> for ImageIds {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match <serde_json::Value as FromSql<Jsonb, Pg>>::from_sql(bytes)? {
+            serde_json::Value::Null => Ok(ImageIds(Vec::new())),
+            images => serde_json::from_value(images)
+                .map(ImageIds)
+                .map_err(|e| format!("ad images must be a list of ids: {}", e).into()),
+        }
+    }
+}
+
+#[derive(
+    Clone,
+    Serialize,
+    utoipa::ToSchema,
+    Queryable,
+    Selectable,
+    Insertable,
+    AsChangeset,
+    QueryableByName,
+    Debug,
+)]
 #[diesel(table_name = crate::db::schema::ads)]
 pub struct Ad {
-    pub id: i32,
+    pub id: AdId,
     pub title: String,
     pub description: String,
-    pub price: BigDecimal,
+    pub price: Money,
+    #[schema(value_type = AdStatus)]
     pub status: String,
     pub user_email: String,
     pub user_phone: String,
-    pub created_at: chrono::NaiveDateTime,
-    pub updated_at: chrono::NaiveDateTime,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+    /// Listed ahead of other ads; set only by `AdRepo::promote`.
     pub top_ad: bool,
-    pub images: serde_json::Value,
+    /// Ids of the ad's images, in display order.
+    #[diesel(deserialize_as = ImageIds, serialize_as = ImageIds)]
+    pub images: Vec<String>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[schema(value_type = AdCategory)]
+    pub category: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub view_count: i64,
+    #[schema(value_type = Currency)]
+    pub currency: String,
+    /// Set once the owner deletes the ad; such ads are hidden until restored or purged.
+    pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Shareable URL name, see `ad_slug`. Kept in sync with the title.
+    pub slug: String,
+    /// End of a promotion started with `AdRepo::promote`; `top_ad` is cleared by the
+    /// expiry job once it passes. `None` if the ad isn't promoted or has no end date.
+    pub top_ad_until: Option<chrono::DateTime<chrono::Utc>>,
+    /// The seller takes offers ("or best offer") rather than asking a fixed price.
+    pub negotiable: bool,
+    /// Code of the language the ad is written in, see `Locale`.
+    #[schema(value_type = Locale)]
+    pub locale: String,
+    /// The seller confirmed `user_phone` with a code sent to it, see
+    /// `AdRepo::verify_phone`. Cleared when the number changes.
+    pub phone_verified: bool,
+}
+
+impl Ad {
+    /// A strong `ETag` for this version of the ad: `updated_at` in microseconds, the
+    /// precision it is stored with, e.g. `"1760572800123456"`.
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.updated_at.timestamp_micros())
+    }
+}
+
+/// The `updated_at` an `ETag` from `Ad::etag` stands for, if it is one.
+pub fn parse_etag(etag: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let micros = etag.strip_prefix('"')?.strip_suffix('"')?.parse().ok()?;
+    chrono::DateTime::from_timestamp_micros(micros)
+}
+
+/// An `Ad` as served to the public. Unless the viewer is the owner or an admin, the
+/// owner's email and phone are masked so listings can't be scraped for contacts.
+#[derive(Serialize, utoipa::ToSchema, Debug)]
+#[serde(transparent)]
+pub struct PublicAd(Ad);
+
+impl PublicAd {
+    pub fn for_viewer(mut ad: Ad, viewer: Option<&AuthUser>) -> Self {
+        let full = viewer.is_some_and(|viewer| viewer.is_admin || viewer.email == ad.user_email);
+        if !full {
+            ad.user_email = mask_email(&ad.user_email);
+            ad.user_phone = mask_phone(&ad.user_phone);
+        }
+        PublicAd(ad)
+    }
+
+    /// Whether `viewer` may see the ad at all: hidden ads and drafts are left to the owner
+    /// and admins.
+    pub fn is_visible_to(ad: &Ad, viewer: Option<&AuthUser>) -> bool {
+        !AdStatus::RESTRICTED
+            .iter()
+            .any(|status| ad.status == status.as_str())
+            || viewer.is_some_and(|viewer| viewer.is_admin || viewer.email == ad.user_email)
+    }
+
+    pub fn into_inner(self) -> Ad {
+        self.0
+    }
+
+    /// The ad as served with `?expand=images`: each image id in `images` is replaced by
+    /// its `ImageLinks` under `base_url`, with the image's `dimensions` if they are known.
+    pub fn with_image_links(
+        self,
+        base_url: &str,
+        dimensions: &HashMap<String, ImageDimensions>,
+    ) -> Result<serde_json::Value, RepoError> {
+        self.with_signed_image_links(base_url, dimensions, &HashMap::new())
+    }
+
+    /// Like `with_image_links`, with the `url` of each image in `signed` pointing straight
+    /// at storage until it expires, as served with `?expand=signed`.
+    pub fn with_signed_image_links(
+        self,
+        base_url: &str,
+        dimensions: &HashMap<String, ImageDimensions>,
+        signed: &HashMap<String, SignedUrl>,
+    ) -> Result<serde_json::Value, RepoError> {
+        let links: Vec<_> = self
+            .0
+            .images
+            .iter()
+            .map(|id| {
+                let links = ImageLinks {
+                    dimensions: dimensions.get(id).copied(),
+                    ..ImageLinks::new(id.clone(), base_url)
+                };
+                match signed.get(id) {
+                    Some(signed) => ImageLinks {
+                        url: signed.url.clone(),
+                        expires_at: Some(signed.expires_at),
+                        ..links
+                    },
+                    None => links,
+                }
+            })
+            .collect();
+        let mut ad = serde_json::to_value(self)?;
+        ad["images"] = serde_json::to_value(links)?;
+        Ok(ad)
+    }
+}
+
+/// Keeps the first character of the local part and the domain: `j***@example.com`.
+fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            let first: String = local.chars().take(1).collect();
+            format!("{}***@{}", first, domain)
+        }
+        None => "***".to_string(),
+    }
+}
+
+/// Masks every digit but the last three, keeping the separators: `+*** *** *** 456`.
+fn mask_phone(phone: &str) -> String {
+    let digits = phone.chars().filter(char::is_ascii_digit).count();
+    let mut seen = 0;
+    phone
+        .chars()
+        .map(|c| {
+            if !c.is_ascii_digit() {
+                return c;
+            }
+            seen += 1;
+            if seen + 3 > digits {
+                c
+            } else {
+                '*'
+            }
+        })
+        .collect()
+}
+
+/// Longest part of a slug taken from the title.
+const MAX_SLUG_TITLE_LEN: usize = 80;
+
+/// The title lowercased, with each run of anything but ASCII letters and digits turned
+/// into one `-`, followed by the id, which keeps slugs unique: `mountain-bike-42`.
+pub fn ad_slug(title: &str, id: AdId) -> String {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_SLUG_TITLE_LEN);
+
+    match slug.trim_end_matches('-') {
+        "" => id.to_string(),
+        title => format!("{}-{}", title, id),
+    }
+}
+
+#[derive(
+    serde::Deserialize,
+    Serialize,
+    async_graphql::Enum,
+    utoipa::ToSchema,
+    Default,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AdStatus {
+    #[default]
+    Active,
+    Sold,
+    Expired,
+    /// Taken down by an admin, usually after reports. Only admins set or lift it.
+    Hidden,
+    /// Saved by its owner but not published yet; see `AdRepo::publish`.
+    Draft,
+}
+
+impl AdStatus {
+    /// Statuses whose ads are left to their owner and admins.
+    pub const RESTRICTED: [AdStatus; 2] = [AdStatus::Hidden, AdStatus::Draft];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdStatus::Active => "active",
+            AdStatus::Sold => "sold",
+            AdStatus::Expired => "expired",
+            AdStatus::Hidden => "hidden",
+            AdStatus::Draft => "draft",
+        }
+    }
+}
+
+#[derive(
+    serde::Deserialize,
+    Serialize,
+    async_graphql::Enum,
+    utoipa::ToSchema,
+    Default,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AdCategory {
+    Electronics,
+    Vehicles,
+    RealEstate,
+    HomeAndGarden,
+    Fashion,
+    Jobs,
+    Services,
+    #[default]
+    Other,
+}
+
+impl AdCategory {
+    pub const ALL: [AdCategory; 8] = [
+        AdCategory::Electronics,
+        AdCategory::Vehicles,
+        AdCategory::RealEstate,
+        AdCategory::HomeAndGarden,
+        AdCategory::Fashion,
+        AdCategory::Jobs,
+        AdCategory::Services,
+        AdCategory::Other,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdCategory::Electronics => "electronics",
+            AdCategory::Vehicles => "vehicles",
+            AdCategory::RealEstate => "real_estate",
+            AdCategory::HomeAndGarden => "home_and_garden",
+            AdCategory::Fashion => "fashion",
+            AdCategory::Jobs => "jobs",
+            AdCategory::Services => "services",
+            AdCategory::Other => "other",
+        }
+    }
+}
+
+impl FromStr for AdCategory {
+    type Err = FieldError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        AdCategory::ALL
+            .into_iter()
+            .find(|category| category.as_str() == value)
+            .ok_or_else(|| FieldError {
+                field: "category",
+                message: format!("unknown category: {}", value),
+            })
+    }
+}
+
+/// ISO 4217 currencies a price can be given in.
+#[derive(
+    serde::Deserialize,
+    Serialize,
+    async_graphql::Enum,
+    utoipa::ToSchema,
+    Default,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Currency {
+    #[default]
+    Eur,
+    Usd,
+    Gbp,
+    Chf,
+    Czk,
+    Pln,
+    Huf,
+    Sek,
+    Nok,
+    Dkk,
+}
+
+impl Currency {
+    pub const ALL: [Currency; 10] = [
+        Currency::Eur,
+        Currency::Usd,
+        Currency::Gbp,
+        Currency::Chf,
+        Currency::Czk,
+        Currency::Pln,
+        Currency::Huf,
+        Currency::Sek,
+        Currency::Nok,
+        Currency::Dkk,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Currency::Eur => "EUR",
+            Currency::Usd => "USD",
+            Currency::Gbp => "GBP",
+            Currency::Chf => "CHF",
+            Currency::Czk => "CZK",
+            Currency::Pln => "PLN",
+            Currency::Huf => "HUF",
+            Currency::Sek => "SEK",
+            Currency::Nok => "NOK",
+            Currency::Dkk => "DKK",
+        }
+    }
+
+    /// The currency of ads that don't name one, read from `BASE_CURRENCY`. Defaults to EUR.
+    pub fn base_from_env() -> Result<Self, anyhow::Error> {
+        Ok(crate::db::parse_env("BASE_CURRENCY")?.unwrap_or_default())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = FieldError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Currency::ALL
+            .into_iter()
+            .find(|currency| currency.as_str() == value)
+            .ok_or_else(|| FieldError {
+                field: "currency",
+                message: format!("unknown currency: {}", value),
+            })
+    }
+}
+
+/// Languages an ad can be written in, by ISO 639-1 code.
+#[derive(
+    serde::Deserialize,
+    Serialize,
+    async_graphql::Enum,
+    utoipa::ToSchema,
+    Default,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Sk,
+    Cs,
+    De,
+    Pl,
+    Hu,
+    Uk,
+    Fr,
+    Es,
+    It,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 10] = [
+        Locale::En,
+        Locale::Sk,
+        Locale::Cs,
+        Locale::De,
+        Locale::Pl,
+        Locale::Hu,
+        Locale::Uk,
+        Locale::Fr,
+        Locale::Es,
+        Locale::It,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Sk => "sk",
+            Locale::Cs => "cs",
+            Locale::De => "de",
+            Locale::Pl => "pl",
+            Locale::Hu => "hu",
+            Locale::Uk => "uk",
+            Locale::Fr => "fr",
+            Locale::Es => "es",
+            Locale::It => "it",
+        }
+    }
+
+    /// The locale of ads that don't name one, read from `DEFAULT_LOCALE`. Defaults to en.
+    pub fn default_from_env() -> Result<Self, anyhow::Error> {
+        Ok(crate::db::parse_env("DEFAULT_LOCALE")?.unwrap_or_default())
+    }
+}
+
+impl FromStr for Locale {
+    type Err = FieldError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Locale::ALL
+            .into_iter()
+            .find(|locale| locale.as_str() == value)
+            .ok_or_else(|| FieldError {
+                field: "locale",
+                message: format!("unknown locale: {}", value),
+            })
+    }
 }
 
-#[derive(TryFromMultipart)]
+#[derive(TryFromMultipart, utoipa::ToSchema)]
 pub struct AdRequest {
     pub title: String,
     pub description: String,
-    pub price: f64,
-    pub user_email: String,
+    /// Kept as the raw field text and parsed with `parse_price`, so no float is involved.
+    pub price: String,
+    /// ISO 4217 code of `price`; the configured base currency when omitted.
+    pub currency: Option<String>,
     pub user_phone: String,
-    pub top_ad: bool,
+    /// Open to offers rather than a fixed price. Off by default.
+    #[form_data(default)]
+    #[schema(required = false)]
+    pub negotiable: bool,
+    /// One of `Locale`'s codes; the configured default locale when omitted.
+    #[schema(value_type = Option<Locale>)]
+    pub locale: Option<String>,
+    /// One of `AdCategory`'s snake_case names; `other` when omitted.
+    pub category: Option<String>,
+    /// Optional location, given together with `longitude`.
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    // Size is enforced by `ImageLimits` instead of the per-field multipart limit.
+    #[form_data(limit = "unlimited")]
+    #[schema(value_type = Vec<String>, format = Binary, required = false)]
     pub images: Vec<FieldData<NamedTempFile>>,
+    /// Images already stored, e.g. uploaded for another ad, listed ahead of `images`.
+    #[schema(required = false)]
     pub image_ids: Vec<String>,
+    /// Create the ad with the images that pass the checks and list the rejected ones,
+    /// rather than rejecting the whole request. Off by default.
+    #[form_data(default)]
+    #[schema(required = false)]
+    pub partial_ok: bool,
+    /// Save the ad as a draft to publish later. Off by default.
+    #[form_data(default)]
+    #[schema(required = false)]
+    pub draft: bool,
 }
 
 pub struct AdContent {
     pub title: String,
     pub description: String,
-    pub price: f64,
+    pub price: Money,
+    pub currency: Currency,
     pub user_email: String,
     pub user_phone: String,
-    pub top_ad: bool,
+    pub negotiable: bool,
+    pub locale: Locale,
+    pub category: AdCategory,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Saved as a draft, see `AdStatus::Draft`, rather than published right away.
+    pub draft: bool,
+}
+
+impl AdContent {
+    /// Checks every field up front, collecting all failures rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut reject = |field, message: &str| {
+            errors.push(FieldError {
+                field,
+                message: message.to_string(),
+            })
+        };
+
+        if let Some(message) = title_error(&self.title) {
+            reject("title", message);
+        }
+
+        if !is_valid_email(&self.user_email) {
+            reject("user_email", "must be a valid email address");
+        }
+
+        if !is_valid_phone(&self.user_phone) {
+            reject("user_phone", "must be a valid phone number");
+        }
+
+        for (field, message) in location_errors(self.latitude, self.longitude) {
+            reject(field, message);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Optional fields for a partial update; only the ones present are written.
+#[derive(serde::Deserialize, utoipa::ToSchema, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AdPatch {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub price: Option<Money>,
+    pub currency: Option<Currency>,
+    pub user_phone: Option<String>,
+    pub negotiable: Option<bool>,
+    pub locale: Option<Locale>,
+    pub status: Option<AdStatus>,
+    pub category: Option<AdCategory>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+impl AdPatch {
+    /// Same rules as `AdContent::validate`, applied to the fields that are present.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut reject = |field, message: &str| {
+            errors.push(FieldError {
+                field,
+                message: message.to_string(),
+            })
+        };
+
+        if let Some(message) = self.title.as_deref().and_then(title_error) {
+            reject("title", message);
+        }
+
+        if matches!(self.user_phone, Some(ref phone) if !is_valid_phone(phone)) {
+            reject("user_phone", "must be a valid phone number");
+        }
+
+        if self.status == Some(AdStatus::Hidden) {
+            reject("status", "hidden can only be set by an admin");
+        }
+
+        if self.status == Some(AdStatus::Draft) {
+            reject("status", "only new ads can be saved as drafts");
+        }
+
+        for (field, message) in location_errors(self.latitude, self.longitude) {
+            reject(field, message);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn title_error(title: &str) -> Option<&'static str> {
+    if title.trim().is_empty() {
+        Some("must not be empty")
+    } else if title.chars().count() > MAX_TITLE_LEN {
+        Some("must be at most 255 characters")
+    } else {
+        None
+    }
+}
+
+/// A location is optional, but latitude and longitude only make sense as a pair.
+pub fn location_errors(
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+) -> Vec<(&'static str, &'static str)> {
+    let mut errors = Vec::new();
+
+    match (latitude, longitude) {
+        (Some(_), None) => errors.push(("longitude", "must be given together with latitude")),
+        (None, Some(_)) => errors.push(("latitude", "must be given together with longitude")),
+        _ => {}
+    }
+
+    if matches!(latitude, Some(lat) if !(-90.0..=90.0).contains(&lat)) {
+        errors.push(("latitude", "must be between -90 and 90"));
+    }
+
+    if matches!(longitude, Some(lon) if !(-180.0..=180.0).contains(&lon)) {
+        errors.push(("longitude", "must be between -180 and 180"));
+    }
+
+    errors
+}
+
+/// Parses a decimal price straight into `Money`, rejecting anything that isn't a plain
+/// finite number (`NaN`, `inf`, empty), negative amounts and ones too large to store.
+pub fn parse_price(price: &str) -> Result<Money, FieldError> {
+    price.parse().map_err(|e: MoneyError| FieldError {
+        field: "price",
+        message: e.to_string(),
+    })
+}
+
+fn is_valid_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+
+    !local.is_empty()
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty())
+}
+
+/// Digits with optional leading `+` and common separators, 7 to 15 digits (E.164 length).
+fn is_valid_phone(phone: &str) -> bool {
+    let phone = phone.trim();
+    let digits = phone.chars().filter(char::is_ascii_digit).count();
+    let body = phone.strip_prefix('+').unwrap_or(phone);
+
+    (7..=15).contains(&digits)
+        && body
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, ' ' | '-' | '(' | ')'))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bigdecimal::BigDecimal;
+
+    use super::{
+        ad_slug, parse_price, Ad, AdCategory, AdContent, AdId, AdPatch, Currency, Locale, PublicAd,
+    };
+    use crate::auth::AuthUser;
+    use crate::repos::error::RepoError;
+
+    fn valid_ad() -> AdContent {
+        AdContent {
+            title: "Bike".to_string(),
+            description: "Barely used".to_string(),
+            price: 100.into(),
+            currency: Currency::default(),
+            user_email: "seller@example.com".to_string(),
+            user_phone: "+421 900 123 456".to_string(),
+            negotiable: false,
+            locale: Locale::default(),
+            category: AdCategory::default(),
+            latitude: None,
+            longitude: None,
+            draft: false,
+        }
+    }
+
+    fn rejected_fields(ad: AdContent) -> Vec<&'static str> {
+        ad.validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.field)
+            .collect()
+    }
+
+    #[test]
+    fn test_accepts_valid_ad() {
+        assert!(valid_ad().validate().is_ok());
+    }
+
+    #[test]
+    fn test_rejects_bad_title() {
+        for title in ["".to_string(), "   ".to_string(), "x".repeat(256)] {
+            let ad = AdContent {
+                title,
+                ..valid_ad()
+            };
+            assert_eq!(rejected_fields(ad), vec!["title"]);
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_email() {
+        for email in [
+            "",
+            "seller",
+            "@example.com",
+            "seller@",
+            "a@b@c.com",
+            "seller@localhost",
+            "seller@example..com",
+            "sel ler@example.com",
+        ] {
+            let ad = AdContent {
+                user_email: email.to_string(),
+                ..valid_ad()
+            };
+            assert_eq!(rejected_fields(ad), vec!["user_email"], "{:?}", email);
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_phone() {
+        for phone in ["", "123", "call me", "12345678901234567", "+1 555 0100 ext"] {
+            let ad = AdContent {
+                user_phone: phone.to_string(),
+                ..valid_ad()
+            };
+            assert_eq!(rejected_fields(ad), vec!["user_phone"], "{:?}", phone);
+        }
+    }
+
+    #[test]
+    fn test_reports_every_invalid_field() {
+        let ad = AdContent {
+            title: String::new(),
+            currency: Currency::default(),
+            user_email: "nope".to_string(),
+            user_phone: "nope".to_string(),
+            ..valid_ad()
+        };
+        assert_eq!(
+            rejected_fields(ad),
+            vec!["title", "user_email", "user_phone"]
+        );
+    }
+
+    #[test]
+    fn test_parse_price() {
+        assert_eq!(
+            parse_price("19.99").unwrap().amount(),
+            &BigDecimal::from_str("19.99").unwrap()
+        );
+        assert_eq!(
+            parse_price("0.1").unwrap().amount() + parse_price("0.2").unwrap().amount(),
+            *parse_price("0.3").unwrap().amount()
+        );
+        assert_eq!(parse_price("12.349").unwrap().to_string(), "12.35");
+
+        for price in ["", "abc", "NaN", "inf", "-inf", "-5", "1,50"] {
+            assert!(
+                parse_price(price).is_err(),
+                "{:?} should be rejected",
+                price
+            );
+        }
+    }
+
+    #[test]
+    fn test_validates_only_present_patch_fields() {
+        assert!(AdPatch::default().validate().is_ok());
+
+        let patch = AdPatch {
+            title: Some(" ".to_string()),
+            user_phone: Some("nope".to_string()),
+            ..Default::default()
+        };
+        let fields: Vec<_> = patch
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.field)
+            .collect();
+        assert_eq!(fields, vec!["title", "user_phone"]);
+    }
+
+    #[test]
+    fn test_parse_category() {
+        for category in AdCategory::ALL {
+            assert_eq!(category.as_str().parse::<AdCategory>().unwrap(), category);
+        }
+        assert_eq!(
+            "spaceships".parse::<AdCategory>().unwrap_err().field,
+            "category"
+        );
+    }
+
+    #[test]
+    fn test_parse_locale() {
+        for locale in Locale::ALL {
+            assert_eq!(locale.as_str().parse::<Locale>().unwrap(), locale);
+        }
+        for code in ["EN", "en-US", "xx", ""] {
+            assert_eq!(
+                code.parse::<Locale>().unwrap_err().field,
+                "locale",
+                "{:?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_currency() {
+        for currency in Currency::ALL {
+            assert_eq!(currency.as_str().parse::<Currency>().unwrap(), currency);
+        }
+        for code in ["XYZ", "eur", "EURO", "", "€"] {
+            assert_eq!(
+                code.parse::<Currency>().unwrap_err().field,
+                "currency",
+                "{:?}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_location() {
+        let located = |latitude, longitude| AdContent {
+            latitude,
+            longitude,
+            ..valid_ad()
+        };
+
+        assert!(located(Some(48.1486), Some(17.1077)).validate().is_ok());
+        assert!(located(Some(-90.0), Some(180.0)).validate().is_ok());
+
+        assert_eq!(
+            rejected_fields(located(Some(90.5), Some(0.0))),
+            vec!["latitude"]
+        );
+        assert_eq!(
+            rejected_fields(located(Some(0.0), Some(-181.0))),
+            vec!["longitude"]
+        );
+        assert_eq!(
+            rejected_fields(located(Some(f64::NAN), Some(0.0))),
+            vec!["latitude"]
+        );
+        assert_eq!(rejected_fields(located(Some(0.0), None)), vec!["longitude"]);
+        assert_eq!(rejected_fields(located(None, Some(0.0))), vec!["latitude"]);
+    }
+
+    #[test]
+    fn test_ad_slug() {
+        assert_eq!(ad_slug("Mountain bike", AdId(42)), "mountain-bike-42");
+        assert_eq!(
+            ad_slug("  iPhone 12 -- 128GB!", AdId(7)),
+            "iphone-12-128gb-7"
+        );
+        assert_eq!(ad_slug("Čerstvé vajcia", AdId(3)), "erstv-vajcia-3");
+        assert_eq!(ad_slug("!!!", AdId(5)), "5");
+        assert_eq!(ad_slug(&"a ".repeat(100), AdId(1)).len(), 79 + 2);
+    }
+
+    #[test]
+    fn test_ad_id_parse() {
+        let id: AdId = "42".parse().unwrap();
+        assert_eq!(id, AdId(42));
+        assert_eq!(id.to_string(), "42");
+        assert_eq!(serde_json::to_value(id).unwrap(), serde_json::json!(42));
+
+        let err = "4x2".parse::<AdId>().unwrap_err();
+        assert!(matches!(err, RepoError::InvalidId(message) if message == "invalid ad id: 4x2"));
+    }
+
+    #[test]
+    fn test_public_ad_masks_contacts() {
+        let now = chrono::Utc::now();
+        let ad = Ad {
+            id: AdId(1),
+            title: "Bike".to_string(),
+            description: "Barely used".to_string(),
+            price: 100.into(),
+            status: "active".to_string(),
+            user_email: "seller@example.com".to_string(),
+            user_phone: "+421 900 123 456".to_string(),
+            created_at: now,
+            updated_at: now,
+            top_ad: false,
+            images: Vec::new(),
+            expires_at: None,
+            category: "other".to_string(),
+            latitude: None,
+            longitude: None,
+            view_count: 0,
+            currency: "EUR".to_string(),
+            deleted_at: None,
+            slug: "bike-1".to_string(),
+            top_ad_until: None,
+            negotiable: false,
+            locale: "en".to_string(),
+            phone_verified: false,
+        };
+        let viewer = |email: &str, is_admin| AuthUser {
+            email: email.to_string(),
+            is_admin,
+        };
+        let contacts = |viewer: Option<&AuthUser>| {
+            let json = serde_json::to_value(PublicAd::for_viewer(ad.clone(), viewer)).unwrap();
+            (json["user_email"].clone(), json["user_phone"].clone())
+        };
+
+        let masked = (
+            serde_json::json!("s***@example.com"),
+            serde_json::json!("+*** *** *** 456"),
+        );
+        let full = (
+            serde_json::json!("seller@example.com"),
+            serde_json::json!("+421 900 123 456"),
+        );
+        assert_eq!(contacts(None), masked);
+        assert_eq!(contacts(Some(&viewer("buyer@example.com", false))), masked);
+        assert_eq!(contacts(Some(&viewer("seller@example.com", false))), full);
+        assert_eq!(contacts(Some(&viewer("admin@example.com", true))), full);
+    }
 }
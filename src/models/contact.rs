@@ -0,0 +1,42 @@
+use serde::Deserialize;
+
+use crate::repos::error::FieldError;
+
+/// Longest message a buyer can send a seller, in characters.
+pub const MAX_CONTACT_MESSAGE_LEN: usize = 5000;
+
+/// Body of `POST /ads/:id/contact`.
+#[derive(Deserialize, utoipa::ToSchema, Debug)]
+pub struct ContactRequest {
+    pub message: String,
+    /// Where the seller's reply goes; the seller's own address is never revealed.
+    pub reply_to: String,
+}
+
+impl ContactRequest {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut reject = |field, message: &str| {
+            errors.push(FieldError {
+                field,
+                message: message.to_string(),
+            })
+        };
+
+        if self.message.trim().is_empty() {
+            reject("message", "must not be empty");
+        } else if self.message.chars().count() > MAX_CONTACT_MESSAGE_LEN {
+            reject("message", "must be at most 5000 characters");
+        }
+
+        if self.reply_to.parse::<lettre::Address>().is_err() {
+            reject("reply_to", "must be an email address");
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use serde::Deserialize;
+
+use crate::auth::AuthUser;
+use crate::models::ad::{Ad, PublicAd};
+use crate::models::image::{image_url, ImageDimensions};
+use crate::repos::error::RepoError;
+
+/// Columns of a CSV export. Those `POST /ads/import` knows are named the same, so an
+/// export can be imported again once its images are.
+const CSV_COLUMNS: [&str; 15] = [
+    "id",
+    "status",
+    "title",
+    "description",
+    "price",
+    "currency",
+    "user_phone",
+    "negotiable",
+    "locale",
+    "category",
+    "latitude",
+    "longitude",
+    "created_at",
+    "updated_at",
+    "image_urls",
+];
+
+/// How `GET /users/:email/export` writes the ads: a JSON array of ads as served with
+/// `?expand=images`, or CSV with one row per ad.
+#[derive(Deserialize, utoipa::ToSchema, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "ads.json",
+            ExportFormat::Csv => "ads.csv",
+        }
+    }
+
+    /// Written ahead of the first ad.
+    pub fn start(self) -> String {
+        match self {
+            ExportFormat::Json => "[".to_string(),
+            ExportFormat::Csv => format!("{}\r\n", CSV_COLUMNS.join(",")),
+        }
+    }
+
+    /// One ad, as `viewer` may see it, with image links under `base_url`. JSON lists the
+    /// images' `dimensions` too. `first` is set for the first ad written, which JSON
+    /// doesn't separate from the ones before.
+    pub fn record(
+        self,
+        ad: Ad,
+        viewer: &AuthUser,
+        base_url: &str,
+        dimensions: &HashMap<String, ImageDimensions>,
+        first: bool,
+    ) -> Result<String, RepoError> {
+        let ad = PublicAd::for_viewer(ad, Some(viewer));
+        match self {
+            ExportFormat::Json => {
+                let ad = serde_json::to_string(&ad.with_image_links(base_url, dimensions)?)?;
+                Ok(if first { ad } else { format!(",{}", ad) })
+            }
+            ExportFormat::Csv => Ok(csv_record(&ad.into_inner(), base_url)),
+        }
+    }
+
+    /// Written after the last ad.
+    pub fn end(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "]\n",
+            ExportFormat::Csv => "",
+        }
+    }
+}
+
+fn csv_record(ad: &Ad, base_url: &str) -> String {
+    let optional = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+    let image_urls: Vec<_> = ad.images.iter().map(|id| image_url(base_url, id)).collect();
+    let cells = [
+        ad.id.0.to_string(),
+        ad.status.clone(),
+        ad.title.clone(),
+        ad.description.clone(),
+        ad.price.to_string(),
+        ad.currency.clone(),
+        ad.user_phone.clone(),
+        ad.negotiable.to_string(),
+        ad.locale.clone(),
+        ad.category.clone(),
+        optional(ad.latitude),
+        optional(ad.longitude),
+        ad.created_at.to_rfc3339(),
+        ad.updated_at.to_rfc3339(),
+        image_urls.join(" "),
+    ];
+
+    let cells: Vec<_> = cells.iter().map(|cell| csv_cell(cell)).collect();
+    format!("{}\r\n", cells.join(","))
+}
+
+/// `cell` double-quoted if it holds a comma, quote or line break, with quotes doubled.
+fn csv_cell(cell: &str) -> Cow<'_, str> {
+    if cell.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", cell.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(cell)
+    }
+}
@@ -1,9 +1,32 @@
+use std::{pin::Pin, time::Duration};
+
+use axum_typed_multipart::{FieldData, TryFromMultipart};
 use serde::{Serialize, Serializer};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncRead;
+
+use crate::db::parse_env;
+use crate::repos::error::FieldError;
+
+pub const DEFAULT_MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+pub const DEFAULT_MAX_IMAGES_PER_AD: usize = 10;
+pub const DEFAULT_MAX_BODY_BYTES: usize = 25 * 1024 * 1024;
+pub const DEFAULT_IMAGE_QUOTA_BYTES: u64 = 500 * 1024 * 1024;
 
+/// Bounds on the `?ttl=` of signed image URLs, in seconds: long enough to load a page,
+/// and no longer than the week S3 lets a presigned URL live.
+pub const MIN_SIGNED_URL_TTL_SECS: u64 = 60;
+pub const MAX_SIGNED_URL_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+pub const DEFAULT_SIGNED_URL_TTL_SECS: u64 = 15 * 60;
+
+#[derive(Clone)]
 pub struct Image {
     pub id: Option<String>,
     pub file_name: String,
     pub mime_type: String,
+    /// Recorded when the image was stored; `None` for images stored before that, until
+    /// `ImageRepo::repair` backfills them.
+    pub dimensions: Option<ImageDimensions>,
     pub bytes: Vec<u8>,
 }
 
@@ -15,3 +38,364 @@ impl Serialize for Image {
         serializer.serialize_some(&self.id)
     }
 }
+
+/// Size of an image in pixels, so clients can lay out a grid before fetching it.
+#[derive(Serialize, utoipa::ToSchema, Clone, Copy, Debug, PartialEq)]
+pub struct ImageDimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageDimensions {
+    /// Read from the image header, without decoding the pixels. `None` if `bytes` isn't
+    /// an image the `image` crate knows.
+    pub fn of(bytes: &[u8]) -> Option<ImageDimensions> {
+        let (width, height) = image::ImageReader::new(std::io::Cursor::new(bytes))
+            .with_guessed_format()
+            .ok()?
+            .into_dimensions()
+            .ok()?;
+        Some(ImageDimensions { width, height })
+    }
+}
+
+/// Where to fetch an image and its thumbnail, so clients don't have to know the routes,
+/// and its `width` and `height` where they are known.
+#[derive(Serialize, utoipa::ToSchema, Debug, PartialEq)]
+pub struct ImageLinks {
+    pub id: String,
+    pub url: String,
+    pub thumbnail_url: String,
+    #[serde(flatten)]
+    pub dimensions: Option<ImageDimensions>,
+    /// When `url` is a signed URL straight to storage, the time it stops working.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ImageLinks {
+    /// Links built with `image_url`.
+    pub fn new(id: String, base_url: &str) -> Self {
+        let url = image_url(base_url, &id);
+        ImageLinks {
+            thumbnail_url: format!("{}/thumbnail", url),
+            url,
+            id,
+            dimensions: None,
+            expires_at: None,
+        }
+    }
+}
+
+/// The link to image `id` under `base_url`, see `AppConfig::image_base_url`, without a
+/// trailing slash; with an empty one it is a path relative to the API.
+pub fn image_url(base_url: &str, id: &str) -> String {
+    format!("{}/images/{}", base_url, id)
+}
+
+/// A time-limited URL to fetch an image from storage directly, without the API in between.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedUrl {
+    pub url: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// How long signed image URLs stay valid: `ttl` seconds, `DEFAULT_SIGNED_URL_TTL_SECS`
+/// when omitted, within `MIN_SIGNED_URL_TTL_SECS` and `MAX_SIGNED_URL_TTL_SECS`.
+pub fn signed_url_ttl(ttl: Option<u64>) -> Result<Duration, FieldError> {
+    let ttl = ttl.unwrap_or(DEFAULT_SIGNED_URL_TTL_SECS);
+    if !(MIN_SIGNED_URL_TTL_SECS..=MAX_SIGNED_URL_TTL_SECS).contains(&ttl) {
+        return Err(FieldError {
+            field: "ttl",
+            message: format!(
+                "must be between {} and {} seconds",
+                MIN_SIGNED_URL_TTL_SECS, MAX_SIGNED_URL_TTL_SECS
+            ),
+        });
+    }
+    Ok(Duration::from_secs(ttl))
+}
+
+/// An image opened for streaming, so the bytes never have to sit in memory at once.
+pub struct ImageStream {
+    pub file_name: String,
+    pub mime_type: String,
+    /// Size of the whole image, even when only `range` is being read.
+    pub len: u64,
+    /// Inclusive byte offsets `reader` yields, or `None` for the whole image.
+    pub range: Option<(u64, u64)>,
+    pub reader: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+/// A single range from a `Range: bytes=...` header, before it is checked against the size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteRange {
+    /// `bytes=first-last`
+    FromTo(u64, u64),
+    /// `bytes=first-`
+    From(u64),
+    /// `bytes=-suffix_len`
+    Last(u64),
+}
+
+impl ByteRange {
+    /// Parses a single-range `Range` header. Multiple ranges and other units yield `None`,
+    /// in which case the whole image is served, as RFC 9110 allows.
+    pub fn parse(header: &str) -> Option<ByteRange> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+
+        let (first, last) = spec.split_once('-')?;
+        match (first.trim(), last.trim()) {
+            ("", "") => None,
+            ("", suffix) => suffix.parse().ok().map(ByteRange::Last),
+            (first, "") => first.parse().ok().map(ByteRange::From),
+            (first, last) => {
+                let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                (first <= last).then_some(ByteRange::FromTo(first, last))
+            }
+        }
+    }
+
+    /// Inclusive offsets within an image of `len` bytes, or `None` if unsatisfiable.
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        let (first, last) = match *self {
+            ByteRange::FromTo(first, last) => (first, last.min(len.checked_sub(1)?)),
+            ByteRange::From(first) => (first, len.checked_sub(1)?),
+            ByteRange::Last(0) => return None,
+            ByteRange::Last(suffix) => (len.saturating_sub(suffix), len.checked_sub(1)?),
+        };
+
+        (first <= last).then_some((first, last))
+    }
+}
+
+/// Detects the image type from its magic bytes, ignoring any client-supplied content type.
+pub fn sniff_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some("image/png")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// What `ImageRepo::repair` found for an image.
+#[derive(serde::Serialize, utoipa::ToSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairOutcome {
+    /// The metadata was readable; nothing was changed.
+    Intact,
+    /// The metadata was missing, unreadable or without the image's dimensions, and has
+    /// been rebuilt from the bytes. The file name is kept if it could be read, otherwise
+    /// the id stands in for it.
+    Repaired,
+    /// The bytes aren't an image type the upload accepts, so there is nothing to rebuild
+    /// the metadata from.
+    Unrecoverable,
+}
+
+/// Body of `POST /ads/:id/images` and `POST /images`: one or more `images` file fields.
+#[derive(TryFromMultipart, utoipa::ToSchema)]
+pub struct ImagesRequest {
+    // Size is enforced by `ImageLimits` instead of the per-field multipart limit.
+    #[form_data(limit = "unlimited")]
+    #[schema(value_type = Vec<String>, format = Binary)]
+    pub images: Vec<FieldData<NamedTempFile>>,
+}
+
+/// Body of `PUT /ads/:id/images`: `keep` fields naming the ad's images to hold on to, in
+/// order, and `images` file fields uploaded after them.
+#[derive(TryFromMultipart, utoipa::ToSchema)]
+pub struct ReplaceImagesRequest {
+    #[schema(required = false)]
+    pub keep: Vec<String>,
+    #[form_data(limit = "unlimited")]
+    #[schema(value_type = Vec<String>, format = Binary, required = false)]
+    pub images: Vec<FieldData<NamedTempFile>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImageLimits {
+    pub max_bytes: usize,
+    pub max_count: usize,
+    /// Cap on a whole upload request, all of its images and other fields together;
+    /// larger bodies are refused with 413 before any image is stored.
+    pub max_body_bytes: usize,
+    /// Total bytes of images one user may have stored, across all of their uploads.
+    pub quota_bytes: u64,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        ImageLimits {
+            max_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            max_count: DEFAULT_MAX_IMAGES_PER_AD,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            quota_bytes: DEFAULT_IMAGE_QUOTA_BYTES,
+        }
+    }
+}
+
+impl ImageLimits {
+    /// Reads `MAX_IMAGE_BYTES`, `MAX_IMAGES_PER_AD`, `MAX_BODY_BYTES` and
+    /// `IMAGE_QUOTA_BYTES`, falling back to the defaults.
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let defaults = ImageLimits::default();
+        let limits = ImageLimits {
+            max_bytes: parse_env("MAX_IMAGE_BYTES")?.unwrap_or(defaults.max_bytes),
+            max_count: parse_env("MAX_IMAGES_PER_AD")?.unwrap_or(defaults.max_count),
+            max_body_bytes: parse_env("MAX_BODY_BYTES")?.unwrap_or(defaults.max_body_bytes),
+            quota_bytes: parse_env("IMAGE_QUOTA_BYTES")?.unwrap_or(defaults.quota_bytes),
+        };
+
+        // Otherwise an image within MAX_IMAGE_BYTES could never be uploaded.
+        if limits.max_body_bytes < limits.max_bytes {
+            return Err(anyhow::Error::msg(
+                "MAX_BODY_BYTES must be at least MAX_IMAGE_BYTES",
+            ));
+        }
+
+        Ok(limits)
+    }
+
+    pub fn validate_count(&self, count: usize) -> Result<(), String> {
+        if count > self.max_count {
+            return Err(format!(
+                "too many images: {} uploaded, at most {} allowed",
+                count, self.max_count
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that `adding` more bytes keep a user who already stores `used` within the
+    /// quota.
+    pub fn validate_quota(&self, used: u64, adding: u64) -> Result<(), String> {
+        if used.saturating_add(adding) > self.quota_bytes {
+            return Err(format!(
+                "storing {} more bytes of images would exceed the quota of {} bytes, {} already used",
+                adding, self.quota_bytes, used
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the sniffed MIME type when the image is an allowed type within the size limit.
+    pub fn validate(&self, file_name: &str, bytes: &[u8]) -> Result<&'static str, String> {
+        if bytes.len() > self.max_bytes {
+            return Err(format!(
+                "image {} is {} bytes, at most {} allowed",
+                file_name,
+                bytes.len(),
+                self.max_bytes
+            ));
+        }
+        sniff_mime_type(bytes).ok_or_else(|| {
+            format!(
+                "image {} is not a supported type (image/jpeg, image/png, image/webp)",
+                file_name
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{
+        signed_url_ttl, sniff_mime_type, ByteRange, ImageLimits, DEFAULT_SIGNED_URL_TTL_SECS,
+        MAX_SIGNED_URL_TTL_SECS,
+    };
+
+    const PNG_HEADER: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0];
+
+    #[test]
+    fn test_sniff_mime_type() {
+        assert_eq!(
+            sniff_mime_type(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniff_mime_type(PNG_HEADER), Some("image/png"));
+        assert_eq!(
+            sniff_mime_type(b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(sniff_mime_type(b"RIFF\x24\x00\x00\x00WAVEfmt "), None);
+        assert_eq!(sniff_mime_type(b""), None);
+    }
+
+    #[test]
+    fn test_signed_url_ttl() {
+        assert_eq!(
+            signed_url_ttl(None).unwrap(),
+            Duration::from_secs(DEFAULT_SIGNED_URL_TTL_SECS)
+        );
+        assert_eq!(signed_url_ttl(Some(300)).unwrap(), Duration::from_secs(300));
+        assert_eq!(signed_url_ttl(Some(0)).unwrap_err().field, "ttl");
+        assert!(signed_url_ttl(Some(MAX_SIGNED_URL_TTL_SECS + 1)).is_err());
+    }
+
+    #[test]
+    fn test_rejects_mislabeled_text_file() {
+        let limits = ImageLimits::default();
+        let text = b"definitely not a picture, even if it says image/png";
+
+        assert!(limits.validate("photo.png", text).is_err());
+    }
+
+    #[test]
+    fn test_rejects_oversized_image() {
+        let limits = ImageLimits {
+            max_bytes: 4,
+            max_count: 1,
+            ..ImageLimits::default()
+        };
+
+        assert!(limits.validate("photo.png", PNG_HEADER).is_err());
+        assert!(limits.validate_count(2).is_err());
+        assert!(limits.validate_count(1).is_ok());
+
+        let limits = ImageLimits {
+            quota_bytes: 100,
+            ..ImageLimits::default()
+        };
+        assert!(limits.validate_quota(60, 40).is_ok());
+        assert!(limits.validate_quota(60, 41).is_err());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-99"),
+            Some(ByteRange::FromTo(0, 99))
+        );
+        assert_eq!(ByteRange::parse("bytes=100-"), Some(ByteRange::From(100)));
+        assert_eq!(ByteRange::parse("bytes=-50"), Some(ByteRange::Last(50)));
+        for header in [
+            "bytes=5-1",
+            "bytes=-",
+            "bytes=a-b",
+            "bytes=0-1,4-5",
+            "items=0-1",
+        ] {
+            assert_eq!(ByteRange::parse(header), None, "{:?}", header);
+        }
+
+        assert_eq!(ByteRange::FromTo(0, 99).resolve(1000), Some((0, 99)));
+        assert_eq!(ByteRange::FromTo(900, 5000).resolve(1000), Some((900, 999)));
+        assert_eq!(ByteRange::From(100).resolve(1000), Some((100, 999)));
+        assert_eq!(ByteRange::Last(50).resolve(1000), Some((950, 999)));
+        assert_eq!(ByteRange::Last(5000).resolve(1000), Some((0, 999)));
+
+        assert_eq!(ByteRange::From(1000).resolve(1000), None);
+        assert_eq!(ByteRange::Last(0).resolve(1000), None);
+        assert_eq!(ByteRange::From(0).resolve(0), None);
+    }
+}
@@ -0,0 +1,340 @@
+use serde::{Deserialize, Deserializer};
+
+use crate::models::ad::{parse_price, AdCategory, AdContent, Currency, Locale};
+use crate::repos::error::FieldError;
+
+/// Most records a single `POST /ads/import` takes.
+pub const MAX_IMPORT_ROWS: usize = 500;
+
+/// Columns a CSV import can have, named like the fields of a JSON record.
+const COLUMNS: [&str; 11] = [
+    "title",
+    "description",
+    "price",
+    "currency",
+    "user_phone",
+    "negotiable",
+    "locale",
+    "category",
+    "latitude",
+    "longitude",
+    "image_ids",
+];
+
+/// One ad of a bulk import, as a JSON object or a CSV row. Images are referenced by the
+/// ids `POST /images` returned; fetching them from URLs elsewhere isn't supported.
+#[derive(Deserialize, utoipa::ToSchema, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ImportRecord {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    /// Kept as text and parsed with `parse_price`, like the form field. JSON numbers are
+    /// taken too.
+    #[serde(deserialize_with = "price_text")]
+    pub price: String,
+    /// ISO 4217 code of `price`; the configured base currency when omitted.
+    pub currency: Option<String>,
+    pub user_phone: String,
+    #[serde(default)]
+    pub negotiable: bool,
+    /// One of `Locale`'s codes; the configured default locale when omitted.
+    pub locale: Option<String>,
+    /// One of `AdCategory`'s snake_case names; `other` when omitted.
+    pub category: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// In a CSV row, separated by spaces.
+    #[serde(default)]
+    pub image_ids: Vec<String>,
+}
+
+fn price_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Price {
+        Text(String),
+        Number(serde_json::Number),
+    }
+
+    Ok(match Price::deserialize(deserializer)? {
+        Price::Text(text) => text,
+        Price::Number(number) => number.to_string(),
+    })
+}
+
+impl ImportRecord {
+    /// The ad this record creates for `user_email`, checked like a form upload, and its
+    /// image ids. Whether those images exist is up to the caller.
+    pub fn into_content(
+        self,
+        user_email: &str,
+        base_currency: Currency,
+        default_locale: Locale,
+    ) -> Result<(AdContent, Vec<String>), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let price = parse_price(&self.price).map_err(|e| errors.push(e)).ok();
+        let currency = match self.currency {
+            Some(currency) => currency.parse().map_err(|e| errors.push(e)).ok(),
+            None => Some(base_currency),
+        };
+        let locale = match self.locale {
+            Some(locale) => locale.parse().map_err(|e| errors.push(e)).ok(),
+            None => Some(default_locale),
+        };
+        let category = match self.category {
+            Some(category) => category.parse().map_err(|e| errors.push(e)).ok(),
+            None => Some(AdCategory::default()),
+        };
+        let (Some(price), Some(currency), Some(locale), Some(category)) =
+            (price, currency, locale, category)
+        else {
+            return Err(errors);
+        };
+
+        let ad = AdContent {
+            title: self.title,
+            description: self.description,
+            price,
+            currency,
+            user_email: user_email.to_string(),
+            user_phone: self.user_phone,
+            negotiable: self.negotiable,
+            locale,
+            category,
+            latitude: self.latitude,
+            longitude: self.longitude,
+            draft: false,
+        };
+        ad.validate()?;
+
+        Ok((ad, self.image_ids))
+    }
+}
+
+/// A record that can't be read at all, e.g. a missing field, reported against `record`.
+fn unreadable(message: impl ToString) -> Vec<FieldError> {
+    vec![FieldError {
+        field: "record",
+        message: message.to_string(),
+    }]
+}
+
+/// Reads a JSON array of records. Only a body that isn't an array fails as a whole;
+/// each malformed element is an error in its place.
+pub fn json_records(body: &[u8]) -> Result<Vec<Result<ImportRecord, Vec<FieldError>>>, String> {
+    let values: Vec<serde_json::Value> =
+        serde_json::from_slice(body).map_err(|e| format!("expected a JSON array of ads: {}", e))?;
+
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(unreadable))
+        .collect())
+}
+
+/// Reads CSV with a header row naming the columns, see `COLUMNS`, in any order. Empty
+/// cells count as missing. The file fails as a whole if it can't be split into rows or
+/// the header names an unknown column; each malformed row is an error in its place.
+pub fn csv_records(body: &str) -> Result<Vec<Result<ImportRecord, Vec<FieldError>>>, String> {
+    let mut rows = csv_rows(body)?.into_iter();
+    let header = rows
+        .next()
+        .ok_or_else(|| "missing the header row".to_string())?
+        .iter()
+        .map(|name| {
+            COLUMNS
+                .into_iter()
+                .find(|column| column == name)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown column `{}`, expected some of {}",
+                        name,
+                        COLUMNS.join(", ")
+                    )
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows
+        .map(|row| {
+            if row.len() != header.len() {
+                return Err(unreadable(format!(
+                    "has {} cells, the header {}",
+                    row.len(),
+                    header.len()
+                )));
+            }
+            let mut record = serde_json::Map::new();
+            for (&column, cell) in header.iter().zip(row) {
+                if !cell.is_empty() {
+                    record.insert(column.to_string(), csv_value(column, cell)?);
+                }
+            }
+            serde_json::from_value(record.into()).map_err(unreadable)
+        })
+        .collect())
+}
+
+/// A cell as the JSON value the same field of a JSON record would have.
+fn csv_value(column: &'static str, cell: String) -> Result<serde_json::Value, Vec<FieldError>> {
+    let invalid = |message: &str| {
+        vec![FieldError {
+            field: column,
+            message: message.to_string(),
+        }]
+    };
+
+    Ok(match column {
+        "latitude" | "longitude" => {
+            let number = cell
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| invalid("must be a number"))?;
+            serde_json::json!(number)
+        }
+        "negotiable" => match cell.trim() {
+            "true" => true.into(),
+            "false" => false.into(),
+            _ => return Err(invalid("must be true or false")),
+        },
+        "image_ids" => cell.split_whitespace().collect::<Vec<_>>().into(),
+        _ => cell.into(),
+    })
+}
+
+/// Splits CSV into rows of cells. Cells may be double-quoted to hold commas, line breaks
+/// or `""` for a quote. Blank lines are skipped.
+fn csv_rows(body: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut cell = String::new();
+    let mut chars = body.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if cell.is_empty() => loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        cell.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => cell.push(c),
+                    None => return Err("unterminated quote".to_string()),
+                }
+            },
+            ',' => row.push(std::mem::take(&mut cell)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut cell));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => cell.push(c),
+        }
+    }
+    row.push(cell);
+    rows.push(row);
+
+    rows.retain(|row| !(row.len() == 1 && row[0].trim().is_empty()));
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{csv_records, json_records, ImportRecord};
+    use crate::models::ad::{Currency, Locale};
+
+    fn record(title: &str, price: &str) -> ImportRecord {
+        ImportRecord {
+            title: title.to_string(),
+            description: String::new(),
+            price: price.to_string(),
+            currency: None,
+            user_phone: "+421 900 123 456".to_string(),
+            negotiable: false,
+            locale: None,
+            category: None,
+            latitude: None,
+            longitude: None,
+            image_ids: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_read_csv_records() {
+        let csv = "title,price,user_phone,description,negotiable,image_ids\r\n\
+                   Bike,100,+421 900 123 456,,true,a b\r\n\
+                   \"Lamp, brass\",25.50,+421 900 123 456,\"Says \"\"vintage\"\"\nWorks\",,\n\
+                   \n\
+                   Short,1\n\
+                   Chair,10,+421 900 123 456,,maybe,\n";
+        let records = csv_records(csv).unwrap();
+        assert_eq!(records.len(), 4);
+
+        assert_eq!(
+            records[0].as_ref().unwrap(),
+            &ImportRecord {
+                negotiable: true,
+                image_ids: vec!["a".to_string(), "b".to_string()],
+                ..record("Bike", "100")
+            }
+        );
+        assert_eq!(
+            records[1].as_ref().unwrap(),
+            &ImportRecord {
+                description: "Says \"vintage\"\nWorks".to_string(),
+                ..record("Lamp, brass", "25.50")
+            }
+        );
+        assert_eq!(records[2].as_ref().unwrap_err()[0].field, "record");
+        assert_eq!(records[3].as_ref().unwrap_err()[0].field, "negotiable");
+
+        assert!(csv_records("title,colour\nBike,red\n").is_err());
+        assert!(csv_records("title,price\n\"Bike,1\n").is_err());
+    }
+
+    #[test]
+    fn test_read_json_records() {
+        let json = br#"[
+            {"title": "Bike", "price": 100, "user_phone": "+421 900 123 456"},
+            {"title": "Lamp", "price": "25.50", "user_phone": "+421 900 123 456", "colour": "red"}
+        ]"#;
+        let records = json_records(json).unwrap();
+        assert_eq!(records[0].as_ref().unwrap(), &record("Bike", "100"));
+        assert_eq!(records[1].as_ref().unwrap_err()[0].field, "record");
+
+        assert!(json_records(br#"{"title": "Bike"}"#).is_err());
+    }
+
+    #[test]
+    fn test_import_record_into_content() {
+        let (ad, image_ids) = ImportRecord {
+            image_ids: vec!["a".to_string()],
+            ..record("Bike", "100")
+        }
+        .into_content("seller@test.com", Currency::Eur, Locale::Sk)
+        .unwrap();
+        assert_eq!(ad.user_email, "seller@test.com");
+        assert_eq!(ad.currency, Currency::Eur);
+        assert_eq!(ad.locale, Locale::Sk);
+        assert_eq!(image_ids, vec!["a".to_string()]);
+
+        let errors = ImportRecord {
+            currency: Some("XYZ".to_string()),
+            locale: Some("klingon".to_string()),
+            category: Some("sports".to_string()),
+            ..record("Bike", "cheap")
+        }
+        .into_content("seller@test.com", Currency::Eur, Locale::Sk)
+        .err()
+        .unwrap();
+        let fields: Vec<_> = errors.iter().map(|e| e.field).collect();
+        assert_eq!(fields, ["price", "currency", "locale", "category"]);
+
+        let errors = record(" ", "1")
+            .into_content("seller@test.com", Currency::Eur, Locale::Sk)
+            .err()
+            .unwrap();
+        assert_eq!(errors[0].field, "title");
+    }
+}
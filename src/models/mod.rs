@@ -1,2 +1,11 @@
 pub mod ad;
+pub mod contact;
+pub mod export;
 pub mod image;
+pub mod import;
+pub mod money;
+pub mod outbox;
+pub mod price_history;
+pub mod report;
+pub mod rss;
+pub mod stats;
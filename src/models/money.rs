@@ -0,0 +1,238 @@
+use std::{fmt, str::FromStr};
+
+use bigdecimal::{BigDecimal, RoundingMode, Zero};
+use diesel::{
+    deserialize::{self, FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    serialize::{self, Output, ToSql},
+    sql_types::Numeric,
+};
+use serde_derive::Serialize;
+use utoipa::openapi::{
+    schema::{ObjectBuilder, Schema, Type},
+    RefOr,
+};
+
+/// A non-negative amount below `Money::LIMIT` with exactly `Money::SCALE` decimal places,
+/// rounded half-up when made, so `12.349` and `12.35` are the same price. Stored as a
+/// plain `NUMERIC` and serialized like `BigDecimal`, as a decimal string.
+#[derive(
+    Serialize, AsExpression, FromSqlRow, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[serde(transparent)]
+#[diesel(sql_type = Numeric)]
+pub struct Money(BigDecimal);
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum MoneyError {
+    #[error("must be a number")]
+    NotANumber,
+    #[error("must not be negative")]
+    Negative,
+    #[error("must be less than {}", Money::LIMIT)]
+    TooLarge,
+}
+
+impl Money {
+    pub const SCALE: i64 = 2;
+    /// Amounts must stay below this to fit the `DECIMAL(10,2)` columns.
+    pub const LIMIT: u64 = 100_000_000;
+
+    /// `amount` rounded half-up to `SCALE` places. Negative amounts are rejected before
+    /// rounding, so `-0.001` doesn't slip through as zero, and too large ones after, so
+    /// `99999999.995` is caught once it rounds up to `100000000.00`. Amounts with more
+    /// whole digits than `LIMIT` are rejected up front: rescaling `1e1000000000` would
+    /// take the thread minutes.
+    pub fn new(amount: BigDecimal) -> Result<Money, MoneyError> {
+        if amount < BigDecimal::zero() {
+            return Err(MoneyError::Negative);
+        }
+        let whole_digits =
+            i128::from(amount.digits()) - i128::from(amount.fractional_digit_count());
+        if !amount.is_zero() && whole_digits > i128::from(Money::LIMIT.ilog10() + 1) {
+            return Err(MoneyError::TooLarge);
+        }
+        let money = Money::rounded(amount);
+        if money.0 >= BigDecimal::from(Money::LIMIT) {
+            return Err(MoneyError::TooLarge);
+        }
+        Ok(money)
+    }
+
+    fn rounded(amount: BigDecimal) -> Money {
+        Money(amount.with_scale_round(Money::SCALE, RoundingMode::HalfUp))
+    }
+
+    pub fn amount(&self) -> &BigDecimal {
+        &self.0
+    }
+}
+
+impl Default for Money {
+    fn default() -> Self {
+        Money::rounded(BigDecimal::zero())
+    }
+}
+
+impl From<u32> for Money {
+    fn from(amount: u32) -> Self {
+        Money::rounded(amount.into())
+    }
+}
+
+impl From<Money> for BigDecimal {
+    fn from(money: Money) -> Self {
+        money.0
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for Money {
+    type Err = MoneyError;
+
+    /// Only plain finite numbers; `NaN`, `inf` and empty strings aren't amounts.
+    fn from_str(amount: &str) -> Result<Self, Self::Err> {
+        let amount = BigDecimal::from_str(amount.trim()).map_err(|_| MoneyError::NotANumber)?;
+        Money::new(amount)
+    }
+}
+
+/// Accepts whatever `BigDecimal` does, a decimal string or a JSON number, then applies
+/// the rules of `Money::new`.
+impl<'de> serde::Deserialize<'de> for Money {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let amount = BigDecimal::deserialize(deserializer)?;
+        Money::new(amount).map_err(|e| serde::de::Error::custom(format!("amount {}", e)))
+    }
+}
+
+/// Documented as the decimal string it is serialized as; see `Deserialize` for what is
+/// accepted.
+impl utoipa::PartialSchema for Money {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .description(Some(
+                "A non-negative amount below 100000000 with two decimal places.",
+            ))
+            .examples(["19.99"])
+            .into()
+    }
+}
+
+impl utoipa::ToSchema for Money {}
+
+impl ToSql<Numeric, Pg> for Money {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        <BigDecimal as ToSql<Numeric, Pg>>::to_sql(&self.0, out)
+    }
+}
+
+/// The columns are `DECIMAL(10,2)`, so this only fixes the scale of what's read back.
+impl FromSql<Numeric, Pg> for Money {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        BigDecimal::from_sql(bytes).map(Money::rounded)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::ad::parse_price;
+
+    #[test]
+    fn test_rounds_half_up() {
+        for (amount, expected) in [
+            ("12.349", "12.35"),
+            ("12.345", "12.35"),
+            ("12.344", "12.34"),
+            ("12", "12.00"),
+            ("0.005", "0.01"),
+        ] {
+            assert_eq!(
+                Money::from_str(amount).unwrap().to_string(),
+                expected,
+                "{}",
+                amount
+            );
+        }
+        assert_eq!(
+            Money::from_str("12.349").unwrap(),
+            Money::from_str("12.35").unwrap()
+        );
+        assert_eq!(Money::from(100), Money::from_str("100.00").unwrap());
+    }
+
+    #[test]
+    fn test_rejects_negatives() {
+        for amount in ["-1", "-0.01", "-0.001"] {
+            assert_eq!(
+                Money::from_str(amount),
+                Err(MoneyError::Negative),
+                "{}",
+                amount
+            );
+        }
+        for amount in ["", "abc", "NaN", "inf"] {
+            assert_eq!(
+                Money::from_str(amount),
+                Err(MoneyError::NotANumber),
+                "{}",
+                amount
+            );
+        }
+        assert_eq!(Money::from_str("0").unwrap(), Money::default());
+    }
+
+    #[test]
+    fn test_rejects_amounts_too_large_to_store() {
+        for amount in ["100000000", "99999999.995", "1e9", "1000000000.00"] {
+            assert_eq!(
+                Money::from_str(amount),
+                Err(MoneyError::TooLarge),
+                "{}",
+                amount
+            );
+        }
+        assert_eq!(
+            Money::from_str("99999999.994").unwrap().to_string(),
+            "99999999.99"
+        );
+        assert_eq!(
+            parse_price("123456789").unwrap_err().message,
+            "must be less than 100000000"
+        );
+    }
+
+    #[test]
+    fn test_rejects_huge_exponents_without_rescaling() {
+        let started = std::time::Instant::now();
+        for amount in ["1e1000000000", "1e9223372036854775807", "123.4e999999999"] {
+            assert_eq!(
+                Money::from_str(amount),
+                Err(MoneyError::TooLarge),
+                "{}",
+                amount
+            );
+        }
+        assert!(started.elapsed() < std::time::Duration::from_millis(100));
+        assert_eq!(Money::from_str("1e-1000000000").unwrap(), Money::default());
+        assert_eq!(Money::from_str("0e1000000000").unwrap(), Money::default());
+    }
+
+    #[test]
+    fn test_serde() {
+        let money: Money = serde_json::from_str("\"19.999\"").unwrap();
+        assert_eq!(serde_json::to_string(&money).unwrap(), "\"20.00\"");
+        let money: Money = serde_json::from_str("5").unwrap();
+        assert_eq!(money.to_string(), "5.00");
+        let error = serde_json::from_str::<Money>("\"-5\"").unwrap_err();
+        assert!(error.to_string().contains("must not be negative"));
+    }
+}
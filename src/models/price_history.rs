@@ -0,0 +1,14 @@
+use diesel::{Queryable, Selectable};
+use serde_derive::Serialize;
+
+use crate::models::money::Money;
+
+/// A price an ad was changed to, from `AdRepo::price_history`. The price the ad was
+/// created with isn't a change, so it has no entry.
+#[derive(Clone, Serialize, utoipa::ToSchema, Queryable, Selectable, Debug, PartialEq)]
+#[diesel(table_name = crate::db::schema::price_history)]
+pub struct PriceChange {
+    pub price: Money,
+    pub currency: String,
+    pub changed_at: chrono::DateTime<chrono::Utc>,
+}
@@ -0,0 +1,42 @@
+use serde::Deserialize;
+use serde_derive::Serialize;
+
+use crate::models::ad::Ad;
+use crate::repos::error::FieldError;
+
+/// Longest reason a report can give, in characters.
+pub const MAX_REPORT_REASON_LEN: usize = 1000;
+
+/// Body of `POST /ads/:id/report`.
+#[derive(Deserialize, utoipa::ToSchema, Debug)]
+pub struct ReportRequest {
+    pub reason: String,
+}
+
+impl ReportRequest {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let reason = |message: &str| {
+            vec![FieldError {
+                field: "reason",
+                message: message.to_string(),
+            }]
+        };
+
+        if self.reason.trim().is_empty() {
+            Err(reason("must not be empty"))
+        } else if self.reason.chars().count() > MAX_REPORT_REASON_LEN {
+            Err(reason("must be at most 1000 characters"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// An ad in the moderation queue, with how often and how recently it was reported.
+#[derive(Serialize, utoipa::ToSchema, Debug)]
+pub struct ReportedAd {
+    #[serde(flatten)]
+    pub ad: Ad,
+    pub report_count: i64,
+    pub last_reported_at: chrono::DateTime<chrono::Utc>,
+}
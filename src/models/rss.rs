@@ -0,0 +1,79 @@
+use std::fmt::Write;
+
+use crate::models::ad::Ad;
+
+/// Ads in `GET /ads/feed.xml`, the most recent first.
+pub const RSS_FEED_SIZE: u32 = 50;
+
+/// An RSS 2.0 feed of `ads`, each item linking to the ad under `base_url`, the API's public
+/// URL; with an empty one the links are relative paths. The price leads the description.
+pub fn rss_feed(ads: &[Ad], base_url: &str) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\">\n<channel>\n");
+    element(&mut xml, "title", "Bazaars");
+    element(&mut xml, "link", &format!("{}/ads", base_url));
+    element(&mut xml, "description", "The newest ads on Bazaars");
+    if let Some(newest) = ads.iter().map(|ad| ad.created_at).max() {
+        element(&mut xml, "lastBuildDate", &newest.to_rfc2822());
+    }
+
+    for ad in ads {
+        let link = format!("{}/ads/{}", base_url, ad.id.0);
+        let mut price = format!("{} {}", ad.price, ad.currency);
+        if ad.negotiable {
+            price.push_str(" or best offer");
+        }
+
+        xml.push_str("<item>\n");
+        element(&mut xml, "title", &ad.title);
+        element(&mut xml, "link", &link);
+        element(
+            &mut xml,
+            "description",
+            &format!("{}\n\n{}", price, ad.description),
+        );
+        element(&mut xml, "category", &ad.category);
+        element(&mut xml, "guid", &link);
+        element(&mut xml, "pubDate", &ad.created_at.to_rfc2822());
+        xml.push_str("</item>\n");
+    }
+
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+fn element(xml: &mut String, name: &str, text: &str) {
+    let _ = writeln!(xml, "<{}>{}</{}>", name, escape(text), name);
+}
+
+/// `text` with markup characters escaped, and control characters XML 1.0 doesn't allow
+/// left out.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape("Tom & Jerry's <\"cartoon\">\u{0}\n"),
+            "Tom &amp; Jerry&apos;s &lt;&quot;cartoon&quot;&gt;\n"
+        );
+    }
+}
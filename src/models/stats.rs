@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+use bigdecimal::BigDecimal;
+use serde_derive::{Deserialize, Serialize};
+
+/// How many days, up to and including today, `AdStats::created_per_day` covers.
+pub const STATS_DAYS: i32 = 7;
+
+/// Marketplace totals for the admin dashboard, from `AdRepo::stats`. Only listed ads are
+/// counted: active, unexpired and not deleted.
+#[derive(Serialize, utoipa::ToSchema, Debug, PartialEq)]
+pub struct AdStats {
+    pub total_active: i64,
+    /// Keyed by category; categories without ads are left out.
+    pub per_category: BTreeMap<String, i64>,
+    /// Mean price to the cent, per currency, since prices in different currencies can't
+    /// be averaged together.
+    #[schema(value_type = BTreeMap<String, String>)]
+    pub average_price: BTreeMap<String, BigDecimal>,
+    /// Ads created on each of the last `STATS_DAYS` UTC days, oldest first, with empty
+    /// days included. Unlike the other figures this counts every ad that isn't deleted.
+    pub created_per_day: Vec<DailyCount>,
+}
+
+#[derive(Serialize, Deserialize, utoipa::ToSchema, Debug, PartialEq)]
+pub struct DailyCount {
+    pub day: chrono::NaiveDate,
+    pub count: i64,
+}
+
+/// Upper bounds of the price ranges in `AdFacets::price_ranges`, each excluded from its
+/// range; the last range has no upper bound.
+pub const PRICE_BUCKETS: [u32; 5] = [50, 100, 500, 1000, 5000];
+
+/// Counts over the ads matching a search, for its filter sidebar, from `AdRepo::facets`.
+#[derive(Serialize, utoipa::ToSchema, Debug, PartialEq)]
+pub struct AdFacets {
+    /// Keyed by category; categories without matching ads are left out.
+    pub categories: BTreeMap<String, i64>,
+    /// Matching ads per `PRICE_BUCKETS` range, cheapest first, with empty ranges
+    /// included. Prices are compared as plain amounts, like the price filters.
+    pub price_ranges: Vec<PriceRangeCount>,
+}
+
+#[derive(Serialize, utoipa::ToSchema, Debug, PartialEq)]
+pub struct PriceRangeCount {
+    pub min: u32,
+    /// Excluded; `None` for the open-ended top range.
+    pub max: Option<u32>,
+    pub count: i64,
+}
+
+impl AdFacets {
+    /// `bucket_counts` pairs a `price_bucket` index with its count; missing ones are 0.
+    pub fn new(categories: BTreeMap<String, i64>, bucket_counts: &[(i32, i64)]) -> Self {
+        let price_ranges = (0..=PRICE_BUCKETS.len())
+            .map(|bucket| PriceRangeCount {
+                min: bucket
+                    .checked_sub(1)
+                    .map_or(0, |below| PRICE_BUCKETS[below]),
+                max: PRICE_BUCKETS.get(bucket).copied(),
+                count: bucket_counts
+                    .iter()
+                    .filter(|(index, _)| *index as usize == bucket)
+                    .map(|(_, count)| count)
+                    .sum(),
+            })
+            .collect();
+
+        AdFacets {
+            categories,
+            price_ranges,
+        }
+    }
+}
+
+/// Index of the `PRICE_BUCKETS` range `price` falls in, like SQL's `width_bucket`.
+pub fn price_bucket(price: &BigDecimal) -> i32 {
+    PRICE_BUCKETS
+        .iter()
+        .take_while(|bound| *price >= BigDecimal::from(**bound))
+        .count() as i32
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::db::parse_env;
+use crate::repos::error::ErrorResponse;
+
+/// Buckets are pruned once this many clients are tracked.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Per-IP request budgets, read from `RATE_LIMIT_READ_PER_MINUTE`,
+/// `RATE_LIMIT_WRITE_PER_MINUTE`, `RATE_LIMIT_CONTACT_PER_MINUTE`,
+/// `RATE_LIMIT_REPORT_PER_MINUTE` and `RATE_LIMIT_TRUST_FORWARDED_FOR`.
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Budget for `GET`/`HEAD`/`OPTIONS`. Defaults to 120.
+    pub read_per_minute: u32,
+    /// Budget for every other method. Defaults to 20.
+    pub write_per_minute: u32,
+    /// Budget for messages to sellers, on top of the write budget. Defaults to 5.
+    pub contact_per_minute: u32,
+    /// Budget for abuse reports, on top of the write budget. Defaults to 3.
+    pub report_per_minute: u32,
+    /// Key on the first `X-Forwarded-For` address instead of the socket address.
+    /// Only enable behind a proxy that sets it, since clients can forge it otherwise.
+    pub trust_forwarded_for: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            read_per_minute: 120,
+            write_per_minute: 20,
+            contact_per_minute: 5,
+            report_per_minute: 3,
+            trust_forwarded_for: false,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Result<Self, Error> {
+        let defaults = RateLimitConfig::default();
+        let config = RateLimitConfig {
+            read_per_minute: parse_env("RATE_LIMIT_READ_PER_MINUTE")?
+                .unwrap_or(defaults.read_per_minute),
+            write_per_minute: parse_env("RATE_LIMIT_WRITE_PER_MINUTE")?
+                .unwrap_or(defaults.write_per_minute),
+            contact_per_minute: parse_env("RATE_LIMIT_CONTACT_PER_MINUTE")?
+                .unwrap_or(defaults.contact_per_minute),
+            report_per_minute: parse_env("RATE_LIMIT_REPORT_PER_MINUTE")?
+                .unwrap_or(defaults.report_per_minute),
+            trust_forwarded_for: parse_env("RATE_LIMIT_TRUST_FORWARDED_FOR")?
+                .unwrap_or(defaults.trust_forwarded_for),
+        };
+
+        if config.read_per_minute == 0
+            || config.write_per_minute == 0
+            || config.contact_per_minute == 0
+            || config.report_per_minute == 0
+        {
+            return Err(Error::msg(
+                "rate limits must be at least 1 request per minute",
+            ));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Holds up to `per_minute` tokens and refills continuously at `per_minute / 60` per second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_minute: u32, now: Instant) -> Self {
+        let capacity = f64::from(per_minute.max(1));
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / 60.0,
+            last_refill: now,
+        }
+    }
+
+    /// Takes one token, or returns how long until one is available.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.refill_per_sec,
+            ))
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens + elapsed.as_secs_f64() * self.refill_per_sec >= self.capacity
+    }
+}
+
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        // A full bucket behaves exactly like a fresh one, so it's safe to forget.
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(self.per_minute, now))
+            .try_acquire(now)
+    }
+}
+
+pub struct RateLimits {
+    read: RateLimiter,
+    write: RateLimiter,
+    contact: RateLimiter,
+    report: RateLimiter,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Arc<RateLimits> {
+        Arc::new(RateLimits {
+            read: RateLimiter::new(config.read_per_minute),
+            write: RateLimiter::new(config.write_per_minute),
+            contact: RateLimiter::new(config.contact_per_minute),
+            report: RateLimiter::new(config.report_per_minute),
+            trust_forwarded_for: config.trust_forwarded_for,
+        })
+    }
+}
+
+fn client_ip(req: &Request, trust_forwarded_for: bool) -> IpAddr {
+    let forwarded = trust_forwarded_for
+        .then(|| req.headers().get("x-forwarded-for"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+
+    forwarded
+        .or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        })
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+/// Rejects clients over their per-minute budget with 429 and `Retry-After`.
+/// Reads and writes are metered separately so browsing doesn't eat into posting.
+pub async fn rate_limit(
+    State(limits): State<Arc<RateLimits>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let limiter = match *req.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => &limits.read,
+        _ => &limits.write,
+    };
+
+    throttle(limiter, limits.trust_forwarded_for, req, next).await
+}
+
+/// The separate, stricter budget for `POST /ads/:id/contact`, which sends email on the
+/// client's behalf.
+pub async fn contact_rate_limit(
+    State(limits): State<Arc<RateLimits>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    throttle(&limits.contact, limits.trust_forwarded_for, req, next).await
+}
+
+/// The separate budget for `POST /ads/:id/report`, so the report queue can't be flooded
+/// to bury or smear an ad.
+pub async fn report_rate_limit(
+    State(limits): State<Arc<RateLimits>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    throttle(&limits.report, limits.trust_forwarded_for, req, next).await
+}
+
+async fn throttle(
+    limiter: &RateLimiter,
+    trust_forwarded_for: bool,
+    req: Request,
+    next: Next,
+) -> Response {
+    match limiter.check(client_ip(&req, trust_forwarded_for), Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            let retry_after = (retry_after.as_secs_f64().ceil() as u64).max(1);
+            (
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                ErrorResponse::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limited",
+                    format!("too many requests, retry in {}s", retry_after),
+                ),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn test_bucket_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(60, start);
+
+        for _ in 0..60 {
+            assert!(bucket.try_acquire(start).is_ok());
+        }
+        let retry_after = bucket.try_acquire(start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        // One token per second at 60/min.
+        let later = start + Duration::from_millis(1500);
+        assert!(bucket.try_acquire(later).is_ok());
+        assert!(bucket.try_acquire(later).is_err());
+
+        // Refill is capped at capacity, however long the client was idle.
+        let much_later = later + Duration::from_secs(3600);
+        for _ in 0..60 {
+            assert!(bucket.try_acquire(much_later).is_ok());
+        }
+        assert!(bucket.try_acquire(much_later).is_err());
+    }
+}
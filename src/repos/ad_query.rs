@@ -0,0 +1,348 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::{de::IntoDeserializer, Deserialize};
+
+use crate::models::ad::parse_price;
+use crate::models::money::Money;
+use crate::repos::ad_repo::{AdFilter, AdFilterBuilder, AdSort};
+use crate::repos::error::FieldError;
+
+/// Keys a term of the query language can have, in the order they are listed in errors.
+const KEYS: [&str; 13] = [
+    "title",
+    "description",
+    "category",
+    "currency",
+    "price",
+    "created",
+    "updated",
+    "images",
+    "top",
+    "negotiable",
+    "locale",
+    "verified",
+    "sort",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Op {
+    Eq,
+    Lt,
+    Gt,
+}
+
+impl Op {
+    fn from_char(c: char) -> Option<Op> {
+        match c {
+            ':' => Some(Op::Eq),
+            '<' => Some(Op::Lt),
+            '>' => Some(Op::Gt),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Op::Eq => ":",
+            Op::Lt => "<",
+            Op::Gt => ">",
+        })
+    }
+}
+
+enum Term<'a> {
+    /// Free text, searched for like `AdFilter::search`.
+    Word(&'a str),
+    Field {
+        key: &'a str,
+        op: Op,
+        value: &'a str,
+    },
+}
+
+/// Splits the query into whitespace-separated terms. A term is `key:value`, `key<value`,
+/// `key>value` or a bare word; values and bare words may be double-quoted to hold spaces.
+fn terms(q: &str) -> Result<Vec<Term<'_>>, String> {
+    let mut terms = Vec::new();
+    let mut rest = q.trim_start();
+
+    while !rest.is_empty() {
+        let key_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let op = rest[key_len..].chars().next().and_then(Op::from_char);
+
+        let term = match op {
+            Some(op) if key_len == 0 => return Err(format!("missing a key before `{}`", op)),
+            Some(op) => {
+                let key = &rest[..key_len];
+                let (value, tail) = value(&rest[key_len + 1..])?;
+                if value.is_empty() {
+                    return Err(format!("`{}{}` needs a value", key, op));
+                }
+                rest = tail;
+                Term::Field { key, op, value }
+            }
+            None => {
+                let (word, tail) = value(rest)?;
+                rest = tail;
+                Term::Word(word)
+            }
+        };
+
+        terms.push(term);
+        rest = rest.trim_start();
+    }
+
+    Ok(terms)
+}
+
+/// The value at the start of `input`, up to the next whitespace or between double quotes,
+/// and what follows it.
+fn value(input: &str) -> Result<(&str, &str), String> {
+    match input.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted
+                .find('"')
+                .ok_or_else(|| "unterminated quote".to_string())?;
+            Ok((&quoted[..end], &quoted[end + 1..]))
+        }
+        None => {
+            let end = input.find(char::is_whitespace).unwrap_or(input.len());
+            Ok((&input[..end], &input[end..]))
+        }
+    }
+}
+
+fn price(key: &str, value: &str) -> Result<Money, String> {
+    parse_price(value).map_err(|e| format!("{} {}, got `{}`", key, e.message, value))
+}
+
+/// A date, taken as midnight UTC, or a full RFC 3339 timestamp.
+fn timestamp(key: &str, value: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+        .or_else(|_| chrono::DateTime::parse_from_rfc3339(value).map(|at| at.to_utc()))
+        .map_err(|_| {
+            format!(
+                "{} must be a date like 2026-01-31 or an RFC 3339 timestamp, got `{}`",
+                key, value
+            )
+        })
+}
+
+fn flag(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" => Ok(true),
+        "false" | "no" => Ok(false),
+        _ => Err(format!("{} must be true or false, got `{}`", key, value)),
+    }
+}
+
+fn apply(
+    builder: AdFilterBuilder,
+    key: &str,
+    op: Op,
+    value: &str,
+) -> Result<AdFilterBuilder, String> {
+    Ok(match (key, op) {
+        ("title", Op::Eq) => builder.title_contains(value),
+        ("description", Op::Eq) => builder.description_contains(value),
+        ("category", Op::Eq) => builder.category(value.parse().map_err(|e: FieldError| e.message)?),
+        ("currency", Op::Eq) => builder.currency(
+            value
+                .to_uppercase()
+                .parse()
+                .map_err(|e: FieldError| e.message)?,
+        ),
+        ("price", Op::Lt) => builder.price_below(price(key, value)?),
+        ("price", Op::Gt) => builder.price_above(price(key, value)?),
+        // `price:100..500` includes both ends; `price:100` is exactly 100.
+        ("price", Op::Eq) => {
+            let (min, max) = value.split_once("..").unwrap_or((value, value));
+            builder.price_between(price(key, min)?, price(key, max)?)
+        }
+        ("created", Op::Lt) => builder.created_before(timestamp(key, value)?),
+        ("created", Op::Gt) => builder.created_after(timestamp(key, value)?),
+        ("updated", Op::Lt) => builder.updated_before(timestamp(key, value)?),
+        ("updated", Op::Gt) => builder.updated_after(timestamp(key, value)?),
+        ("images", Op::Eq) => builder.has_images(flag(key, value)?),
+        ("top", Op::Eq) => builder.top_ad(flag(key, value)?),
+        ("negotiable", Op::Eq) => builder.negotiable(flag(key, value)?),
+        ("locale", Op::Eq) => builder.locale(value.parse().map_err(|e: FieldError| e.message)?),
+        ("verified", Op::Eq) => builder.phone_verified(flag(key, value)?),
+        ("sort", Op::Eq) => {
+            let sort: Result<AdSort, serde::de::value::Error> =
+                AdSort::deserialize(value.into_deserializer());
+            builder.sort_by(sort.map_err(|_| format!("unknown sort: {}", value))?)
+        }
+        (key, op) if KEYS.contains(&key) => {
+            return Err(format!("{} can't be compared with `{}`", key, op))
+        }
+        (key, _) => {
+            return Err(format!(
+                "unknown key `{}`, expected one of {}",
+                key,
+                KEYS.join(", ")
+            ))
+        }
+    })
+}
+
+/// Parses the search language of `GET /ads/search` into an `AdFilter`, e.g.
+/// `price<500 title:bike category:vehicles`. Bare words are a full-text `search`, so they
+/// can't be mixed with `title:` or `description:`. Errors are reported against `q`.
+///
+/// ```
+/// use bazaars::repos::ad_query::parse_ad_query;
+///
+/// let filter = parse_ad_query(r#"price<500 title:"road bike""#).unwrap();
+/// assert_eq!(filter.title_contains.as_deref(), Some("road bike"));
+/// assert!(parse_ad_query("price<").is_err());
+/// ```
+pub fn parse_ad_query(q: &str) -> Result<AdFilter, Vec<FieldError>> {
+    let invalid = |message: String| {
+        vec![FieldError {
+            field: "q",
+            message,
+        }]
+    };
+
+    let mut builder = AdFilter::builder();
+    let mut words = Vec::new();
+    let mut seen = HashSet::new();
+
+    for term in terms(q).map_err(invalid)? {
+        match term {
+            Term::Word(word) => words.push(word),
+            Term::Field { key, op, value } => {
+                if !seen.insert((key, op)) {
+                    return Err(invalid(format!("`{}{}` is given more than once", key, op)));
+                }
+                builder = apply(builder, key, op, value).map_err(invalid)?;
+            }
+        }
+    }
+
+    if !words.is_empty() {
+        if seen
+            .iter()
+            .any(|(key, _)| *key == "title" || *key == "description")
+        {
+            return Err(invalid(
+                "free text can't be combined with title: or description:".to_string(),
+            ));
+        }
+        builder = builder.search(words.join(" "));
+    }
+
+    builder.build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_ad_query;
+    use crate::models::ad::{AdCategory, Currency, Locale};
+    use crate::models::money::Money;
+    use crate::repos::ad_repo::AdSort;
+
+    fn error(q: &str) -> String {
+        let errors = parse_ad_query(q).err().expect(q);
+        assert_eq!(errors[0].field, "q");
+        errors[0].message.clone()
+    }
+
+    #[test]
+    fn test_parse_text_terms() {
+        let filter = parse_ad_query(r#"title:bike description:"barely used""#).unwrap();
+        assert_eq!(filter.title_contains.as_deref(), Some("bike"));
+        assert_eq!(filter.description_contains.as_deref(), Some("barely used"));
+        assert_eq!(filter.search, None);
+
+        let filter = parse_ad_query(r#"  road   "bike lock"  "#).unwrap();
+        assert_eq!(filter.search.as_deref(), Some("road bike lock"));
+
+        assert!(parse_ad_query("").unwrap().search.is_none());
+    }
+
+    #[test]
+    fn test_parse_price_terms() {
+        let filter = parse_ad_query("price<500 price>100").unwrap();
+        assert_eq!(filter.price_lt, Some(Money::from(500)));
+        assert_eq!(filter.price_gt, Some(Money::from(100)));
+
+        let filter = parse_ad_query("price:100..250.50").unwrap();
+        assert_eq!(
+            filter.price_between,
+            Some((Money::from(100), "250.50".parse().unwrap()))
+        );
+        let filter = parse_ad_query("price:99").unwrap();
+        assert_eq!(
+            filter.price_between,
+            Some((Money::from(99), Money::from(99)))
+        );
+    }
+
+    #[test]
+    fn test_parse_other_terms() {
+        let filter = parse_ad_query(
+            "category:vehicles currency:usd images:true top:no negotiable:yes locale:cs \
+             verified:true sort:price_asc created>2026-01-01 created<2026-02-01T12:00:00Z \
+             updated>2026-01-15",
+        )
+        .unwrap();
+        assert_eq!(filter.category_eq, Some(AdCategory::Vehicles));
+        assert_eq!(filter.currency_eq, Some(Currency::Usd));
+        assert_eq!(filter.has_images, Some(true));
+        assert_eq!(filter.top_ad, Some(false));
+        assert_eq!(filter.negotiable_eq, Some(true));
+        assert_eq!(filter.locale_eq, Some(Locale::Cs));
+        assert_eq!(filter.phone_verified_eq, Some(true));
+        assert_eq!(filter.sort_by, Some(AdSort::PriceAsc));
+        assert_eq!(
+            filter.created_at_gt,
+            Some("2026-01-01T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(
+            filter.created_at_lt,
+            Some("2026-02-01T12:00:00Z".parse().unwrap())
+        );
+        assert_eq!(
+            filter.updated_at_gt,
+            Some("2026-01-15T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(filter.updated_at_lt, None);
+    }
+
+    #[test]
+    fn test_reject_invalid_queries() {
+        assert_eq!(error("price<"), "`price<` needs a value");
+        assert_eq!(error("<500"), "missing a key before `<`");
+        assert_eq!(error(r#"title:"road bike"#), "unterminated quote");
+        assert_eq!(error("price<cheap"), "price must be a number, got `cheap`");
+        assert_eq!(error("title>bike"), "title can't be compared with `>`");
+        assert_eq!(error("category:sports"), "unknown category: sports");
+        assert_eq!(error("sort:newest"), "unknown sort: newest");
+        assert_eq!(
+            error("images:maybe"),
+            "images must be true or false, got `maybe`"
+        );
+        assert_eq!(
+            error("price<5 price<10"),
+            "`price<` is given more than once"
+        );
+        assert_eq!(
+            error("bike title:road"),
+            "free text can't be combined with title: or description:"
+        );
+        assert!(error("colour:red").starts_with("unknown key `colour`, expected one of title"));
+        assert!(error("created>yesterday").starts_with("created must be a date"));
+
+        // Well-formed, but rejected by `AdFilter::validate` like the query string would be.
+        let errors = parse_ad_query("price>500 price<100").err().unwrap();
+        assert_eq!(errors[0].field, "price_gt");
+    }
+}
@@ -1,18 +1,370 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Error;
 use axum::async_trait;
-use bigdecimal::{BigDecimal, FromPrimitive};
+use dashmap::DashMap;
+use diesel::dsl::{count_star, sql};
 use diesel::pg::Pg;
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery};
 use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::serialize::ToSql;
 use diesel::sql_query;
+use diesel::sql_types::{
+    Array, BigInt, Bool, Double, Float, HasSqlType, Integer, Jsonb, Nullable, Text, Timestamptz,
+};
 use diesel::PgConnection;
 use diesel::QueryableByName;
 use diesel::{debug_query, prelude::*};
 
-use crate::db::schema::ads;
-use crate::db::DbManager;
-use crate::models::ad::{Ad, AdContent};
+use crate::db::schema::{ad_slugs, ads, idempotency_keys, phone_codes, price_history};
+use crate::db::{parse_env, DbManager};
+use crate::models::ad::{
+    ad_slug, location_errors, Ad, AdCategory, AdContent, AdId, AdPatch, AdStatus, Currency,
+    ImageIds, Locale,
+};
+use crate::models::money::Money;
+use crate::models::price_history::PriceChange;
+use crate::models::stats::{AdFacets, AdStats, PRICE_BUCKETS, STATS_DAYS};
+use crate::repos::error::{FieldError, RepoError};
+
+/// How long a new ad stays listed before it expires.
+pub const AD_LIFETIME_DAYS: i64 = 30;
+
+/// How long after being posted, or last renewed, an ad can be renewed again.
+pub const RENEW_COOLDOWN_HOURS: i64 = 24;
+
+/// How long a code sent by `AdRepo::start_phone_verification` can be entered.
+pub const PHONE_CODE_TTL_MINUTES: i64 = 10;
+
+/// Wrong codes `AdRepo::verify_phone` takes before the pending code is dropped and a new
+/// one has to be started.
+pub const MAX_PHONE_CODE_ATTEMPTS: i32 = 5;
+
+/// How long an `Idempotency-Key` keeps replaying the ad it created.
+pub const IDEMPOTENCY_KEY_TTL_HOURS: i64 = 24;
+
+/// Longest `Idempotency-Key` accepted, the width of its column.
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Upper bound on ids accepted by a single `get_by_ids`.
+pub const MAX_BATCH_IDS: usize = 100;
+
+/// Deepest `offset` accepted when listing ads; pages beyond it are cheaper to reach
+/// through keyset pagination anyway.
+pub const MAX_OFFSET: u32 = 10_000;
+
+/// What `POST /ads` does about an ad that looks like a repost of one the seller created
+/// shortly before, see `AdRepo::find_similar`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    Off,
+    /// Create the ad, but point out the earlier one in the response.
+    #[default]
+    Warn,
+    /// Refuse the ad with a conflict.
+    Block,
+}
+
+impl std::str::FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(DuplicatePolicy::Off),
+            "warn" => Ok(DuplicatePolicy::Warn),
+            "block" => Ok(DuplicatePolicy::Block),
+            _ => Err(format!("expected off, warn or block, got {}", value)),
+        }
+    }
+}
+
+/// Duplicate ad detection, read from `DUPLICATE_ADS` (`off`, `warn` or `block`) and
+/// `DUPLICATE_WINDOW_SECS`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DuplicateCheck {
+    /// Defaults to warn.
+    pub policy: DuplicatePolicy,
+    /// How far back to look for an earlier ad. Defaults to a day.
+    pub window: Duration,
+}
+
+impl Default for DuplicateCheck {
+    fn default() -> Self {
+        DuplicateCheck {
+            policy: DuplicatePolicy::default(),
+            window: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl DuplicateCheck {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let defaults = DuplicateCheck::default();
+        Ok(DuplicateCheck {
+            policy: parse_env("DUPLICATE_ADS")?.unwrap_or(defaults.policy),
+            window: parse_env("DUPLICATE_WINDOW_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.window),
+        })
+    }
+
+    /// Ads created before this aren't compared against.
+    pub fn since(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Duration::from_std(self.window)
+            .ok()
+            .and_then(|window| chrono::Utc::now().checked_sub_signed(window))
+            .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC)
+    }
+}
+
+/// Page sizes for listing ads, read from `DEFAULT_PER_PAGE` and `MAX_PER_PAGE`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageLimits {
+    /// Used when `per_page` is missing. Defaults to 10.
+    pub default_per_page: u32,
+    /// Larger requests are cut down to this. Defaults to 100.
+    pub max_per_page: u32,
+    /// Answer an `offset` past the last item with 404 rather than an empty page, read
+    /// from `STRICT_PAGINATION`. Off by default.
+    pub strict: bool,
+}
+
+impl Default for PageLimits {
+    fn default() -> Self {
+        PageLimits {
+            default_per_page: 10,
+            max_per_page: 100,
+            strict: false,
+        }
+    }
+}
+
+impl PageLimits {
+    pub fn from_env() -> Result<Self, anyhow::Error> {
+        let defaults = PageLimits::default();
+        let limits = PageLimits {
+            default_per_page: parse_env("DEFAULT_PER_PAGE")?.unwrap_or(defaults.default_per_page),
+            max_per_page: parse_env("MAX_PER_PAGE")?.unwrap_or(defaults.max_per_page),
+            strict: parse_env("STRICT_PAGINATION")?.unwrap_or(defaults.strict),
+        };
+
+        if limits.default_per_page == 0 || limits.default_per_page > limits.max_per_page {
+            return Err(anyhow::Error::msg(
+                "DEFAULT_PER_PAGE must be between 1 and MAX_PER_PAGE",
+            ));
+        }
+
+        Ok(limits)
+    }
+
+    /// The page size to use for a requested `per_page`; 0 is rejected.
+    pub fn per_page(&self, requested: Option<u32>) -> Result<u32, RepoError> {
+        match requested {
+            None => Ok(self.default_per_page),
+            Some(0) => Err(page_error("per_page", "must be greater than 0")),
+            Some(per_page) => Ok(per_page.min(self.max_per_page)),
+        }
+    }
+
+    /// The requested `offset`, if it isn't past `MAX_OFFSET`.
+    pub fn offset(&self, requested: Option<u32>) -> Result<u32, RepoError> {
+        match requested.unwrap_or(0) {
+            offset if offset > MAX_OFFSET => Err(page_error(
+                "offset",
+                &format!("must be at most {}", MAX_OFFSET),
+            )),
+            offset => Ok(offset),
+        }
+    }
+
+    /// With `strict`, `NotFound` for an `offset` past the last of `total` items. The first
+    /// page is always there, even when nothing matched.
+    pub fn check_offset(&self, offset: u32, total: u64) -> Result<(), RepoError> {
+        if self.strict && offset > 0 && u64::from(offset) >= total {
+            return Err(RepoError::NotFound);
+        }
+        Ok(())
+    }
+}
+
+fn page_error(field: &'static str, message: &str) -> RepoError {
+    RepoError::InvalidFields(vec![FieldError {
+        field,
+        message: message.to_string(),
+    }])
+}
+
+/// Mean Earth radius used for Haversine distances.
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Upper bound on rows returned by a single `FETCH`.
+pub const MAX_CURSOR_FETCH: u32 = 100;
+
+/// Open cursors a `PostgresAdRepo` keeps unless `MAX_OPEN_CURSORS` says otherwise.
+pub const DEFAULT_MAX_OPEN_CURSORS: usize = 100;
+
+/// Cursor names are interpolated into `FETCH`/`CLOSE`, so only the exact
+/// `c_[0-9a-f]{10}` shape `new_cursor` generates is accepted.
+pub(crate) fn validate_cursor_name(cursor_name: &str) -> Result<(), RepoError> {
+    let valid = cursor_name.len() == 12
+        && cursor_name.starts_with("c_")
+        && cursor_name[2..]
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(RepoError::Validation(format!(
+            "invalid cursor name: {}",
+            cursor_name
+        )))
+    }
+}
+
+/// A batch of rows fetched from a cursor opened by `AdRepo::new_cursor`.
+#[derive(serde::Serialize, utoipa::ToSchema, Debug)]
+pub struct CursorPage<T> {
+    pub items: Vec<T>,
+    /// Whether the batch came back full, so fetching again may return more; the next
+    /// batch can still turn out empty.
+    pub has_more: bool,
+    /// The cursor to fetch the next batch from, or `None` once it has been closed.
+    pub cursor: Option<String>,
+}
+
+impl<T> CursorPage<T> {
+    /// The page of a fetch for `count` rows from `cursor_name` that returned `items`.
+    pub(crate) fn new(items: Vec<T>, count: usize, cursor_name: String, closed: bool) -> Self {
+        CursorPage {
+            has_more: items.len() == count && !closed,
+            cursor: (!closed).then_some(cursor_name),
+            items,
+        }
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(Vec<T>) -> Vec<U>) -> CursorPage<U> {
+        CursorPage {
+            items: f(self.items),
+            has_more: self.has_more,
+            cursor: self.cursor,
+        }
+    }
+}
+
+/// `InvalidFields` unless `filter` is the one the cursor was declared with, of which
+/// `declared` is the `AdFilter::fingerprint`. The filter is baked into the cursor, so a
+/// client sending another one would page on under the old predicates without noticing.
+pub(crate) fn check_cursor_filter(declared: u64, filter: &AdFilter) -> Result<(), RepoError> {
+    if filter.fingerprint() == declared {
+        return Ok(());
+    }
+    Err(RepoError::InvalidFields(vec![FieldError {
+        field: "filters",
+        message: "must be the filters the cursor was declared with".to_string(),
+    }]))
+}
+
+struct CursorEntry {
+    /// `AdFilter::fingerprint` of the filter the cursor was declared with.
+    fingerprint: u64,
+    declared_at: Instant,
+    used_at: Instant,
+}
+
+/// The cursors a `PostgresAdRepo` has declared and not yet closed, by name. Cursors only
+/// live on this process' cursor connection, see `DbManager::cursor_conn`, so a map in
+/// memory covers them all. Each
+/// holds a snapshot on the server until closed, hence the cap of `max_open`.
+#[derive(Clone)]
+struct CursorRegistry {
+    cursors: Arc<DashMap<String, CursorEntry>>,
+    max_open: usize,
+}
+
+impl CursorRegistry {
+    fn new(max_open: usize) -> Self {
+        CursorRegistry {
+            cursors: Arc::new(DashMap::new()),
+            max_open,
+        }
+    }
+
+    /// Registers a cursor just declared over `filter`. Returns the oldest cursors it
+    /// pushed over `max_open`, no longer registered, for the caller to close.
+    fn insert(&self, cursor_name: &str, filter: &AdFilter) -> Vec<String> {
+        let now = Instant::now();
+        self.cursors.insert(
+            cursor_name.to_string(),
+            CursorEntry {
+                fingerprint: filter.fingerprint(),
+                declared_at: now,
+                used_at: now,
+            },
+        );
+
+        let excess = self.cursors.len().saturating_sub(self.max_open);
+        if excess == 0 {
+            return Vec::new();
+        }
+        let mut oldest: Vec<_> = self
+            .cursors
+            .iter()
+            .filter(|entry| entry.key() != cursor_name)
+            .map(|entry| (entry.declared_at, entry.key().clone()))
+            .collect();
+        oldest.sort();
+        oldest
+            .into_iter()
+            .take(excess)
+            .filter_map(|(_, name)| self.cursors.remove(&name).map(|(name, _)| name))
+            .collect()
+    }
+
+    /// Marks the cursor as fetched from, restarting its time to live. A cursor that isn't
+    /// registered is left for the fetch to report as expired.
+    fn touch(&self, cursor_name: &str, filter: Option<&AdFilter>) -> Result<(), RepoError> {
+        let Some(mut entry) = self.cursors.get_mut(cursor_name) else {
+            return Ok(());
+        };
+        if let Some(filter) = filter {
+            check_cursor_filter(entry.fingerprint, filter)?;
+        }
+        entry.used_at = Instant::now();
+        Ok(())
+    }
+
+    fn remove(&self, cursor_name: &str) {
+        self.cursors.remove(cursor_name);
+    }
+
+    /// Registered cursors not fetched from for longer than `ttl`.
+    fn idle(&self, ttl: Duration) -> Vec<String> {
+        self.cursors
+            .iter()
+            .filter(|entry| entry.used_at.elapsed() > ttl)
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.cursors
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+}
+
+/// Whether `e` is Postgres reporting that the cursor being fetched from isn't open,
+/// e.g. because it was closed or was declared on a connection that has since closed.
+fn is_missing_cursor(e: &diesel::result::Error) -> bool {
+    matches!(e, diesel::result::Error::DatabaseError(_, info)
+        if info.message().starts_with("cursor ") && info.message().ends_with("does not exist"))
+}
 
 pub struct Cursor {
     pub cursor_name: String,
@@ -21,307 +373,4893 @@ pub struct Cursor {
 
 impl Cursor {
     pub fn new(cursor_name: String, db_manager: DbManager) -> Cursor {
-        let pool = db_manager.get_read_pool();
+        // Cursors only exist on the connection that declared them.
+        let pool = db_manager.get_cursor_pool();
         Cursor { cursor_name, pool }
     }
 
-    pub fn get_next<T>(&self, count: u8) -> Result<Vec<T>, Error>
+    pub fn get_next<T>(&self, count: u32) -> Result<Vec<T>, RepoError>
     where
         T: QueryableByName<Pg> + 'static, // Ensure T can be converted from SQL and has a 'static lifetime
     {
+        validate_cursor_name(&self.cursor_name)?;
+        let count = count.clamp(1, MAX_CURSOR_FETCH);
         let query = format!("FETCH FORWARD {} FROM {}", count, self.cursor_name);
-        let conn = &mut self.pool.get().map_err(|e| Error::msg(e.to_string()))?;
-        sql_query(query).load::<T>(conn).map_err(Error::from)
+        let conn = &mut self.pool.get()?;
+        sql_query(query).load::<T>(conn).map_err(RepoError::from)
     }
 }
 
-#[derive(serde::Deserialize, Default, Clone)]
+#[derive(
+    serde::Deserialize,
+    serde::Serialize,
+    async_graphql::Enum,
+    utoipa::ToSchema,
+    Default,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AdSort {
+    PriceAsc,
+    PriceDesc,
+    CreatedAtAsc,
+    #[default]
+    CreatedAtDesc,
+    UpdatedAtDesc,
+    /// Nearest first; needs `near_lat` and `near_lon`. Ads without a location come last.
+    DistanceAsc,
+}
+
+#[derive(
+    serde::Deserialize, serde::Serialize, utoipa::ToSchema, utoipa::IntoParams, Default, Clone,
+)]
+#[into_params(parameter_in = Query)]
 pub struct AdFilter {
+    /// Full-text search over title and description, ranked by relevance; takes
+    /// precedence over `title_contains` and `description_contains`.
+    pub search: Option<String>,
     pub title_contains: Option<String>,
     pub description_contains: Option<String>,
-    pub price_lt: Option<BigDecimal>,
-    pub price_gt: Option<BigDecimal>,
-    pub updated_at_lt: Option<chrono::NaiveDateTime>,
-    pub updated_at_gt: Option<chrono::NaiveDateTime>,
+    /// Exclusive bounds, compared as plain amounts whatever the ad's currency; combine
+    /// with `currency_eq` to compare like with like. With both set, only ads priced
+    /// strictly between them match, so `price_gt` must not exceed `price_lt`.
+    pub price_lt: Option<Money>,
+    pub price_gt: Option<Money>,
+    /// Inclusive `(min, max)` bounds, given as `min,max` in the query string or as a
+    /// two-element array in JSON. Applies on top of `price_lt` and `price_gt`.
+    #[serde(default, deserialize_with = "deserialize_price_between")]
+    #[schema(value_type = Option<Vec<Money>>, min_items = 2, max_items = 2)]
+    #[param(value_type = Option<String>, example = "100,500")]
+    pub price_between: Option<(Money, Money)>,
+    pub currency_eq: Option<Currency>,
+    /// Exclusive bounds like the price ones; `updated_at_gt` must not be after `updated_at_lt`.
+    pub updated_at_lt: Option<chrono::DateTime<chrono::Utc>>,
+    pub updated_at_gt: Option<chrono::DateTime<chrono::Utc>>,
+    /// Exclusive bounds on when the ad was posted, e.g. `created_at_gt` a week ago for
+    /// this week's ads; `created_at_gt` must not be after `created_at_lt`.
+    pub created_at_lt: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at_gt: Option<chrono::DateTime<chrono::Utc>>,
+    pub category_eq: Option<AdCategory>,
+    /// Center point for `radius_km` and `sort_by=distance_asc`.
+    pub near_lat: Option<f64>,
+    pub near_lon: Option<f64>,
+    /// Only ads within this many kilometres of the center; ads without a location are excluded.
+    pub radius_km: Option<f64>,
+    /// `true` keeps only ads with at least one image, `false` only ads without any.
+    pub has_images: Option<bool>,
+    /// `true` keeps only promoted ads, e.g. for a featured carousel, `false` only the rest.
+    pub top_ad: Option<bool>,
+    /// `true` keeps only ads open to offers, `false` only fixed-price ones.
+    pub negotiable_eq: Option<bool>,
+    /// Only ads written in this language.
+    pub locale_eq: Option<Locale>,
+    /// `true` keeps only ads whose seller verified their phone, `false` only the rest.
+    pub phone_verified_eq: Option<bool>,
+    /// Only ads posted by exactly this email or phone, as stored. These reveal who posted
+    /// what, so handlers only accept them from admins, see `needs_admin`.
+    pub user_email_eq: Option<String>,
+    pub user_phone_eq: Option<String>,
+    /// Also return ads whose `expires_at` has passed; they are hidden by default.
+    #[serde(default)]
+    pub include_expired: bool,
+    /// Also return soft-deleted ads. Never read from the query string, so only code
+    /// acting for an admin can set it.
+    #[serde(skip)]
+    pub include_deleted: bool,
+    /// Only ads with this status are returned; `None` means active ads only.
+    pub status_eq: Option<AdStatus>,
+    pub sort_by: Option<AdSort>,
 }
 
-#[async_trait]
-pub trait AdRepo: Send + Sync {
-    async fn new_cursor(&self, filter: AdFilter) -> Result<String, Error>;
-    async fn fetch_from_cursor(&self, cursor_name: String, count: u8) -> Result<Vec<Ad>, Error>;
-    async fn get_by_id(&self, id: i32) -> Result<Option<Ad>, Error>;
-    async fn get_page(&self, page: u32, per_page: u32, filter: AdFilter) -> Result<Vec<Ad>, Error>;
-    async fn create(&self, ad: AdContent, image_ids: Vec<String>) -> Result<Ad, Error>;
-    async fn update(&self, id: i32, ad: Ad) -> Result<Ad, Error>;
-    async fn delete(&self, id: i32) -> Result<usize, Error>;
-}
+impl AdFilter {
+    pub(crate) fn center(&self) -> Option<(f64, f64)> {
+        self.near_lat.zip(self.near_lon)
+    }
 
-#[derive(Clone)]
-pub struct PostgresAdRepo {
-    pub db_manager: DbManager,
-}
+    /// Whether the filter looks ads up by who posted them, which only admins may do.
+    pub fn needs_admin(&self) -> bool {
+        self.user_email_eq.is_some() || self.user_phone_eq.is_some()
+    }
 
-impl PostgresAdRepo {
-    pub fn new(db_manager: DbManager) -> Arc<PostgresAdRepo> {
-        Arc::new(PostgresAdRepo { db_manager })
+    /// Tells filters apart for `check_cursor_filter`. Values are compared as given, so
+    /// `100` and `100.00` make different filters.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        serde_json::to_string(self)
+            .expect("AdFilter serializes to JSON")
+            .hash(&mut hasher);
+        // Skipped when serializing.
+        self.include_deleted.hash(&mut hasher);
+        hasher.finish()
     }
-}
 
-#[async_trait]
-impl AdRepo for PostgresAdRepo {
-    async fn new_cursor(&self, filter: AdFilter) -> Result<String, Error> {
-        let mut query = ads::table.into_boxed();
+    /// The predicates of `filtered_query`, checked against an ad in memory. A search
+    /// matches its words as a substring of the title or description instead of going
+    /// through the full-text index.
+    pub fn matches(&self, ad: &Ad) -> bool {
+        let now = chrono::Utc::now();
+        let below = |bound: &Option<Money>| bound.as_ref().is_none_or(|b| ad.price < *b);
+        let above = |bound: &Option<Money>| bound.as_ref().is_none_or(|b| ad.price > *b);
+        let contains =
+            |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
 
-        if let Some(ref title_contains) = filter.title_contains {
-            query = query.filter(ads::title.ilike(format!("%{}%", title_contains)));
+        let text_matches = match self.search {
+            Some(ref search) => contains(&ad.title, search) || contains(&ad.description, search),
+            None => {
+                self.title_contains
+                    .as_ref()
+                    .is_none_or(|title| contains(&ad.title, title))
+                    && self
+                        .description_contains
+                        .as_ref()
+                        .is_none_or(|description| contains(&ad.description, description))
+            }
+        };
+
+        let within_radius = match (self.center(), self.radius_km) {
+            (Some(center), Some(radius_km)) => {
+                ad_distance_km(ad, center).is_some_and(|d| d <= radius_km)
+            }
+            _ => true,
+        };
+
+        text_matches
+            && within_radius
+            && below(&self.price_lt)
+            && above(&self.price_gt)
+            && self
+                .price_between
+                .as_ref()
+                .is_none_or(|(min, max)| (min..=max).contains(&&ad.price))
+            && self
+                .currency_eq
+                .is_none_or(|currency| ad.currency == currency.as_str())
+            && self.updated_at_lt.is_none_or(|lt| ad.updated_at < lt)
+            && self.updated_at_gt.is_none_or(|gt| ad.updated_at > gt)
+            && self.created_at_lt.is_none_or(|lt| ad.created_at < lt)
+            && self.created_at_gt.is_none_or(|gt| ad.created_at > gt)
+            && self
+                .category_eq
+                .is_none_or(|category| ad.category == category.as_str())
+            && self
+                .has_images
+                .is_none_or(|has_images| ad.images.is_empty() != has_images)
+            && self.top_ad.is_none_or(|top_ad| ad.top_ad == top_ad)
+            && self
+                .negotiable_eq
+                .is_none_or(|negotiable| ad.negotiable == negotiable)
+            && self
+                .locale_eq
+                .is_none_or(|locale| ad.locale == locale.as_str())
+            && self
+                .phone_verified_eq
+                .is_none_or(|verified| ad.phone_verified == verified)
+            && self
+                .user_email_eq
+                .as_ref()
+                .is_none_or(|email| ad.user_email == *email)
+            && self
+                .user_phone_eq
+                .as_ref()
+                .is_none_or(|phone| ad.user_phone == *phone)
+            && (self.include_expired || ad.expires_at.is_none_or(|expires_at| expires_at >= now))
+            && (self.include_deleted || ad.deleted_at.is_none())
+            && ad.status == self.status_eq.unwrap_or_default().as_str()
+    }
+
+    /// Checks the location parameters, which can't be expressed in the query string types.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors: Vec<_> = location_errors(self.near_lat, self.near_lon)
+            .into_iter()
+            .map(|(field, message)| FieldError {
+                field: if field == "latitude" {
+                    "near_lat"
+                } else {
+                    "near_lon"
+                },
+                message: message.to_string(),
+            })
+            .collect();
+        let mut reject = |field, message: &str| {
+            errors.push(FieldError {
+                field,
+                message: message.to_string(),
+            })
+        };
+
+        if let Some(radius_km) = self.radius_km {
+            if !(radius_km > 0.0 && radius_km.is_finite()) {
+                reject("radius_km", "must be a positive number");
+            }
+            if self.center().is_none() {
+                reject("radius_km", "needs near_lat and near_lon");
+            }
         }
 
-        if let Some(ref description_contains) = filter.description_contains {
-            query = query.filter(ads::description.ilike(format!("%{}%", description_contains)));
+        if self.sort_by == Some(AdSort::DistanceAsc) && self.center().is_none() {
+            reject("sort_by", "distance_asc needs near_lat and near_lon");
         }
 
-        if let Some(ref filter_price_lt) = filter.price_lt {
-            query = query.filter(ads::price.lt(filter_price_lt));
+        if matches!((&self.price_gt, &self.price_lt), (Some(gt), Some(lt)) if gt > lt) {
+            reject("price_gt", "must not be greater than price_lt");
         }
 
-        if let Some(ref filter_price_gt) = filter.price_gt {
-            query = query.filter(ads::price.gt(filter_price_gt));
+        if matches!(self.price_between, Some((ref min, ref max)) if min > max) {
+            reject("price_between", "min must not be greater than max");
         }
 
-        if let Some(ref updated_at_lt) = filter.updated_at_lt {
-            query = query.filter(ads::updated_at.lt(updated_at_lt));
+        if matches!((self.updated_at_gt, self.updated_at_lt), (Some(gt), Some(lt)) if gt > lt) {
+            reject("updated_at_gt", "must not be later than updated_at_lt");
         }
 
-        if let Some(ref updated_at_gt) = filter.updated_at_gt {
-            query = query.filter(ads::updated_at.gt(updated_at_gt));
+        if matches!((self.created_at_gt, self.created_at_lt), (Some(gt), Some(lt)) if gt > lt) {
+            reject("created_at_gt", "must not be later than created_at_lt");
         }
 
-        let conn = &mut self
-            .db_manager
-            .get_write_pool()
-            .get()
-            .map_err(|e| Error::msg(e.to_string()))?;
+        if self.status_eq == Some(AdStatus::Hidden) {
+            reject("status_eq", "hidden ads are not listed");
+        }
 
-        let cursor_name = format!(
-            "c_{}",
-            uuid::Uuid::new_v4().to_string().replace("-", "")[..10].to_string()
-        );
+        if self.status_eq == Some(AdStatus::Draft) {
+            reject("status_eq", "drafts are not listed");
+        }
 
-        let cursor_query_str = format!(
-            "DECLARE {} CURSOR WITH HOLD FOR {}",
-            cursor_name,
-            debug_query(&query).to_string()
-        );
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
 
-        println!("{}", cursor_query_str);
+/// Builds an [`AdFilter`] for code that assembles one itself rather than reading it
+/// from a request, checking it the same way a request's filter is checked.
+///
+/// ```
+/// use bazaars::models::ad::{AdCategory, Currency};
+/// use bazaars::repos::ad_repo::{AdFilter, AdSort};
+///
+/// let filter = AdFilter::builder()
+///     .title_contains("bike")
+///     .price_between(100, 500)
+///     .currency(Currency::Eur)
+///     .category(AdCategory::Vehicles)
+///     .sort_by(AdSort::PriceAsc)
+///     .build()
+///     .unwrap();
+/// assert_eq!(filter.title_contains.as_deref(), Some("bike"));
+/// ```
+///
+/// Combinations no ad could match, or where one setting would silently override
+/// another, are rejected by `build`:
+///
+/// ```
+/// use bazaars::repos::ad_repo::{AdFilter, AdSort};
+///
+/// let errors = AdFilter::builder()
+///     .price_between(500, 100)
+///     .sort_by(AdSort::DistanceAsc)
+///     .build()
+///     .err()
+///     .unwrap();
+/// let fields: Vec<_> = errors.iter().map(|e| e.field).collect();
+/// assert_eq!(fields, ["sort_by", "price_between"]);
+///
+/// assert!(AdFilter::builder().search("bike").title_contains("lock").build().is_err());
+/// ```
+#[derive(Default)]
+pub struct AdFilterBuilder {
+    filter: AdFilter,
+}
 
-        let mut cursor_query = sql_query(cursor_query_str).into_boxed::<Pg>();
+impl AdFilter {
+    pub fn builder() -> AdFilterBuilder {
+        AdFilterBuilder::default()
+    }
+}
 
-        if let Some(ref title_contains) = filter.title_contains {
-            cursor_query =
-                cursor_query.bind::<diesel::sql_types::Text, _>(format!("%{}%", title_contains));
-        }
+impl AdFilterBuilder {
+    pub fn search(mut self, text: impl Into<String>) -> Self {
+        self.filter.search = Some(text.into());
+        self
+    }
 
-        if let Some(ref description_contains) = filter.description_contains {
-            cursor_query = cursor_query
-                .bind::<diesel::sql_types::Text, _>(format!("%{}%", description_contains));
-        }
+    pub fn title_contains(mut self, text: impl Into<String>) -> Self {
+        self.filter.title_contains = Some(text.into());
+        self
+    }
 
-        if let Some(ref filter_price_lt) = filter.price_lt {
-            cursor_query = cursor_query.bind::<diesel::sql_types::Numeric, _>(filter_price_lt);
-        }
+    pub fn description_contains(mut self, text: impl Into<String>) -> Self {
+        self.filter.description_contains = Some(text.into());
+        self
+    }
 
-        if let Some(ref filter_price_gt) = filter.price_gt {
-            cursor_query = cursor_query.bind::<diesel::sql_types::Numeric, _>(filter_price_gt);
-        }
+    /// Ads priced strictly below `price`.
+    pub fn price_below(mut self, price: impl Into<Money>) -> Self {
+        self.filter.price_lt = Some(price.into());
+        self
+    }
 
-        if let Some(ref updated_at_lt) = filter.updated_at_lt {
-            cursor_query = cursor_query.bind::<diesel::sql_types::Timestamp, _>(updated_at_lt);
-        }
+    /// Ads priced strictly above `price`.
+    pub fn price_above(mut self, price: impl Into<Money>) -> Self {
+        self.filter.price_gt = Some(price.into());
+        self
+    }
 
-        if let Some(ref updated_at_gt) = filter.updated_at_gt {
-            cursor_query = cursor_query.bind::<diesel::sql_types::Timestamp, _>(updated_at_gt);
-        }
+    /// Ads priced from `min` to `max`, both included.
+    pub fn price_between(mut self, min: impl Into<Money>, max: impl Into<Money>) -> Self {
+        self.filter.price_between = Some((min.into(), max.into()));
+        self
+    }
 
-        println!("{}", debug_query(&cursor_query).to_string());
+    pub fn currency(mut self, currency: Currency) -> Self {
+        self.filter.currency_eq = Some(currency);
+        self
+    }
 
-        cursor_query.execute(conn).map_err(Error::from)?;
+    pub fn category(mut self, category: AdCategory) -> Self {
+        self.filter.category_eq = Some(category);
+        self
+    }
 
-        Ok(cursor_name)
+    pub fn created_after(mut self, at: chrono::DateTime<chrono::Utc>) -> Self {
+        self.filter.created_at_gt = Some(at);
+        self
     }
 
-    async fn fetch_from_cursor(&self, cursor_name: String, count: u8) -> Result<Vec<Ad>, Error> {
-        let query = format!("FETCH FORWARD {} FROM {}", count, cursor_name);
-        let conn = &mut self
-            .db_manager
-            .get_read_pool()
-            .get()
-            .map_err(|e| Error::msg(e.to_string()))?;
-        sql_query(query).load::<Ad>(conn).map_err(Error::from)
+    pub fn created_before(mut self, at: chrono::DateTime<chrono::Utc>) -> Self {
+        self.filter.created_at_lt = Some(at);
+        self
     }
 
-    async fn get_by_id(&self, id: i32) -> Result<Option<Ad>, Error> {
-        ads::table
-            .find(id)
-            .first::<Ad>(
-                &mut self
-                    .db_manager
-                    .get_read_pool()
-                    .get()
-                    .map_err(|e| Error::msg(e.to_string()))?,
-            )
-            .optional()
-            .map_err(Error::from)
+    pub fn updated_after(mut self, at: chrono::DateTime<chrono::Utc>) -> Self {
+        self.filter.updated_at_gt = Some(at);
+        self
     }
 
-    async fn get_page(
-        &self,
-        offset: u32,
-        per_page: u32,
-        filter: AdFilter,
-    ) -> Result<Vec<Ad>, Error> {
-        let mut query = ads::table.into_boxed();
+    pub fn updated_before(mut self, at: chrono::DateTime<chrono::Utc>) -> Self {
+        self.filter.updated_at_lt = Some(at);
+        self
+    }
 
-        if let Some(ref title_contains) = filter.title_contains {
-            query = query.filter(ads::title.ilike(format!("%{}%", title_contains)));
+    /// Center point for `within_km` and `AdSort::DistanceAsc`.
+    pub fn near(mut self, lat: f64, lon: f64) -> Self {
+        self.filter.near_lat = Some(lat);
+        self.filter.near_lon = Some(lon);
+        self
+    }
+
+    pub fn within_km(mut self, radius_km: f64) -> Self {
+        self.filter.radius_km = Some(radius_km);
+        self
+    }
+
+    /// Only ads with images, or with `false` only ads without.
+    pub fn has_images(mut self, has_images: bool) -> Self {
+        self.filter.has_images = Some(has_images);
+        self
+    }
+
+    /// Only promoted ads, or with `false` only ads that aren't.
+    pub fn top_ad(mut self, top_ad: bool) -> Self {
+        self.filter.top_ad = Some(top_ad);
+        self
+    }
+
+    /// Only ads open to offers, or with `false` only fixed-price ones.
+    pub fn negotiable(mut self, negotiable: bool) -> Self {
+        self.filter.negotiable_eq = Some(negotiable);
+        self
+    }
+
+    /// Only ads written in `locale`.
+    pub fn locale(mut self, locale: Locale) -> Self {
+        self.filter.locale_eq = Some(locale);
+        self
+    }
+
+    /// Only ads with a verified phone, or with `false` only ads without one.
+    pub fn phone_verified(mut self, verified: bool) -> Self {
+        self.filter.phone_verified_eq = Some(verified);
+        self
+    }
+
+    /// Only ads posted by exactly `email`.
+    pub fn user_email(mut self, email: impl Into<String>) -> Self {
+        self.filter.user_email_eq = Some(email.into());
+        self
+    }
+
+    /// Only ads posted with exactly `phone`.
+    pub fn user_phone(mut self, phone: impl Into<String>) -> Self {
+        self.filter.user_phone_eq = Some(phone.into());
+        self
+    }
+
+    pub fn include_expired(mut self) -> Self {
+        self.filter.include_expired = true;
+        self
+    }
+
+    pub fn include_deleted(mut self) -> Self {
+        self.filter.include_deleted = true;
+        self
+    }
+
+    pub fn status(mut self, status: AdStatus) -> Self {
+        self.filter.status_eq = Some(status);
+        self
+    }
+
+    pub fn sort_by(mut self, sort: AdSort) -> Self {
+        self.filter.sort_by = Some(sort);
+        self
+    }
+
+    /// The filter, if `AdFilter::validate` accepts it and no text filter would be
+    /// ignored in favour of `search`.
+    pub fn build(self) -> Result<AdFilter, Vec<FieldError>> {
+        let filter = self.filter;
+        let mut errors = filter.validate().err().unwrap_or_default();
+
+        if filter.search.is_some() {
+            if filter.title_contains.is_some() {
+                errors.push(FieldError {
+                    field: "title_contains",
+                    message: "is ignored when search is set".to_string(),
+                });
+            }
+            if filter.description_contains.is_some() {
+                errors.push(FieldError {
+                    field: "description_contains",
+                    message: "is ignored when search is set".to_string(),
+                });
+            }
         }
 
-        if let Some(ref description_contains) = filter.description_contains {
-            query = query.filter(ads::description.ilike(format!("%{}%", description_contains)));
+        if errors.is_empty() {
+            Ok(filter)
+        } else {
+            Err(errors)
         }
+    }
+}
 
-        if let Some(ref filter_price_lt) = filter.price_lt {
-            query = query.filter(ads::price.lt(filter_price_lt));
+/// Reads `price_between` from a `min,max` string or a `[min, max]` sequence.
+fn deserialize_price_between<'de, D>(deserializer: D) -> Result<Option<(Money, Money)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct PriceBetween;
+
+    impl<'de> serde::de::Visitor<'de> for PriceBetween {
+        type Value = Option<(Money, Money)>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("two prices as `min,max` or `[min, max]`")
         }
 
-        if let Some(ref filter_price_gt) = filter.price_gt {
-            query = query.filter(ads::price.gt(filter_price_gt));
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            let parse = |price: &str| price.parse::<Money>().ok();
+            value
+                .split_once(',')
+                .and_then(|(min, max)| Some((parse(min)?, parse(max)?)))
+                .map(Some)
+                .ok_or_else(|| E::invalid_value(serde::de::Unexpected::Str(value), &self))
         }
 
-        if let Some(ref updated_at_lt) = filter.updated_at_lt {
-            query = query.filter(ads::updated_at.lt(updated_at_lt));
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> Result<Self::Value, A::Error> {
+            let min = seq
+                .next_element()?
+                .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+            let max = seq
+                .next_element()?
+                .ok_or_else(|| serde::de::Error::invalid_length(1, &self))?;
+            Ok(Some((min, max)))
         }
 
-        if let Some(ref updated_at_gt) = filter.updated_at_gt {
-            query = query.filter(ads::updated_at.gt(updated_at_gt));
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
         }
 
-        query = query.offset(offset.into()).limit(per_page.into());
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
 
-        let conn = &mut self
-            .db_manager
-            .get_read_pool()
-            .get()
-            .map_err(|e| Error::msg(e.to_string()))?;
-        let res = query.load::<Ad>(conn).map_err(Error::from)?;
-
-        Ok(res)
-    }
-
-    async fn create(&self, ad: AdContent, image_ids: Vec<String>) -> Result<Ad, Error> {
-        diesel::insert_into(ads::table)
-            .values((
-                ads::title.eq(ad.title),
-                ads::description.eq(ad.description),
-                ads::price.eq(BigDecimal::from_f64(ad.price).unwrap()),
-                ads::status.eq("active"),
-                ads::user_email.eq(ad.user_email),
-                ads::user_phone.eq(ad.user_phone),
-                ads::top_ad.eq(ad.top_ad),
-                ads::images.eq(serde_json::to_value(image_ids).map_err(Error::from)?),
-                ads::created_at.eq(chrono::Utc::now().naive_utc()),
-                ads::updated_at.eq(chrono::Utc::now().naive_utc()),
-            ))
-            .get_result::<Ad>(
-                &mut self
-                    .db_manager
-                    .get_write_pool()
-                    .get()
-                    .map_err(|e| Error::msg(e.to_string()))?,
-            )
-            .map_err(Error::from)
-    }
-
-    async fn update(&self, id: i32, ad: Ad) -> Result<Ad, Error> {
-        diesel::update(ads::table.find(id))
-            .set(&ad)
-            .get_result::<Ad>(
-                &mut self
-                    .db_manager
-                    .get_write_pool()
-                    .get()
-                    .map_err(|e| Error::msg(e.to_string()))?,
-            )
-            .map_err(Error::from)
+        fn visit_some<D: serde::Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
     }
 
-    async fn delete(&self, id: i32) -> Result<usize, Error> {
-        diesel::delete(ads::table.find(id))
-            .execute(
-                &mut self
-                    .db_manager
-                    .get_write_pool()
-                    .get()
-                    .map_err(|e| Error::msg(e.to_string()))?,
-            )
-            .map_err(Error::from)
-    }
+    deserializer.deserialize_any(PriceBetween)
 }
 
-#[cfg(test)]
-mod test {
-    use crate::{
-        models::ad::AdContent,
-        repos::ad_repo::{AdFilter, AdRepo, PostgresAdRepo},
-    };
-    use std::env;
+/// Same formula as the SQL `distance_km`, for one ad in memory; `None` without a location.
+pub(crate) fn ad_distance_km(ad: &Ad, (lat, lon): (f64, f64)) -> Option<f64> {
+    let (ad_lat, ad_lon) = ad.latitude.zip(ad.longitude)?;
+    let a = ((ad_lat - lat).to_radians() / 2.0).sin().powi(2)
+        + lat.to_radians().cos()
+            * ad_lat.to_radians().cos()
+            * ((ad_lon - lon).to_radians() / 2.0).sin().powi(2);
 
-    #[tokio::test]
-    async fn test_ad_repo() {
-        let db_manager = crate::db::DbManager::new(
-            env::var("DATABASE_URL")
-                .expect("DATABASE_URL must be set")
-                .as_str(),
-        );
-
-        let ad_repo = PostgresAdRepo::new(db_manager);
-
-        for i in 0..10 {
-            let ad = AdContent {
-                title: "Test Ad".to_string(),
-                description: "Test Description".to_string(),
-                price: 100.into(),
-                user_email: "test@test.com".to_string(),
-                user_phone: "1234567890".to_string(),
-                top_ad: false,
-            };
+    Some(EARTH_RADIUS_KM * 2.0 * a.sqrt().min(1.0).asin())
+}
 
-            ad_repo
-                .create(ad, vec![])
-                .await
-                .expect("Failed to create ad");
-        }
+/// Haversine great-circle distance in km from `(lat, lon)` to each ad's location,
+/// `NULL` for ads without one. Binds `lat` twice, then `lon`.
+fn distance_km(
+    (lat, lon): (f64, f64),
+) -> Box<dyn BoxableExpression<ads::table, Pg, SqlType = Nullable<Double>>> {
+    Box::new(
+        sql::<Nullable<Double>>(&format!(
+            "{} * 2 * asin(least(1, sqrt(power(sin(radians(latitude - ",
+            EARTH_RADIUS_KM
+        ))
+        .bind::<Double, _>(lat)
+        .sql(") / 2), 2) + cos(radians(")
+        .bind::<Double, _>(lat)
+        .sql(")) * cos(radians(latitude)) * power(sin(radians(longitude - ")
+        .bind::<Double, _>(lon)
+        .sql(") / 2), 2))))"),
+    )
+}
 
-        let cursor_name = ad_repo
-            .new_cursor(AdFilter {
-                title_contains: Some("test".to_string()),
-                description_contains: None,
-                price_lt: None,
-                price_gt: None,
-                updated_at_lt: None,
-                updated_at_gt: None,
-            })
-            .await
-            .expect("Failed to get cursor");
+/// Applies every `AdFilter` predicate, without ordering, so the same rows can be
+/// paged, counted or declared as a cursor.
+fn filtered_query(filter: &AdFilter) -> ads::BoxedQuery<'_, Pg> {
+    let mut query = ads::table.into_boxed();
 
-        let ads = ad_repo.fetch_from_cursor(cursor_name, 2).await;
+    if let Some(ref search) = filter.search {
+        query = query.filter(
+            sql::<Bool>("search_vector @@ plainto_tsquery('english', ")
+                .bind::<Text, _>(search.clone())
+                .sql(")"),
+        );
+    } else {
+        if let Some(ref title_contains) = filter.title_contains {
+            query = query.filter(ads::title.ilike(format!("%{}%", title_contains)));
+        }
 
-        if ads.is_err() {
-            println!("{:?}", ads);
+        if let Some(ref description_contains) = filter.description_contains {
+            query = query.filter(ads::description.ilike(format!("%{}%", description_contains)));
         }
+    }
+
+    if let Some(ref filter_price_lt) = filter.price_lt {
+        query = query.filter(ads::price.lt(filter_price_lt));
+    }
+
+    if let Some(ref filter_price_gt) = filter.price_gt {
+        query = query.filter(ads::price.gt(filter_price_gt));
+    }
+
+    if let Some((ref min, ref max)) = filter.price_between {
+        query = query.filter(ads::price.between(min, max));
+    }
 
-        assert_eq!(ads.is_ok(), true);
+    if let Some(currency_eq) = filter.currency_eq {
+        query = query.filter(ads::currency.eq(currency_eq.as_str()));
+    }
 
-        println!("{:?}", ads);
+    if let Some(ref updated_at_lt) = filter.updated_at_lt {
+        query = query.filter(ads::updated_at.lt(updated_at_lt));
+    }
+
+    if let Some(ref updated_at_gt) = filter.updated_at_gt {
+        query = query.filter(ads::updated_at.gt(updated_at_gt));
+    }
+
+    if let Some(ref created_at_lt) = filter.created_at_lt {
+        query = query.filter(ads::created_at.lt(created_at_lt));
+    }
+
+    if let Some(ref created_at_gt) = filter.created_at_gt {
+        query = query.filter(ads::created_at.gt(created_at_gt));
+    }
+
+    if let Some(category_eq) = filter.category_eq {
+        query = query.filter(ads::category.eq(category_eq.as_str()));
+    }
+
+    if let (Some(center), Some(radius_km)) = (filter.center(), filter.radius_km) {
+        query = query.filter(distance_km(center).le(radius_km));
+    }
+
+    // Literal SQL without binds, so `new_cursor` has nothing to bind for it.
+    if let Some(has_images) = filter.has_images {
+        query = query.filter(sql::<Bool>(if has_images {
+            "jsonb_array_length(images) > 0"
+        } else {
+            "jsonb_array_length(images) = 0"
+        }));
+    }
+
+    if let Some(top_ad) = filter.top_ad {
+        query = query.filter(ads::top_ad.eq(top_ad));
+    }
+
+    if let Some(negotiable) = filter.negotiable_eq {
+        query = query.filter(ads::negotiable.eq(negotiable));
+    }
+
+    if let Some(locale_eq) = filter.locale_eq {
+        query = query.filter(ads::locale.eq(locale_eq.as_str()));
+    }
+
+    if let Some(verified) = filter.phone_verified_eq {
+        query = query.filter(ads::phone_verified.eq(verified));
+    }
+
+    if let Some(ref user_email) = filter.user_email_eq {
+        query = query.filter(ads::user_email.eq(user_email));
+    }
+
+    if let Some(ref user_phone) = filter.user_phone_eq {
+        query = query.filter(ads::user_phone.eq(user_phone));
+    }
+
+    if !filter.include_expired {
+        query = query.filter(
+            ads::expires_at
+                .is_null()
+                .or(ads::expires_at.ge(chrono::Utc::now())),
+        );
+    }
+
+    if !filter.include_deleted {
+        query = query.filter(ads::deleted_at.is_null());
+    }
+
+    query.filter(ads::status.eq(filter.status_eq.unwrap_or_default().as_str()))
+}
+
+/// Whether the ad's promotion is running. A promotion past its `top_ad_until` stops
+/// counting right away rather than once `end_promotions` clears it; ads promoted before
+/// promotions had an end date have none and stay promoted.
+fn promotion_running() -> Box<dyn BoxableExpression<ads::table, Pg, SqlType = Bool>> {
+    Box::new(
+        ads::top_ad
+            .and(
+                ads::top_ad_until
+                    .is_null()
+                    .or(ads::top_ad_until.gt(diesel::dsl::now)),
+            )
+            .assume_not_null(),
+    )
+}
+
+/// Orders ads with a running promotion first, then as `sort_within`.
+fn apply_sort<'a>(query: ads::BoxedQuery<'a, Pg>, filter: &AdFilter) -> ads::BoxedQuery<'a, Pg> {
+    sort_within(query.order(promotion_running().desc()), filter)
+}
+
+/// Orders by search rank when searching, then by the requested sort. `DistanceAsc`
+/// without a center falls back to the default. Without a search, the default and price
+/// sorts match an index on `(created_at DESC, id DESC)` or `(price, id DESC)` column for
+/// column, so keep the two in step.
+fn sort_within<'a>(
+    mut query: ads::BoxedQuery<'a, Pg>,
+    filter: &AdFilter,
+) -> ads::BoxedQuery<'a, Pg> {
+    if let Some(ref search) = filter.search {
+        query = query.then_order_by(
+            sql::<Float>("ts_rank(search_vector, plainto_tsquery('english', ")
+                .bind::<Text, _>(search.clone())
+                .sql("))")
+                .desc(),
+        );
+    }
+    let query = match filter.sort_by.unwrap_or_default() {
+        AdSort::PriceAsc => query.then_order_by(ads::price.asc()),
+        AdSort::PriceDesc => query.then_order_by(ads::price.desc()),
+        AdSort::CreatedAtAsc => query.then_order_by(ads::created_at.asc()),
+        AdSort::CreatedAtDesc => query.then_order_by(ads::created_at.desc()),
+        AdSort::UpdatedAtDesc => query.then_order_by(ads::updated_at.desc()),
+        AdSort::DistanceAsc => match filter.center() {
+            Some(center) => query.then_order_by(distance_km(center).asc()),
+            None => query.then_order_by(ads::created_at.desc()),
+        },
+    };
+    // Ads inserted together share `created_at`, and often a price; without a last key
+    // their order could change between pages, repeating some ads and skipping others.
+    query.then_order_by(ads::id.desc())
+}
+
+/// Position of the last ad seen in `(created_at, id)` order, used for keyset pagination.
+#[derive(serde::Deserialize, serde::Serialize, utoipa::ToSchema, Clone, Copy, Debug, PartialEq)]
+pub struct AdKeyset {
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub id: AdId,
+}
+
+impl From<&Ad> for AdKeyset {
+    fn from(ad: &Ad) -> Self {
+        AdKeyset {
+            created_at: ad.created_at,
+            id: ad.id,
+        }
+    }
+}
+
+/// The raw `DECLARE` statement of `new_cursor`, counting binds as they are added, so the
+/// count logged with it is the number of placeholders actually filled.
+struct CursorQuery<'a> {
+    query: BoxedSqlQuery<'a, Pg, SqlQuery>,
+    binds: usize,
+}
+
+impl<'a> CursorQuery<'a> {
+    fn new(sql: String) -> Self {
+        CursorQuery {
+            query: sql_query(sql).into_boxed(),
+            binds: 0,
+        }
+    }
+
+    fn bind<T, U>(mut self, value: U) -> Self
+    where
+        Pg: HasSqlType<T>,
+        U: ToSql<T, Pg> + Send + 'a,
+        T: Send + 'a,
+    {
+        self.query = self.query.bind::<T, U>(value);
+        self.binds += 1;
+        self
+    }
+}
+
+/// Re-binds the center the same way `distance_km` does, for the raw cursor query.
+fn bind_distance(query: CursorQuery<'_>, (lat, lon): (f64, f64)) -> CursorQuery<'_> {
+    query
+        .bind::<Double, _>(lat)
+        .bind::<Double, _>(lat)
+        .bind::<Double, _>(lon)
+}
+
+#[async_trait]
+pub trait AdRepo: Send + Sync {
+    async fn new_cursor(&self, filter: AdFilter) -> Result<String, RepoError>;
+    /// Fetches up to `count` rows, clamped to `1..=MAX_CURSOR_FETCH`; with `auto_close` the cursor is closed once fewer
+    /// rows than requested come back, i.e. the result set is exhausted. `CursorExpired`
+    /// if the cursor isn't open, so the client knows to start over. A `filter` is checked
+    /// against the one the cursor was declared with, see `check_cursor_filter`.
+    async fn fetch_from_cursor(
+        &self,
+        cursor_name: String,
+        count: u32,
+        auto_close: bool,
+        filter: Option<&AdFilter>,
+    ) -> Result<CursorPage<Ad>, RepoError>;
+    /// Closes a cursor opened by `new_cursor`. One already closed or expired is fine, as
+    /// there is nothing left to do.
+    async fn close_cursor(&self, cursor_name: String) -> Result<(), RepoError>;
+    /// With `increment`, also counts a view, atomically in the same statement. Soft-deleted
+    /// ads are not found.
+    async fn get_by_id(&self, id: AdId, increment: bool) -> Result<Option<Ad>, RepoError>;
+    /// The live ad whose slug is, or was before its title changed, `slug`. Doesn't count
+    /// a view.
+    async fn get_by_slug(&self, slug: &str) -> Result<Option<Ad>, RepoError>;
+    /// Prices the ad was changed to by `patch`, oldest first.
+    async fn price_history(&self, id: AdId) -> Result<Vec<PriceChange>, RepoError>;
+    /// Whether a live (not soft-deleted) ad with this id exists, without loading the row.
+    async fn exists(&self, id: AdId) -> Result<bool, RepoError>;
+    /// The newest live ad of `user_email` created since `since` with the same price and,
+    /// ignoring case and surrounding whitespace, the same title; a likely repost.
+    async fn find_similar(
+        &self,
+        user_email: &str,
+        title: &str,
+        price: &Money,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Ads for `ids` in the order requested, in one query. Missing and soft-deleted ids are
+    /// skipped, as are repeats of an id already returned.
+    async fn get_by_ids(&self, ids: &[AdId]) -> Result<Vec<Ad>, RepoError>;
+    async fn get_page(
+        &self,
+        page: u32,
+        per_page: u32,
+        filter: AdFilter,
+    ) -> Result<Vec<Ad>, RepoError>;
+    /// Newest-first page of ads strictly after `after`, seeking on `(created_at, id)`
+    /// instead of using `OFFSET`. `sort_by` is ignored on this path.
+    async fn get_page_after(
+        &self,
+        after: Option<AdKeyset>,
+        per_page: u32,
+        filter: AdFilter,
+    ) -> Result<Vec<Ad>, RepoError>;
+    async fn count(&self, filter: AdFilter) -> Result<u64, RepoError>;
+    /// A page of `user_email`'s ads, newest first, and how many there are in all. Only
+    /// active, unexpired ads unless `include_unlisted`, which adds drafts, expired, sold
+    /// and hidden ones for the owner's own view. Soft-deleted ads are never included.
+    async fn get_by_user(
+        &self,
+        user_email: &str,
+        include_unlisted: bool,
+        offset: u32,
+        per_page: u32,
+    ) -> Result<(Vec<Ad>, u64), RepoError>;
+    /// Category and price range counts over the ads `filter` matches, see `AdFacets`.
+    async fn facets(&self, filter: AdFilter) -> Result<AdFacets, RepoError>;
+    /// Aggregates for the admin dashboard, see `AdStats`.
+    async fn stats(&self) -> Result<AdStats, RepoError>;
+    async fn create(&self, ad: AdContent, image_ids: Vec<String>) -> Result<Ad, RepoError>;
+    /// Creates each ad with its image ids in one transaction, with a single multi-row
+    /// insert rather than a round trip per ad, e.g. for seeding and imports. All or none
+    /// are created; they come back in the order given.
+    async fn create_many(&self, ads: Vec<(AdContent, Vec<String>)>) -> Result<Vec<Ad>, RepoError>;
+    /// The ad `user_email` created under idempotency `key`, unless the key has expired.
+    async fn get_by_idempotency_key(
+        &self,
+        user_email: &str,
+        key: &str,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Like `create`, but records `key` for the ad's owner in the same transaction. If the
+    /// owner already used `key` and it hasn't expired, nothing is inserted and the ad created
+    /// back then is returned with `false`.
+    async fn create_idempotent(
+        &self,
+        ad: AdContent,
+        image_ids: Vec<String>,
+        key: &str,
+    ) -> Result<(Ad, bool), RepoError>;
+    /// Owner of the ad, unless it doesn't exist or is soft-deleted.
+    async fn get_owner(&self, id: AdId) -> Result<Option<String>, RepoError>;
+    /// Replaces the ad's content with `ad` and bumps `updated_at`, under the same conditions
+    /// as `patch`; `None` means no row matched. The status, promotion and images are left
+    /// as they are, and `ad.user_email` is ignored.
+    async fn update(
+        &self,
+        id: AdId,
+        user_email: &str,
+        ad: AdContent,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Writes only the fields set in `changes` and bumps `updated_at`, if the ad belongs
+    /// to `user_email` and isn't hidden by an admin; `None` means no row matched. A new
+    /// title also renames the slug, and the old one keeps resolving in `get_by_slug`.
+    /// With `expected_updated_at`, only if the ad wasn't changed since that version.
+    async fn patch(
+        &self,
+        id: AdId,
+        user_email: &str,
+        changes: AdPatch,
+        expected_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Stores the ad's images in the order of `image_ids`, which must list each of its
+    /// current images exactly once; the first becomes the primary image. Only if the ad
+    /// belongs to `user_email`; `None` means no row matched.
+    async fn reorder_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_ids: Vec<String>,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Appends `image_ids` to the ad's images if it belongs to `user_email`, unless the ad
+    /// would then hold more than `max_count`; `None` means no row matched.
+    async fn add_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_ids: Vec<String>,
+        max_count: usize,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Replaces the ad's images with `kept`, which may only name its current images, then
+    /// `added`, if it belongs to `user_email` and ends up with at most `max_count`. Returns
+    /// the ad with the images it no longer lists, which are left to the caller; `None`
+    /// means no row matched.
+    async fn set_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        kept: Vec<String>,
+        added: Vec<String>,
+        max_count: usize,
+    ) -> Result<Option<(Ad, Vec<String>)>, RepoError>;
+    /// Drops `image_id` from the ad's images if it belongs to `user_email`; `NotFound` if
+    /// the ad has no such image, `None` if no row matched. The stored image is left to
+    /// the caller.
+    async fn remove_image(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_id: &str,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Soft-deletes the ad only if it belongs to `user_email`, returning the affected row
+    /// count. The row and its images are kept until `purge`.
+    async fn delete(&self, id: AdId, user_email: &str) -> Result<usize, RepoError>;
+    /// Brings back a soft-deleted ad of any owner; `None` if there is no such deleted ad.
+    /// Callers must restrict this to admins.
+    async fn restore(&self, id: AdId) -> Result<Option<Ad>, RepoError>;
+    /// Removes a soft-deleted ad for good and returns the removed row, so the caller can
+    /// delete its images; `None` if there is no such deleted ad. Callers must restrict
+    /// this to admins.
+    async fn purge(&self, id: AdId) -> Result<Option<Ad>, RepoError>;
+    /// Removes every ad of `user_email` for good, live or soft-deleted, in one statement,
+    /// e.g. when the account is closed. Returns the removed ads so their images can be
+    /// discarded.
+    async fn delete_by_user(&self, user_email: &str) -> Result<Vec<Ad>, RepoError>;
+    /// Those of the image `ids` that an ad, soft-deleted or not, still lists, as ads can
+    /// share images.
+    async fn images_in_use(&self, ids: &[String]) -> Result<Vec<String>, RepoError>;
+    /// Sets the status of a live ad of any owner and bumps `updated_at`; `None` if there
+    /// is no such ad. Callers must restrict this to admins, as it can hide or unhide ads.
+    async fn set_status(&self, id: AdId, status: AdStatus) -> Result<Option<Ad>, RepoError>;
+    /// Makes a draft of `user_email` active, restarting `created_at`, `updated_at` and the
+    /// expiry as if it had just been created; `None` if no such draft matched.
+    async fn publish(&self, id: AdId, user_email: &str) -> Result<Option<Ad>, RepoError>;
+    /// Bumps an active ad of `user_email` to the top of the newest ads, restarting
+    /// `created_at`, `updated_at` and the expiry without changing its content; `None` if no
+    /// such ad was posted or renewed more than `RENEW_COOLDOWN_HOURS` ago.
+    async fn renew(&self, id: AdId, user_email: &str) -> Result<Option<Ad>, RepoError>;
+    /// Promotes an active ad of `user_email` until `until`, replacing any earlier end
+    /// date; `None` if no such ad matched.
+    async fn promote(
+        &self,
+        id: AdId,
+        user_email: &str,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Stores `code` as the pending phone verification code of a live ad of `user_email`,
+    /// for its current `user_phone`, replacing any earlier one. Returns when the code
+    /// expires, after `PHONE_CODE_TTL_MINUTES`; `None` if no such ad matched. Sending the
+    /// code is left to the caller.
+    async fn start_phone_verification(
+        &self,
+        id: AdId,
+        user_email: &str,
+        code: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>, RepoError>;
+    /// Marks the phone of a live ad of `user_email` verified if `code` is its pending,
+    /// unexpired code for the current `user_phone`, using the code up. `None` if it isn't;
+    /// after `MAX_PHONE_CODE_ATTEMPTS` wrong codes the pending one is dropped.
+    async fn verify_phone(
+        &self,
+        id: AdId,
+        user_email: &str,
+        code: &str,
+    ) -> Result<Option<Ad>, RepoError>;
+    /// Clears `top_ad` on ads whose `top_ad_until` has passed, returning how many changed.
+    async fn end_promotions(&self) -> Result<usize, RepoError>;
+    /// Flips active ads past their `expires_at` to `expired`, returning how many changed.
+    async fn mark_expired(&self) -> Result<usize, RepoError>;
+    /// Closes `WITH HOLD` cursors not fetched from for longer than `max_age`, returning
+    /// how many were closed.
+    async fn close_stale_cursors(&self, max_age: Duration) -> Result<usize, RepoError>;
+    /// Forgets idempotency keys older than `IDEMPOTENCY_KEY_TTL_HOURS`, returning how many
+    /// were removed.
+    async fn purge_idempotency_keys(&self) -> Result<usize, RepoError>;
+}
+
+#[derive(Clone)]
+pub struct PostgresAdRepo {
+    pub db_manager: DbManager,
+    cursors: CursorRegistry,
+}
+
+/// Cursors `close_cursors` finds open: those named in `$1`, and given a max age in seconds
+/// as `$3`, any older ones that aren't among the registered `$2`.
+const CLOSABLE_CURSORS: &str = "SELECT name FROM pg_cursors \
+     WHERE is_holdable AND (name = ANY($1) \
+     OR (name <> ALL($2) AND creation_time < now() - make_interval(secs => $3)))";
+
+#[derive(QueryableByName)]
+struct OpenCursor {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+impl PostgresAdRepo {
+    pub fn new(db_manager: DbManager) -> Arc<PostgresAdRepo> {
+        PostgresAdRepo::with_max_open_cursors(db_manager, DEFAULT_MAX_OPEN_CURSORS)
+    }
+
+    /// Declaring a cursor beyond `max_open_cursors` closes the oldest open ones.
+    pub fn with_max_open_cursors(
+        db_manager: DbManager,
+        max_open_cursors: usize,
+    ) -> Arc<PostgresAdRepo> {
+        Arc::new(PostgresAdRepo {
+            db_manager,
+            cursors: CursorRegistry::new(max_open_cursors),
+        })
+    }
+
+    /// Closes the cursors in `names` on the cursor connection, `conn` if the caller
+    /// already holds it. With `max_age`, also closes unregistered cursors older than that,
+    /// e.g. ones evicted when closing them failed.
+    fn close_cursors(
+        &self,
+        conn: Option<&mut PgConnection>,
+        names: &[String],
+        max_age: Option<Duration>,
+    ) -> Result<usize, RepoError> {
+        let mut cursor_conn;
+        let conn = match conn {
+            Some(conn) => conn,
+            None => {
+                cursor_conn = self.db_manager.cursor_conn()?;
+                &mut *cursor_conn
+            }
+        };
+
+        let open = sql_query(CLOSABLE_CURSORS)
+            .bind::<Array<Text>, _>(names)
+            .bind::<Array<Text>, _>(self.cursors.names())
+            .bind::<Nullable<Double>, _>(max_age.map(|max_age| max_age.as_secs_f64()))
+            .load::<OpenCursor>(conn)
+            .map_err(RepoError::from)?;
+
+        let mut closed = 0;
+        for cursor in open {
+            if validate_cursor_name(&cursor.name).is_err() {
+                continue;
+            }
+            sql_query(format!("CLOSE {}", cursor.name))
+                .execute(conn)
+                .map_err(RepoError::from)?;
+            self.cursors.remove(&cursor.name);
+            closed += 1;
+        }
+
+        Ok(closed)
+    }
+
+    /// Runs `f` in a single transaction on a primary connection, committing everything it
+    /// wrote through the `AdTx` if it returns `Ok` and rolling it all back if it returns
+    /// `Err`.
+    pub fn with_transaction<T, F>(&self, f: F) -> Result<T, RepoError>
+    where
+        F: FnOnce(&mut AdTx<'_>) -> Result<T, RepoError>,
+    {
+        self.db_manager.transaction(|conn| f(&mut AdTx { conn }))
+    }
+}
+
+/// The writes of `AdRepo` on one connection, handed out by
+/// `PostgresAdRepo::with_transaction` so several of them commit or roll back together.
+/// Other repos join in through `conn`, e.g. with `outbox_repo::enqueue_on`.
+pub struct AdTx<'c> {
+    conn: &'c mut PgConnection,
+}
+
+impl AdTx<'_> {
+    pub fn conn(&mut self) -> &mut PgConnection {
+        self.conn
+    }
+
+    /// Like `AdRepo::create`.
+    pub fn create(&mut self, ad: AdContent, image_ids: Vec<String>) -> Result<Ad, RepoError> {
+        insert_ad(self.conn, ad, image_ids)
+    }
+
+    /// Like `AdRepo::create_many`.
+    pub fn create_many(
+        &mut self,
+        ads: Vec<(AdContent, Vec<String>)>,
+    ) -> Result<Vec<Ad>, RepoError> {
+        if ads.is_empty() {
+            return Ok(Vec::new());
+        }
+        insert_ads(self.conn, ads)
+    }
+
+    /// Like `AdRepo::patch`.
+    pub fn patch(
+        &mut self,
+        id: AdId,
+        user_email: &str,
+        changes: AdPatch,
+        expected_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Option<Ad>, RepoError> {
+        patch_ad(self.conn, id, user_email, changes, expected_updated_at)
+    }
+}
+
+/// Most rows a single multi-row `INSERT` in `insert_ads` holds, keeping its bind
+/// parameters well under Postgres' limit of 65535.
+const INSERT_BATCH_SIZE: usize = 1000;
+
+/// The row a new ad is inserted as, under an `id` drawn from `ads_id_seq` up front, as
+/// the slug ends in it.
+fn new_ad_row(
+    id: AdId,
+    ad: AdContent,
+    image_ids: Vec<String>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Ad {
+    let status = if ad.draft {
+        AdStatus::Draft
+    } else {
+        AdStatus::Active
+    };
+
+    Ad {
+        id,
+        slug: ad_slug(&ad.title, id),
+        title: ad.title,
+        description: ad.description,
+        price: ad.price,
+        status: status.as_str().to_string(),
+        user_email: ad.user_email,
+        user_phone: ad.user_phone,
+        created_at: now,
+        updated_at: now,
+        top_ad: false,
+        images: image_ids,
+        expires_at: Some(now + chrono::Duration::days(AD_LIFETIME_DAYS)),
+        category: ad.category.as_str().to_string(),
+        latitude: ad.latitude,
+        longitude: ad.longitude,
+        view_count: 0,
+        currency: ad.currency.as_str().to_string(),
+        deleted_at: None,
+        top_ad_until: None,
+        negotiable: ad.negotiable,
+        locale: ad.locale.as_str().to_string(),
+        phone_verified: false,
+    }
+}
+
+fn insert_ad(
+    conn: &mut PgConnection,
+    ad: AdContent,
+    image_ids: Vec<String>,
+) -> Result<Ad, RepoError> {
+    let id = diesel::select(sql::<Integer>("nextval('ads_id_seq')::int4"))
+        .get_result::<i32>(conn)
+        .map(AdId)?;
+
+    diesel::insert_into(ads::table)
+        .values(new_ad_row(id, ad, image_ids, chrono::Utc::now()))
+        .get_result::<Ad>(conn)
+        .map_err(RepoError::from)
+}
+
+/// Inserts the ads with one statement per `INSERT_BATCH_SIZE` of them, drawing all
+/// their ids in one more. Returns them in the order given.
+fn insert_ads(
+    conn: &mut PgConnection,
+    ads: Vec<(AdContent, Vec<String>)>,
+) -> Result<Vec<Ad>, RepoError> {
+    let now = chrono::Utc::now();
+    let mut ids = sql_query("SELECT nextval('ads_id_seq')::int4 AS id FROM generate_series(1, $1)")
+        .bind::<BigInt, _>(ads.len() as i64)
+        .load::<IdRow>(conn)?;
+    // Ascending like the ads, so sorting what `RETURNING` gives back by id restores their
+    // order; Postgres doesn't promise it keeps the order of `VALUES`.
+    ids.sort_by_key(|row| row.id);
+    let mut rows = ids
+        .into_iter()
+        .zip(ads)
+        .map(|(IdRow { id }, (ad, image_ids))| new_ad_row(AdId(id), ad, image_ids, now))
+        .peekable();
+
+    let mut inserted = Vec::new();
+    while rows.peek().is_some() {
+        let batch: Vec<Ad> = rows.by_ref().take(INSERT_BATCH_SIZE).collect();
+        let mut ads = diesel::insert_into(ads::table)
+            .values(batch)
+            .get_results::<Ad>(conn)?;
+        ads.sort_by_key(|ad| ad.id.0);
+        inserted.append(&mut ads);
+    }
+
+    Ok(inserted)
+}
+
+/// The columns `patch_ad` and `update_ad` write. `None` fields are skipped by
+/// `AsChangeset`, leaving those columns untouched.
+#[derive(AsChangeset)]
+#[diesel(table_name = ads)]
+struct AdChangeset {
+    title: Option<String>,
+    description: Option<String>,
+    price: Option<Money>,
+    currency: Option<&'static str>,
+    user_phone: Option<String>,
+    negotiable: Option<bool>,
+    locale: Option<&'static str>,
+    phone_verified: Option<bool>,
+    status: Option<&'static str>,
+    category: Option<&'static str>,
+    latitude: Option<Option<f64>>,
+    longitude: Option<Option<f64>>,
+    slug: Option<String>,
+    updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// `AdRepo::patch` on `conn`, recording the old slug and the price change with it.
+fn patch_ad(
+    conn: &mut PgConnection,
+    id: AdId,
+    user_email: &str,
+    changes: AdPatch,
+    expected_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Option<Ad>, RepoError> {
+    let changeset = AdChangeset {
+        slug: changes.title.as_deref().map(|title| ad_slug(title, id)),
+        title: changes.title,
+        description: changes.description,
+        price: changes.price,
+        currency: changes.currency.map(|currency| currency.as_str()),
+        user_phone: changes.user_phone,
+        negotiable: changes.negotiable,
+        locale: changes.locale.map(|locale| locale.as_str()),
+        phone_verified: None,
+        status: changes.status.map(|status| status.as_str()),
+        category: changes.category.map(|category| category.as_str()),
+        latitude: changes.latitude.map(Some),
+        longitude: changes.longitude.map(Some),
+        updated_at: chrono::Utc::now(),
+    };
+
+    write_ad(conn, id, user_email, changeset, expected_updated_at)
+}
+
+/// `AdRepo::update` on `conn`. Unlike a patch, an ad without a location clears it.
+fn update_ad(
+    conn: &mut PgConnection,
+    id: AdId,
+    user_email: &str,
+    ad: AdContent,
+) -> Result<Option<Ad>, RepoError> {
+    let changeset = AdChangeset {
+        slug: Some(ad_slug(&ad.title, id)),
+        title: Some(ad.title),
+        description: Some(ad.description),
+        price: Some(ad.price),
+        currency: Some(ad.currency.as_str()),
+        user_phone: Some(ad.user_phone),
+        negotiable: Some(ad.negotiable),
+        locale: Some(ad.locale.as_str()),
+        phone_verified: None,
+        status: None,
+        category: Some(ad.category.as_str()),
+        latitude: Some(ad.latitude),
+        longitude: Some(ad.longitude),
+        updated_at: chrono::Utc::now(),
+    };
+
+    write_ad(conn, id, user_email, changeset, None)
+}
+
+/// Writes `changeset` if the ad belongs to `user_email` and isn't hidden by an admin,
+/// recording the old slug and the price change with it.
+fn write_ad(
+    conn: &mut PgConnection,
+    id: AdId,
+    user_email: &str,
+    mut changeset: AdChangeset,
+    expected_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<Option<Ad>, RepoError> {
+    // A different number has to be verified again; sending the same one keeps it.
+    changeset.phone_verified = match changeset.user_phone {
+        Some(ref phone) => ads::table
+            .find(id)
+            .select(ads::user_phone)
+            .first::<String>(conn)
+            .optional()?
+            .filter(|old| old != phone)
+            .map(|_| false),
+        None => None,
+    };
+
+    // What the ad had before, to keep the old slug and record price changes.
+    let before =
+        if changeset.slug.is_some() || changeset.price.is_some() || changeset.currency.is_some() {
+            ads::table
+                .find(id)
+                .select((ads::slug, ads::price, ads::currency))
+                .for_update()
+                .first::<(String, Money, String)>(conn)
+                .optional()?
+        } else {
+            None
+        };
+
+    let mut query = diesel::update(ads::table)
+        .filter(ads::id.eq(id))
+        .filter(ads::user_email.eq(user_email))
+        .filter(ads::deleted_at.is_null())
+        .filter(ads::status.ne(AdStatus::Hidden.as_str()))
+        .into_boxed();
+    // Checked in the same statement, so a concurrent update can't slip in between.
+    if let Some(updated_at) = expected_updated_at {
+        query = query.filter(ads::updated_at.eq(updated_at));
+    }
+
+    let ad = query.set(changeset).get_result::<Ad>(conn).optional()?;
+
+    if let (Some(ad), Some((old_slug, old_price, old_currency))) = (&ad, before) {
+        if ad.slug != old_slug {
+            diesel::insert_into(ad_slugs::table)
+                .values((ad_slugs::slug.eq(old_slug), ad_slugs::ad_id.eq(id)))
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+        // A price in another currency is a change too, even if the number isn't.
+        if ad.price != old_price || ad.currency != old_currency {
+            diesel::insert_into(price_history::table)
+                .values((
+                    price_history::ad_id.eq(id),
+                    price_history::price.eq(&ad.price),
+                    price_history::currency.eq(&ad.currency),
+                    price_history::changed_at.eq(ad.updated_at),
+                ))
+                .execute(conn)?;
+        }
+    }
+
+    Ok(ad)
+}
+
+#[derive(QueryableByName)]
+struct IdRow {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+}
+
+/// Checks that `requested` is a reordering of the ad's `current` images.
+pub(crate) fn check_image_order(current: &[String], requested: &[String]) -> Result<(), RepoError> {
+    if let Some(unknown) = requested.iter().find(|id| !current.contains(id)) {
+        return Err(RepoError::Validation(format!(
+            "image {} does not belong to this ad",
+            unknown
+        )));
+    }
+    let mut sorted = requested.to_vec();
+    sorted.sort();
+    sorted.dedup();
+    if sorted.len() != requested.len() || requested.len() != current.len() {
+        return Err(RepoError::Validation(
+            "image order must list each of the ad's images exactly once".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The ad's `current` images followed by `added`, if that's at most `max_count`.
+pub(crate) fn append_images(
+    current: &[String],
+    added: Vec<String>,
+    max_count: usize,
+) -> Result<Vec<String>, RepoError> {
+    let mut images = current.to_vec();
+    images.extend(added);
+
+    if images.len() > max_count {
+        return Err(RepoError::Validation(format!(
+            "too many images: the ad would have {}, at most {} allowed",
+            images.len(),
+            max_count
+        )));
+    }
+    Ok(images)
+}
+
+/// The ad's images after keeping `kept` of its `current` ones and adding `added`, and
+/// the current ones dropped on the way.
+pub(crate) fn replace_images(
+    current: &[String],
+    kept: Vec<String>,
+    added: Vec<String>,
+    max_count: usize,
+) -> Result<(Vec<String>, Vec<String>), RepoError> {
+    for (n, id) in kept.iter().enumerate() {
+        if !current.contains(id) {
+            return Err(RepoError::Validation(format!(
+                "image {} does not belong to this ad",
+                id
+            )));
+        }
+        if kept[..n].contains(id) {
+            return Err(RepoError::Validation(format!(
+                "image {} is kept more than once",
+                id
+            )));
+        }
+    }
+    let images = append_images(&kept, added, max_count)?;
+    let dropped = current
+        .iter()
+        .filter(|id| !images.contains(id))
+        .cloned()
+        .collect();
+
+    Ok((images, dropped))
+}
+
+/// The ad's `current` images without `image_id`, or `NotFound` if it isn't one of them.
+pub(crate) fn remove_image_id(
+    current: &[String],
+    image_id: &str,
+) -> Result<Vec<String>, RepoError> {
+    let mut images = current.to_vec();
+    let position = images
+        .iter()
+        .position(|id| id == image_id)
+        .ok_or(RepoError::NotFound)?;
+    images.remove(position);
+
+    Ok(images)
+}
+
+/// The live ad if it belongs to `user_email`, locked until the transaction ends so its
+/// images can be read and rewritten without losing a concurrent change.
+fn lock_owned_ad(
+    conn: &mut PgConnection,
+    id: AdId,
+    user_email: &str,
+) -> Result<Option<Ad>, RepoError> {
+    ads::table
+        .find(id)
+        .filter(ads::user_email.eq(user_email))
+        .filter(ads::deleted_at.is_null())
+        .for_update()
+        .first::<Ad>(conn)
+        .optional()
+        .map_err(RepoError::from)
+}
+
+fn set_images(conn: &mut PgConnection, id: AdId, image_ids: Vec<String>) -> Result<Ad, RepoError> {
+    diesel::update(ads::table.find(id))
+        .set((
+            ads::images.eq(ImageIds(image_ids)),
+            ads::updated_at.eq(chrono::Utc::now()),
+        ))
+        .get_result::<Ad>(conn)
+        .map_err(RepoError::from)
+}
+
+pub(crate) fn idempotency_cutoff() -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc::now() - chrono::Duration::hours(IDEMPOTENCY_KEY_TTL_HOURS)
+}
+
+#[async_trait]
+impl AdRepo for PostgresAdRepo {
+    async fn new_cursor(&self, filter: AdFilter) -> Result<String, RepoError> {
+        let query = apply_sort(filtered_query(&filter), &filter);
+
+        let conn = &mut self.db_manager.cursor_conn()?;
+
+        let cursor_name = format!(
+            "c_{}",
+            uuid::Uuid::new_v4().to_string().replace("-", "")[..10].to_string()
+        );
+
+        let cursor_query_str = format!(
+            "DECLARE {} CURSOR WITH HOLD FOR {}",
+            cursor_name,
+            debug_query(&query).to_string()
+        );
+
+        let mut cursor_query = CursorQuery::new(cursor_query_str);
+
+        if let Some(ref search) = filter.search {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Text, _>(search);
+        } else {
+            if let Some(ref title_contains) = filter.title_contains {
+                cursor_query = cursor_query
+                    .bind::<diesel::sql_types::Text, _>(format!("%{}%", title_contains));
+            }
+
+            if let Some(ref description_contains) = filter.description_contains {
+                cursor_query = cursor_query
+                    .bind::<diesel::sql_types::Text, _>(format!("%{}%", description_contains));
+            }
+        }
+
+        if let Some(ref filter_price_lt) = filter.price_lt {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Numeric, _>(filter_price_lt);
+        }
+
+        if let Some(ref filter_price_gt) = filter.price_gt {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Numeric, _>(filter_price_gt);
+        }
+
+        if let Some((ref min, ref max)) = filter.price_between {
+            cursor_query = cursor_query
+                .bind::<diesel::sql_types::Numeric, _>(min)
+                .bind::<diesel::sql_types::Numeric, _>(max);
+        }
+
+        if let Some(currency_eq) = filter.currency_eq {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Varchar, _>(currency_eq.as_str());
+        }
+
+        if let Some(ref updated_at_lt) = filter.updated_at_lt {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Timestamptz, _>(updated_at_lt);
+        }
+
+        if let Some(ref updated_at_gt) = filter.updated_at_gt {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Timestamptz, _>(updated_at_gt);
+        }
+
+        if let Some(ref created_at_lt) = filter.created_at_lt {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Timestamptz, _>(created_at_lt);
+        }
+
+        if let Some(ref created_at_gt) = filter.created_at_gt {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Timestamptz, _>(created_at_gt);
+        }
+
+        if let Some(category_eq) = filter.category_eq {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Varchar, _>(category_eq.as_str());
+        }
+
+        if let (Some(center), Some(radius_km)) = (filter.center(), filter.radius_km) {
+            cursor_query = bind_distance(cursor_query, center).bind::<Double, _>(radius_km);
+        }
+
+        if let Some(top_ad) = filter.top_ad {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Bool, _>(top_ad);
+        }
+
+        if let Some(negotiable) = filter.negotiable_eq {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Bool, _>(negotiable);
+        }
+
+        if let Some(locale_eq) = filter.locale_eq {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Varchar, _>(locale_eq.as_str());
+        }
+
+        if let Some(verified) = filter.phone_verified_eq {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Bool, _>(verified);
+        }
+
+        if let Some(ref user_email) = filter.user_email_eq {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Varchar, _>(user_email);
+        }
+
+        if let Some(ref user_phone) = filter.user_phone_eq {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Varchar, _>(user_phone);
+        }
+
+        if !filter.include_expired {
+            cursor_query =
+                cursor_query.bind::<diesel::sql_types::Timestamptz, _>(chrono::Utc::now());
+        }
+
+        cursor_query = cursor_query
+            .bind::<diesel::sql_types::Varchar, _>(filter.status_eq.unwrap_or_default().as_str());
+
+        // The search term is bound again for the `ts_rank` ordering.
+        if let Some(ref search) = filter.search {
+            cursor_query = cursor_query.bind::<diesel::sql_types::Text, _>(search);
+        }
+
+        // Then the center again for the distance ordering.
+        let by_distance = filter.sort_by == Some(AdSort::DistanceAsc) && filter.center().is_some();
+        if let Some(center) = filter.center().filter(|_| by_distance) {
+            cursor_query = bind_distance(cursor_query, center);
+        }
+
+        tracing::debug!(
+            cursor_name = %cursor_name,
+            bind_count = cursor_query.binds,
+            sql = %debug_query(&cursor_query.query),
+            "declaring cursor"
+        );
+
+        cursor_query.query.execute(conn).map_err(RepoError::from)?;
+        let evicted = self.cursors.insert(&cursor_name, &filter);
+        if !evicted.is_empty() {
+            // The new cursor is open either way; what can't be closed now ages out.
+            match self.close_cursors(Some(conn), &evicted, None) {
+                Ok(closed) => tracing::debug!(closed, "closed cursors over the limit"),
+                Err(e) => tracing::warn!(error = %e, "failed to close cursors over the limit"),
+            }
+        }
+
+        Ok(cursor_name)
+    }
+
+    async fn fetch_from_cursor(
+        &self,
+        cursor_name: String,
+        count: u32,
+        auto_close: bool,
+        filter: Option<&AdFilter>,
+    ) -> Result<CursorPage<Ad>, RepoError> {
+        validate_cursor_name(&cursor_name)?;
+        self.cursors.touch(&cursor_name, filter)?;
+        let count = count.clamp(1, MAX_CURSOR_FETCH);
+        let query = format!("FETCH FORWARD {} FROM {}", count, cursor_name);
+        // Only the connection `new_cursor` declared it on knows the cursor.
+        let conn = &mut self.db_manager.cursor_conn()?;
+        let ads = sql_query(query).load::<Ad>(conn).map_err(|e| {
+            if is_missing_cursor(&e) {
+                self.cursors.remove(&cursor_name);
+                RepoError::CursorExpired
+            } else {
+                RepoError::from(e)
+            }
+        })?;
+
+        let closed = auto_close && ads.len() < count as usize;
+        if closed {
+            sql_query(format!("CLOSE {}", cursor_name))
+                .execute(conn)
+                .map_err(RepoError::from)?;
+            self.cursors.remove(&cursor_name);
+        }
+
+        Ok(CursorPage::new(ads, count as usize, cursor_name, closed))
+    }
+
+    async fn close_cursor(&self, cursor_name: String) -> Result<(), RepoError> {
+        validate_cursor_name(&cursor_name)?;
+        let conn = &mut self.db_manager.cursor_conn()?;
+
+        let open = sql_query("SELECT name FROM pg_cursors WHERE name = $1")
+            .bind::<Text, _>(&cursor_name)
+            .execute(conn)
+            .map_err(RepoError::from)?;
+        if open == 0 {
+            self.cursors.remove(&cursor_name);
+            return Ok(());
+        }
+
+        sql_query(format!("CLOSE {}", cursor_name))
+            .execute(conn)
+            .map_err(RepoError::from)?;
+        self.cursors.remove(&cursor_name);
+
+        Ok(())
+    }
+
+    async fn get_by_id(&self, id: AdId, increment: bool) -> Result<Option<Ad>, RepoError> {
+        if increment {
+            // `view_count + 1` is evaluated under the row lock, so concurrent views all count.
+            return diesel::update(ads::table.find(id).filter(ads::deleted_at.is_null()))
+                .set(ads::view_count.eq(ads::view_count + 1))
+                .get_result::<Ad>(&mut self.db_manager.write_conn()?)
+                .optional()
+                .map_err(RepoError::from);
+        }
+
+        ads::table
+            .find(id)
+            .filter(ads::deleted_at.is_null())
+            .first::<Ad>(&mut self.db_manager.read_conn()?)
+            .optional()
+            .map_err(RepoError::from)
+    }
+
+    async fn get_by_slug(&self, slug: &str) -> Result<Option<Ad>, RepoError> {
+        let former = ad_slugs::table
+            .filter(ad_slugs::slug.eq(slug))
+            .select(ad_slugs::ad_id);
+
+        ads::table
+            .filter(ads::slug.eq(slug).or(ads::id.eq_any(former)))
+            .filter(ads::deleted_at.is_null())
+            .first::<Ad>(&mut self.db_manager.read_conn()?)
+            .optional()
+            .map_err(RepoError::from)
+    }
+
+    async fn price_history(&self, id: AdId) -> Result<Vec<PriceChange>, RepoError> {
+        price_history::table
+            .filter(price_history::ad_id.eq(id))
+            .order((price_history::changed_at.asc(), price_history::id.asc()))
+            .select(PriceChange::as_select())
+            .load(&mut self.db_manager.read_conn()?)
+            .map_err(RepoError::from)
+    }
+
+    async fn find_similar(
+        &self,
+        user_email: &str,
+        title: &str,
+        price: &Money,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<Ad>, RepoError> {
+        // Narrowed down by `idx_ads_user_email_created_at` first.
+        ads::table
+            .filter(ads::user_email.eq(user_email))
+            .filter(ads::created_at.ge(since))
+            .filter(ads::deleted_at.is_null())
+            .filter(ads::price.eq(price))
+            .filter(
+                sql::<Bool>("lower(btrim(title)) = lower(btrim(")
+                    .bind::<Text, _>(title.to_string())
+                    .sql("))"),
+            )
+            .order(ads::created_at.desc())
+            .select(Ad::as_select())
+            .first::<Ad>(&mut self.db_manager.read_conn()?)
+            .optional()
+            .map_err(RepoError::from)
+    }
+
+    async fn exists(&self, id: AdId) -> Result<bool, RepoError> {
+        diesel::select(diesel::dsl::exists(
+            ads::table.find(id).filter(ads::deleted_at.is_null()),
+        ))
+        .get_result::<bool>(&mut self.db_manager.read_conn()?)
+        .map_err(RepoError::from)
+    }
+
+    async fn get_by_ids(&self, ids: &[AdId]) -> Result<Vec<Ad>, RepoError> {
+        if ids.len() > MAX_BATCH_IDS {
+            return Err(RepoError::Validation(format!(
+                "at most {} ids can be fetched at once",
+                MAX_BATCH_IDS
+            )));
+        }
+
+        let mut found: HashMap<AdId, Ad> = ads::table
+            .filter(ads::id.eq_any(ids))
+            .filter(ads::deleted_at.is_null())
+            .load::<Ad>(&mut self.db_manager.read_conn()?)
+            .map_err(RepoError::from)?
+            .into_iter()
+            .map(|ad| (ad.id, ad))
+            .collect();
+
+        Ok(ids.iter().filter_map(|id| found.remove(id)).collect())
+    }
+
+    async fn get_page(
+        &self,
+        offset: u32,
+        per_page: u32,
+        filter: AdFilter,
+    ) -> Result<Vec<Ad>, RepoError> {
+        let conn = &mut self.db_manager.read_conn()?;
+
+        // Ads with a running promotion are few, so they are paged on their own and the rest
+        // off `sort_within`'s index; ordering by `promotion_running` can't use an index, as
+        // it changes with the time, and would sort every matching ad.
+        let promoted = || filtered_query(&filter).filter(promotion_running());
+        let mut page = sort_within(promoted(), &filter)
+            .offset(offset.into())
+            .limit(per_page.into())
+            .load::<Ad>(conn)
+            .map_err(RepoError::from)?;
+
+        let remaining = per_page - page.len() as u32;
+        if remaining > 0 {
+            // A page with promoted ads starts the rest from the top; one past them skips
+            // however many of the rest earlier pages held.
+            let skipped = if page.is_empty() {
+                let promoted_count = promoted()
+                    .count()
+                    .get_result::<i64>(conn)
+                    .map_err(RepoError::from)?;
+                offset.saturating_sub(promoted_count as u32)
+            } else {
+                0
+            };
+            let rest = filtered_query(&filter).filter(diesel::dsl::not(promotion_running()));
+            page.extend(
+                sort_within(rest, &filter)
+                    .offset(skipped.into())
+                    .limit(remaining.into())
+                    .load::<Ad>(conn)
+                    .map_err(RepoError::from)?,
+            );
+        }
+
+        Ok(page)
+    }
+
+    async fn get_page_after(
+        &self,
+        after: Option<AdKeyset>,
+        per_page: u32,
+        filter: AdFilter,
+    ) -> Result<Vec<Ad>, RepoError> {
+        let mut query = filtered_query(&filter);
+
+        if let Some(after) = after {
+            query = query.filter(
+                ads::created_at.lt(after.created_at).or(ads::created_at
+                    .eq(after.created_at)
+                    .and(ads::id.lt(after.id))),
+            );
+        }
+
+        let query = query
+            .order((ads::created_at.desc(), ads::id.desc()))
+            .limit(per_page.into());
+
+        let conn = &mut self.db_manager.read_conn()?;
+        query.load::<Ad>(conn).map_err(RepoError::from)
+    }
+
+    async fn count(&self, filter: AdFilter) -> Result<u64, RepoError> {
+        let conn = &mut self.db_manager.read_conn()?;
+        let count = filtered_query(&filter)
+            .count()
+            .get_result::<i64>(conn)
+            .map_err(RepoError::from)?;
+
+        Ok(count as u64)
+    }
+
+    async fn get_by_user(
+        &self,
+        user_email: &str,
+        include_unlisted: bool,
+        offset: u32,
+        per_page: u32,
+    ) -> Result<(Vec<Ad>, u64), RepoError> {
+        let query = || {
+            let mut query = ads::table
+                .into_boxed()
+                .filter(ads::user_email.eq(user_email))
+                .filter(ads::deleted_at.is_null());
+            if !include_unlisted {
+                query = query
+                    .filter(ads::status.eq(AdStatus::Active.as_str()))
+                    .filter(
+                        ads::expires_at
+                            .is_null()
+                            .or(ads::expires_at.ge(chrono::Utc::now())),
+                    );
+            }
+            query
+        };
+
+        let conn = &mut self.db_manager.read_conn()?;
+        let items = query()
+            .order((ads::created_at.desc(), ads::id.desc()))
+            .offset(offset.into())
+            .limit(per_page.into())
+            .load::<Ad>(conn)
+            .map_err(RepoError::from)?;
+        let total = query()
+            .count()
+            .get_result::<i64>(conn)
+            .map_err(RepoError::from)?;
+
+        Ok((items, total as u64))
+    }
+
+    async fn facets(&self, filter: AdFilter) -> Result<AdFacets, RepoError> {
+        let conn = &mut self.db_manager.read_conn()?;
+        // A boxed query can't be grouped, so the filter picks the ids to group over.
+        let matching = || ads::id.eq_any(filtered_query(&filter).select(ads::id));
+        let categories = ads::table
+            .filter(matching())
+            .group_by(ads::category)
+            .select((ads::category, count_star()))
+            .load::<(String, i64)>(conn)
+            .map_err(RepoError::from)?;
+
+        let bucket = sql::<Integer>(&format!(
+            "width_bucket(price, ARRAY{:?}::numeric[])",
+            PRICE_BUCKETS
+        ));
+        let buckets = ads::table
+            .filter(matching())
+            .group_by(bucket.clone())
+            .select((bucket, count_star()))
+            .load::<(i32, i64)>(conn)
+            .map_err(RepoError::from)?;
+
+        Ok(AdFacets::new(categories.into_iter().collect(), &buckets))
+    }
+
+    async fn stats(&self) -> Result<AdStats, RepoError> {
+        #[derive(QueryableByName)]
+        struct StatsRow {
+            #[diesel(sql_type = BigInt)]
+            total_active: i64,
+            #[diesel(sql_type = Jsonb)]
+            per_category: serde_json::Value,
+            #[diesel(sql_type = Jsonb)]
+            average_price: serde_json::Value,
+            #[diesel(sql_type = Jsonb)]
+            created_per_day: serde_json::Value,
+        }
+
+        // One round trip: each figure is a scalar subquery over the same listed ads. Days
+        // are UTC days, whatever the session's time zone.
+        let row = sql_query(
+            "WITH listed AS ( \
+                 SELECT category, currency, price FROM ads \
+                 WHERE status = $3 AND deleted_at IS NULL \
+                 AND (expires_at IS NULL OR expires_at >= $1) \
+             ), \
+             today AS (SELECT ($1 AT TIME ZONE 'UTC')::date AS today), \
+             days AS ( \
+                 SELECT generate_series(today - $2 + 1, today, interval '1 day')::date AS day \
+                 FROM today \
+             ) \
+             SELECT \
+                 (SELECT COUNT(*) FROM listed) AS total_active, \
+                 (SELECT COALESCE(jsonb_object_agg(category, n), '{}') \
+                  FROM (SELECT category, COUNT(*) AS n FROM listed GROUP BY category) c) \
+                     AS per_category, \
+                 (SELECT COALESCE(jsonb_object_agg(currency, average), '{}') \
+                  FROM (SELECT currency, ROUND(AVG(price), 2)::text AS average \
+                        FROM listed GROUP BY currency) p) \
+                     AS average_price, \
+                 (SELECT jsonb_agg(jsonb_build_object('day', day, 'count', COALESCE(n, 0)) \
+                                   ORDER BY day) \
+                  FROM days LEFT JOIN ( \
+                      SELECT (created_at AT TIME ZONE 'UTC')::date AS day, COUNT(*) AS n \
+                      FROM ads, today \
+                      WHERE deleted_at IS NULL \
+                      AND created_at >= (today - $2 + 1)::timestamp AT TIME ZONE 'UTC' \
+                      GROUP BY 1 \
+                  ) created USING (day)) \
+                     AS created_per_day",
+        )
+        .bind::<Timestamptz, _>(chrono::Utc::now())
+        .bind::<Integer, _>(STATS_DAYS)
+        .bind::<Text, _>(AdStatus::Active.as_str())
+        .get_result::<StatsRow>(&mut self.db_manager.read_conn()?)
+        .map_err(RepoError::from)?;
+
+        let average_price = serde_json::from_value::<BTreeMap<String, String>>(row.average_price)?
+            .into_iter()
+            .map(|(currency, average)| Ok((currency, average.parse()?)))
+            .collect::<Result<_, bigdecimal::ParseBigDecimalError>>()
+            .map_err(RepoError::internal)?;
+
+        Ok(AdStats {
+            total_active: row.total_active,
+            per_category: serde_json::from_value(row.per_category)?,
+            average_price,
+            created_per_day: serde_json::from_value(row.created_per_day)?,
+        })
+    }
+
+    async fn create(&self, ad: AdContent, image_ids: Vec<String>) -> Result<Ad, RepoError> {
+        let ad = self
+            .db_manager
+            .transaction(|conn| insert_ad(conn, ad, image_ids))?;
+
+        metrics::counter!("ads_created_total").increment(1);
+        Ok(ad)
+    }
+
+    async fn create_many(&self, ads: Vec<(AdContent, Vec<String>)>) -> Result<Vec<Ad>, RepoError> {
+        if ads.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ads = self.db_manager.transaction(|conn| insert_ads(conn, ads))?;
+
+        metrics::counter!("ads_created_total").increment(ads.len() as u64);
+        Ok(ads)
+    }
+
+    async fn get_by_idempotency_key(
+        &self,
+        user_email: &str,
+        key: &str,
+    ) -> Result<Option<Ad>, RepoError> {
+        idempotency_keys::table
+            .inner_join(ads::table)
+            .filter(idempotency_keys::user_email.eq(user_email))
+            .filter(idempotency_keys::key.eq(key))
+            .filter(idempotency_keys::created_at.ge(idempotency_cutoff()))
+            .select(Ad::as_select())
+            .first::<Ad>(&mut self.db_manager.read_conn()?)
+            .optional()
+            .map_err(RepoError::from)
+    }
+
+    async fn create_idempotent(
+        &self,
+        ad: AdContent,
+        image_ids: Vec<String>,
+        key: &str,
+    ) -> Result<(Ad, bool), RepoError> {
+        let user_email = ad.user_email.clone();
+
+        let (ad, created) = self.db_manager.transaction(|conn| {
+            // Requests retried concurrently with the same key queue up here until the
+            // first one commits, so only it inserts.
+            sql_query("SELECT pg_advisory_xact_lock(hashtext($1), hashtext($2))")
+                .bind::<Text, _>(&user_email)
+                .bind::<Text, _>(key)
+                .execute(conn)?;
+
+            diesel::delete(
+                idempotency_keys::table
+                    .find((&user_email, key))
+                    .filter(idempotency_keys::created_at.lt(idempotency_cutoff())),
+            )
+            .execute(conn)?;
+
+            let existing = idempotency_keys::table
+                .inner_join(ads::table)
+                .filter(idempotency_keys::user_email.eq(&user_email))
+                .filter(idempotency_keys::key.eq(key))
+                .select(Ad::as_select())
+                .first::<Ad>(conn)
+                .optional()?;
+            if let Some(ad) = existing {
+                return Ok::<_, RepoError>((ad, false));
+            }
+
+            let ad = insert_ad(conn, ad, image_ids)?;
+            diesel::insert_into(idempotency_keys::table)
+                .values((
+                    idempotency_keys::user_email.eq(&user_email),
+                    idempotency_keys::key.eq(key),
+                    idempotency_keys::ad_id.eq(ad.id),
+                    idempotency_keys::created_at.eq(ad.created_at),
+                ))
+                .execute(conn)?;
+
+            Ok((ad, true))
+        })?;
+
+        if created {
+            metrics::counter!("ads_created_total").increment(1);
+        }
+        Ok((ad, created))
+    }
+
+    async fn get_owner(&self, id: AdId) -> Result<Option<String>, RepoError> {
+        ads::table
+            .find(id)
+            .filter(ads::deleted_at.is_null())
+            .select(ads::user_email)
+            .first::<String>(&mut self.db_manager.read_conn()?)
+            .optional()
+            .map_err(RepoError::from)
+    }
+
+    async fn update(
+        &self,
+        id: AdId,
+        user_email: &str,
+        ad: AdContent,
+    ) -> Result<Option<Ad>, RepoError> {
+        self.db_manager
+            .transaction(|conn| update_ad(conn, id, user_email, ad))
+    }
+
+    async fn patch(
+        &self,
+        id: AdId,
+        user_email: &str,
+        changes: AdPatch,
+        expected_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Option<Ad>, RepoError> {
+        self.db_manager
+            .transaction(|conn| patch_ad(conn, id, user_email, changes, expected_updated_at))
+    }
+
+    async fn reorder_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_ids: Vec<String>,
+    ) -> Result<Option<Ad>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            let Some(ad) = lock_owned_ad(conn, id, user_email)? else {
+                return Ok(None);
+            };
+            check_image_order(&ad.images, &image_ids)?;
+
+            set_images(conn, id, image_ids).map(Some)
+        })
+    }
+
+    async fn add_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_ids: Vec<String>,
+        max_count: usize,
+    ) -> Result<Option<Ad>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            let Some(ad) = lock_owned_ad(conn, id, user_email)? else {
+                return Ok(None);
+            };
+            let images = append_images(&ad.images, image_ids, max_count)?;
+
+            set_images(conn, id, images).map(Some)
+        })
+    }
+
+    async fn set_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        kept: Vec<String>,
+        added: Vec<String>,
+        max_count: usize,
+    ) -> Result<Option<(Ad, Vec<String>)>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            let Some(ad) = lock_owned_ad(conn, id, user_email)? else {
+                return Ok(None);
+            };
+            let (images, dropped) = replace_images(&ad.images, kept, added, max_count)?;
+
+            Ok(Some((set_images(conn, id, images)?, dropped)))
+        })
+    }
+
+    async fn remove_image(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_id: &str,
+    ) -> Result<Option<Ad>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            let Some(ad) = lock_owned_ad(conn, id, user_email)? else {
+                return Ok(None);
+            };
+            let images = remove_image_id(&ad.images, image_id)?;
+
+            set_images(conn, id, images).map(Some)
+        })
+    }
+
+    async fn delete(&self, id: AdId, user_email: &str) -> Result<usize, RepoError> {
+        let now = chrono::Utc::now();
+        let deleted = self.db_manager.transaction(|conn| {
+            diesel::update(
+                ads::table
+                    .find(id)
+                    .filter(ads::user_email.eq(user_email))
+                    .filter(ads::deleted_at.is_null()),
+            )
+            .set((ads::deleted_at.eq(now), ads::updated_at.eq(now)))
+            .execute(conn)
+            .map_err(RepoError::from)
+        })?;
+
+        metrics::counter!("ads_deleted_total").increment(deleted as u64);
+        Ok(deleted)
+    }
+
+    async fn restore(&self, id: AdId) -> Result<Option<Ad>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            diesel::update(ads::table.find(id).filter(ads::deleted_at.is_not_null()))
+                .set((
+                    ads::deleted_at.eq(None::<chrono::DateTime<chrono::Utc>>),
+                    ads::updated_at.eq(chrono::Utc::now()),
+                ))
+                .get_result::<Ad>(conn)
+                .optional()
+                .map_err(RepoError::from)
+        })
+    }
+
+    async fn purge(&self, id: AdId) -> Result<Option<Ad>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            diesel::delete(ads::table.find(id).filter(ads::deleted_at.is_not_null()))
+                .get_result::<Ad>(conn)
+                .optional()
+                .map_err(RepoError::from)
+        })
+    }
+
+    async fn delete_by_user(&self, user_email: &str) -> Result<Vec<Ad>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            diesel::delete(ads::table.filter(ads::user_email.eq(user_email)))
+                .get_results::<Ad>(conn)
+                .map_err(RepoError::from)
+        })
+    }
+
+    async fn images_in_use(&self, ids: &[String]) -> Result<Vec<String>, RepoError> {
+        // The primary, as a replica could still miss an ad that was just given the image.
+        let listed = ads::table
+            .filter(ads::images.has_any_key(ids.to_vec()))
+            .select(ads::images)
+            .load::<ImageIds>(&mut self.db_manager.write_conn()?)?;
+
+        let in_use: HashSet<String> = listed.into_iter().flat_map(|images| images.0).collect();
+        Ok(ids
+            .iter()
+            .filter(|id| in_use.contains(*id))
+            .cloned()
+            .collect())
+    }
+
+    async fn set_status(&self, id: AdId, status: AdStatus) -> Result<Option<Ad>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            diesel::update(ads::table.find(id).filter(ads::deleted_at.is_null()))
+                .set((
+                    ads::status.eq(status.as_str()),
+                    ads::updated_at.eq(chrono::Utc::now()),
+                ))
+                .get_result::<Ad>(conn)
+                .optional()
+                .map_err(RepoError::from)
+        })
+    }
+
+    async fn publish(&self, id: AdId, user_email: &str) -> Result<Option<Ad>, RepoError> {
+        let now = chrono::Utc::now();
+        diesel::update(
+            ads::table
+                .find(id)
+                .filter(ads::user_email.eq(user_email))
+                .filter(ads::deleted_at.is_null())
+                .filter(ads::status.eq(AdStatus::Draft.as_str())),
+        )
+        .set((
+            ads::status.eq(AdStatus::Active.as_str()),
+            ads::created_at.eq(now),
+            ads::updated_at.eq(now),
+            ads::expires_at.eq(now + chrono::Duration::days(AD_LIFETIME_DAYS)),
+        ))
+        .get_result::<Ad>(&mut self.db_manager.write_conn()?)
+        .optional()
+        .map_err(RepoError::from)
+    }
+
+    async fn renew(&self, id: AdId, user_email: &str) -> Result<Option<Ad>, RepoError> {
+        let now = chrono::Utc::now();
+        diesel::update(
+            ads::table
+                .find(id)
+                .filter(ads::user_email.eq(user_email))
+                .filter(ads::deleted_at.is_null())
+                .filter(ads::status.eq(AdStatus::Active.as_str()))
+                .filter(ads::created_at.le(now - chrono::Duration::hours(RENEW_COOLDOWN_HOURS))),
+        )
+        .set((
+            ads::created_at.eq(now),
+            ads::updated_at.eq(now),
+            ads::expires_at.eq(now + chrono::Duration::days(AD_LIFETIME_DAYS)),
+        ))
+        .get_result::<Ad>(&mut self.db_manager.write_conn()?)
+        .optional()
+        .map_err(RepoError::from)
+    }
+
+    async fn promote(
+        &self,
+        id: AdId,
+        user_email: &str,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<Ad>, RepoError> {
+        diesel::update(
+            ads::table
+                .find(id)
+                .filter(ads::user_email.eq(user_email))
+                .filter(ads::deleted_at.is_null())
+                .filter(ads::status.eq(AdStatus::Active.as_str())),
+        )
+        .set((
+            ads::top_ad.eq(true),
+            ads::top_ad_until.eq(until),
+            ads::updated_at.eq(chrono::Utc::now()),
+        ))
+        .get_result::<Ad>(&mut self.db_manager.write_conn()?)
+        .optional()
+        .map_err(RepoError::from)
+    }
+
+    async fn start_phone_verification(
+        &self,
+        id: AdId,
+        user_email: &str,
+        code: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>, RepoError> {
+        let now = chrono::Utc::now();
+        let expires_at = now + chrono::Duration::minutes(PHONE_CODE_TTL_MINUTES);
+        self.db_manager.transaction(|conn| {
+            diesel::delete(phone_codes::table.filter(phone_codes::expires_at.le(now)))
+                .execute(conn)?;
+
+            let Some(user_phone) = ads::table
+                .find(id)
+                .filter(ads::user_email.eq(user_email))
+                .filter(ads::deleted_at.is_null())
+                .select(ads::user_phone)
+                .first::<String>(conn)
+                .optional()?
+            else {
+                return Ok(None);
+            };
+
+            diesel::insert_into(phone_codes::table)
+                .values((
+                    phone_codes::ad_id.eq(id),
+                    phone_codes::code.eq(code),
+                    phone_codes::user_phone.eq(&user_phone),
+                    phone_codes::expires_at.eq(expires_at),
+                ))
+                .on_conflict(phone_codes::ad_id)
+                .do_update()
+                .set((
+                    phone_codes::code.eq(code),
+                    phone_codes::user_phone.eq(&user_phone),
+                    phone_codes::attempts.eq(0),
+                    phone_codes::expires_at.eq(expires_at),
+                ))
+                .execute(conn)?;
+
+            Ok(Some(expires_at))
+        })
+    }
+
+    async fn verify_phone(
+        &self,
+        id: AdId,
+        user_email: &str,
+        code: &str,
+    ) -> Result<Option<Ad>, RepoError> {
+        self.db_manager.transaction(|conn| {
+            let Some((pending, user_phone, attempts)) = phone_codes::table
+                .find(id)
+                .filter(phone_codes::expires_at.gt(chrono::Utc::now()))
+                .select((
+                    phone_codes::code,
+                    phone_codes::user_phone,
+                    phone_codes::attempts,
+                ))
+                .for_update()
+                .first::<(String, String, i32)>(conn)
+                .optional()?
+            else {
+                return Ok(None);
+            };
+
+            if pending != code {
+                if attempts + 1 >= MAX_PHONE_CODE_ATTEMPTS {
+                    diesel::delete(phone_codes::table.find(id)).execute(conn)?;
+                } else {
+                    diesel::update(phone_codes::table.find(id))
+                        .set(phone_codes::attempts.eq(attempts + 1))
+                        .execute(conn)?;
+                }
+                return Ok(None);
+            }
+
+            // A code sent to a number the ad no longer has stays pending for it.
+            let ad = diesel::update(
+                ads::table
+                    .find(id)
+                    .filter(ads::user_email.eq(user_email))
+                    .filter(ads::deleted_at.is_null())
+                    .filter(ads::user_phone.eq(&user_phone)),
+            )
+            .set((
+                ads::phone_verified.eq(true),
+                ads::updated_at.eq(chrono::Utc::now()),
+            ))
+            .get_result::<Ad>(conn)
+            .optional()?;
+            if ad.is_some() {
+                diesel::delete(phone_codes::table.find(id)).execute(conn)?;
+            }
+
+            Ok(ad)
+        })
+    }
+
+    async fn end_promotions(&self) -> Result<usize, RepoError> {
+        let now = chrono::Utc::now();
+        diesel::update(ads::table.filter(ads::top_ad_until.le(now)))
+            .set((
+                ads::top_ad.eq(false),
+                ads::top_ad_until.eq(None::<chrono::DateTime<chrono::Utc>>),
+                ads::updated_at.eq(now),
+            ))
+            .execute(&mut self.db_manager.write_conn()?)
+            .map_err(RepoError::from)
+    }
+
+    async fn mark_expired(&self) -> Result<usize, RepoError> {
+        let now = chrono::Utc::now();
+        diesel::update(
+            ads::table
+                .filter(ads::status.eq(AdStatus::Active.as_str()))
+                .filter(ads::expires_at.lt(now)),
+        )
+        .set((
+            ads::status.eq(AdStatus::Expired.as_str()),
+            ads::updated_at.eq(now),
+        ))
+        .execute(&mut self.db_manager.write_conn()?)
+        .map_err(RepoError::from)
+    }
+
+    async fn close_stale_cursors(&self, max_age: Duration) -> Result<usize, RepoError> {
+        self.close_cursors(None, &self.cursors.idle(max_age), Some(max_age))
+    }
+
+    async fn purge_idempotency_keys(&self) -> Result<usize, RepoError> {
+        diesel::delete(
+            idempotency_keys::table.filter(idempotency_keys::created_at.lt(idempotency_cutoff())),
+        )
+        .execute(&mut self.db_manager.write_conn()?)
+        .map_err(RepoError::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        models::{
+            ad::{
+                ad_slug, parse_etag, Ad, AdCategory, AdContent, AdId, AdPatch, AdStatus, Currency,
+                Locale,
+            },
+            price_history::PriceChange,
+        },
+        repos::{
+            ad_repo::{
+                filtered_query, promotion_running, sort_within, validate_cursor_name, AdFilter,
+                AdKeyset, AdRepo, AdSort, PostgresAdRepo, MAX_BATCH_IDS, MAX_PHONE_CODE_ATTEMPTS,
+            },
+            error::RepoError,
+            fixtures::{ad_content, seed_ad, seed_ads, shared_db, test_db, test_repo},
+        },
+    };
+
+    #[tokio::test]
+    async fn test_ad_repo() {
+        let ad_repo = test_repo();
+        // Rows committed by other tests are still visible, so the title is made unique.
+        let title = format!("Test Ad {}", uuid::Uuid::new_v4());
+        seed_ads(&*ad_repo, (0..10).map(|_| ad_content(&title)).collect()).await;
+        seed_ad(&*ad_repo, ad_content("Something else")).await;
+
+        let filter = AdFilter {
+            title_contains: Some(title.clone()),
+            ..Default::default()
+        };
+
+        let total = ad_repo
+            .count(filter.clone())
+            .await
+            .expect("Failed to count ads");
+        assert_eq!(total, 10);
+
+        let cursor_name = ad_repo
+            .new_cursor(filter.clone())
+            .await
+            .expect("Failed to get cursor");
+
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name.clone(), 4, false, None)
+            .await
+            .expect("Failed to fetch from cursor")
+            .items;
+        assert_eq!(ads.len(), 4);
+        assert!(ads.iter().all(|ad| ad.title == title));
+
+        // Other filters than the cursor was declared with are rejected, the same ones pass.
+        let other = AdFilter {
+            title_contains: Some("Something".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            ad_repo
+                .fetch_from_cursor(cursor_name.clone(), 10, true, Some(&other))
+                .await,
+            Err(RepoError::InvalidFields(_))
+        ));
+
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, Some(&filter))
+            .await
+            .expect("Failed to fetch from cursor")
+            .items;
+        assert_eq!(ads.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_delete_requires_owner() {
+        let ad_repo = test_repo();
+
+        let ad = seed_ad(
+            &*ad_repo,
+            AdContent {
+                user_email: "owner@test.com".to_string(),
+                ..ad_content("Owned Ad")
+            },
+        )
+        .await;
+
+        let deleted = ad_repo
+            .delete(ad.id, "intruder@test.com")
+            .await
+            .expect("Failed to delete ad");
+        assert_eq!(deleted, 0);
+
+        let owner = ad_repo.get_owner(ad.id).await.expect("Failed to get owner");
+        assert_eq!(owner.as_deref(), Some("owner@test.com"));
+
+        let deleted = ad_repo
+            .delete(ad.id, "owner@test.com")
+            .await
+            .expect("Failed to delete ad");
+        assert_eq!(deleted, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reorder_images() {
+        let ad_repo = test_repo();
+        let ad = ad_repo
+            .create(
+                ad_content("Reordered"),
+                vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            )
+            .await
+            .unwrap();
+        let order = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        let reordered = ad_repo
+            .reorder_images(ad.id, "test@test.com", order(&["c", "a", "b"]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reordered.images, ["c", "a", "b"]);
+
+        for bad in [
+            &["c", "a"][..],
+            &["c", "a", "a"],
+            &["c", "a", "b", "d"],
+            &["c", "a", "x"],
+        ] {
+            assert!(matches!(
+                ad_repo
+                    .reorder_images(ad.id, "test@test.com", order(bad))
+                    .await,
+                Err(RepoError::Validation(_))
+            ));
+        }
+        assert!(ad_repo
+            .reorder_images(ad.id, "other@test.com", order(&["a", "b", "c"]))
+            .await
+            .unwrap()
+            .is_none());
+
+        let ad = ad_repo.get_by_id(ad.id, false).await.unwrap().unwrap();
+        assert_eq!(ad.images, ["c", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_set_images() {
+        let ad_repo = test_repo();
+        let ad = ad_repo
+            .create(
+                ad_content("Replaced images"),
+                vec!["a".to_string(), "b".to_string()],
+            )
+            .await
+            .unwrap();
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        let (ad, dropped) = ad_repo
+            .set_images(ad.id, "test@test.com", ids(&["b"]), ids(&["c"]), 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ad.images, ["b", "c"]);
+        assert_eq!(dropped, ["a"]);
+
+        for (kept, added) in [
+            (&["a"][..], &[][..]),
+            (&["b", "b"], &[]),
+            (&["b", "c"], &["d", "e"]),
+        ] {
+            assert!(matches!(
+                ad_repo
+                    .set_images(ad.id, "test@test.com", ids(kept), ids(added), 3)
+                    .await,
+                Err(RepoError::Validation(_))
+            ));
+        }
+        assert!(ad_repo
+            .set_images(ad.id, "other@test.com", vec![], ids(&["d"]), 3)
+            .await
+            .unwrap()
+            .is_none());
+
+        let (ad, dropped) = ad_repo
+            .set_images(ad.id, "test@test.com", vec![], vec![], 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(ad.images.is_empty());
+        assert_eq!(dropped, ["b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_add_and_remove_images() {
+        let ad_repo = test_repo();
+        let ad = ad_repo
+            .create(ad_content("With images"), vec!["a".to_string()])
+            .await
+            .unwrap();
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        let ad = ad_repo
+            .add_images(ad.id, "test@test.com", ids(&["b", "c"]), 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ad.images, ["a", "b", "c"]);
+        assert!(matches!(
+            ad_repo
+                .add_images(ad.id, "test@test.com", ids(&["d"]), 3)
+                .await,
+            Err(RepoError::Validation(_))
+        ));
+        assert!(ad_repo
+            .add_images(ad.id, "other@test.com", ids(&["d"]), 10)
+            .await
+            .unwrap()
+            .is_none());
+
+        let ad = ad_repo
+            .remove_image(ad.id, "test@test.com", "b")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ad.images, ["a", "c"]);
+        assert!(matches!(
+            ad_repo.remove_image(ad.id, "test@test.com", "b").await,
+            Err(RepoError::NotFound)
+        ));
+        assert!(ad_repo
+            .remove_image(ad.id, "other@test.com", "a")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_timestamps_round_trip() {
+        use diesel::prelude::*;
+
+        let ad_repo = test_repo();
+        let conn = || ad_repo.db_manager.get_write_pool().get().unwrap();
+        // Stored instants don't depend on the session's zone.
+        diesel::sql_query("SET TIME ZONE 'Asia/Tokyo'")
+            .execute(&mut conn())
+            .unwrap();
+
+        let before = chrono::Utc::now() - chrono::Duration::seconds(1);
+        let created = ad_repo
+            .create(ad_content("Timestamped"), vec![])
+            .await
+            .unwrap();
+        let ad = ad_repo.get_by_id(created.id, false).await.unwrap().unwrap();
+        assert_eq!(ad.created_at, created.created_at);
+        assert_eq!(ad.updated_at, created.updated_at);
+        assert!(ad.created_at > before && ad.created_at <= chrono::Utc::now());
+
+        let json = serde_json::to_value(&ad).unwrap();
+        let created_at = json["created_at"].as_str().unwrap();
+        assert!(created_at.ends_with('Z'), "{}", created_at);
+        assert_eq!(
+            chrono::DateTime::parse_from_rfc3339(created_at).unwrap(),
+            ad.created_at
+        );
+
+        let filter = |created_at_gt, created_at_lt| AdFilter {
+            title_contains: Some("Timestamped".to_string()),
+            created_at_gt: Some(created_at_gt),
+            created_at_lt: Some(created_at_lt),
+            ..Default::default()
+        };
+        let second = chrono::Duration::seconds(1);
+        assert!(
+            ad_repo
+                .count(filter(ad.created_at - second, ad.created_at + second))
+                .await
+                .unwrap()
+                >= 1
+        );
+        assert_eq!(
+            ad_repo
+                .count(filter(ad.created_at, ad.created_at + second))
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_images_round_trip() {
+        use crate::db::schema::ads;
+        use diesel::prelude::*;
+
+        let ad_repo = test_repo();
+        let conn = || ad_repo.db_manager.get_write_pool().get().unwrap();
+
+        let without = ad_repo
+            .create(ad_content("Without images"), vec![])
+            .await
+            .unwrap();
+        let with = ad_repo
+            .create(
+                ad_content("With images"),
+                vec!["a".to_string(), "b".to_string()],
+            )
+            .await
+            .unwrap();
+        let get = |id| ad_repo.get_by_id(id, false);
+        assert_eq!(
+            get(without.id).await.unwrap().unwrap().images,
+            Vec::<String>::new()
+        );
+        assert_eq!(get(with.id).await.unwrap().unwrap().images, ["a", "b"]);
+        assert_eq!(
+            serde_json::to_value(&without).unwrap()["images"],
+            serde_json::json!([])
+        );
+
+        // Rows from before images were always a list.
+        diesel::update(ads::table.find(without.id))
+            .set(ads::images.eq(serde_json::Value::Null))
+            .execute(&mut conn())
+            .unwrap();
+        assert_eq!(
+            get(without.id).await.unwrap().unwrap().images,
+            Vec::<String>::new()
+        );
+
+        diesel::update(ads::table.find(without.id))
+            .set(ads::images.eq(serde_json::json!({"primary": "a"})))
+            .execute(&mut conn())
+            .unwrap();
+        assert!(get(without.id).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delete_by_user() {
+        let ad_repo = test_repo();
+        let owner = format!("{}@test.com", uuid::Uuid::new_v4());
+        let other = seed_ad(&*ad_repo, ad_content("Someone else's")).await;
+
+        let mut ids = Vec::new();
+        for title in ["First", "Second", "Third"] {
+            let content = AdContent {
+                user_email: owner.clone(),
+                ..ad_content(title)
+            };
+            ids.push(seed_ad(&*ad_repo, content).await.id);
+        }
+        // Soft-deleted ads go too.
+        ad_repo.delete(ids[0], &owner).await.unwrap();
+
+        let mut deleted: Vec<_> = ad_repo
+            .delete_by_user(&owner)
+            .await
+            .unwrap()
+            .iter()
+            .map(|ad| ad.id)
+            .collect();
+        deleted.sort();
+        assert_eq!(deleted, ids);
+
+        assert!(ad_repo.restore(ids[0]).await.unwrap().is_none());
+        assert!(ad_repo.get_by_ids(&ids).await.unwrap().is_empty());
+        assert!(ad_repo.exists(other.id).await.unwrap());
+        assert!(ad_repo.delete_by_user(&owner).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_publish_draft() {
+        let ad_repo = test_repo();
+        let title = format!("Draft {}", uuid::Uuid::new_v4());
+        let draft = seed_ad(
+            &*ad_repo,
+            AdContent {
+                draft: true,
+                ..ad_content(&title)
+            },
+        )
+        .await;
+        assert_eq!(draft.status, AdStatus::Draft.as_str());
+        let by_title = || AdFilter {
+            title_contains: Some(title.clone()),
+            ..Default::default()
+        };
+        assert_eq!(ad_repo.count(by_title()).await.unwrap(), 0);
+
+        assert!(ad_repo
+            .publish(draft.id, "other@test.com")
+            .await
+            .unwrap()
+            .is_none());
+        let published = ad_repo
+            .publish(draft.id, "test@test.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(published.status, AdStatus::Active.as_str());
+        assert!(published.created_at > draft.created_at);
+        assert!(published.expires_at > draft.expires_at);
+        assert_eq!(ad_repo.count(by_title()).await.unwrap(), 1);
+
+        // Only drafts are published.
+        assert!(ad_repo
+            .publish(draft.id, "test@test.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_renew() {
+        use crate::db::schema::ads;
+        use diesel::prelude::*;
+
+        let ad_repo = test_repo();
+        let ad = seed_ad(&*ad_repo, ad_content("Renewed")).await;
+
+        // Just posted, so still inside the cooldown.
+        assert!(ad_repo
+            .renew(ad.id, "test@test.com")
+            .await
+            .unwrap()
+            .is_none());
+
+        let posted = chrono::Utc::now() - chrono::Duration::days(2);
+        diesel::update(ads::table.find(ad.id))
+            .set((ads::created_at.eq(posted), ads::updated_at.eq(posted)))
+            .execute(&mut ad_repo.db_manager.get_write_pool().get().unwrap())
+            .expect("Failed to backdate ad");
+
+        assert!(ad_repo
+            .renew(ad.id, "other@test.com")
+            .await
+            .unwrap()
+            .is_none());
+        let renewed = ad_repo
+            .renew(ad.id, "test@test.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(renewed.created_at > posted);
+        assert!(renewed.updated_at > posted);
+        assert!(renewed.expires_at > ad.expires_at);
+        assert_eq!(renewed.title, ad.title);
+
+        // A second renewal within the cooldown is refused.
+        assert!(ad_repo
+            .renew(ad.id, "test@test.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_by_user() {
+        let ad_repo = test_repo();
+        let seller = format!("{}@test.com", uuid::Uuid::new_v4());
+        let other = format!("{}@test.com", uuid::Uuid::new_v4());
+        let content = |user_email: &str, draft: bool| AdContent {
+            user_email: user_email.to_string(),
+            draft,
+            ..ad_content("Profile")
+        };
+
+        let older = seed_ad(&*ad_repo, content(&seller, false)).await;
+        let newer = seed_ad(&*ad_repo, content(&seller, false)).await;
+        let draft = seed_ad(&*ad_repo, content(&seller, true)).await;
+        let others = seed_ad(&*ad_repo, content(&other, false)).await;
+
+        let ids = |(ads, total): (Vec<Ad>, u64)| (ads.iter().map(|ad| ad.id).collect(), total);
+        assert_eq!(
+            ids(ad_repo.get_by_user(&seller, false, 0, 10).await.unwrap()),
+            (vec![newer.id, older.id], 2)
+        );
+        assert_eq!(
+            ids(ad_repo.get_by_user(&seller, true, 0, 10).await.unwrap()),
+            (vec![draft.id, newer.id, older.id], 3)
+        );
+        assert_eq!(
+            ids(ad_repo.get_by_user(&seller, true, 1, 1).await.unwrap()),
+            (vec![newer.id], 3)
+        );
+        assert_eq!(
+            ids(ad_repo.get_by_user(&other, true, 0, 10).await.unwrap()),
+            (vec![others.id], 1)
+        );
+
+        ad_repo.delete(newer.id, &seller).await.unwrap();
+        assert_eq!(
+            ids(ad_repo.get_by_user(&seller, true, 0, 10).await.unwrap()),
+            (vec![draft.id, older.id], 2)
+        );
+        assert_eq!(
+            ids(ad_repo
+                .get_by_user("nobody@test.com", true, 0, 10)
+                .await
+                .unwrap()),
+            (vec![], 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_similar() {
+        let ad_repo = test_repo();
+        let seller = format!("{}@test.com", uuid::Uuid::new_v4());
+        let content = |title: &str, price: u32| AdContent {
+            user_email: seller.clone(),
+            price: price.into(),
+            ..ad_content(title)
+        };
+        let since = chrono::Utc::now() - chrono::Duration::hours(1);
+
+        seed_ad(&*ad_repo, content("Road bike", 100)).await;
+        let newer = seed_ad(&*ad_repo, content("Road Bike ", 100)).await;
+        seed_ad(&*ad_repo, content("Road bike", 120)).await;
+
+        let similar = ad_repo
+            .find_similar(&seller, " road bike", &100.into(), since)
+            .await
+            .unwrap();
+        assert_eq!(similar.map(|ad| ad.id), Some(newer.id));
+        for (user_email, title, price, since) in [
+            (seller.as_str(), "Road bike", 99, since),
+            (seller.as_str(), "Mountain bike", 100, since),
+            ("other@test.com", "Road bike", 100, since),
+            (seller.as_str(), "Road bike", 100, chrono::Utc::now()),
+        ] {
+            let similar = ad_repo
+                .find_similar(user_email, title, &price.into(), since)
+                .await
+                .unwrap();
+            assert!(similar.is_none(), "{} {}", title, price);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_many() {
+        let ad_repo = test_repo();
+        let ads = (0..50)
+            .map(|n| {
+                let content = AdContent {
+                    draft: n % 10 == 0,
+                    ..ad_content(&format!("Batch {}", n))
+                };
+                (content, vec![format!("image-{}", n)])
+            })
+            .collect();
+
+        let created = ad_repo.create_many(ads).await.unwrap();
+
+        assert_eq!(created.len(), 50);
+        for (n, ad) in created.iter().enumerate() {
+            assert_eq!(ad.title, format!("Batch {}", n));
+            assert_eq!(ad.images, vec![format!("image-{}", n)]);
+            assert_eq!(ad.slug, ad_slug(&ad.title, ad.id));
+            let status = if n % 10 == 0 { "draft" } else { "active" };
+            assert_eq!(ad.status, status);
+            let stored = ad_repo.get_by_id(ad.id, false).await.unwrap().unwrap();
+            assert_eq!(stored.title, ad.title);
+        }
+        assert!(ad_repo.create_many(Vec::new()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_promotion_ends() {
+        let ad_repo = test_repo();
+        let title = format!("Promoted {}", uuid::Uuid::new_v4());
+        let older = seed_ad(&*ad_repo, ad_content(&title)).await;
+        let newer = seed_ad(&*ad_repo, ad_content(&title)).await;
+        let filter = AdFilter {
+            title_contains: Some(title.clone()),
+            ..Default::default()
+        };
+        let ids = || async {
+            let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+            page.iter().map(|ad| ad.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids().await, vec![newer.id, older.id]);
+
+        let until = chrono::Utc::now() + chrono::Duration::hours(1);
+        assert!(ad_repo
+            .promote(older.id, "other@test.com", until)
+            .await
+            .unwrap()
+            .is_none());
+        let promoted = ad_repo
+            .promote(older.id, "test@test.com", until)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(promoted.top_ad);
+        assert_eq!(
+            promoted.top_ad_until.map(|until| until.timestamp_micros()),
+            Some(until.timestamp_micros())
+        );
+        assert_eq!(ad_repo.end_promotions().await.unwrap(), 0);
+        assert_eq!(ids().await, vec![older.id, newer.id]);
+        // Pages split across the promoted ads and the rest line up.
+        for (offset, expected) in [(0, older.id), (1, newer.id)] {
+            let page = ad_repo.get_page(offset, 1, filter.clone()).await.unwrap();
+            assert_eq!(page.iter().map(|ad| ad.id).collect::<Vec<_>>(), [expected]);
+        }
+
+        // Promoted again with an end date that has already passed: it no longer sorts
+        // first, even before the expiry job clears it.
+        let until = chrono::Utc::now() - chrono::Duration::seconds(1);
+        ad_repo
+            .promote(older.id, "test@test.com", until)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids().await, vec![newer.id, older.id]);
+        let cursor_name = ad_repo.new_cursor(filter.clone()).await.unwrap();
+        let page = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .unwrap();
+        let cursor_ids: Vec<_> = page.items.iter().map(|ad| ad.id).collect();
+        assert_eq!(cursor_ids, vec![newer.id, older.id]);
+        assert_eq!(ad_repo.end_promotions().await.unwrap(), 1);
+        assert_eq!(ids().await, vec![newer.id, older.id]);
+        let ended = ad_repo.get_by_id(older.id, false).await.unwrap().unwrap();
+        assert!(!ended.top_ad);
+        assert_eq!(ended.top_ad_until, None);
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_and_restore() {
+        let ad_repo = test_repo();
+        let title = format!("Soft deleted {}", uuid::Uuid::new_v4());
+
+        let ad = seed_ad(
+            &*ad_repo,
+            AdContent {
+                user_email: "owner@test.com".to_string(),
+                ..ad_content(&title)
+            },
+        )
+        .await;
+        let filter = AdFilter {
+            title_contains: Some(title),
+            ..Default::default()
+        };
+
+        assert!(ad_repo.exists(ad.id).await.unwrap());
+        assert!(!ad_repo.exists(AdId(-1)).await.unwrap());
+
+        // Live ads can't be restored or purged.
+        assert!(ad_repo.restore(ad.id).await.unwrap().is_none());
+        assert!(ad_repo.purge(ad.id).await.unwrap().is_none());
+
+        assert_eq!(ad_repo.delete(ad.id, "owner@test.com").await.unwrap(), 1);
+        assert_eq!(ad_repo.delete(ad.id, "owner@test.com").await.unwrap(), 0);
+        assert!(!ad_repo.exists(ad.id).await.unwrap());
+        assert!(ad_repo.get_by_id(ad.id, false).await.unwrap().is_none());
+        assert!(ad_repo.get_by_id(ad.id, true).await.unwrap().is_none());
+        assert!(ad_repo.get_by_ids(&[ad.id]).await.unwrap().is_empty());
+        assert!(ad_repo.get_owner(ad.id).await.unwrap().is_none());
+        assert!(ad_repo
+            .get_page(0, 10, filter.clone())
+            .await
+            .unwrap()
+            .is_empty());
+        let cursor_name = ad_repo.new_cursor(filter.clone()).await.unwrap();
+        assert!(ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .unwrap()
+            .items
+            .is_empty());
+
+        let with_deleted = AdFilter {
+            include_deleted: true,
+            ..filter.clone()
+        };
+        let page = ad_repo.get_page(0, 10, with_deleted).await.unwrap();
+        assert_eq!(page.len(), 1);
+        assert!(page[0].deleted_at.is_some());
+
+        let restored = ad_repo.restore(ad.id).await.unwrap().unwrap();
+        assert!(restored.deleted_at.is_none());
+        assert!(ad_repo.exists(ad.id).await.unwrap());
+        assert_eq!(ad_repo.get_page(0, 10, filter).await.unwrap().len(), 1);
+        assert!(ad_repo.get_by_id(ad.id, false).await.unwrap().is_some());
+
+        ad_repo.delete(ad.id, "owner@test.com").await.unwrap();
+        assert_eq!(
+            ad_repo.purge(ad.id).await.unwrap().map(|ad| ad.id),
+            Some(ad.id)
+        );
+        assert!(ad_repo.restore(ad.id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_has_images() {
+        let ad_repo = test_repo();
+        let title = format!("Photos {}", uuid::Uuid::new_v4());
+
+        let with_images = ad_repo
+            .create(ad_content(&title), vec![uuid::Uuid::new_v4().to_string()])
+            .await
+            .unwrap();
+        let without_images = seed_ad(&*ad_repo, ad_content(&title)).await;
+
+        for (has_images, expected) in [
+            (None, vec![with_images.id, without_images.id]),
+            (Some(true), vec![with_images.id]),
+            (Some(false), vec![without_images.id]),
+        ] {
+            let filter = AdFilter {
+                title_contains: Some(title.clone()),
+                has_images,
+                sort_by: Some(AdSort::CreatedAtAsc),
+                ..Default::default()
+            };
+
+            let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+            let ids: Vec<_> = page.iter().map(|ad| ad.id).collect();
+            assert_eq!(ids, expected, "{:?}", has_images);
+
+            let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+            let ids: Vec<_> = ad_repo
+                .fetch_from_cursor(cursor_name, 10, true, None)
+                .await
+                .unwrap()
+                .items
+                .iter()
+                .map(|ad| ad.id)
+                .collect();
+            assert_eq!(ids, expected, "{:?}", has_images);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_top_ad() {
+        let ad_repo = test_repo();
+        let title = format!("Featured {}", uuid::Uuid::new_v4());
+
+        let promoted = seed_ad(&*ad_repo, ad_content(&title)).await;
+        let until = chrono::Utc::now() + chrono::Duration::hours(1);
+        ad_repo
+            .promote(promoted.id, "test@test.com", until)
+            .await
+            .unwrap()
+            .unwrap();
+        let regular = seed_ad(&*ad_repo, ad_content(&title)).await;
+
+        for (top_ad, expected) in [
+            (None, vec![promoted.id, regular.id]),
+            (Some(true), vec![promoted.id]),
+            (Some(false), vec![regular.id]),
+        ] {
+            let filter = AdFilter {
+                title_contains: Some(title.clone()),
+                top_ad,
+                ..Default::default()
+            };
+
+            let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+            let ids: Vec<_> = page.iter().map(|ad| ad.id).collect();
+            assert_eq!(ids, expected, "{:?}", top_ad);
+
+            let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+            let ids: Vec<_> = ad_repo
+                .fetch_from_cursor(cursor_name, 10, true, None)
+                .await
+                .unwrap()
+                .items
+                .iter()
+                .map(|ad| ad.id)
+                .collect();
+            assert_eq!(ids, expected, "{:?}", top_ad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_negotiable() {
+        let ad_repo = test_repo();
+        let title = format!("Offers {}", uuid::Uuid::new_v4());
+
+        let negotiable = seed_ad(
+            &*ad_repo,
+            AdContent {
+                negotiable: true,
+                ..ad_content(&title)
+            },
+        )
+        .await;
+        assert!(negotiable.negotiable);
+        let fixed = seed_ad(&*ad_repo, ad_content(&title)).await;
+        assert!(!fixed.negotiable);
+
+        for (negotiable_eq, expected) in [
+            (None, vec![negotiable.id, fixed.id]),
+            (Some(true), vec![negotiable.id]),
+            (Some(false), vec![fixed.id]),
+        ] {
+            let filter = AdFilter {
+                title_contains: Some(title.clone()),
+                negotiable_eq,
+                sort_by: Some(AdSort::CreatedAtAsc),
+                ..Default::default()
+            };
+
+            let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+            let ids: Vec<_> = page.iter().map(|ad| ad.id).collect();
+            assert_eq!(ids, expected, "{:?}", negotiable_eq);
+            assert_eq!(
+                ad_repo.count(filter.clone()).await.unwrap(),
+                expected.len() as u64
+            );
+
+            let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+            let ids: Vec<_> = ad_repo
+                .fetch_from_cursor(cursor_name, 10, true, None)
+                .await
+                .unwrap()
+                .items
+                .iter()
+                .map(|ad| ad.id)
+                .collect();
+            assert_eq!(ids, expected, "{:?}", negotiable_eq);
+        }
+
+        let patched = ad_repo
+            .patch(
+                fixed.id,
+                "test@test.com",
+                AdPatch {
+                    negotiable: Some(true),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(patched.negotiable);
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_locale() {
+        let ad_repo = test_repo();
+        let title = format!("Locale {}", uuid::Uuid::new_v4());
+
+        let czech = seed_ad(
+            &*ad_repo,
+            AdContent {
+                locale: Locale::Cs,
+                ..ad_content(&title)
+            },
+        )
+        .await;
+        assert_eq!(czech.locale, "cs");
+        let english = seed_ad(&*ad_repo, ad_content(&title)).await;
+        assert_eq!(english.locale, "en");
+
+        for (locale_eq, expected) in [
+            (None, vec![czech.id, english.id]),
+            (Some(Locale::Cs), vec![czech.id]),
+            (Some(Locale::En), vec![english.id]),
+            (Some(Locale::De), vec![]),
+        ] {
+            let filter = AdFilter {
+                title_contains: Some(title.clone()),
+                locale_eq,
+                sort_by: Some(AdSort::CreatedAtAsc),
+                ..Default::default()
+            };
+
+            let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+            let ids: Vec<_> = page.iter().map(|ad| ad.id).collect();
+            assert_eq!(ids, expected, "{:?}", locale_eq);
+            assert_eq!(
+                ad_repo.count(filter.clone()).await.unwrap(),
+                expected.len() as u64
+            );
+
+            let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+            let ids: Vec<_> = ad_repo
+                .fetch_from_cursor(cursor_name, 10, true, None)
+                .await
+                .unwrap()
+                .items
+                .iter()
+                .map(|ad| ad.id)
+                .collect();
+            assert_eq!(ids, expected, "{:?}", locale_eq);
+        }
+
+        let patched = ad_repo
+            .patch(
+                english.id,
+                "test@test.com",
+                AdPatch {
+                    locale: Some(Locale::De),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(patched.locale, "de");
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_user() {
+        let ad_repo = test_repo();
+        let title = format!("User {}", uuid::Uuid::new_v4());
+        let seller = format!("{}@test.com", uuid::Uuid::new_v4());
+
+        let own = seed_ad(
+            &*ad_repo,
+            AdContent {
+                user_email: seller.clone(),
+                user_phone: "555000111".to_string(),
+                ..ad_content(&title)
+            },
+        )
+        .await;
+        let other = seed_ad(&*ad_repo, ad_content(&title)).await;
+
+        for (filter, expected) in [
+            (AdFilter::builder().user_email(seller.clone()), vec![own.id]),
+            // Exact, unlike `title_contains`.
+            (
+                AdFilter::builder().user_email(seller.to_uppercase()),
+                vec![],
+            ),
+            (AdFilter::builder().user_email("test.com"), vec![]),
+            (AdFilter::builder().user_phone("555000111"), vec![own.id]),
+            (
+                AdFilter::builder().user_email("test@test.com"),
+                vec![other.id],
+            ),
+        ] {
+            let filter = filter
+                .title_contains(&title)
+                .sort_by(AdSort::CreatedAtAsc)
+                .build()
+                .unwrap();
+            assert!(filter.needs_admin());
+
+            let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+            let ids: Vec<_> = page.iter().map(|ad| ad.id).collect();
+            assert_eq!(ids, expected);
+            assert_eq!(
+                ad_repo.count(filter.clone()).await.unwrap(),
+                expected.len() as u64
+            );
+
+            let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+            let ids: Vec<_> = ad_repo
+                .fetch_from_cursor(cursor_name, 10, true, None)
+                .await
+                .unwrap()
+                .items
+                .iter()
+                .map(|ad| ad.id)
+                .collect();
+            assert_eq!(ids, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_phone() {
+        let ad_repo = test_repo();
+        let title = format!("Verify {}", uuid::Uuid::new_v4());
+        let ad = seed_ad(&*ad_repo, ad_content(&title)).await;
+        let other = seed_ad(&*ad_repo, ad_content(&title)).await;
+        assert!(!ad.phone_verified);
+
+        assert_eq!(
+            ad_repo
+                .start_phone_verification(ad.id, "someone@else.com", "123456")
+                .await
+                .unwrap(),
+            None
+        );
+        let expires_at = ad_repo
+            .start_phone_verification(ad.id, "test@test.com", "123456")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(expires_at > chrono::Utc::now());
+
+        // A wrong code, or the right one from another user, doesn't verify.
+        for (user_email, code) in [("test@test.com", "654321"), ("someone@else.com", "123456")] {
+            let verified = ad_repo.verify_phone(ad.id, user_email, code).await.unwrap();
+            assert!(verified.is_none(), "{} {}", user_email, code);
+        }
+        let verified = ad_repo
+            .verify_phone(ad.id, "test@test.com", "123456")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(verified.phone_verified);
+        // Used up.
+        assert!(ad_repo
+            .verify_phone(ad.id, "test@test.com", "123456")
+            .await
+            .unwrap()
+            .is_none());
+
+        for (verified, expected) in [(true, ad.id), (false, other.id)] {
+            let filter = AdFilter {
+                title_contains: Some(title.clone()),
+                phone_verified_eq: Some(verified),
+                ..Default::default()
+            };
+            let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+            let ids: Vec<_> = page.iter().map(|ad| ad.id).collect();
+            assert_eq!(ids, [expected], "{}", verified);
+
+            let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+            let page = ad_repo
+                .fetch_from_cursor(cursor_name, 10, true, None)
+                .await
+                .unwrap();
+            let ids: Vec<_> = page.items.iter().map(|ad| ad.id).collect();
+            assert_eq!(ids, [expected], "{}", verified);
+        }
+
+        // Too many wrong codes drop the pending one.
+        ad_repo
+            .start_phone_verification(other.id, "test@test.com", "111111")
+            .await
+            .unwrap()
+            .unwrap();
+        for _ in 0..MAX_PHONE_CODE_ATTEMPTS {
+            let verified = ad_repo.verify_phone(other.id, "test@test.com", "000000");
+            assert!(verified.await.unwrap().is_none());
+        }
+        assert!(ad_repo
+            .verify_phone(other.id, "test@test.com", "111111")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Keeping the number keeps it verified; a new one has to be verified again.
+        for (phone, verified) in [("1234567890", true), ("+421 900 000 000", false)] {
+            let patched = ad_repo
+                .patch(
+                    ad.id,
+                    "test@test.com",
+                    AdPatch {
+                        user_phone: Some(phone.to_string()),
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(patched.phone_verified, verified, "{}", phone);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pages_of_ads_inserted_together() {
+        let ad_repo = test_repo();
+        let title = format!("Tie {}", uuid::Uuid::new_v4());
+        // Inserted in one statement, so they share `created_at` and `updated_at`.
+        let seeded = seed_ads(&*ad_repo, (0..6).map(|_| ad_content(&title)).collect()).await;
+        assert!(seeded
+            .iter()
+            .all(|ad| ad.created_at == seeded[0].created_at));
+        let mut expected: Vec<_> = seeded.iter().map(|ad| ad.id).collect();
+        expected.sort_by_key(|id| std::cmp::Reverse(id.0));
+
+        for sort in [
+            AdSort::CreatedAtDesc,
+            AdSort::CreatedAtAsc,
+            AdSort::UpdatedAtDesc,
+            AdSort::PriceAsc,
+            AdSort::PriceDesc,
+        ] {
+            let filter = AdFilter {
+                title_contains: Some(title.clone()),
+                sort_by: Some(sort),
+                ..Default::default()
+            };
+
+            let mut ids = Vec::new();
+            for offset in [0, 3] {
+                let page = ad_repo.get_page(offset, 3, filter.clone()).await.unwrap();
+                ids.extend(page.iter().map(|ad| ad.id));
+            }
+            assert_eq!(ids, expected, "{:?}", sort);
+
+            let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let page = ad_repo
+                    .fetch_from_cursor(cursor_name.clone(), 3, false, None)
+                    .await
+                    .unwrap();
+                ids.extend(page.items.iter().map(|ad| ad.id));
+            }
+            ad_repo.close_cursor(cursor_name).await.unwrap();
+            assert_eq!(ids, expected, "{:?}", sort);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_page_after() {
+        let ad_repo = test_repo();
+        let title = format!("Seek {}", uuid::Uuid::new_v4());
+
+        for _ in 0..5 {
+            seed_ad(&*ad_repo, ad_content(&title)).await;
+        }
+
+        let filter = AdFilter {
+            title_contains: Some(title),
+            ..Default::default()
+        };
+
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let page = ad_repo
+                .get_page_after(after, 2, filter.clone())
+                .await
+                .expect("Failed to get page");
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().map(AdKeyset::from);
+            seen.extend(page.iter().map(AdKeyset::from));
+        }
+
+        assert_eq!(seen.len(), 5);
+        assert!(seen
+            .windows(2)
+            .all(|w| (w[0].created_at, w[0].id) > (w[1].created_at, w[1].id)));
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let ad_repo = test_repo();
+        let word = format!("srch{}", uuid::Uuid::new_v4().simple());
+
+        for (title, description) in [
+            ("Plain Ad".to_string(), format!("Mentions {}", word)),
+            (format!("{} for sale", word), format!("Great {}", word)),
+        ] {
+            seed_ad(
+                &*ad_repo,
+                AdContent {
+                    description,
+                    ..ad_content(&title)
+                },
+            )
+            .await;
+        }
+
+        let filter = AdFilter {
+            search: Some(word.clone()),
+            title_contains: Some("no such title".to_string()),
+            ..Default::default()
+        };
+
+        let page = ad_repo
+            .get_page(0, 10, filter.clone())
+            .await
+            .expect("Failed to get page");
+        assert_eq!(page.len(), 2);
+        assert!(page[0].title.contains(&word));
+
+        let cursor_name = ad_repo
+            .new_cursor(filter)
+            .await
+            .expect("Failed to get cursor");
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .expect("Failed to fetch from cursor")
+            .items;
+        assert_eq!(ads.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cursor_lifecycle() {
+        let ad_repo = PostgresAdRepo::new(shared_db());
+        let title = format!("Cursor {}", uuid::Uuid::new_v4());
+
+        for _ in 0..3 {
+            seed_ad(&*ad_repo, ad_content(&title)).await;
+        }
+
+        let filter = AdFilter {
+            title_contains: Some(title),
+            ..Default::default()
+        };
+
+        let cursor_name = ad_repo
+            .new_cursor(filter.clone())
+            .await
+            .expect("Failed to get cursor");
+        let page = ad_repo
+            .fetch_from_cursor(cursor_name.clone(), 2, true, None)
+            .await
+            .expect("Failed to fetch from cursor");
+        assert_eq!(page.items.len(), 2);
+        assert!(page.has_more);
+        assert_eq!(page.cursor.as_ref(), Some(&cursor_name));
+
+        // The short page exhausts the cursor, which is then closed.
+        let page = ad_repo
+            .fetch_from_cursor(cursor_name.clone(), 2, true, None)
+            .await
+            .expect("Failed to fetch from cursor");
+        assert_eq!(page.items.len(), 1);
+        assert!(!page.has_more);
+        assert_eq!(page.cursor, None);
+        assert!(matches!(
+            ad_repo
+                .fetch_from_cursor(cursor_name.clone(), 2, true, None)
+                .await,
+            Err(RepoError::CursorExpired)
+        ));
+        // Closing it again is a no-op.
+        ad_repo.close_cursor(cursor_name).await.unwrap();
+
+        let cursor_name = ad_repo
+            .new_cursor(filter)
+            .await
+            .expect("Failed to get cursor");
+        ad_repo
+            .close_cursor(cursor_name.clone())
+            .await
+            .expect("Failed to close cursor");
+        assert!(matches!(
+            ad_repo.fetch_from_cursor(cursor_name, 2, false, None).await,
+            Err(RepoError::CursorExpired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cursor_stays_on_its_connection() {
+        let ad_repo = PostgresAdRepo::new(shared_db());
+        let title = format!("Pinned {}", uuid::Uuid::new_v4());
+        for _ in 0..3 {
+            seed_ad(&*ad_repo, ad_content(&title)).await;
+        }
+        let filter = AdFilter {
+            title_contains: Some(title),
+            ..Default::default()
+        };
+
+        let cursor_name = ad_repo.new_cursor(filter.clone()).await.unwrap();
+        // Busy pooled connections, as under load, don't take the cursor with them.
+        let pool = ad_repo.db_manager.get_write_pool();
+        let held: Vec<_> = (0..3).map(|_| pool.get().unwrap()).collect();
+        let other = ad_repo.new_cursor(filter).await.unwrap();
+
+        for cursor_name in [&cursor_name, &other] {
+            let page = ad_repo
+                .fetch_from_cursor(cursor_name.clone(), 2, false, None)
+                .await
+                .unwrap();
+            assert_eq!(page.items.len(), 2);
+        }
+        drop(held);
+        let page = ad_repo
+            .fetch_from_cursor(cursor_name.clone(), 2, true, None)
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.cursor, None);
+
+        ad_repo.close_cursor(other.clone()).await.unwrap();
+        assert!(matches!(
+            ad_repo.fetch_from_cursor(other, 2, false, None).await,
+            Err(RepoError::CursorExpired)
+        ));
+    }
+
+    #[test]
+    fn test_validate_cursor_name() {
+        assert!(validate_cursor_name("c_0123456789").is_ok());
+        assert!(validate_cursor_name("c_abcdef0123").is_ok());
+
+        for name in [
+            "",
+            "c_",
+            "c_ABCDEF0123",
+            "c_0123456789a",
+            "d_0123456789",
+            "c_012345678g",
+            "c_0123456789; DROP TABLE ads; --",
+        ] {
+            assert!(
+                matches!(validate_cursor_name(name), Err(RepoError::Validation(_))),
+                "{:?} should be rejected",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_malicious_cursor_name() {
+        let ad_repo = test_repo();
+        let name = "c_0; DROP TABLE ads; --".to_string();
+
+        assert!(matches!(
+            ad_repo
+                .fetch_from_cursor(name.clone(), 2, false, None)
+                .await,
+            Err(RepoError::Validation(_))
+        ));
+        assert!(matches!(
+            ad_repo.close_cursor(name).await,
+            Err(RepoError::Validation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        use crate::db::schema::ads;
+        use diesel::prelude::*;
+
+        let ad_repo = test_repo();
+        let title = format!("Expiring {}", uuid::Uuid::new_v4());
+
+        let ad = seed_ad(&*ad_repo, ad_content(&title)).await;
+        assert_eq!(
+            ad.expires_at,
+            Some(ad.created_at + chrono::Duration::days(super::AD_LIFETIME_DAYS))
+        );
+
+        diesel::update(ads::table.find(ad.id))
+            .set(ads::expires_at.eq(chrono::Utc::now() - chrono::Duration::hours(1)))
+            .execute(&mut ad_repo.db_manager.get_write_pool().get().unwrap())
+            .expect("Failed to backdate ad");
+
+        let filter = AdFilter {
+            title_contains: Some(title),
+            ..Default::default()
+        };
+        assert_eq!(ad_repo.count(filter.clone()).await.unwrap(), 0);
+
+        let cursor_name = ad_repo.new_cursor(filter.clone()).await.unwrap();
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .unwrap()
+            .items;
+        assert!(ads.is_empty());
+
+        let filter = AdFilter {
+            include_expired: true,
+            ..filter
+        };
+        assert_eq!(ad_repo.count(filter.clone()).await.unwrap(), 1);
+
+        assert!(ad_repo.mark_expired().await.unwrap() >= 1);
+        let ad = ad_repo.get_by_id(ad.id, false).await.unwrap().unwrap();
+        assert_eq!(ad.status, AdStatus::Expired.as_str());
+        assert_eq!(ad_repo.count(filter).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_stale_cursors() {
+        let ad_repo = PostgresAdRepo::new(shared_db());
+
+        let cursor_name = ad_repo
+            .new_cursor(AdFilter::default())
+            .await
+            .expect("Failed to get cursor");
+
+        assert_eq!(
+            ad_repo
+                .close_stale_cursors(std::time::Duration::from_secs(3600))
+                .await
+                .expect("Failed to close stale cursors"),
+            0
+        );
+
+        let closed = ad_repo
+            .close_stale_cursors(std::time::Duration::ZERO)
+            .await
+            .expect("Failed to close stale cursors");
+        assert!(closed >= 1);
+        assert!(ad_repo
+            .fetch_from_cursor(cursor_name, 2, false, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_open_cursors() {
+        let ad_repo = PostgresAdRepo::with_max_open_cursors(test_db(), 2);
+        let title = format!("Cursors {}", uuid::Uuid::new_v4());
+        seed_ad(&*ad_repo, ad_content(&title)).await;
+        let filter = || AdFilter {
+            title_contains: Some(title.clone()),
+            ..Default::default()
+        };
+
+        let mut cursors = Vec::new();
+        for _ in 0..3 {
+            cursors.push(ad_repo.new_cursor(filter()).await.unwrap());
+        }
+
+        for cursor_name in &cursors[1..] {
+            let page = ad_repo
+                .fetch_from_cursor(cursor_name.clone(), 1, false, None)
+                .await
+                .unwrap();
+            assert_eq!(page.items.len(), 1);
+        }
+        // Declaring the third closed the first. Checked last, as the failed fetch aborts
+        // the test transaction.
+        assert!(matches!(
+            ad_repo
+                .fetch_from_cursor(cursors[0].clone(), 1, false, None)
+                .await,
+            Err(RepoError::CursorExpired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_close_idle_cursors() {
+        let ad_repo = test_repo();
+        let used = ad_repo.new_cursor(AdFilter::default()).await.unwrap();
+        let idle = ad_repo.new_cursor(AdFilter::default()).await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        ad_repo
+            .fetch_from_cursor(used.clone(), 1, false, None)
+            .await
+            .unwrap();
+
+        let closed = ad_repo
+            .close_stale_cursors(std::time::Duration::from_millis(40))
+            .await
+            .unwrap();
+        assert_eq!(closed, 1);
+        assert!(ad_repo
+            .fetch_from_cursor(used, 1, false, None)
+            .await
+            .is_ok());
+        assert!(matches!(
+            ad_repo.fetch_from_cursor(idle, 1, false, None).await,
+            Err(RepoError::CursorExpired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_patch_only_touches_given_fields() {
+        let ad_repo = test_repo();
+
+        let ad = seed_ad(
+            &*ad_repo,
+            AdContent {
+                user_email: "owner@test.com".to_string(),
+                ..ad_content("Patched Ad")
+            },
+        )
+        .await;
+
+        let changes = || AdPatch {
+            price: Some(80.into()),
+            ..Default::default()
+        };
+
+        let patched = ad_repo
+            .patch(ad.id, "intruder@test.com", changes(), None)
+            .await
+            .expect("Failed to patch ad");
+        assert!(patched.is_none());
+
+        let patched = ad_repo
+            .patch(ad.id, "owner@test.com", changes(), None)
+            .await
+            .expect("Failed to patch ad")
+            .expect("Ad should match");
+
+        assert_eq!(patched.price, 80.into());
+        assert_eq!(patched.title, ad.title);
+        assert_eq!(patched.description, ad.description);
+        assert_eq!(patched.status, ad.status);
+        assert!(patched.updated_at > ad.updated_at);
+    }
+
+    #[tokio::test]
+    async fn test_update_replaces_content() {
+        let ad_repo = test_repo();
+        let ad = seed_ad(
+            &*ad_repo,
+            AdContent {
+                latitude: Some(48.15),
+                longitude: Some(17.11),
+                ..ad_content("Updated Ad")
+            },
+        )
+        .await;
+
+        let content = || AdContent {
+            price: 80.into(),
+            ..ad_content("Replaced Ad")
+        };
+        let updated = ad_repo
+            .update(ad.id, "intruder@test.com", content())
+            .await
+            .unwrap();
+        assert!(updated.is_none());
+
+        let updated = ad_repo
+            .update(ad.id, &ad.user_email, content())
+            .await
+            .unwrap()
+            .expect("Ad should match");
+        assert_eq!(updated.title, "Replaced Ad");
+        assert_eq!(updated.slug, format!("replaced-ad-{}", ad.id));
+        assert_eq!(updated.price, 80.into());
+        assert_eq!(updated.status, ad.status);
+        assert_eq!(updated.created_at, ad.created_at);
+        // A replacement without a location clears it, unlike a patch.
+        assert_eq!((updated.latitude, updated.longitude), (None, None));
+
+        let found = ad_repo.get_by_slug(&ad.slug).await.unwrap().unwrap();
+        assert_eq!(found.id, ad.id);
+        let history = ad_repo.price_history(ad.id).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].price, 80.into());
+    }
+
+    #[tokio::test]
+    async fn test_price_history() {
+        let ad_repo = test_repo();
+        let ad = seed_ad(&*ad_repo, ad_content("Price history")).await;
+        let patch = |changes: AdPatch| {
+            let ad_repo = ad_repo.clone();
+            async move {
+                ad_repo
+                    .patch(ad.id, "test@test.com", changes, None)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+
+        let first = patch(AdPatch {
+            price: Some(90.into()),
+            ..Default::default()
+        })
+        .await;
+        // Neither other fields nor the same price again are changes.
+        patch(AdPatch {
+            title: Some("Still 90".to_string()),
+            ..Default::default()
+        })
+        .await;
+        patch(AdPatch {
+            price: Some("90.00".parse().unwrap()),
+            ..Default::default()
+        })
+        .await;
+        let second = patch(AdPatch {
+            price: Some(75.into()),
+            ..Default::default()
+        })
+        .await;
+
+        let history = ad_repo.price_history(ad.id).await.unwrap();
+        assert_eq!(
+            history,
+            vec![
+                PriceChange {
+                    price: 90.into(),
+                    currency: ad.currency.clone(),
+                    changed_at: first.updated_at,
+                },
+                PriceChange {
+                    price: 75.into(),
+                    currency: ad.currency.clone(),
+                    changed_at: second.updated_at,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patch_rejects_stale_version() {
+        let ad_repo = test_repo();
+        let ad = seed_ad(&*ad_repo, ad_content("Versioned")).await;
+        let changes = |price: u32| AdPatch {
+            price: Some(price.into()),
+            ..Default::default()
+        };
+
+        // Both editors loaded the same version; only the first to write gets through.
+        let first = ad_repo
+            .patch(ad.id, &ad.user_email, changes(90), Some(ad.updated_at))
+            .await
+            .unwrap()
+            .expect("First update should apply");
+        let second = ad_repo
+            .patch(ad.id, &ad.user_email, changes(80), Some(ad.updated_at))
+            .await
+            .unwrap();
+        assert!(second.is_none());
+
+        let stored = ad_repo.get_by_id(ad.id, false).await.unwrap().unwrap();
+        assert_eq!(stored.price, 90.into());
+        assert_eq!(stored.etag(), first.etag());
+        assert_ne!(first.etag(), ad.etag());
+        assert_eq!(parse_etag(&first.etag()), Some(first.updated_at));
+
+        let third = ad_repo
+            .patch(ad.id, &ad.user_email, changes(80), Some(first.updated_at))
+            .await
+            .unwrap();
+        assert_eq!(third.unwrap().price, 80.into());
+    }
+
+    #[tokio::test]
+    async fn test_slug_follows_title() {
+        let ad_repo = test_repo();
+        let ad = seed_ad(&*ad_repo, ad_content("Mountain bike, Specialized!")).await;
+        assert_eq!(ad.slug, format!("mountain-bike-specialized-{}", ad.id));
+
+        let found = ad_repo.get_by_slug(&ad.slug).await.unwrap().unwrap();
+        assert_eq!(found.id, ad.id);
+
+        let renamed = ad_repo
+            .patch(
+                ad.id,
+                &ad.user_email,
+                AdPatch {
+                    title: Some("Road bike".to_string()),
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(renamed.slug, format!("road-bike-{}", ad.id));
+
+        // The old slug still leads to the ad, which now carries the new one.
+        for slug in [&ad.slug, &renamed.slug] {
+            let found = ad_repo.get_by_slug(slug).await.unwrap().unwrap();
+            assert_eq!(found.slug, renamed.slug);
+        }
+        assert!(ad_repo.get_by_slug("road-bike").await.unwrap().is_none());
+
+        ad_repo.delete(ad.id, &ad.user_email).await.unwrap();
+        assert!(ad_repo.get_by_slug(&ad.slug).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rolls_back_on_error() {
+        use crate::db::schema::ads;
+        use diesel::prelude::*;
+
+        let ad_repo = test_repo();
+        let title = format!("Rolled back {}", uuid::Uuid::new_v4());
+
+        let result: Result<(), RepoError> = ad_repo.db_manager.transaction(|conn| {
+            for _ in 0..2 {
+                diesel::insert_into(ads::table)
+                    .values((
+                        ads::title.eq(&title),
+                        ads::description.eq("Test Description"),
+                        ads::price.eq(bigdecimal::BigDecimal::from(100)),
+                        ads::status.eq("active"),
+                        ads::user_email.eq("test@test.com"),
+                        ads::user_phone.eq("1234567890"),
+                        ads::created_at.eq(chrono::Utc::now()),
+                        ads::updated_at.eq(chrono::Utc::now()),
+                        ads::slug.eq(uuid::Uuid::new_v4().to_string()),
+                    ))
+                    .execute(conn)?;
+            }
+            Err(RepoError::Conflict("abort".to_string()))
+        });
+        assert!(matches!(result, Err(RepoError::Conflict(_))));
+
+        let count: i64 = ads::table
+            .filter(ads::title.eq(&title))
+            .count()
+            .get_result(&mut ad_repo.db_manager.get_write_pool().get().unwrap())
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_with_transaction() {
+        use diesel::prelude::*;
+
+        use crate::db::schema::outbox;
+        use crate::notify::ContactMessage;
+        use crate::repos::outbox_repo::enqueue_on;
+
+        let ad_repo = test_repo();
+        let title = format!("Transaction {}", uuid::Uuid::new_v4());
+        let repriced = || AdPatch {
+            price: Some(80.into()),
+            ..AdPatch::default()
+        };
+        let by_title = || AdFilter {
+            title_contains: Some(title.clone()),
+            ..Default::default()
+        };
+        let message = ContactMessage {
+            to: "test@test.com".to_string(),
+            reply_to: "buyer@test.com".to_string(),
+            subject: title.clone(),
+            body: "Still available?".to_string(),
+        };
+        let queued = || {
+            outbox::table
+                .filter(outbox::payload.eq(serde_json::to_value(&message).unwrap()))
+                .count()
+                .get_result::<i64>(&mut ad_repo.db_manager.write_conn().unwrap())
+                .unwrap()
+        };
+
+        let result: Result<(), RepoError> = ad_repo.with_transaction(|tx| {
+            let ad = tx.create(ad_content(&title), vec![])?;
+            tx.patch(ad.id, "test@test.com", repriced(), None)?;
+            enqueue_on(tx.conn(), &message)?;
+            Err(RepoError::Conflict("abort".to_string()))
+        });
+        assert!(matches!(result, Err(RepoError::Conflict(_))));
+        assert_eq!(ad_repo.count(by_title()).await.unwrap(), 0);
+        assert_eq!(queued(), 0);
+
+        let ad = ad_repo
+            .with_transaction(|tx| {
+                let ad = tx.create(ad_content(&title), vec![])?;
+                let ad = tx.patch(ad.id, "test@test.com", repriced(), None)?;
+                enqueue_on(tx.conn(), &message)?;
+                Ok(ad)
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(ad.price, 80.into());
+        assert_eq!(ad_repo.count(by_title()).await.unwrap(), 1);
+        assert_eq!(ad_repo.price_history(ad.id).await.unwrap().len(), 1);
+        assert_eq!(queued(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_category() {
+        let ad_repo = test_repo();
+        let title = format!("Category {}", uuid::Uuid::new_v4());
+
+        for category in [AdCategory::Vehicles, AdCategory::Vehicles, AdCategory::Jobs] {
+            seed_ad(
+                &*ad_repo,
+                AdContent {
+                    category,
+                    ..ad_content(&title)
+                },
+            )
+            .await;
+        }
+
+        let filter = AdFilter {
+            title_contains: Some(title),
+            category_eq: Some(AdCategory::Vehicles),
+            ..Default::default()
+        };
+
+        let page = ad_repo
+            .get_page(0, 10, filter.clone())
+            .await
+            .expect("Failed to get page");
+        assert_eq!(page.len(), 2);
+        assert!(page.iter().all(|ad| ad.category == "vehicles"));
+
+        let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .unwrap()
+            .items;
+        assert_eq!(ads.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_currency() {
+        let ad_repo = test_repo();
+        let title = format!("Currency {}", uuid::Uuid::new_v4());
+
+        for (price, currency) in [
+            (50, Currency::Eur),
+            (50, Currency::Czk),
+            (5000, Currency::Czk),
+        ] {
+            seed_ad(
+                &*ad_repo,
+                AdContent {
+                    price: price.into(),
+                    currency,
+                    ..ad_content(&title)
+                },
+            )
+            .await;
+        }
+
+        // Price bounds alone ignore the currency.
+        let filter = AdFilter {
+            title_contains: Some(title),
+            price_lt: Some(100.into()),
+            ..Default::default()
+        };
+        let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+        assert_eq!(page.len(), 2);
+
+        let filter = AdFilter {
+            currency_eq: Some(Currency::Czk),
+            ..filter
+        };
+        let page = ad_repo.get_page(0, 10, filter.clone()).await.unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].currency, "CZK");
+
+        let cursor_name = ad_repo.new_cursor(filter).await.unwrap();
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .unwrap()
+            .items;
+        assert_eq!(ads.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_price_range() {
+        let ad_repo = test_repo();
+        let title = format!("Priced {}", uuid::Uuid::new_v4());
+
+        for price in [10, 20, 30] {
+            seed_ad(
+                &*ad_repo,
+                AdContent {
+                    price: price.into(),
+                    ..ad_content(&title)
+                },
+            )
+            .await;
+        }
+
+        let prices = |ads: Vec<crate::models::ad::Ad>| {
+            let mut prices: Vec<_> = ads.into_iter().map(|ad| ad.price.to_string()).collect();
+            prices.sort();
+            prices
+        };
+        let by_title = AdFilter {
+            title_contains: Some(title),
+            ..Default::default()
+        };
+
+        // Both exclusive bounds set: an open interval.
+        let open = AdFilter {
+            price_gt: Some(10.into()),
+            price_lt: Some(30.into()),
+            ..by_title.clone()
+        };
+        let page = ad_repo.get_page(0, 10, open).await.unwrap();
+        assert_eq!(prices(page), vec!["20.00"]);
+
+        let between = AdFilter {
+            price_between: Some((10.into(), 20.into())),
+            ..by_title
+        };
+        assert!(between.validate().is_ok());
+        let page = ad_repo.get_page(0, 10, between.clone()).await.unwrap();
+        assert_eq!(prices(page), vec!["10.00", "20.00"]);
+        assert_eq!(ad_repo.count(between.clone()).await.unwrap(), 2);
+
+        let cursor_name = ad_repo.new_cursor(between).await.unwrap();
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .unwrap()
+            .items;
+        assert_eq!(prices(ads), vec!["10.00", "20.00"]);
+    }
+
+    #[test]
+    fn test_validate_contradictory_ranges() {
+        let fields = |filter: AdFilter| {
+            filter
+                .validate()
+                .err()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|e| e.field)
+                .collect::<Vec<_>>()
+        };
+        let now = chrono::Utc::now();
+
+        assert_eq!(
+            fields(AdFilter {
+                price_gt: Some(20.into()),
+                price_lt: Some(10.into()),
+                ..Default::default()
+            }),
+            vec!["price_gt"]
+        );
+        assert!(fields(AdFilter {
+            price_gt: Some(10.into()),
+            price_lt: Some(10.into()),
+            ..Default::default()
+        })
+        .is_empty());
+        assert_eq!(
+            fields(AdFilter {
+                price_between: Some((20.into(), 10.into())),
+                ..Default::default()
+            }),
+            vec!["price_between"]
+        );
+        assert_eq!(
+            fields(AdFilter {
+                updated_at_gt: Some(now),
+                updated_at_lt: Some(now - chrono::Duration::days(1)),
+                ..Default::default()
+            }),
+            vec!["updated_at_gt"]
+        );
+        assert_eq!(
+            fields(AdFilter {
+                created_at_gt: Some(now),
+                created_at_lt: Some(now - chrono::Duration::days(1)),
+                ..Default::default()
+            }),
+            vec!["created_at_gt"]
+        );
+    }
+
+    #[test]
+    fn test_price_between_from_json() {
+        let filter: AdFilter = serde_json::from_str(r#"{"price_between": [10, "20.5"]}"#).unwrap();
+        assert_eq!(
+            filter.price_between,
+            Some((10.into(), "20.5".parse().unwrap()))
+        );
+
+        let filter: AdFilter = serde_json::from_str(r#"{"price_between": null}"#).unwrap();
+        assert_eq!(filter.price_between, None);
+
+        assert!(serde_json::from_str::<AdFilter>(r#"{"price_between": [10]}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_filter_by_created_at() {
+        use crate::db::schema::ads;
+        use diesel::prelude::*;
+
+        let ad_repo = test_repo();
+        let title = format!("Posted {}", uuid::Uuid::new_v4());
+        let now = chrono::Utc::now();
+
+        for days_ago in [1, 5, 10] {
+            let ad = seed_ad(
+                &*ad_repo,
+                AdContent {
+                    description: format!("{} days ago", days_ago),
+                    ..ad_content(&title)
+                },
+            )
+            .await;
+            diesel::update(ads::table.find(ad.id))
+                .set(ads::created_at.eq(now - chrono::Duration::days(days_ago)))
+                .execute(&mut ad_repo.db_manager.get_write_pool().get().unwrap())
+                .expect("Failed to backdate ad");
+        }
+
+        let descriptions = |ads: Vec<crate::models::ad::Ad>| {
+            ads.into_iter().map(|ad| ad.description).collect::<Vec<_>>()
+        };
+        let this_week = AdFilter {
+            title_contains: Some(title),
+            created_at_gt: Some(now - chrono::Duration::days(7)),
+            created_at_lt: Some(now - chrono::Duration::days(2)),
+            ..Default::default()
+        };
+        assert!(this_week.validate().is_ok());
+
+        let page = ad_repo.get_page(0, 10, this_week.clone()).await.unwrap();
+        assert_eq!(descriptions(page), vec!["5 days ago"]);
+        assert_eq!(ad_repo.count(this_week.clone()).await.unwrap(), 1);
+
+        let cursor_name = ad_repo.new_cursor(this_week).await.unwrap();
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .unwrap()
+            .items;
+        assert_eq!(descriptions(ads), vec!["5 days ago"]);
+    }
+
+    #[tokio::test]
+    async fn test_radius_search() {
+        let ad_repo = test_repo();
+        let title = format!("Located {}", uuid::Uuid::new_v4());
+
+        // Bratislava, Vienna (~55 km away), Budapest (~160 km away) and nowhere.
+        for (city, location) in [
+            ("budapest", Some((47.4979, 19.0402))),
+            ("vienna", Some((48.2082, 16.3738))),
+            ("nowhere", None),
+            ("bratislava", Some((48.1486, 17.1077))),
+        ] {
+            seed_ad(
+                &*ad_repo,
+                AdContent {
+                    description: city.to_string(),
+                    latitude: location.map(|(lat, _)| lat),
+                    longitude: location.map(|(_, lon)| lon),
+                    ..ad_content(&title)
+                },
+            )
+            .await;
+        }
+
+        let cities = |ads: Vec<crate::models::ad::Ad>| {
+            ads.into_iter().map(|ad| ad.description).collect::<Vec<_>>()
+        };
+        let near_bratislava = AdFilter {
+            title_contains: Some(title),
+            near_lat: Some(48.1486),
+            near_lon: Some(17.1077),
+            sort_by: Some(AdSort::DistanceAsc),
+            ..Default::default()
+        };
+
+        let page = ad_repo
+            .get_page(0, 10, near_bratislava.clone())
+            .await
+            .expect("Failed to get page");
+        assert_eq!(
+            cities(page),
+            vec!["bratislava", "vienna", "budapest", "nowhere"]
+        );
+
+        let within_100_km = AdFilter {
+            radius_km: Some(100.0),
+            ..near_bratislava
+        };
+        assert!(within_100_km.validate().is_ok());
+
+        let page = ad_repo
+            .get_page(0, 10, within_100_km.clone())
+            .await
+            .expect("Failed to get page");
+        assert_eq!(cities(page), vec!["bratislava", "vienna"]);
+        assert_eq!(ad_repo.count(within_100_km.clone()).await.unwrap(), 2);
+
+        let cursor_name = ad_repo.new_cursor(within_100_km).await.unwrap();
+        let ads = ad_repo
+            .fetch_from_cursor(cursor_name, 10, true, None)
+            .await
+            .unwrap()
+            .items;
+        assert_eq!(cities(ads), vec!["bratislava", "vienna"]);
+    }
+
+    #[test]
+    fn test_validate_location_filter() {
+        let fields = |filter: AdFilter| {
+            filter
+                .validate()
+                .unwrap_err()
+                .into_iter()
+                .map(|e| e.field)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(AdFilter::default().validate().is_ok());
+        assert_eq!(
+            fields(AdFilter {
+                near_lat: Some(91.0),
+                near_lon: Some(0.0),
+                ..Default::default()
+            }),
+            vec!["near_lat"]
+        );
+        assert_eq!(
+            fields(AdFilter {
+                radius_km: Some(10.0),
+                ..Default::default()
+            }),
+            vec!["radius_km"]
+        );
+        assert_eq!(
+            fields(AdFilter {
+                near_lat: Some(0.0),
+                near_lon: Some(0.0),
+                radius_km: Some(-1.0),
+                ..Default::default()
+            }),
+            vec!["radius_km"]
+        );
+        assert_eq!(
+            fields(AdFilter {
+                sort_by: Some(AdSort::DistanceAsc),
+                ..Default::default()
+            }),
+            vec!["sort_by"]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_views() {
+        let ad_repo = PostgresAdRepo::new(shared_db());
+        let ad = seed_ad(&*ad_repo, ad_content("Popular")).await;
+        assert_eq!(ad.view_count, 0);
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let ad_repo = ad_repo.clone();
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        ad_repo.get_by_id(ad.id, true).await.unwrap().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // A preview reads the count without adding to it.
+        let ad = ad_repo.get_by_id(ad.id, false).await.unwrap().unwrap();
+        assert_eq!(ad.view_count, 80);
+        let ad = ad_repo.get_by_id(ad.id, false).await.unwrap().unwrap();
+        assert_eq!(ad.view_count, 80);
+    }
+
+    #[tokio::test]
+    async fn test_facets() {
+        use crate::models::stats::PriceRangeCount;
+
+        let ad_repo = test_repo();
+        let title = format!("Faceted {}", uuid::Uuid::new_v4());
+        let seeded = [
+            (AdCategory::Vehicles, 100),
+            (AdCategory::Vehicles, 5000),
+            (AdCategory::Vehicles, 499),
+            (AdCategory::Electronics, 50),
+            (AdCategory::Electronics, 10),
+        ];
+        let mut ads = Vec::new();
+        for (category, price) in seeded {
+            let content = AdContent {
+                category,
+                price: price.into(),
+                ..ad_content(&title)
+            };
+            ads.push(seed_ad(&*ad_repo, content).await);
+        }
+        // Not listed, so not counted either.
+        ad_repo.set_status(ads[2].id, AdStatus::Sold).await.unwrap();
+
+        let filter = AdFilter {
+            title_contains: Some(title.clone()),
+            ..Default::default()
+        };
+        let facets = ad_repo.facets(filter.clone()).await.unwrap();
+        assert_eq!(
+            facets.categories.into_iter().collect::<Vec<_>>(),
+            vec![("electronics".to_string(), 2), ("vehicles".to_string(), 2)]
+        );
+        let range = |min, max, count| PriceRangeCount { min, max, count };
+        assert_eq!(
+            facets.price_ranges,
+            vec![
+                range(0, Some(50), 1),
+                range(50, Some(100), 1),
+                range(100, Some(500), 1),
+                range(500, Some(1000), 0),
+                range(1000, Some(5000), 0),
+                range(5000, None, 1),
+            ]
+        );
+
+        // Narrowing the filter narrows the counts.
+        let filter = AdFilter {
+            category_eq: Some(AdCategory::Electronics),
+            ..filter
+        };
+        let facets = ad_repo.facets(filter).await.unwrap();
+        assert_eq!(
+            facets.categories.into_iter().collect::<Vec<_>>(),
+            vec![("electronics".to_string(), 2)]
+        );
+        let counts: Vec<_> = facets
+            .price_ranges
+            .iter()
+            .map(|range| range.count)
+            .collect();
+        assert_eq!(counts, vec![1, 1, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        use crate::db::schema::ads;
+        use crate::models::stats::DailyCount;
+        use diesel::prelude::*;
+
+        let ad_repo = test_repo();
+        // The test database has a single connection, so it is only borrowed in between.
+        let conn = || ad_repo.db_manager.get_write_pool().get().unwrap();
+        // Other tests' ads would skew every figure: keep them from committing more until
+        // this test's transaction is rolled back, and hide the ones already there.
+        diesel::sql_query("LOCK TABLE ads IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut conn())
+            .unwrap();
+        diesel::update(ads::table.filter(ads::deleted_at.is_null()))
+            .set(ads::deleted_at.eq(chrono::Utc::now()))
+            .execute(&mut conn())
+            .unwrap();
+
+        let now = chrono::Utc::now();
+        let seeded = [
+            (AdCategory::Vehicles, Currency::Eur, 100, 0),
+            (AdCategory::Vehicles, Currency::Eur, 201, 0),
+            (AdCategory::Electronics, Currency::Czk, 50, 2),
+            (AdCategory::Electronics, Currency::Czk, 60, 2),
+            (AdCategory::Fashion, Currency::Eur, 10, 10),
+        ];
+        let mut ads = Vec::new();
+        for (category, currency, price, days_ago) in seeded {
+            let ad = seed_ad(
+                &*ad_repo,
+                AdContent {
+                    category,
+                    currency,
+                    price: price.into(),
+                    ..ad_content("Counted")
+                },
+            )
+            .await;
+            diesel::update(ads::table.find(ad.id))
+                .set(ads::created_at.eq(now - chrono::Duration::days(days_ago)))
+                .execute(&mut conn())
+                .unwrap();
+            ads.push(ad);
+        }
+        // Created this week, but no longer listed.
+        ad_repo.set_status(ads[3].id, AdStatus::Sold).await.unwrap();
+
+        let stats = ad_repo.stats().await.unwrap();
+        assert_eq!(stats.total_active, 4);
+        assert_eq!(
+            stats.per_category.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("electronics".to_string(), 1),
+                ("fashion".to_string(), 1),
+                ("vehicles".to_string(), 2),
+            ]
+        );
+        assert_eq!(
+            stats.average_price.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("CZK".to_string(), "50.00".parse().unwrap()),
+                ("EUR".to_string(), "103.67".parse().unwrap()),
+            ]
+        );
+
+        let today = now.date_naive();
+        let days: Vec<_> = (0..7)
+            .rev()
+            .map(|days_ago| DailyCount {
+                day: today - chrono::Duration::days(days_ago),
+                count: match days_ago {
+                    0 | 2 => 2,
+                    _ => 0,
+                },
+            })
+            .collect();
+        assert_eq!(stats.created_per_day, days);
+    }
+
+    #[tokio::test]
+    async fn test_get_by_ids() {
+        let ad_repo = test_repo();
+        let mut ids = Vec::new();
+        for title in ["First", "Second", "Third"] {
+            let ad = seed_ad(&*ad_repo, ad_content(title)).await;
+            ids.push(ad.id);
+        }
+
+        let requested = [ids[2], AdId(-1), ids[0], AdId(i32::MAX), ids[1], ids[2]];
+        let ads = ad_repo.get_by_ids(&requested).await.unwrap();
+        let titles: Vec<_> = ads.iter().map(|ad| ad.title.as_str()).collect();
+        assert_eq!(titles, vec!["Third", "First", "Second"]);
+
+        assert!(ad_repo.get_by_ids(&[]).await.unwrap().is_empty());
+        assert!(matches!(
+            ad_repo.get_by_ids(&[AdId(0); MAX_BATCH_IDS + 1]).await,
+            Err(RepoError::Validation(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_create_idempotent() {
+        use crate::db::schema::ads;
+        use diesel::prelude::*;
+
+        let ad_repo = PostgresAdRepo::new(shared_db());
+        let title = format!("Idempotent {}", uuid::Uuid::new_v4());
+        let key = uuid::Uuid::new_v4().to_string();
+        let content = |user_email: &str| AdContent {
+            title: title.clone(),
+            description: "Test Description".to_string(),
+            price: 100.into(),
+            currency: Currency::default(),
+            user_email: user_email.to_string(),
+            user_phone: "1234567890".to_string(),
+            negotiable: false,
+            locale: Locale::default(),
+            category: AdCategory::default(),
+            latitude: None,
+            longitude: None,
+            draft: false,
+        };
+
+        assert!(ad_repo
+            .get_by_idempotency_key("test@test.com", &key)
+            .await
+            .unwrap()
+            .is_none());
+
+        let (first, created) = ad_repo
+            .create_idempotent(content("test@test.com"), vec![], &key)
+            .await
+            .unwrap();
+        assert!(created);
+        let (second, created) = ad_repo
+            .create_idempotent(content("test@test.com"), vec![], &key)
+            .await
+            .unwrap();
+        assert!(!created);
+        assert_eq!(second.id, first.id);
+        assert_eq!(
+            ad_repo
+                .get_by_idempotency_key("test@test.com", &key)
+                .await
+                .unwrap()
+                .map(|ad| ad.id),
+            Some(first.id)
+        );
+
+        // Concurrent retries still insert at most once.
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let ad_repo = ad_repo.clone();
+                let ad = content("test@test.com");
+                let key = key.clone();
+                tokio::spawn(async move { ad_repo.create_idempotent(ad, vec![], &key).await })
+            })
+            .collect();
+        for task in tasks {
+            let (ad, created) = task.await.unwrap().unwrap();
+            assert!(!created);
+            assert_eq!(ad.id, first.id);
+        }
+
+        let count = || {
+            ads::table
+                .filter(ads::title.eq(&title))
+                .count()
+                .get_result::<i64>(&mut ad_repo.db_manager.get_write_pool().get().unwrap())
+                .unwrap()
+        };
+        assert_eq!(count(), 1);
+
+        // Keys are scoped per user.
+        let (other, created) = ad_repo
+            .create_idempotent(content("other@test.com"), vec![], &key)
+            .await
+            .unwrap();
+        assert!(created);
+        assert_ne!(other.id, first.id);
+        assert_eq!(count(), 2);
+    }
+
+    /// Past the promoted ads, the feed's main sorts should be read off their index,
+    /// stopping after a page, rather than sorting every matching ad.
+    #[test]
+    fn test_feed_sorts_use_index() {
+        use diesel::{
+            pg::Pg,
+            prelude::*,
+            query_builder::{AstPass, Query, QueryFragment, QueryId},
+            sql_query,
+            sql_types::Text,
+        };
+
+        struct Explain<Q>(Q);
+
+        impl<Q> QueryId for Explain<Q> {
+            type QueryId = ();
+            const HAS_STATIC_QUERY_ID: bool = false;
+        }
+
+        impl<Q: QueryFragment<Pg>> QueryFragment<Pg> for Explain<Q> {
+            fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+                out.push_sql("EXPLAIN ");
+                self.0.walk_ast(out.reborrow())
+            }
+        }
+
+        impl<Q> Query for Explain<Q> {
+            type SqlType = Text;
+        }
+
+        impl<Q> RunQueryDsl<PgConnection> for Explain<Q> {}
+
+        let db = test_db();
+        let conn = &mut db.write_conn().unwrap();
+        // The test table is small, and its statistics depend on what other tests left in
+        // it, so a sequential or bitmap scan and a sort could win; rule them out so only an
+        // ordered index scan is left.
+        let settings = [
+            "enable_seqscan",
+            "enable_bitmapscan",
+            "enable_sort",
+            "enable_incremental_sort",
+        ];
+        for setting in settings {
+            sql_query(format!("SET LOCAL {} = off", setting))
+                .execute(conn)
+                .unwrap();
+        }
+
+        for (sort, index) in [
+            (AdSort::CreatedAtDesc, "idx_ads_created_at_id"),
+            (AdSort::PriceAsc, "idx_ads_price_asc_id"),
+            (AdSort::PriceDesc, "idx_ads_price_desc_id"),
+        ] {
+            let filter = AdFilter {
+                sort_by: Some(sort),
+                ..Default::default()
+            };
+            let rest = filtered_query(&filter).filter(diesel::dsl::not(promotion_running()));
+            let query = sort_within(rest, &filter).limit(10);
+            let plan = Explain(query).load::<String>(conn).unwrap().join("\n");
+            assert!(
+                plan.contains(index),
+                "{:?} doesn't use {}:\n{}",
+                sort,
+                index,
+                plan
+            );
+            assert!(!plan.contains("Sort"), "{:?} sorts:\n{}", sort, plan);
+        }
     }
 }
@@ -0,0 +1,277 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use diesel::result::DatabaseErrorKind;
+
+/// A single rejected input field, reported back to the client as-is.
+#[derive(Debug, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RepoError {
+    #[error("not found")]
+    NotFound,
+    #[error("forbidden")]
+    Forbidden,
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("validation failed: {0}")]
+    Validation(String),
+    /// A malformed id in the path, told apart from a well-formed id that matches nothing.
+    #[error("{0}")]
+    InvalidId(String),
+    /// `If-Match` names another version than the stored one, which changed in between.
+    #[error("precondition failed")]
+    PreconditionFailed,
+    /// An update was sent without `If-Match`.
+    #[error("precondition required")]
+    PreconditionRequired,
+    /// The requested byte range lies outside an image of this many bytes.
+    #[error("range not satisfiable for {0} bytes")]
+    RangeNotSatisfiable(u64),
+    /// The action is on a cooldown that ends in this many seconds.
+    #[error("too soon, retry in {0}s")]
+    TooSoon(u64),
+    /// The cursor being fetched from was closed, or was lost with its connection.
+    #[error("cursor expired")]
+    CursorExpired,
+    /// Storing the upload would take its owner past `ImageLimits::quota_bytes`.
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("invalid fields: {0:?}")]
+    InvalidFields(Vec<FieldError>),
+    #[error("database error: {0}")]
+    Database(diesel::result::Error),
+    /// No connection could be acquired, e.g. the database is down or the pool is exhausted.
+    #[error("database unavailable: {0}")]
+    Unavailable(String),
+    /// Writes are turned away for maintenance; retry in this many seconds.
+    #[error("down for maintenance, retry in {0}s")]
+    Maintenance(u64),
+    /// Postgres cancelled a statement that ran past `PoolConfig::statement_timeout`.
+    #[error("database statement timed out")]
+    Timeout,
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl RepoError {
+    /// Wraps any other failure, e.g. from a storage backend SDK, as an internal error.
+    pub fn internal<E: Into<anyhow::Error>>(e: E) -> Self {
+        RepoError::Internal(e.into())
+    }
+}
+
+impl From<diesel::result::Error> for RepoError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => RepoError::NotFound,
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                RepoError::Conflict(info.message().to_string())
+            }
+            diesel::result::Error::DatabaseError(_, info)
+                if info.message() == "canceling statement due to statement timeout" =>
+            {
+                RepoError::Timeout
+            }
+            e => RepoError::Database(e),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for RepoError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        RepoError::Unavailable(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for RepoError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => RepoError::NotFound,
+            _ => RepoError::Internal(e.into()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for RepoError {
+    fn from(e: serde_json::Error) -> Self {
+        RepoError::Internal(e.into())
+    }
+}
+
+impl IntoResponse for RepoError {
+    fn into_response(self) -> Response {
+        let error = match self {
+            RepoError::NotFound => {
+                ErrorResponse::new(StatusCode::NOT_FOUND, "not_found", "not found")
+            }
+            RepoError::Forbidden => ErrorResponse::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                "not allowed for this user",
+            ),
+            RepoError::Conflict(message) => {
+                ErrorResponse::new(StatusCode::CONFLICT, "conflict", message)
+            }
+            RepoError::Validation(message) => {
+                ErrorResponse::new(StatusCode::BAD_REQUEST, "validation_failed", message)
+            }
+            RepoError::InvalidId(message) => {
+                ErrorResponse::new(StatusCode::BAD_REQUEST, "invalid_id", message)
+            }
+            RepoError::PreconditionFailed => ErrorResponse::new(
+                StatusCode::PRECONDITION_FAILED,
+                "precondition_failed",
+                "the ad was changed since it was fetched",
+            ),
+            RepoError::PreconditionRequired => ErrorResponse::new(
+                StatusCode::PRECONDITION_REQUIRED,
+                "precondition_required",
+                "If-Match with the ad's ETag is required",
+            ),
+            RepoError::RangeNotSatisfiable(len) => {
+                return (
+                    [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+                    ErrorResponse::new(
+                        StatusCode::RANGE_NOT_SATISFIABLE,
+                        "range_not_satisfiable",
+                        format!("the image is {} bytes long", len),
+                    ),
+                )
+                    .into_response()
+            }
+            RepoError::TooSoon(retry_after) => {
+                return (
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    ErrorResponse::new(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "too_soon",
+                        format!("not allowed again yet, retry in {}s", retry_after),
+                    ),
+                )
+                    .into_response()
+            }
+            RepoError::CursorExpired => ErrorResponse::new(
+                StatusCode::GONE,
+                "cursor_expired",
+                "the cursor is no longer open, start over with a new one",
+            ),
+            RepoError::QuotaExceeded(message) => {
+                ErrorResponse::new(StatusCode::PAYLOAD_TOO_LARGE, "quota_exceeded", message)
+            }
+            RepoError::InvalidFields(errors) => ErrorResponse::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_fields",
+                "one or more fields are invalid",
+            )
+            .with_details(serde_json::json!(errors)),
+            RepoError::Unavailable(_) => {
+                // Server-side details stay in the logs.
+                tracing::error!("{}", self);
+                ErrorResponse::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "unavailable",
+                    "service unavailable",
+                )
+            }
+            RepoError::Maintenance(retry_after) => {
+                return (
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    ErrorResponse::new(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "maintenance",
+                        "down for maintenance, only reads are served",
+                    ),
+                )
+                    .into_response()
+            }
+            RepoError::Timeout => {
+                tracing::error!("{}", self);
+                ErrorResponse::new(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "timeout",
+                    "the request took too long",
+                )
+            }
+            RepoError::Database(_) | RepoError::Internal(_) => {
+                tracing::error!("{}", self);
+                ErrorResponse::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal",
+                    "internal error",
+                )
+            }
+        };
+
+        error.into_response()
+    }
+}
+
+/// The JSON body of every error response: a stable, machine-readable `code`, a message
+/// for humans and, for some codes, structured `details` such as the rejected fields.
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct ErrorResponse {
+    #[serde(skip)]
+    pub status: StatusCode,
+    #[schema(example = "not_found")]
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ErrorResponse {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ErrorResponse {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(self, details: serde_json::Value) -> Self {
+        ErrorResponse {
+            details: Some(details),
+            ..self
+        }
+    }
+
+    /// For errors raised outside the handlers, e.g. extractor rejections, whose only
+    /// classification is the status. The code is the status's snake_case reason.
+    pub fn from_status(status: StatusCode, message: impl Into<String>) -> Self {
+        let reason = status.canonical_reason().unwrap_or("error");
+        let code = match status {
+            StatusCode::BAD_REQUEST => "bad_request",
+            StatusCode::UNAUTHORIZED => "unauthorized",
+            StatusCode::NOT_FOUND => "not_found",
+            StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+            StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+            StatusCode::UNPROCESSABLE_ENTITY => "unprocessable_entity",
+            StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+            _ if status.is_client_error() => "client_error",
+            _ => "internal",
+        };
+        let message = message.into();
+        let message = if message.is_empty() {
+            reason.to_lowercase()
+        } else {
+            message
+        };
+
+        ErrorResponse::new(status, code, message)
+    }
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
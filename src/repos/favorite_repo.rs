@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error};
+
+use crate::db::schema::{ads, favorites};
+use crate::db::DbManager;
+use crate::models::ad::{Ad, AdId, AdStatus};
+use crate::repos::error::RepoError;
+
+#[async_trait]
+pub trait FavoriteRepo: Send + Sync {
+    /// Saves the ad for `user_email`. Saving it again is a no-op; `NotFound` if there is no
+    /// such ad or it was deleted.
+    async fn add(&self, user_email: &str, ad_id: AdId) -> Result<(), RepoError>;
+    /// Forgets a saved ad, returning whether it had been saved.
+    async fn remove(&self, user_email: &str, ad_id: AdId) -> Result<bool, RepoError>;
+    /// The user's saved ads, most recently saved first, leaving out deleted and hidden ones.
+    async fn list_for_user(&self, user_email: &str) -> Result<Vec<Ad>, RepoError>;
+}
+
+#[derive(Clone)]
+pub struct PostgresFavoriteRepo {
+    pub db_manager: DbManager,
+}
+
+impl PostgresFavoriteRepo {
+    pub fn new(db_manager: DbManager) -> Arc<PostgresFavoriteRepo> {
+        Arc::new(PostgresFavoriteRepo { db_manager })
+    }
+}
+
+#[async_trait]
+impl FavoriteRepo for PostgresFavoriteRepo {
+    async fn add(&self, user_email: &str, ad_id: AdId) -> Result<(), RepoError> {
+        let conn = &mut self.db_manager.write_conn()?;
+
+        // Soft-deleted ads still satisfy the foreign key, so they are checked for here.
+        let deleted = ads::table
+            .find(ad_id)
+            .select(ads::deleted_at.is_not_null())
+            .first::<bool>(conn)
+            .optional()
+            .map_err(RepoError::from)?;
+        if deleted == Some(true) {
+            return Err(RepoError::NotFound);
+        }
+
+        diesel::insert_into(favorites::table)
+            .values((
+                favorites::user_email.eq(user_email),
+                favorites::ad_id.eq(ad_id),
+                favorites::created_at.eq(chrono::Utc::now()),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .map_err(|e| match e {
+                Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                    RepoError::NotFound
+                }
+                e => RepoError::from(e),
+            })?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, user_email: &str, ad_id: AdId) -> Result<bool, RepoError> {
+        let deleted = diesel::delete(favorites::table.find((user_email, ad_id)))
+            .execute(&mut self.db_manager.write_conn()?)
+            .map_err(RepoError::from)?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn list_for_user(&self, user_email: &str) -> Result<Vec<Ad>, RepoError> {
+        favorites::table
+            .inner_join(ads::table)
+            .filter(favorites::user_email.eq(user_email))
+            .filter(ads::deleted_at.is_null())
+            .filter(ads::status.ne_all(AdStatus::RESTRICTED.map(|status| status.as_str())))
+            .order((favorites::created_at.desc(), favorites::ad_id.desc()))
+            .select(Ad::as_select())
+            .load::<Ad>(&mut self.db_manager.read_conn()?)
+            .map_err(RepoError::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::repos::{
+        ad_repo::{AdRepo, PostgresAdRepo},
+        error::RepoError,
+        favorite_repo::{FavoriteRepo, PostgresFavoriteRepo},
+        fixtures::{ad_content, seed_ad, test_db},
+    };
+
+    #[tokio::test]
+    async fn test_favorites() {
+        let db_manager = test_db();
+        let ad_repo = PostgresAdRepo::new(db_manager.clone());
+        let favorite_repo = PostgresFavoriteRepo::new(db_manager);
+        let user = format!("{}@test.com", uuid::Uuid::new_v4());
+
+        let first = seed_ad(&*ad_repo, ad_content("First")).await;
+        let second = seed_ad(&*ad_repo, ad_content("Second")).await;
+
+        favorite_repo.add(&user, first.id).await.unwrap();
+        favorite_repo.add(&user, second.id).await.unwrap();
+        // Saving twice is idempotent.
+        favorite_repo.add(&user, first.id).await.unwrap();
+
+        let saved: Vec<_> = favorite_repo
+            .list_for_user(&user)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|ad| ad.id)
+            .collect();
+        assert_eq!(saved.len(), 2);
+        assert!(saved.contains(&first.id) && saved.contains(&second.id));
+
+        assert!(favorite_repo.remove(&user, first.id).await.unwrap());
+        assert!(!favorite_repo.remove(&user, first.id).await.unwrap());
+
+        // Deleting the ad hides it from everyone's favorites.
+        ad_repo.delete(second.id, &second.user_email).await.unwrap();
+        assert!(favorite_repo.list_for_user(&user).await.unwrap().is_empty());
+
+        assert!(matches!(
+            favorite_repo.add(&user, second.id).await,
+            Err(RepoError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,63 @@
+use std::{env, sync::Arc};
+
+use crate::{
+    db::{DbManager, PoolConfig},
+    models::ad::{Ad, AdCategory, AdContent, Currency, Locale},
+    repos::ad_repo::{AdRepo, PostgresAdRepo},
+};
+
+pub(crate) fn database_url() -> String {
+    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+}
+
+/// A database whose writes are rolled back when the test ends, see `DbManager::for_test`.
+pub fn test_db() -> DbManager {
+    DbManager::for_test(&database_url()).expect("Failed to create test connection")
+}
+
+/// A pooled database whose writes are committed, for tests that need several sessions
+/// at once or expect a statement to fail. Rows should be made unique to the test.
+pub fn shared_db() -> DbManager {
+    DbManager::new(&database_url(), None, &PoolConfig::default()).expect("Failed to create pool")
+}
+
+/// An `AdRepo` on `test_db`.
+pub fn test_repo() -> Arc<PostgresAdRepo> {
+    PostgresAdRepo::new(test_db())
+}
+
+/// A valid ad with placeholder fields, to be adjusted with struct update syntax.
+pub fn ad_content(title: &str) -> AdContent {
+    AdContent {
+        title: title.to_string(),
+        description: "Test Description".to_string(),
+        price: 100.into(),
+        currency: Currency::default(),
+        user_email: "test@test.com".to_string(),
+        user_phone: "1234567890".to_string(),
+        negotiable: false,
+        locale: Locale::default(),
+        category: AdCategory::default(),
+        latitude: None,
+        longitude: None,
+        draft: false,
+    }
+}
+
+/// Creates one ad per entry of `contents`, in order, with a single `create_many`.
+pub async fn seed_ads(ad_repo: &dyn AdRepo, contents: Vec<AdContent>) -> Vec<Ad> {
+    ad_repo
+        .create_many(
+            contents
+                .into_iter()
+                .map(|content| (content, vec![]))
+                .collect(),
+        )
+        .await
+        .expect("Failed to create ads")
+}
+
+/// Creates a single ad from `content`.
+pub async fn seed_ad(ad_repo: &dyn AdRepo, content: AdContent) -> Ad {
+    seed_ads(ad_repo, vec![content]).await.remove(0)
+}
@@ -1,86 +1,1234 @@
-use std::sync::Arc;
+use std::{collections::HashMap, io::SeekFrom, pin::Pin, sync::Arc, time::Duration};
 
-use crate::models::image::Image;
-use anyhow::Error;
+use crate::models::image::{
+    sniff_mime_type, ByteRange, Image, ImageDimensions, ImageStream, RepairOutcome, SignedUrl,
+};
+use crate::repos::error::RepoError;
+use anyhow::{Context, Error};
+use aws_sdk_s3::{operation::get_object::GetObjectOutput, presigning::PresigningConfig};
 use axum::async_trait;
+use futures_util::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 
 #[async_trait]
 pub trait ImageRepo: Send + Sync {
-    async fn get_image(&self, id: &str) -> Result<Image, Error>;
+    async fn get_image(&self, id: &str) -> Result<Image, RepoError>;
+    /// Like `get_image`, but leaves the bytes to be read incrementally. With a `range`,
+    /// only that slice is read, or `RangeNotSatisfiable` if it lies outside the image.
+    async fn open_image_stream(
+        &self,
+        id: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ImageStream, RepoError>;
     async fn create_image(
         &self,
         id: String,
         bytes: Vec<u8>,
         mime_type: String,
-    ) -> Result<String, Error>;
-    async fn delete_image(&self, id: &str) -> Result<(), Error>;
+    ) -> Result<String, RepoError>;
+    /// Deletes the image along with any thumbnails `get_thumbnail` cached for it.
+    async fn delete_image(&self, id: &str) -> Result<(), RepoError>;
+    /// Deletes each of `ids` like `delete_image`, up to `DELETE_CONCURRENCY` at a time,
+    /// and reports how each went in the order given, so one missing image doesn't stop
+    /// the rest from being deleted.
+    async fn delete_images(
+        &self,
+        ids: &[&str],
+    ) -> Result<Vec<(String, Result<(), RepoError>)>, RepoError> {
+        // Owned ids, as a stream borrowing them fails the `Send` bound of `async_trait`.
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        Ok(stream::iter(ids)
+            .map(|id| async move {
+                let result = self.delete_image(&id).await;
+                (id, result)
+            })
+            .buffered(DELETE_CONCURRENCY)
+            .collect()
+            .await)
+    }
+    /// Whether an image is stored under `id`. An id that can't name an image isn't.
+    async fn exists(&self, id: &str) -> Result<bool, RepoError>;
+    /// The image's dimensions as recorded when it was stored, read without fetching the
+    /// image itself. `None` if none were recorded.
+    async fn get_dimensions(&self, id: &str) -> Result<Option<ImageDimensions>, RepoError>;
+    async fn get_thumbnail(&self, id: &str, max_dim: u32) -> Result<Image, RepoError>;
+    /// A URL that fetches the image straight from storage for `ttl`, or `None` if the
+    /// storage can't hand out URLs of its own and images are served through the API.
+    async fn presigned_url(&self, id: &str, ttl: Duration) -> Result<Option<SignedUrl>, RepoError>;
+    /// Writes and removes a small probe, so storage that has become unwritable shows up
+    /// in `/ready` before an upload fails.
+    async fn health_check(&self) -> Result<(), RepoError>;
+    /// Rebuilds the image's metadata if it was lost or corrupted, which would otherwise
+    /// fail every read of the image, and records the dimensions of images stored before
+    /// they were. `NotFound` if the image bytes themselves are gone.
+    async fn repair(&self, id: &str) -> Result<RepairOutcome, RepoError>;
+}
+
+/// Most deletes `ImageRepo::delete_images` has in flight at once, each a round trip to
+/// the disk or the bucket.
+const DELETE_CONCURRENCY: usize = 8;
+
+/// The id `create_image` stores `bytes` under: random, or with `dedup` a UUID made from
+/// their SHA-256, so uploading the same bytes again names the image already stored.
+fn image_id(bytes: &[u8], dedup: bool) -> String {
+    if !dedup {
+        return uuid::Uuid::new_v4().to_string();
+    }
+
+    let hash = Sha256::digest(bytes);
+    let mut custom = [0; 16];
+    custom.copy_from_slice(&hash[..16]);
+    uuid::Builder::from_custom_bytes(custom)
+        .into_uuid()
+        .to_string()
+}
+
+/// Contents of the probe `health_check` writes.
+const HEALTH_CHECK_PROBE: &[u8] = b"ok";
+
+/// Downscales `bytes` to fit within `max_dim` x `max_dim`, keeping the aspect ratio and
+/// the source format. Images already within bounds are returned unchanged.
+pub(crate) async fn make_thumbnail(bytes: Vec<u8>, max_dim: u32) -> Result<Vec<u8>, RepoError> {
+    tokio::task::spawn_blocking(move || -> Result<Vec<u8>, Error> {
+        let format = image::guess_format(&bytes)?;
+        let source = image::load_from_memory_with_format(&bytes, format)?;
+
+        if source.width() <= max_dim && source.height() <= max_dim {
+            return Ok(bytes);
+        }
+
+        let mut thumbnail = std::io::Cursor::new(Vec::new());
+        source
+            .thumbnail(max_dim, max_dim)
+            .write_to(&mut thumbnail, format)?;
+
+        Ok(thumbnail.into_inner())
+    })
+    .await
+    .map_err(Error::from)?
+    .map_err(RepoError::from)
 }
 
 #[derive(Clone)]
 pub struct LocalImageRepo {
     image_dir: String,
+    dedup: bool,
+    /// Serve images with the type sniffed from their bytes, not the stored one a client
+    /// may have got wrong. Images of no type `sniff_mime_type` knows keep the stored one.
+    sniff_content_type: bool,
 }
 
 impl LocalImageRepo {
-    pub fn new(image_dir: String) -> Arc<LocalImageRepo> {
-        Arc::new(LocalImageRepo { image_dir })
+    /// Creates `image_dir` and any missing parents, then checks that images can be
+    /// written there, so a bad `IMAGE_DIR` fails at startup rather than on first upload.
+    pub fn new(
+        image_dir: String,
+        dedup: bool,
+        sniff_content_type: bool,
+    ) -> Result<Arc<LocalImageRepo>, Error> {
+        std::fs::create_dir_all(&image_dir)
+            .with_context(|| format!("cannot create image directory {}", image_dir))?;
+
+        let probe = format!("{}/.health-check-{}", image_dir, uuid::Uuid::new_v4());
+        std::fs::write(&probe, HEALTH_CHECK_PROBE)
+            .and_then(|()| std::fs::remove_file(&probe))
+            .with_context(|| format!("image directory {} is not writable", image_dir))?;
+
+        Ok(Arc::new(LocalImageRepo {
+            image_dir,
+            dedup,
+            sniff_content_type,
+        }))
+    }
+
+    /// The type to serve an image starting with `head` as, given the `stored` one.
+    fn content_type(&self, stored: String, head: &[u8]) -> String {
+        match sniff_mime_type(head) {
+            Some(sniffed) if self.sniff_content_type => sniffed.to_string(),
+            _ => stored,
+        }
+    }
+
+    /// Paths of the image file and its metadata. Ids come from clients, so anything
+    /// other than the UUID `create_image` generates is refused before it can name a
+    /// file outside `image_dir`.
+    fn paths(&self, id: &str) -> Result<(String, String), RepoError> {
+        let is_uuid = uuid::Uuid::parse_str(id).is_ok_and(|uuid| uuid.to_string() == id);
+        if !is_uuid {
+            return Err(RepoError::Validation(format!("invalid image id: {}", id)));
+        }
+
+        Ok((
+            format!("{}/{}", self.image_dir, id),
+            format!("{}/{}.meta", self.image_dir, id),
+        ))
     }
 }
 
+/// Writes `bytes` to a temporary file next to `path`, then renames it into place, so
+/// `path` is either missing or complete, even if the process dies halfway.
+async fn write_atomically(path: &str, bytes: &[u8]) -> Result<(), RepoError> {
+    // Not a UUID, so `LocalImageRepo::paths` never hands it out as an image.
+    let tmp_path = format!("{}.tmp-{}", path, uuid::Uuid::new_v4().simple());
+
+    let written = match tokio::fs::write(&tmp_path, bytes).await {
+        Ok(()) => tokio::fs::rename(&tmp_path, path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        // Not `RepoError::from`, which would turn a missing directory into `NotFound`.
+        return Err(RepoError::internal(e));
+    }
+
+    Ok(())
+}
+
 #[derive(Deserialize, Serialize)]
 struct ImageMetadataFile {
     file_name: String,
     mime_type: String,
+    /// Missing from the files of images stored before dimensions were recorded.
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+}
+
+impl ImageMetadataFile {
+    fn dimensions(&self) -> Option<ImageDimensions> {
+        Some(ImageDimensions {
+            width: self.width?,
+            height: self.height?,
+        })
+    }
+}
+
+async fn read_metadata(meta_path: &str) -> Result<ImageMetadataFile, RepoError> {
+    let metadata = tokio::fs::read_to_string(meta_path).await?;
+    Ok(serde_json::from_str(&metadata)?)
 }
 
 #[async_trait]
 impl ImageRepo for LocalImageRepo {
-    async fn get_image(&self, id: &str) -> Result<Image, Error> {
-        let path = format!("{}/{}", self.image_dir, id);
-        let meta_path = format!("{}/{}.meta", self.image_dir, id);
+    async fn get_image(&self, id: &str) -> Result<Image, RepoError> {
+        let (path, meta_path) = self.paths(id)?;
 
         let bytes = tokio::fs::read(path).await?;
-        let metadata_str = tokio::fs::read_to_string(meta_path).await?;
-
-        let metadata: ImageMetadataFile = serde_json::from_str(&metadata_str)?;
+        let metadata = read_metadata(&meta_path).await?;
 
         Ok(Image {
             id: Some(id.to_string()),
+            dimensions: metadata.dimensions(),
             file_name: metadata.file_name,
-            mime_type: metadata.mime_type,
+            mime_type: self.content_type(metadata.mime_type, &bytes),
             bytes,
         })
     }
 
+    async fn open_image_stream(
+        &self,
+        id: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ImageStream, RepoError> {
+        let (path, meta_path) = self.paths(id)?;
+
+        let mut file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let metadata = read_metadata(&meta_path).await?;
+        let mime_type = if self.sniff_content_type {
+            // Enough of the header for `sniff_mime_type`.
+            let mut head = Vec::new();
+            (&mut file).take(12).read_to_end(&mut head).await?;
+            file.rewind().await?;
+            self.content_type(metadata.mime_type, &head)
+        } else {
+            metadata.mime_type
+        };
+
+        let (range, reader): (_, Pin<Box<dyn AsyncRead + Send>>) = match range {
+            Some(range) => {
+                let (first, last) = range
+                    .resolve(len)
+                    .ok_or(RepoError::RangeNotSatisfiable(len))?;
+                file.seek(SeekFrom::Start(first)).await?;
+                (Some((first, last)), Box::pin(file.take(last - first + 1)))
+            }
+            None => (None, Box::pin(file)),
+        };
+
+        Ok(ImageStream {
+            file_name: metadata.file_name,
+            mime_type,
+            len,
+            range,
+            reader,
+        })
+    }
+
     async fn create_image(
         &self,
         file_name: String,
         bytes: Vec<u8>,
         mime_type: String,
-    ) -> Result<String, Error> {
-        let image_id = uuid::Uuid::new_v4().to_string();
-        let path = format!("{}/{}", self.image_dir, image_id);
-        let meta_path = format!("{}/{}.meta", self.image_dir, image_id);
+    ) -> Result<String, RepoError> {
+        let image_id = image_id(&bytes, self.dedup);
+        let (path, meta_path) = self.paths(&image_id)?;
+        // The image file is the last to appear, so once it's there the image is complete.
+        if self.dedup && tokio::fs::try_exists(&path).await? {
+            return Ok(image_id);
+        }
 
+        let dimensions = ImageDimensions::of(&bytes);
         let meta = ImageMetadataFile {
             file_name,
             mime_type: mime_type.clone(),
+            width: dimensions.map(|dimensions| dimensions.width),
+            height: dimensions.map(|dimensions| dimensions.height),
         };
 
-        tokio::fs::write(path, bytes).await?;
-        tokio::fs::write(meta_path, serde_json::to_string(&meta)?).await?;
+        // Readers open the image file first, so the metadata is in place before it, and
+        // each file only appears complete.
+        write_atomically(&meta_path, serde_json::to_string(&meta)?.as_bytes()).await?;
+        write_atomically(&path, &bytes).await?;
 
         Ok(image_id)
     }
 
-    async fn delete_image(&self, id: &str) -> Result<(), Error> {
-        let path = format!("{}/{}", self.image_dir, id);
-        let meta_path = format!("{}/{}.meta", self.image_dir, id);
+    async fn delete_image(&self, id: &str) -> Result<(), RepoError> {
+        let (path, meta_path) = self.paths(id)?;
+
+        // Thumbnails go first, so one that fails to delete leaves the image to retry with.
+        let thumb_prefix = format!("{}.thumb.", id);
+        let mut entries = tokio::fs::read_dir(&self.image_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let is_thumb = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(&thumb_prefix));
+            if is_thumb {
+                match tokio::fs::remove_file(entry.path()).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
 
         tokio::fs::remove_file(path).await?;
         tokio::fs::remove_file(meta_path).await?;
 
         Ok(())
     }
+
+    async fn exists(&self, id: &str) -> Result<bool, RepoError> {
+        let Ok((path, _)) = self.paths(id) else {
+            return Ok(false);
+        };
+
+        Ok(tokio::fs::try_exists(path).await?)
+    }
+
+    async fn get_dimensions(&self, id: &str) -> Result<Option<ImageDimensions>, RepoError> {
+        let (_, meta_path) = self.paths(id)?;
+        Ok(read_metadata(&meta_path).await?.dimensions())
+    }
+
+    async fn get_thumbnail(&self, id: &str, max_dim: u32) -> Result<Image, RepoError> {
+        // Validates `id` before it is used in the thumbnail path.
+        let source = self.get_image(id).await?;
+        let thumb_path = format!("{}/{}.thumb.{}", self.image_dir, id, max_dim);
+
+        let bytes = match tokio::fs::read(&thumb_path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let bytes = make_thumbnail(source.bytes, max_dim).await?;
+                write_atomically(&thumb_path, &bytes).await?;
+                bytes
+            }
+            Err(e) => return Err(RepoError::from(e)),
+        };
+
+        Ok(Image {
+            dimensions: ImageDimensions::of(&bytes),
+            bytes,
+            ..source
+        })
+    }
+
+    /// Local files are only reachable through the API.
+    async fn presigned_url(
+        &self,
+        _id: &str,
+        _ttl: Duration,
+    ) -> Result<Option<SignedUrl>, RepoError> {
+        Ok(None)
+    }
+
+    async fn health_check(&self) -> Result<(), RepoError> {
+        // Unique per check, so concurrent checks don't remove each other's probe.
+        let path = format!("{}/.health-check-{}", self.image_dir, uuid::Uuid::new_v4());
+
+        tokio::fs::write(&path, HEALTH_CHECK_PROBE).await?;
+        tokio::fs::remove_file(&path).await?;
+
+        Ok(())
+    }
+
+    async fn repair(&self, id: &str) -> Result<RepairOutcome, RepoError> {
+        let (path, meta_path) = self.paths(id)?;
+
+        let bytes = tokio::fs::read(path).await?;
+        let dimensions = ImageDimensions::of(&bytes);
+        let meta = match read_metadata(&meta_path).await {
+            // Dimensions that can't be read from the bytes can't be backfilled either.
+            Ok(meta) if meta.dimensions().is_some() || dimensions.is_none() => {
+                return Ok(RepairOutcome::Intact)
+            }
+            Ok(meta) => meta,
+            Err(_) => {
+                let Some(mime_type) = sniff_mime_type(&bytes) else {
+                    return Ok(RepairOutcome::Unrecoverable);
+                };
+                ImageMetadataFile {
+                    file_name: id.to_string(),
+                    mime_type: mime_type.to_string(),
+                    width: None,
+                    height: None,
+                }
+            }
+        };
+        let meta = ImageMetadataFile {
+            width: dimensions.map(|dimensions| dimensions.width),
+            height: dimensions.map(|dimensions| dimensions.height),
+            ..meta
+        };
+        write_atomically(&meta_path, serde_json::to_string(&meta)?.as_bytes()).await?;
+
+        Ok(RepairOutcome::Repaired)
+    }
+}
+
+#[derive(Clone)]
+pub struct S3ImageRepo {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+    prefix: String,
+    dedup: bool,
+}
+
+impl S3ImageRepo {
+    pub fn new(
+        client: aws_sdk_s3::Client,
+        bucket: String,
+        prefix: String,
+        dedup: bool,
+    ) -> Arc<S3ImageRepo> {
+        Arc::new(S3ImageRepo {
+            client,
+            bucket,
+            prefix,
+            dedup,
+        })
+    }
+
+    fn key(&self, id: &str) -> String {
+        if self.prefix.is_empty() {
+            id.to_string()
+        } else {
+            format!("{}/{}", self.prefix.trim_end_matches('/'), id)
+        }
+    }
+
+    async fn get_object(
+        &self,
+        id: &str,
+        range: Option<String>,
+    ) -> Result<GetObjectOutput, RepoError> {
+        self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .set_range(range)
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(service_err) if service_err.is_no_such_key() => RepoError::NotFound,
+                _ => RepoError::internal(e),
+            })
+    }
+}
+
+/// Reads the `file_name`/`mime_type` pair `create_image` stores as object metadata.
+fn object_metadata(
+    id: &str,
+    metadata: Option<&HashMap<String, String>>,
+) -> Result<(String, String), RepoError> {
+    let metadata = metadata.cloned().unwrap_or_default();
+    let file_name = metadata
+        .get("file_name")
+        .cloned()
+        .ok_or_else(|| Error::msg(format!("image {} has no file_name metadata", id)))?;
+    let mime_type = metadata
+        .get("mime_type")
+        .cloned()
+        .ok_or_else(|| Error::msg(format!("image {} has no mime_type metadata", id)))?;
+
+    Ok((file_name, mime_type))
+}
+
+/// Reads the `width`/`height` pair `create_image` stores as object metadata, if it did.
+fn object_dimensions(metadata: Option<&HashMap<String, String>>) -> Option<ImageDimensions> {
+    let metadata = metadata?;
+    Some(ImageDimensions {
+        width: metadata.get("width")?.parse().ok()?,
+        height: metadata.get("height")?.parse().ok()?,
+    })
+}
+
+fn object_len(id: &str, content_length: Option<i64>) -> Result<u64, RepoError> {
+    content_length
+        .and_then(|len| u64::try_from(len).ok())
+        .ok_or_else(|| Error::msg(format!("image {} has no content length", id)).into())
+}
+
+#[async_trait]
+impl ImageRepo for S3ImageRepo {
+    async fn get_image(&self, id: &str) -> Result<Image, RepoError> {
+        let output = self.get_object(id, None).await?;
+        let (file_name, mime_type) = object_metadata(id, output.metadata())?;
+        let dimensions = object_dimensions(output.metadata());
+
+        let bytes = output
+            .body
+            .collect()
+            .await
+            .map_err(RepoError::internal)?
+            .into_bytes()
+            .to_vec();
+
+        Ok(Image {
+            id: Some(id.to_string()),
+            file_name,
+            mime_type,
+            dimensions,
+            bytes,
+        })
+    }
+
+    async fn open_image_stream(
+        &self,
+        id: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ImageStream, RepoError> {
+        let Some(range) = range else {
+            let output = self.get_object(id, None).await?;
+            let (file_name, mime_type) = object_metadata(id, output.metadata())?;
+            let len = object_len(id, output.content_length())?;
+
+            return Ok(ImageStream {
+                file_name,
+                mime_type,
+                len,
+                range: None,
+                reader: Box::pin(output.body.into_async_read()),
+            });
+        };
+
+        // The size is needed up front to resolve suffix ranges and report a 416.
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(service_err) if service_err.is_not_found() => RepoError::NotFound,
+                _ => RepoError::internal(e),
+            })?;
+        let (file_name, mime_type) = object_metadata(id, head.metadata())?;
+        let len = object_len(id, head.content_length())?;
+        let (first, last) = range
+            .resolve(len)
+            .ok_or(RepoError::RangeNotSatisfiable(len))?;
+
+        let output = self
+            .get_object(id, Some(format!("bytes={}-{}", first, last)))
+            .await?;
+
+        Ok(ImageStream {
+            file_name,
+            mime_type,
+            len,
+            range: Some((first, last)),
+            reader: Box::pin(output.body.into_async_read()),
+        })
+    }
+
+    async fn create_image(
+        &self,
+        file_name: String,
+        bytes: Vec<u8>,
+        mime_type: String,
+    ) -> Result<String, RepoError> {
+        let image_id = image_id(&bytes, self.dedup);
+        if self.dedup {
+            let stored = self
+                .client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(self.key(&image_id))
+                .send()
+                .await;
+            match stored {
+                Ok(_) => return Ok(image_id),
+                Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => {}
+                Err(e) => return Err(RepoError::internal(e)),
+            }
+        }
+
+        let mut put = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.key(&image_id))
+            .content_type(&mime_type)
+            .metadata("file_name", file_name)
+            .metadata("mime_type", mime_type);
+        if let Some(dimensions) = ImageDimensions::of(&bytes) {
+            put = put
+                .metadata("width", dimensions.width.to_string())
+                .metadata("height", dimensions.height.to_string());
+        }
+        put.body(bytes.into())
+            .send()
+            .await
+            .map_err(RepoError::internal)?;
+
+        Ok(image_id)
+    }
+
+    async fn delete_image(&self, id: &str) -> Result<(), RepoError> {
+        // S3 deletes are idempotent, so check existence first to fail like the local repo does.
+        self.client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(service_err) if service_err.is_not_found() => RepoError::NotFound,
+                _ => RepoError::internal(e),
+            })?;
+
+        // Thumbnails go first, so one that fails to delete leaves the image to retry with.
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(self.key(&format!("{}.thumb.", id)))
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            for thumb in page.map_err(RepoError::internal)?.contents() {
+                self.client
+                    .delete_object()
+                    .bucket(&self.bucket)
+                    .set_key(thumb.key().map(str::to_string))
+                    .send()
+                    .await
+                    .map_err(RepoError::internal)?;
+            }
+        }
+
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .send()
+            .await
+            .map_err(RepoError::internal)?;
+
+        Ok(())
+    }
+
+    async fn exists(&self, id: &str) -> Result<bool, RepoError> {
+        let stored = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .send()
+            .await;
+
+        match stored {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(RepoError::internal(e)),
+        }
+    }
+
+    async fn get_dimensions(&self, id: &str) -> Result<Option<ImageDimensions>, RepoError> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(service_err) if service_err.is_not_found() => RepoError::NotFound,
+                _ => RepoError::internal(e),
+            })?;
+
+        Ok(object_dimensions(head.metadata()))
+    }
+
+    async fn get_thumbnail(&self, id: &str, max_dim: u32) -> Result<Image, RepoError> {
+        let source = self.get_image(id).await?;
+        let thumb_key = self.key(&format!("{}.thumb.{}", id, max_dim));
+
+        let cached = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&thumb_key)
+            .send()
+            .await;
+
+        let bytes = match cached {
+            Ok(output) => output
+                .body
+                .collect()
+                .await
+                .map_err(RepoError::internal)?
+                .into_bytes()
+                .to_vec(),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                let bytes = make_thumbnail(source.bytes, max_dim).await?;
+                self.client
+                    .put_object()
+                    .bucket(&self.bucket)
+                    .key(&thumb_key)
+                    .content_type(&source.mime_type)
+                    .body(bytes.clone().into())
+                    .send()
+                    .await
+                    .map_err(RepoError::internal)?;
+                bytes
+            }
+            Err(e) => return Err(RepoError::internal(e)),
+        };
+
+        Ok(Image {
+            dimensions: ImageDimensions::of(&bytes),
+            bytes,
+            ..source
+        })
+    }
+
+    /// Signed locally with the client's credentials, so it doesn't check the image exists.
+    async fn presigned_url(&self, id: &str, ttl: Duration) -> Result<Option<SignedUrl>, RepoError> {
+        let start = std::time::SystemTime::now();
+        let config = PresigningConfig::builder()
+            .start_time(start)
+            .expires_in(ttl)
+            .build()
+            .map_err(RepoError::internal)?;
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.key(id))
+            .presigned(config)
+            .await
+            .map_err(RepoError::internal)?;
+
+        Ok(Some(SignedUrl {
+            url: request.uri().to_string(),
+            expires_at: chrono::DateTime::<chrono::Utc>::from(start + ttl),
+        }))
+    }
+
+    async fn health_check(&self) -> Result<(), RepoError> {
+        let key = self.key(".health-check");
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .body(HEALTH_CHECK_PROBE.to_vec().into())
+            .send()
+            .await
+            .map_err(RepoError::internal)?;
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(RepoError::internal)?;
+
+        Ok(())
+    }
+
+    async fn repair(&self, id: &str) -> Result<RepairOutcome, RepoError> {
+        let output = self.get_object(id, None).await?;
+        let metadata = object_metadata(id, output.metadata()).ok();
+        if metadata.is_some() && object_dimensions(output.metadata()).is_some() {
+            return Ok(RepairOutcome::Intact);
+        }
+
+        let bytes = output
+            .body
+            .collect()
+            .await
+            .map_err(RepoError::internal)?
+            .into_bytes();
+        let dimensions = ImageDimensions::of(&bytes);
+        let (file_name, mime_type) = match metadata {
+            // Dimensions that can't be read from the bytes can't be backfilled either.
+            Some(_) if dimensions.is_none() => return Ok(RepairOutcome::Intact),
+            Some(metadata) => metadata,
+            None => match sniff_mime_type(&bytes) {
+                Some(mime_type) => (id.to_string(), mime_type.to_string()),
+                None => return Ok(RepairOutcome::Unrecoverable),
+            },
+        };
+        // Object metadata can't be edited in place, so the object is copied onto itself.
+        let key = self.key(id);
+        let mut copy = self
+            .client
+            .copy_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .copy_source(format!("{}/{}", self.bucket, key))
+            .metadata_directive(aws_sdk_s3::types::MetadataDirective::Replace)
+            .content_type(&mime_type)
+            .metadata("file_name", file_name)
+            .metadata("mime_type", mime_type);
+        if let Some(dimensions) = dimensions {
+            copy = copy
+                .metadata("width", dimensions.width.to_string())
+                .metadata("height", dimensions.height.to_string());
+        }
+        copy.send().await.map_err(RepoError::internal)?;
+
+        Ok(RepairOutcome::Repaired)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, time::Duration};
+
+    use super::{image_id, ImageRepo, LocalImageRepo, S3ImageRepo};
+    use crate::models::image::{ImageDimensions, RepairOutcome};
+    use crate::repos::error::RepoError;
+
+    #[tokio::test]
+    async fn test_local_repo_refuses_path_traversal() {
+        let root = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let image_dir = root.join("images");
+        tokio::fs::create_dir_all(&image_dir).await.unwrap();
+
+        // A file next to `image_dir` that a traversal id would reach.
+        let secret = root.join("secret");
+        tokio::fs::write(&secret, b"secret").await.unwrap();
+        tokio::fs::write(root.join("secret.meta"), b"{}")
+            .await
+            .unwrap();
+
+        let repo = LocalImageRepo::new(image_dir.display().to_string(), false, false).unwrap();
+        for id in [
+            "../secret",
+            "..",
+            "/etc/passwd",
+            "sub/dir",
+            "..%2Fsecret",
+            "",
+            // A UUID with a traversal suffix still isn't a plain UUID.
+            "67e55044-10b1-426f-9247-bb680e5fe0c8/../../secret",
+        ] {
+            assert!(
+                matches!(repo.get_image(id).await, Err(RepoError::Validation(_))),
+                "{:?}",
+                id
+            );
+            assert!(
+                matches!(
+                    repo.open_image_stream(id, None).await,
+                    Err(RepoError::Validation(_))
+                ),
+                "{:?}",
+                id
+            );
+            assert!(
+                matches!(repo.delete_image(id).await, Err(RepoError::Validation(_))),
+                "{:?}",
+                id
+            );
+        }
+        assert!(tokio::fs::try_exists(&secret).await.unwrap());
+
+        // Ids the repo generated itself still work.
+        let id = repo
+            .create_image(
+                "photo.png".to_string(),
+                vec![1, 2, 3],
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(repo.get_image(&id).await.unwrap().bytes, vec![1, 2, 3]);
+        repo.delete_image(&id).await.unwrap();
+        assert!(matches!(
+            repo.get_image(&id).await,
+            Err(RepoError::NotFound)
+        ));
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_presigned_url() {
+        use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+
+        // Presigning happens locally, so nothing has to listen at the localstack endpoint.
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .endpoint_url("http://localhost:4566")
+            .force_path_style(true)
+            .build();
+        let repo = S3ImageRepo::new(
+            aws_sdk_s3::Client::from_conf(config),
+            "bazaars".to_string(),
+            "images/".to_string(),
+            false,
+        );
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+        let before = chrono::Utc::now();
+        let signed = repo
+            .presigned_url(id, Duration::from_secs(300))
+            .await
+            .unwrap()
+            .unwrap();
+        let url: axum::http::Uri = signed.url.parse().unwrap();
+        assert_eq!(url.host(), Some("localhost"));
+        assert_eq!(url.path(), format!("/bazaars/images/{}", id));
+        let query = url.query().unwrap();
+        assert!(query.contains("X-Amz-Expires=300"), "{}", query);
+        assert!(query.contains("X-Amz-Signature="), "{}", query);
+        let ttl = chrono::Duration::seconds(300);
+        assert!(signed.expires_at >= before + ttl - chrono::Duration::seconds(1));
+        assert!(signed.expires_at <= chrono::Utc::now() + ttl);
+
+        let image_dir = tempfile::tempdir().unwrap();
+        let local =
+            LocalImageRepo::new(image_dir.path().display().to_string(), false, false).unwrap();
+        assert_eq!(
+            local
+                .presigned_url(id, Duration::from_secs(300))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_health_check() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let image_dir = tempfile::tempdir().unwrap();
+        let repo =
+            LocalImageRepo::new(image_dir.path().display().to_string(), false, false).unwrap();
+        repo.health_check().await.unwrap();
+
+        let read_only = std::fs::Permissions::from_mode(0o555);
+        std::fs::set_permissions(image_dir.path(), read_only).unwrap();
+        // Root writes regardless of permissions, leaving only the missing directory below.
+        if std::fs::write(image_dir.path().join("probe"), b"").is_err() {
+            assert!(repo.health_check().await.is_err());
+        }
+        std::fs::set_permissions(image_dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let removed = image_dir.path().join("removed");
+        let repo = LocalImageRepo::new(removed.display().to_string(), false, false).unwrap();
+        std::fs::remove_dir(&removed).unwrap();
+        assert!(repo.health_check().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_repo_creates_image_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let image_dir = root.path().join("uploads/images");
+
+        let repo = LocalImageRepo::new(image_dir.display().to_string(), false, false).unwrap();
+        let id = repo
+            .create_image(
+                "photo.png".to_string(),
+                vec![1, 2, 3],
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(repo.get_image(&id).await.unwrap().bytes, vec![1, 2, 3]);
+
+        // An existing directory is used as it is.
+        assert!(LocalImageRepo::new(image_dir.display().to_string(), false, false).is_ok());
+
+        // A file where the directory should be can't be turned into one.
+        let file = root.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let error = LocalImageRepo::new(file.join("images").display().to_string(), false, false)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("cannot create image directory"));
+    }
+
+    #[tokio::test]
+    async fn test_local_repo_dedups_identical_uploads() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let repo =
+            LocalImageRepo::new(image_dir.path().display().to_string(), true, false).unwrap();
+        let create = |file_name: &str, bytes: &[u8]| {
+            repo.create_image(
+                file_name.to_string(),
+                bytes.to_vec(),
+                "image/png".to_string(),
+            )
+        };
+
+        let first = create("front.png", b"same photo").await.unwrap();
+        let second = create("again.png", b"same photo").await.unwrap();
+        assert_eq!(first, second);
+        // The image and its metadata, stored once.
+        assert_eq!(std::fs::read_dir(image_dir.path()).unwrap().count(), 2);
+        assert_eq!(repo.get_image(&first).await.unwrap().file_name, "front.png");
+
+        let other = create("back.png", b"another photo").await.unwrap();
+        assert_ne!(other, first);
+        assert_eq!(std::fs::read_dir(image_dir.path()).unwrap().count(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_local_repo_deletes_images() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let repo =
+            LocalImageRepo::new(image_dir.path().display().to_string(), false, false).unwrap();
+        let mut ids = Vec::new();
+        for file_name in ["front.png", "back.png", "side.png"] {
+            let id = repo
+                .create_image(
+                    file_name.to_string(),
+                    file_name.as_bytes().to_vec(),
+                    "image/png".to_string(),
+                )
+                .await
+                .unwrap();
+            ids.push(id);
+        }
+        let missing = uuid::Uuid::new_v4().to_string();
+
+        let results = repo
+            .delete_images(&[&ids[0], &missing, &ids[1], "../secret"])
+            .await
+            .unwrap();
+        let ids_back: Vec<_> = results.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids_back, [&ids[0], &missing, &ids[1], "../secret"]);
+        assert!(results[0].1.is_ok());
+        assert!(matches!(results[1].1, Err(RepoError::NotFound)));
+        assert!(results[2].1.is_ok());
+        assert!(matches!(results[3].1, Err(RepoError::Validation(_))));
+
+        assert!(!repo.exists(&ids[0]).await.unwrap());
+        assert!(!repo.exists(&ids[1]).await.unwrap());
+        assert!(repo.exists(&ids[2]).await.unwrap());
+        assert!(repo.delete_images(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_local_repo_deletes_thumbnails() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let repo =
+            LocalImageRepo::new(image_dir.path().display().to_string(), false, false).unwrap();
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(300, 200)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let create = || {
+            repo.create_image(
+                "photo.png".to_string(),
+                png.get_ref().clone(),
+                "image/png".to_string(),
+            )
+        };
+        let id = create().await.unwrap();
+        let other = create().await.unwrap();
+        for max_dim in [64, 128] {
+            repo.get_thumbnail(&id, max_dim).await.unwrap();
+        }
+        repo.get_thumbnail(&other, 64).await.unwrap();
+        assert_eq!(std::fs::read_dir(image_dir.path()).unwrap().count(), 7);
+
+        repo.delete_image(&id).await.unwrap();
+        let mut left: Vec<_> = std::fs::read_dir(image_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                other.clone(),
+                format!("{}.meta", other),
+                format!("{}.thumb.64", other)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_repo_repairs_metadata() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let repo =
+            LocalImageRepo::new(image_dir.path().display().to_string(), false, false).unwrap();
+        let png = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0];
+        let create = |bytes: &[u8]| {
+            repo.create_image(
+                "photo.png".to_string(),
+                bytes.to_vec(),
+                "image/png".to_string(),
+            )
+        };
+        let meta_path = |id: &str| image_dir.path().join(format!("{}.meta", id));
+
+        let id = create(&png).await.unwrap();
+        assert_eq!(repo.repair(&id).await.unwrap(), RepairOutcome::Intact);
+        assert_eq!(repo.get_image(&id).await.unwrap().file_name, "photo.png");
+
+        std::fs::remove_file(meta_path(&id)).unwrap();
+        assert!(repo.get_image(&id).await.is_err());
+        assert_eq!(repo.repair(&id).await.unwrap(), RepairOutcome::Repaired);
+        let image = repo.get_image(&id).await.unwrap();
+        assert_eq!(image.file_name, id);
+        assert_eq!(image.mime_type, "image/png");
+        assert_eq!(image.bytes, png);
+
+        // Corrupted metadata is rebuilt the same way.
+        std::fs::write(meta_path(&id), b"{\"file_na").unwrap();
+        assert_eq!(repo.repair(&id).await.unwrap(), RepairOutcome::Repaired);
+        assert!(repo.get_image(&id).await.is_ok());
+
+        // Bytes that aren't a known image type leave nothing to go on.
+        let unknown = create(b"not an image").await.unwrap();
+        std::fs::remove_file(meta_path(&unknown)).unwrap();
+        assert_eq!(
+            repo.repair(&unknown).await.unwrap(),
+            RepairOutcome::Unrecoverable
+        );
+        assert!(!meta_path(&unknown).exists());
+
+        assert!(matches!(
+            repo.repair(&uuid::Uuid::new_v4().to_string()).await,
+            Err(RepoError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_local_repo_records_dimensions() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let repo =
+            LocalImageRepo::new(image_dir.path().display().to_string(), false, false).unwrap();
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(3, 2)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let dimensions = Some(ImageDimensions {
+            width: 3,
+            height: 2,
+        });
+
+        let id = repo
+            .create_image(
+                "wide.png".to_string(),
+                png.into_inner(),
+                "image/png".to_string(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(repo.get_dimensions(&id).await.unwrap(), dimensions);
+        assert_eq!(repo.get_image(&id).await.unwrap().dimensions, dimensions);
+
+        // Metadata written before dimensions were recorded is backfilled, the rest kept.
+        let meta_path = image_dir.path().join(format!("{}.meta", id));
+        std::fs::write(
+            &meta_path,
+            br#"{"file_name":"wide.png","mime_type":"image/png"}"#,
+        )
+        .unwrap();
+        assert_eq!(repo.get_dimensions(&id).await.unwrap(), None);
+        assert_eq!(repo.repair(&id).await.unwrap(), RepairOutcome::Repaired);
+        assert_eq!(repo.get_dimensions(&id).await.unwrap(), dimensions);
+        assert_eq!(repo.get_image(&id).await.unwrap().file_name, "wide.png");
+        assert_eq!(repo.repair(&id).await.unwrap(), RepairOutcome::Intact);
+    }
+
+    #[tokio::test]
+    async fn test_local_repo_writes_atomically() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let repo =
+            LocalImageRepo::new(image_dir.path().display().to_string(), true, false).unwrap();
+        let bytes: Vec<u8> = (0..4 << 20).map(|n| n as u8).collect();
+        let id = image_id(&bytes, true);
+        let path = image_dir.path().join(&id);
+
+        // A writer caught halfway: metadata in place, the image still in its temp file.
+        std::fs::write(
+            image_dir.path().join(format!("{}.meta", id)),
+            r#"{"file_name":"photo.png","mime_type":"image/png"}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            image_dir.path().join(format!("{}.tmp-partial", id)),
+            &bytes[..bytes.len() / 2],
+        )
+        .unwrap();
+        assert!(matches!(
+            repo.get_image(&id).await,
+            Err(RepoError::NotFound)
+        ));
+        std::fs::remove_file(image_dir.path().join(format!("{}.tmp-partial", id))).unwrap();
+
+        // Readers racing a real write see either nothing or the whole image.
+        let writer = {
+            let repo = repo.clone();
+            let bytes = bytes.clone();
+            tokio::spawn(async move {
+                repo.create_image("photo.png".to_string(), bytes, "image/png".to_string())
+                    .await
+            })
+        };
+        while !writer.is_finished() {
+            match repo.get_image(&id).await {
+                Ok(image) => {
+                    assert_eq!(image.bytes.len(), bytes.len());
+                    assert_eq!(image.mime_type, "image/png");
+                }
+                Err(RepoError::NotFound) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(writer.await.unwrap().unwrap(), id);
+        assert_eq!(repo.get_image(&id).await.unwrap().bytes, bytes);
+        assert!(path.exists());
+
+        // No temp files are left behind.
+        assert_eq!(std::fs::read_dir(image_dir.path()).unwrap().count(), 2);
+    }
 }
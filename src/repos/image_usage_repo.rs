@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use bigdecimal::{BigDecimal, ToPrimitive};
+use diesel::prelude::*;
+
+use crate::db::schema::image_uploads;
+use crate::db::DbManager;
+use crate::repos::error::RepoError;
+
+/// Bytes of stored images per user, checked against `ImageLimits::quota_bytes` before an
+/// upload is stored. An image counts once per user who uploaded it, however many of their
+/// ads list it.
+#[async_trait]
+pub trait ImageUsageRepo: Send + Sync {
+    /// Counts the image against `user_email`. Recording it again is a no-op.
+    async fn record(&self, user_email: &str, image_id: &str, bytes: u64) -> Result<(), RepoError>;
+    /// Total bytes of the images counted against `user_email`.
+    async fn used_bytes(&self, user_email: &str) -> Result<u64, RepoError>;
+    /// Stops counting the images, for whoever uploaded them, once they are deleted.
+    async fn forget(&self, image_ids: &[String]) -> Result<(), RepoError>;
+}
+
+#[derive(Clone)]
+pub struct PostgresImageUsageRepo {
+    pub db_manager: DbManager,
+}
+
+impl PostgresImageUsageRepo {
+    pub fn new(db_manager: DbManager) -> Arc<PostgresImageUsageRepo> {
+        Arc::new(PostgresImageUsageRepo { db_manager })
+    }
+}
+
+#[async_trait]
+impl ImageUsageRepo for PostgresImageUsageRepo {
+    async fn record(&self, user_email: &str, image_id: &str, bytes: u64) -> Result<(), RepoError> {
+        diesel::insert_into(image_uploads::table)
+            .values((
+                image_uploads::user_email.eq(user_email),
+                image_uploads::image_id.eq(image_id),
+                image_uploads::bytes.eq(i64::try_from(bytes).map_err(RepoError::internal)?),
+                image_uploads::created_at.eq(chrono::Utc::now()),
+            ))
+            .on_conflict_do_nothing()
+            .execute(&mut self.db_manager.write_conn()?)
+            .map_err(RepoError::from)?;
+
+        Ok(())
+    }
+
+    async fn used_bytes(&self, user_email: &str) -> Result<u64, RepoError> {
+        let used = image_uploads::table
+            .filter(image_uploads::user_email.eq(user_email))
+            .select(diesel::dsl::sum(image_uploads::bytes))
+            .first::<Option<BigDecimal>>(&mut self.db_manager.read_conn()?)
+            .map_err(RepoError::from)?;
+
+        Ok(used.and_then(|used| used.to_u64()).unwrap_or_default())
+    }
+
+    async fn forget(&self, image_ids: &[String]) -> Result<(), RepoError> {
+        if image_ids.is_empty() {
+            return Ok(());
+        }
+        diesel::delete(image_uploads::table.filter(image_uploads::image_id.eq_any(image_ids)))
+            .execute(&mut self.db_manager.write_conn()?)
+            .map_err(RepoError::from)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::repos::{
+        fixtures::test_db,
+        image_usage_repo::{ImageUsageRepo, PostgresImageUsageRepo},
+    };
+
+    #[tokio::test]
+    async fn test_image_usage() {
+        let usage_repo = PostgresImageUsageRepo::new(test_db());
+        let user = format!("{}@test.com", uuid::Uuid::new_v4());
+        let first = uuid::Uuid::new_v4().to_string();
+        let second = uuid::Uuid::new_v4().to_string();
+
+        assert_eq!(usage_repo.used_bytes(&user).await.unwrap(), 0);
+
+        usage_repo.record(&user, &first, 100).await.unwrap();
+        usage_repo.record(&user, &second, 50).await.unwrap();
+        // The same image again isn't counted twice.
+        usage_repo.record(&user, &first, 100).await.unwrap();
+        assert_eq!(usage_repo.used_bytes(&user).await.unwrap(), 150);
+
+        usage_repo.forget(&[first]).await.unwrap();
+        assert_eq!(usage_repo.used_bytes(&user).await.unwrap(), 50);
+    }
+}
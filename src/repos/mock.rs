@@ -0,0 +1,1280 @@
+//! In-memory `AdRepo`, `ImageRepo`, `ImageUsageRepo` and `OutboxRepo`, for handler tests and running locally without
+//! Postgres or disk. Built with `cfg(test)` or the `testing` feature.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use bigdecimal::{BigDecimal, RoundingMode};
+use chrono::SubsecRound;
+
+use crate::models::ad::{ad_slug, Ad, AdContent, AdId, AdPatch, AdStatus};
+use crate::models::image::{
+    ByteRange, Image, ImageDimensions, ImageStream, RepairOutcome, SignedUrl,
+};
+use crate::models::money::Money;
+use crate::models::outbox::{OutboxEntry, OutboxStatus};
+use crate::models::price_history::PriceChange;
+use crate::models::stats::{price_bucket, AdFacets, AdStats, DailyCount, STATS_DAYS};
+use crate::notify::ContactMessage;
+use crate::repos::ad_repo::{
+    ad_distance_km, append_images, check_cursor_filter, check_image_order, idempotency_cutoff,
+    remove_image_id, replace_images, validate_cursor_name, AdFilter, AdKeyset, AdRepo, AdSort,
+    CursorPage, AD_LIFETIME_DAYS, MAX_BATCH_IDS, MAX_CURSOR_FETCH, MAX_PHONE_CODE_ATTEMPTS,
+    PHONE_CODE_TTL_MINUTES, RENEW_COOLDOWN_HOURS,
+};
+use crate::repos::error::RepoError;
+use crate::repos::image_repo::{make_thumbnail, ImageRepo};
+use crate::repos::image_usage_repo::ImageUsageRepo;
+use crate::repos::outbox_repo::OutboxRepo;
+
+#[derive(Default)]
+struct AdStore {
+    next_id: i32,
+    ads: HashMap<AdId, Ad>,
+    /// Rows are fixed when the cursor is declared, like a `WITH HOLD` cursor, and kept
+    /// with when it was last fetched from and the fingerprint of the filter.
+    cursors: HashMap<String, (Instant, u64, VecDeque<Ad>)>,
+    /// `(user_email, key)` to the ad created and when the key was recorded.
+    idempotency_keys: HashMap<(String, String), (AdId, chrono::DateTime<chrono::Utc>)>,
+    /// Slugs ads had before their title changed.
+    old_slugs: HashMap<String, AdId>,
+    /// Price changes of every ad, oldest first.
+    price_history: Vec<(AdId, PriceChange)>,
+    /// Pending phone verification code of an ad, with the number it was sent to, wrong
+    /// attempts so far and when it expires.
+    phone_codes: HashMap<AdId, PhoneCode>,
+}
+
+struct PhoneCode {
+    code: String,
+    user_phone: String,
+    attempts: i32,
+    expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl AdStore {
+    fn insert(&mut self, ad: AdContent, image_ids: Vec<String>) -> Result<Ad, RepoError> {
+        let now = now();
+        self.next_id += 1;
+        let id = AdId(self.next_id);
+
+        let ad = Ad {
+            id,
+            slug: ad_slug(&ad.title, id),
+            title: ad.title,
+            description: ad.description,
+            price: ad.price,
+            status: if ad.draft {
+                AdStatus::Draft
+            } else {
+                AdStatus::Active
+            }
+            .as_str()
+            .to_string(),
+            user_email: ad.user_email,
+            user_phone: ad.user_phone,
+            created_at: now,
+            updated_at: now,
+            top_ad: false,
+            images: image_ids,
+            expires_at: Some(now + chrono::Duration::days(AD_LIFETIME_DAYS)),
+            category: ad.category.as_str().to_string(),
+            latitude: ad.latitude,
+            longitude: ad.longitude,
+            view_count: 0,
+            currency: ad.currency.as_str().to_string(),
+            deleted_at: None,
+            top_ad_until: None,
+            negotiable: ad.negotiable,
+            locale: ad.locale.as_str().to_string(),
+            phone_verified: false,
+        };
+        self.ads.insert(ad.id, ad.clone());
+
+        Ok(ad)
+    }
+
+    fn live(&mut self, id: AdId) -> Option<&mut Ad> {
+        self.ads.get_mut(&id).filter(|ad| ad.deleted_at.is_none())
+    }
+
+    fn owned(&mut self, id: AdId, user_email: &str) -> Option<&mut Ad> {
+        self.live(id).filter(|ad| ad.user_email == user_email)
+    }
+
+    /// Records what an edit changed, like `write_ad`: the old slug keeps resolving and a
+    /// new price joins the price history.
+    fn record_edit(&mut self, ad: &Ad, old_slug: Option<String>, old_price: (Money, String)) {
+        if let Some(old_slug) = old_slug.filter(|old_slug| *old_slug != ad.slug) {
+            self.old_slugs.insert(old_slug, ad.id);
+        }
+        if old_price != (ad.price.clone(), ad.currency.clone()) {
+            self.price_history.push((
+                ad.id,
+                PriceChange {
+                    price: ad.price.clone(),
+                    currency: ad.currency.clone(),
+                    changed_at: ad.updated_at,
+                },
+            ));
+        }
+    }
+
+    fn deleted(&mut self, id: AdId) -> Option<&mut Ad> {
+        self.ads.get_mut(&id).filter(|ad| ad.deleted_at.is_some())
+    }
+
+    /// Drops the ad for good, along with the rows that reference it, like the cascading
+    /// foreign keys in Postgres.
+    fn remove(&mut self, id: AdId) -> Option<Ad> {
+        self.idempotency_keys.retain(|_, (ad_id, _)| *ad_id != id);
+        self.old_slugs.retain(|_, ad_id| *ad_id != id);
+        self.price_history.retain(|(ad_id, _)| *ad_id != id);
+        self.phone_codes.remove(&id);
+        self.ads.remove(&id)
+    }
+
+    /// Matching ads in `filter`'s order, as `new_cursor` and `get_page` return them.
+    fn sorted(&self, filter: &AdFilter) -> Vec<Ad> {
+        let mut ads = self.filtered(filter);
+        ads.sort_by(|a, b| compare(a, b, filter));
+        ads
+    }
+
+    fn filtered(&self, filter: &AdFilter) -> Vec<Ad> {
+        self.ads
+            .values()
+            .filter(|ad| filter.matches(ad))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The current time at the microsecond precision of a Postgres `TIMESTAMP`, so an
+/// `ETag` made from `updated_at` matches the stored value again.
+fn now() -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc::now().trunc_subsecs(6)
+}
+
+/// The ordering of `apply_sort`, minus search ranking; ties go to the newest id so pages
+/// are stable.
+fn compare(a: &Ad, b: &Ad, filter: &AdFilter) -> Ordering {
+    let by_sort = match filter.sort_by.unwrap_or_default() {
+        AdSort::PriceAsc => a.price.cmp(&b.price),
+        AdSort::PriceDesc => b.price.cmp(&a.price),
+        AdSort::CreatedAtAsc => a.created_at.cmp(&b.created_at),
+        AdSort::CreatedAtDesc => b.created_at.cmp(&a.created_at),
+        AdSort::UpdatedAtDesc => b.updated_at.cmp(&a.updated_at),
+        // Like `ORDER BY ... ASC` in Postgres, ads without a location come last.
+        AdSort::DistanceAsc => match filter.center() {
+            Some(center) => match (ad_distance_km(a, center), ad_distance_km(b, center)) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+            None => b.created_at.cmp(&a.created_at),
+        },
+    };
+
+    let now = now();
+    let promoted = |ad: &Ad| ad.top_ad && ad.top_ad_until.is_none_or(|until| until > now);
+    promoted(b)
+        .cmp(&promoted(a))
+        .then(by_sort)
+        .then(b.id.cmp(&a.id))
+}
+
+/// `AdRepo` over a `HashMap`. Ids are handed out sequentially from 1.
+#[derive(Default)]
+pub struct InMemoryAdRepo {
+    store: Mutex<AdStore>,
+}
+
+impl InMemoryAdRepo {
+    pub fn new() -> Arc<InMemoryAdRepo> {
+        Arc::new(InMemoryAdRepo::default())
+    }
+}
+
+#[async_trait]
+impl AdRepo for InMemoryAdRepo {
+    async fn new_cursor(&self, filter: AdFilter) -> Result<String, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let cursor_name = format!("c_{}", &uuid::Uuid::new_v4().simple().to_string()[..10]);
+        let rows = store.sorted(&filter).into();
+        store.cursors.insert(
+            cursor_name.clone(),
+            (Instant::now(), filter.fingerprint(), rows),
+        );
+
+        Ok(cursor_name)
+    }
+
+    async fn fetch_from_cursor(
+        &self,
+        cursor_name: String,
+        count: u32,
+        auto_close: bool,
+        filter: Option<&AdFilter>,
+    ) -> Result<CursorPage<Ad>, RepoError> {
+        validate_cursor_name(&cursor_name)?;
+        let count = count.clamp(1, MAX_CURSOR_FETCH) as usize;
+        let mut store = self.store.lock().unwrap();
+
+        let (used_at, declared, rows) = store
+            .cursors
+            .get_mut(&cursor_name)
+            .ok_or(RepoError::CursorExpired)?;
+        if let Some(filter) = filter {
+            check_cursor_filter(*declared, filter)?;
+        }
+        *used_at = Instant::now();
+        let ads: Vec<Ad> = rows.drain(..count.min(rows.len())).collect();
+
+        let closed = auto_close && ads.len() < count;
+        if closed {
+            store.cursors.remove(&cursor_name);
+        }
+
+        Ok(CursorPage::new(ads, count, cursor_name, closed))
+    }
+
+    async fn close_cursor(&self, cursor_name: String) -> Result<(), RepoError> {
+        validate_cursor_name(&cursor_name)?;
+        self.store.lock().unwrap().cursors.remove(&cursor_name);
+        Ok(())
+    }
+
+    async fn get_by_id(&self, id: AdId, increment: bool) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        Ok(store.live(id).map(|ad| {
+            if increment {
+                ad.view_count += 1;
+            }
+            ad.clone()
+        }))
+    }
+
+    async fn get_by_slug(&self, slug: &str) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let id = match store.old_slugs.get(slug) {
+            Some(id) => Some(*id),
+            None => store
+                .ads
+                .values()
+                .find(|ad| ad.slug == slug)
+                .map(|ad| ad.id),
+        };
+        Ok(id.and_then(|id| store.live(id)).map(|ad| ad.clone()))
+    }
+
+    async fn price_history(&self, id: AdId) -> Result<Vec<PriceChange>, RepoError> {
+        let store = self.store.lock().unwrap();
+        Ok(store
+            .price_history
+            .iter()
+            .filter(|(ad_id, _)| *ad_id == id)
+            .map(|(_, change)| change.clone())
+            .collect())
+    }
+
+    async fn exists(&self, id: AdId) -> Result<bool, RepoError> {
+        Ok(self.store.lock().unwrap().live(id).is_some())
+    }
+
+    async fn find_similar(
+        &self,
+        user_email: &str,
+        title: &str,
+        price: &Money,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<Ad>, RepoError> {
+        let title = title.trim().to_lowercase();
+        Ok(self
+            .store
+            .lock()
+            .unwrap()
+            .ads
+            .values()
+            .filter(|ad| ad.deleted_at.is_none() && ad.user_email == user_email)
+            .filter(|ad| ad.created_at >= since && ad.price == *price)
+            .filter(|ad| ad.title.trim().to_lowercase() == title)
+            .max_by_key(|ad| (ad.created_at, ad.id.0))
+            .cloned())
+    }
+
+    async fn get_by_ids(&self, ids: &[AdId]) -> Result<Vec<Ad>, RepoError> {
+        if ids.len() > MAX_BATCH_IDS {
+            return Err(RepoError::Validation(format!(
+                "at most {} ids can be fetched at once",
+                MAX_BATCH_IDS
+            )));
+        }
+
+        let mut store = self.store.lock().unwrap();
+        let mut seen = Vec::new();
+        Ok(ids
+            .iter()
+            .filter(|id| {
+                let first = !seen.contains(*id);
+                seen.push(**id);
+                first
+            })
+            .filter_map(|id| store.live(*id).map(|ad| ad.clone()))
+            .collect())
+    }
+
+    async fn get_page(
+        &self,
+        offset: u32,
+        per_page: u32,
+        filter: AdFilter,
+    ) -> Result<Vec<Ad>, RepoError> {
+        let store = self.store.lock().unwrap();
+        Ok(store
+            .sorted(&filter)
+            .into_iter()
+            .skip(offset as usize)
+            .take(per_page as usize)
+            .collect())
+    }
+
+    async fn get_page_after(
+        &self,
+        after: Option<AdKeyset>,
+        per_page: u32,
+        filter: AdFilter,
+    ) -> Result<Vec<Ad>, RepoError> {
+        let store = self.store.lock().unwrap();
+        let mut ads: Vec<Ad> = store
+            .filtered(&filter)
+            .into_iter()
+            .filter(|ad| {
+                after.is_none_or(|after| (ad.created_at, ad.id) < (after.created_at, after.id))
+            })
+            .collect();
+        ads.sort_by_key(|ad| Reverse((ad.created_at, ad.id)));
+        ads.truncate(per_page as usize);
+
+        Ok(ads)
+    }
+
+    async fn count(&self, filter: AdFilter) -> Result<u64, RepoError> {
+        Ok(self.store.lock().unwrap().filtered(&filter).len() as u64)
+    }
+
+    async fn get_by_user(
+        &self,
+        user_email: &str,
+        include_unlisted: bool,
+        offset: u32,
+        per_page: u32,
+    ) -> Result<(Vec<Ad>, u64), RepoError> {
+        // The default filter lists exactly the active, unexpired, live ads.
+        let listed = AdFilter::default();
+        let store = self.store.lock().unwrap();
+        let mut ads: Vec<Ad> = store
+            .ads
+            .values()
+            .filter(|ad| ad.user_email == user_email && ad.deleted_at.is_none())
+            .filter(|ad| include_unlisted || listed.matches(ad))
+            .cloned()
+            .collect();
+        ads.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+        let total = ads.len() as u64;
+
+        Ok((
+            ads.into_iter()
+                .skip(offset as usize)
+                .take(per_page as usize)
+                .collect(),
+            total,
+        ))
+    }
+
+    async fn facets(&self, filter: AdFilter) -> Result<AdFacets, RepoError> {
+        let mut categories = BTreeMap::new();
+        let mut buckets = Vec::new();
+        for ad in self.store.lock().unwrap().filtered(&filter) {
+            *categories.entry(ad.category).or_insert(0) += 1;
+            buckets.push((price_bucket(ad.price.amount()), 1));
+        }
+
+        Ok(AdFacets::new(categories, &buckets))
+    }
+
+    async fn stats(&self) -> Result<AdStats, RepoError> {
+        let store = self.store.lock().unwrap();
+        let listed = store.filtered(&AdFilter::default());
+        let today = chrono::Utc::now().date_naive();
+
+        let mut per_category = BTreeMap::new();
+        let mut prices: BTreeMap<String, Vec<&BigDecimal>> = BTreeMap::new();
+        for ad in &listed {
+            *per_category.entry(ad.category.clone()).or_insert(0) += 1;
+            prices
+                .entry(ad.currency.clone())
+                .or_default()
+                .push(ad.price.amount());
+        }
+        let average_price = prices
+            .into_iter()
+            .map(|(currency, prices)| {
+                let sum: BigDecimal = prices.iter().copied().sum();
+                let average = sum / prices.len() as i64;
+                (currency, average.with_scale_round(2, RoundingMode::HalfUp))
+            })
+            .collect();
+
+        let created_per_day = (0..STATS_DAYS)
+            .rev()
+            .map(|days_ago| {
+                let day = today - chrono::Duration::days(days_ago.into());
+                let count = store
+                    .ads
+                    .values()
+                    .filter(|ad| ad.deleted_at.is_none() && ad.created_at.date_naive() == day)
+                    .count();
+                DailyCount {
+                    day,
+                    count: count as i64,
+                }
+            })
+            .collect();
+
+        Ok(AdStats {
+            total_active: listed.len() as i64,
+            per_category,
+            average_price,
+            created_per_day,
+        })
+    }
+
+    async fn create(&self, ad: AdContent, image_ids: Vec<String>) -> Result<Ad, RepoError> {
+        self.store.lock().unwrap().insert(ad, image_ids)
+    }
+
+    async fn create_many(&self, ads: Vec<(AdContent, Vec<String>)>) -> Result<Vec<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        ads.into_iter()
+            .map(|(ad, image_ids)| store.insert(ad, image_ids))
+            .collect()
+    }
+
+    async fn get_by_idempotency_key(
+        &self,
+        user_email: &str,
+        key: &str,
+    ) -> Result<Option<Ad>, RepoError> {
+        let store = self.store.lock().unwrap();
+        Ok(store
+            .idempotency_keys
+            .get(&(user_email.to_string(), key.to_string()))
+            .filter(|(_, created_at)| *created_at >= idempotency_cutoff())
+            .and_then(|(id, _)| store.ads.get(id))
+            .cloned())
+    }
+
+    async fn create_idempotent(
+        &self,
+        ad: AdContent,
+        image_ids: Vec<String>,
+        key: &str,
+    ) -> Result<(Ad, bool), RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let entry = (ad.user_email.clone(), key.to_string());
+
+        let existing = store
+            .idempotency_keys
+            .get(&entry)
+            .filter(|(_, created_at)| *created_at >= idempotency_cutoff())
+            .and_then(|(id, _)| store.ads.get(id));
+        if let Some(ad) = existing {
+            return Ok((ad.clone(), false));
+        }
+
+        let ad = store.insert(ad, image_ids)?;
+        store.idempotency_keys.insert(entry, (ad.id, ad.created_at));
+
+        Ok((ad, true))
+    }
+
+    async fn get_owner(&self, id: AdId) -> Result<Option<String>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        Ok(store.live(id).map(|ad| ad.user_email.clone()))
+    }
+
+    async fn update(
+        &self,
+        id: AdId,
+        user_email: &str,
+        content: AdContent,
+    ) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let Some(ad) = store
+            .owned(id, user_email)
+            .filter(|ad| ad.status != AdStatus::Hidden.as_str())
+        else {
+            return Ok(None);
+        };
+
+        let old_slug = Some(ad.slug.clone());
+        let old_price = (ad.price.clone(), ad.currency.clone());
+        if content.user_phone != ad.user_phone {
+            ad.phone_verified = false;
+        }
+        ad.slug = ad_slug(&content.title, id);
+        ad.title = content.title;
+        ad.description = content.description;
+        ad.price = content.price;
+        ad.currency = content.currency.as_str().to_string();
+        ad.user_phone = content.user_phone;
+        ad.negotiable = content.negotiable;
+        ad.locale = content.locale.as_str().to_string();
+        ad.category = content.category.as_str().to_string();
+        ad.latitude = content.latitude;
+        ad.longitude = content.longitude;
+        ad.updated_at = now();
+        let ad = ad.clone();
+
+        store.record_edit(&ad, old_slug, old_price);
+        Ok(Some(ad))
+    }
+
+    async fn patch(
+        &self,
+        id: AdId,
+        user_email: &str,
+        changes: AdPatch,
+        expected_updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let Some(ad) = store
+            .owned(id, user_email)
+            .filter(|ad| ad.status != AdStatus::Hidden.as_str())
+            .filter(|ad| expected_updated_at.is_none_or(|at| at == ad.updated_at))
+        else {
+            return Ok(None);
+        };
+
+        let old_slug = changes.title.as_ref().map(|_| ad.slug.clone());
+        let old_price = (ad.price.clone(), ad.currency.clone());
+        if let Some(title) = changes.title {
+            ad.slug = ad_slug(&title, id);
+            ad.title = title;
+        }
+        if let Some(description) = changes.description {
+            ad.description = description;
+        }
+        if let Some(price) = changes.price {
+            ad.price = price;
+        }
+        if let Some(currency) = changes.currency {
+            ad.currency = currency.as_str().to_string();
+        }
+        if let Some(user_phone) = changes.user_phone {
+            if user_phone != ad.user_phone {
+                ad.phone_verified = false;
+            }
+            ad.user_phone = user_phone;
+        }
+        if let Some(negotiable) = changes.negotiable {
+            ad.negotiable = negotiable;
+        }
+        if let Some(locale) = changes.locale {
+            ad.locale = locale.as_str().to_string();
+        }
+        if let Some(status) = changes.status {
+            ad.status = status.as_str().to_string();
+        }
+        if let Some(category) = changes.category {
+            ad.category = category.as_str().to_string();
+        }
+        if let Some(latitude) = changes.latitude {
+            ad.latitude = Some(latitude);
+        }
+        if let Some(longitude) = changes.longitude {
+            ad.longitude = Some(longitude);
+        }
+        ad.updated_at = now();
+        let ad = ad.clone();
+
+        store.record_edit(&ad, old_slug, old_price);
+        Ok(Some(ad))
+    }
+
+    async fn reorder_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_ids: Vec<String>,
+    ) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let Some(ad) = store.owned(id, user_email) else {
+            return Ok(None);
+        };
+        check_image_order(&ad.images, &image_ids)?;
+
+        ad.images = image_ids;
+        ad.updated_at = now();
+        Ok(Some(ad.clone()))
+    }
+
+    async fn add_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_ids: Vec<String>,
+        max_count: usize,
+    ) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let Some(ad) = store.owned(id, user_email) else {
+            return Ok(None);
+        };
+
+        ad.images = append_images(&ad.images, image_ids, max_count)?;
+        ad.updated_at = now();
+        Ok(Some(ad.clone()))
+    }
+
+    async fn set_images(
+        &self,
+        id: AdId,
+        user_email: &str,
+        kept: Vec<String>,
+        added: Vec<String>,
+        max_count: usize,
+    ) -> Result<Option<(Ad, Vec<String>)>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let Some(ad) = store.owned(id, user_email) else {
+            return Ok(None);
+        };
+
+        let (images, dropped) = replace_images(&ad.images, kept, added, max_count)?;
+        ad.images = images;
+        ad.updated_at = now();
+        Ok(Some((ad.clone(), dropped)))
+    }
+
+    async fn remove_image(
+        &self,
+        id: AdId,
+        user_email: &str,
+        image_id: &str,
+    ) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let Some(ad) = store.owned(id, user_email) else {
+            return Ok(None);
+        };
+
+        ad.images = remove_image_id(&ad.images, image_id)?;
+        ad.updated_at = now();
+        Ok(Some(ad.clone()))
+    }
+
+    async fn delete(&self, id: AdId, user_email: &str) -> Result<usize, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let now = now();
+
+        Ok(store
+            .owned(id, user_email)
+            .map(|ad| {
+                ad.deleted_at = Some(now);
+                ad.updated_at = now;
+            })
+            .map_or(0, |_| 1))
+    }
+
+    async fn restore(&self, id: AdId) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        Ok(store.deleted(id).map(|ad| {
+            ad.deleted_at = None;
+            ad.updated_at = now();
+            ad.clone()
+        }))
+    }
+
+    async fn purge(&self, id: AdId) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        if store.deleted(id).is_none() {
+            return Ok(None);
+        }
+
+        Ok(store.remove(id))
+    }
+
+    async fn delete_by_user(&self, user_email: &str) -> Result<Vec<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let ids: Vec<AdId> = store
+            .ads
+            .values()
+            .filter(|ad| ad.user_email == user_email)
+            .map(|ad| ad.id)
+            .collect();
+
+        Ok(ids.into_iter().filter_map(|id| store.remove(id)).collect())
+    }
+
+    async fn images_in_use(&self, ids: &[String]) -> Result<Vec<String>, RepoError> {
+        let store = self.store.lock().unwrap();
+        let mut in_use = Vec::new();
+        for ad in store.ads.values() {
+            in_use.extend(ad.images.iter().filter(|id| ids.contains(id)));
+        }
+        Ok(ids
+            .iter()
+            .filter(|id| in_use.contains(id))
+            .cloned()
+            .collect())
+    }
+
+    async fn set_status(&self, id: AdId, status: AdStatus) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        Ok(store.live(id).map(|ad| {
+            ad.status = status.as_str().to_string();
+            ad.updated_at = now();
+            ad.clone()
+        }))
+    }
+
+    async fn publish(&self, id: AdId, user_email: &str) -> Result<Option<Ad>, RepoError> {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        Ok(store
+            .owned(id, user_email)
+            .filter(|ad| ad.status == AdStatus::Draft.as_str())
+            .map(|ad| {
+                ad.status = AdStatus::Active.as_str().to_string();
+                ad.created_at = now;
+                ad.updated_at = now;
+                ad.expires_at = Some(now + chrono::Duration::days(AD_LIFETIME_DAYS));
+                ad.clone()
+            }))
+    }
+
+    async fn renew(&self, id: AdId, user_email: &str) -> Result<Option<Ad>, RepoError> {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        Ok(store
+            .owned(id, user_email)
+            .filter(|ad| ad.status == AdStatus::Active.as_str())
+            .filter(|ad| ad.created_at <= now - chrono::Duration::hours(RENEW_COOLDOWN_HOURS))
+            .map(|ad| {
+                ad.created_at = now;
+                ad.updated_at = now;
+                ad.expires_at = Some(now + chrono::Duration::days(AD_LIFETIME_DAYS));
+                ad.clone()
+            }))
+    }
+
+    async fn promote(
+        &self,
+        id: AdId,
+        user_email: &str,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<Ad>, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        Ok(store
+            .owned(id, user_email)
+            .filter(|ad| ad.status == AdStatus::Active.as_str())
+            .map(|ad| {
+                ad.top_ad = true;
+                ad.top_ad_until = Some(until);
+                ad.updated_at = now();
+                ad.clone()
+            }))
+    }
+
+    async fn start_phone_verification(
+        &self,
+        id: AdId,
+        user_email: &str,
+        code: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>, RepoError> {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        store
+            .phone_codes
+            .retain(|_, pending| pending.expires_at > now);
+
+        let Some(user_phone) = store.owned(id, user_email).map(|ad| ad.user_phone.clone()) else {
+            return Ok(None);
+        };
+        let expires_at = now + chrono::Duration::minutes(PHONE_CODE_TTL_MINUTES);
+        store.phone_codes.insert(
+            id,
+            PhoneCode {
+                code: code.to_string(),
+                user_phone,
+                attempts: 0,
+                expires_at,
+            },
+        );
+
+        Ok(Some(expires_at))
+    }
+
+    async fn verify_phone(
+        &self,
+        id: AdId,
+        user_email: &str,
+        code: &str,
+    ) -> Result<Option<Ad>, RepoError> {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        let Some(pending) = store
+            .phone_codes
+            .get_mut(&id)
+            .filter(|pending| pending.expires_at > now)
+        else {
+            return Ok(None);
+        };
+
+        if pending.code != code {
+            pending.attempts += 1;
+            if pending.attempts >= MAX_PHONE_CODE_ATTEMPTS {
+                store.phone_codes.remove(&id);
+            }
+            return Ok(None);
+        }
+
+        let user_phone = pending.user_phone.clone();
+        let ad = store
+            .owned(id, user_email)
+            .filter(|ad| ad.user_phone == user_phone)
+            .map(|ad| {
+                ad.phone_verified = true;
+                ad.updated_at = now;
+                ad.clone()
+            });
+        if ad.is_some() {
+            store.phone_codes.remove(&id);
+        }
+
+        Ok(ad)
+    }
+
+    async fn end_promotions(&self) -> Result<usize, RepoError> {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        let mut ended = 0;
+
+        for ad in store.ads.values_mut() {
+            if ad.top_ad_until.is_some_and(|until| until <= now) {
+                ad.top_ad = false;
+                ad.top_ad_until = None;
+                ad.updated_at = now;
+                ended += 1;
+            }
+        }
+
+        Ok(ended)
+    }
+
+    async fn mark_expired(&self) -> Result<usize, RepoError> {
+        let now = now();
+        let mut store = self.store.lock().unwrap();
+        let mut changed = 0;
+
+        for ad in store.ads.values_mut() {
+            if ad.status == AdStatus::Active.as_str() && ad.expires_at.is_some_and(|e| e < now) {
+                ad.status = AdStatus::Expired.as_str().to_string();
+                ad.updated_at = now;
+                changed += 1;
+            }
+        }
+
+        Ok(changed)
+    }
+
+    async fn close_stale_cursors(&self, max_age: Duration) -> Result<usize, RepoError> {
+        let mut store = self.store.lock().unwrap();
+        let open = store.cursors.len();
+        store
+            .cursors
+            .retain(|_, (used_at, _, _)| used_at.elapsed() <= max_age);
+
+        Ok(open - store.cursors.len())
+    }
+
+    async fn purge_idempotency_keys(&self) -> Result<usize, RepoError> {
+        let cutoff = idempotency_cutoff();
+        let mut store = self.store.lock().unwrap();
+        let recorded = store.idempotency_keys.len();
+        store
+            .idempotency_keys
+            .retain(|_, (_, created_at)| *created_at >= cutoff);
+
+        Ok(recorded - store.idempotency_keys.len())
+    }
+}
+
+/// `ImageUsageRepo` over a `HashMap` from `(user_email, image_id)` to bytes.
+#[derive(Default)]
+pub struct InMemoryImageUsageRepo {
+    uploads: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl InMemoryImageUsageRepo {
+    pub fn new() -> Arc<InMemoryImageUsageRepo> {
+        Arc::new(InMemoryImageUsageRepo::default())
+    }
+}
+
+#[async_trait]
+impl ImageUsageRepo for InMemoryImageUsageRepo {
+    async fn record(&self, user_email: &str, image_id: &str, bytes: u64) -> Result<(), RepoError> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .entry((user_email.to_string(), image_id.to_string()))
+            .or_insert(bytes);
+
+        Ok(())
+    }
+
+    async fn used_bytes(&self, user_email: &str) -> Result<u64, RepoError> {
+        Ok(self
+            .uploads
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((email, _), _)| email == user_email)
+            .map(|(_, bytes)| bytes)
+            .sum())
+    }
+
+    async fn forget(&self, image_ids: &[String]) -> Result<(), RepoError> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .retain(|(_, image_id), _| !image_ids.contains(image_id));
+
+        Ok(())
+    }
+}
+
+/// `ImageRepo` over a `HashMap`, keyed by random UUIDs like the other repos.
+#[derive(Default)]
+pub struct InMemoryImageRepo {
+    images: Mutex<HashMap<String, Image>>,
+}
+
+impl InMemoryImageRepo {
+    pub fn new() -> Arc<InMemoryImageRepo> {
+        Arc::new(InMemoryImageRepo::default())
+    }
+
+    /// Number of images stored, so tests can check uploads were kept or discarded.
+    pub fn len(&self) -> usize {
+        self.images.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl ImageRepo for InMemoryImageRepo {
+    async fn get_image(&self, id: &str) -> Result<Image, RepoError> {
+        self.images
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or(RepoError::NotFound)
+    }
+
+    async fn open_image_stream(
+        &self,
+        id: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ImageStream, RepoError> {
+        let image = self.get_image(id).await?;
+        let len = image.bytes.len() as u64;
+        let range = match range {
+            Some(range) => Some(
+                range
+                    .resolve(len)
+                    .ok_or(RepoError::RangeNotSatisfiable(len))?,
+            ),
+            None => None,
+        };
+        let bytes = match range {
+            Some((first, last)) => image.bytes[first as usize..=last as usize].to_vec(),
+            None => image.bytes,
+        };
+
+        Ok(ImageStream {
+            file_name: image.file_name,
+            mime_type: image.mime_type,
+            len,
+            range,
+            reader: Box::pin(std::io::Cursor::new(bytes)),
+        })
+    }
+
+    async fn create_image(
+        &self,
+        file_name: String,
+        bytes: Vec<u8>,
+        mime_type: String,
+    ) -> Result<String, RepoError> {
+        let id = uuid::Uuid::new_v4().to_string();
+        self.images.lock().unwrap().insert(
+            id.clone(),
+            Image {
+                id: Some(id.clone()),
+                file_name,
+                mime_type,
+                dimensions: ImageDimensions::of(&bytes),
+                bytes,
+            },
+        );
+
+        Ok(id)
+    }
+
+    async fn delete_image(&self, id: &str) -> Result<(), RepoError> {
+        match self.images.lock().unwrap().remove(id) {
+            Some(_) => Ok(()),
+            None => Err(RepoError::NotFound),
+        }
+    }
+
+    async fn exists(&self, id: &str) -> Result<bool, RepoError> {
+        Ok(self.images.lock().unwrap().contains_key(id))
+    }
+
+    async fn get_dimensions(&self, id: &str) -> Result<Option<ImageDimensions>, RepoError> {
+        Ok(self.get_image(id).await?.dimensions)
+    }
+
+    async fn get_thumbnail(&self, id: &str, max_dim: u32) -> Result<Image, RepoError> {
+        let source = self.get_image(id).await?;
+        let bytes = make_thumbnail(source.bytes.clone(), max_dim).await?;
+
+        Ok(Image {
+            dimensions: ImageDimensions::of(&bytes),
+            bytes,
+            ..source
+        })
+    }
+
+    /// Like local files, only reachable through the API.
+    async fn presigned_url(
+        &self,
+        _id: &str,
+        _ttl: Duration,
+    ) -> Result<Option<SignedUrl>, RepoError> {
+        Ok(None)
+    }
+
+    async fn health_check(&self) -> Result<(), RepoError> {
+        Ok(())
+    }
+
+    /// Nothing here can lose its metadata.
+    async fn repair(&self, id: &str) -> Result<RepairOutcome, RepoError> {
+        self.get_image(id).await.map(|_| RepairOutcome::Intact)
+    }
+}
+
+struct OutboxRow {
+    entry: OutboxEntry,
+    status: OutboxStatus,
+    next_attempt_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// `OutboxRepo` over a `Vec`, with ids counting up from 1.
+#[derive(Default)]
+pub struct InMemoryOutboxRepo {
+    rows: Mutex<Vec<OutboxRow>>,
+}
+
+impl InMemoryOutboxRepo {
+    pub fn new() -> Arc<InMemoryOutboxRepo> {
+        Arc::new(InMemoryOutboxRepo::default())
+    }
+
+    /// Every queued message with its status, oldest first, so tests can check what was
+    /// queued and whether it went out.
+    pub fn messages(&self) -> Vec<(ContactMessage, OutboxStatus)> {
+        self.rows
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|row| (row.entry.message.clone(), row.status))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl OutboxRepo for InMemoryOutboxRepo {
+    async fn enqueue(&self, message: ContactMessage) -> Result<(), RepoError> {
+        let mut rows = self.rows.lock().unwrap();
+        let id = rows.len() as i64 + 1;
+        rows.push(OutboxRow {
+            entry: OutboxEntry {
+                id,
+                message,
+                attempts: 0,
+            },
+            status: OutboxStatus::Pending,
+            next_attempt_at: now(),
+        });
+
+        Ok(())
+    }
+
+    async fn claim_due(&self, limit: u32, lease: Duration) -> Result<Vec<OutboxEntry>, RepoError> {
+        let now = now();
+        let lease = chrono::Duration::from_std(lease).map_err(RepoError::internal)?;
+        let mut rows = self.rows.lock().unwrap();
+        let mut due: Vec<_> = rows
+            .iter_mut()
+            .filter(|row| row.status == OutboxStatus::Pending && row.next_attempt_at <= now)
+            .collect();
+        due.sort_by_key(|row| row.next_attempt_at);
+
+        Ok(due
+            .into_iter()
+            .take(limit as usize)
+            .map(|row| {
+                row.next_attempt_at = now + lease;
+                row.entry.clone()
+            })
+            .collect())
+    }
+
+    async fn mark_sent(&self, id: i64) -> Result<(), RepoError> {
+        let mut rows = self.rows.lock().unwrap();
+        let row = rows
+            .iter_mut()
+            .find(|row| row.entry.id == id)
+            .ok_or(RepoError::NotFound)?;
+        row.status = OutboxStatus::Sent;
+
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        id: i64,
+        _error: &str,
+        retry_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(), RepoError> {
+        let mut rows = self.rows.lock().unwrap();
+        let row = rows
+            .iter_mut()
+            .find(|row| row.entry.id == id)
+            .ok_or(RepoError::NotFound)?;
+        row.entry.attempts += 1;
+        match retry_at {
+            Some(retry_at) => row.next_attempt_at = retry_at,
+            None => row.status = OutboxStatus::Failed,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::ad::{AdCategory, AdId, Currency};
+    use crate::repos::fixtures::ad_content;
+
+    #[tokio::test]
+    async fn test_in_memory_filters_and_pages() {
+        let repo = InMemoryAdRepo::new();
+        for (title, price) in [("Bike", 300), ("Bike lock", 20), ("Sofa", 150)] {
+            let ad = repo
+                .create(
+                    AdContent {
+                        price: price.into(),
+                        ..ad_content(title)
+                    },
+                    vec![],
+                )
+                .await
+                .unwrap();
+            if title == "Sofa" {
+                let until = now() + chrono::Duration::hours(1);
+                repo.promote(ad.id, &ad.user_email, until).await.unwrap();
+            }
+        }
+
+        let filter = AdFilter::builder()
+            .title_contains("bike")
+            .sort_by(AdSort::PriceAsc)
+            .build()
+            .unwrap();
+        let titles = |ads: Vec<Ad>| ads.into_iter().map(|ad| ad.title).collect::<Vec<_>>();
+        assert_eq!(repo.count(filter.clone()).await.unwrap(), 2);
+        assert_eq!(
+            titles(repo.get_page(0, 10, filter).await.unwrap()),
+            ["Bike lock", "Bike"]
+        );
+
+        // Promoted ads come first, then cheapest.
+        let filter = AdFilter::builder()
+            .sort_by(AdSort::PriceAsc)
+            .build()
+            .unwrap();
+        assert_eq!(
+            titles(repo.get_page(1, 2, filter.clone()).await.unwrap()),
+            ["Bike lock", "Bike"]
+        );
+
+        let filter = AdFilter::builder()
+            .price_above(100)
+            .currency(Currency::Eur)
+            .category(AdCategory::default())
+            .build()
+            .unwrap();
+        assert_eq!(repo.count(filter).await.unwrap(), 2);
+
+        repo.delete(AdId(1), "test@test.com").await.unwrap();
+        assert_eq!(repo.count(AdFilter::default()).await.unwrap(), 2);
+        assert!(!repo.exists(AdId(1)).await.unwrap());
+        assert_eq!(repo.restore(AdId(1)).await.unwrap().unwrap().title, "Bike");
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_cursor() {
+        let repo = InMemoryAdRepo::new();
+        for title in ["First", "Second", "Third"] {
+            repo.create(ad_content(title), vec![]).await.unwrap();
+        }
+
+        let cursor = repo.new_cursor(AdFilter::default()).await.unwrap();
+        assert_eq!(
+            repo.fetch_from_cursor(cursor.clone(), 2, true, None)
+                .await
+                .unwrap()
+                .items
+                .len(),
+            2
+        );
+        assert_eq!(
+            repo.fetch_from_cursor(cursor.clone(), 2, true, None)
+                .await
+                .unwrap()
+                .items
+                .len(),
+            1
+        );
+        assert!(matches!(
+            repo.fetch_from_cursor(cursor, 2, true, None).await,
+            Err(RepoError::CursorExpired)
+        ));
+    }
+}
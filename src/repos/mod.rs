@@ -1,2 +1,12 @@
+pub mod ad_query;
 pub mod ad_repo;
+pub mod error;
+pub mod favorite_repo;
+#[cfg(test)]
+pub(crate) mod fixtures;
 pub mod image_repo;
+pub mod image_usage_repo;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
+pub mod outbox_repo;
+pub mod report_repo;
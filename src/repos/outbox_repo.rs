@@ -0,0 +1,224 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::async_trait;
+use diesel::prelude::*;
+
+use crate::db::schema::outbox;
+use crate::db::DbManager;
+use crate::models::outbox::{OutboxEntry, OutboxStatus};
+use crate::notify::ContactMessage;
+use crate::repos::error::RepoError;
+
+/// Messages are written here and sent by `jobs::run_outbox`, so a request never waits on
+/// SMTP and a message survives the mail server being down. Delivery is at least once: a
+/// worker that dies after sending but before `mark_sent` sends the message again.
+#[async_trait]
+pub trait OutboxRepo: Send + Sync {
+    /// Queues `message` for its first attempt right away.
+    async fn enqueue(&self, message: ContactMessage) -> Result<(), RepoError>;
+    /// Up to `limit` pending messages that are due, oldest first. They aren't handed out
+    /// again for `lease`, so concurrent workers don't send them twice; if the worker dies,
+    /// they come back once it runs out.
+    async fn claim_due(&self, limit: u32, lease: Duration) -> Result<Vec<OutboxEntry>, RepoError>;
+    async fn mark_sent(&self, id: i64) -> Result<(), RepoError>;
+    /// Records a failed attempt and its error. The message is tried again at `retry_at`,
+    /// or with `None` given up on and marked failed.
+    async fn mark_failed(
+        &self,
+        id: i64,
+        error: &str,
+        retry_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(), RepoError>;
+}
+
+#[derive(Clone)]
+pub struct PostgresOutboxRepo {
+    pub db_manager: DbManager,
+}
+
+impl PostgresOutboxRepo {
+    pub fn new(db_manager: DbManager) -> Arc<PostgresOutboxRepo> {
+        Arc::new(PostgresOutboxRepo { db_manager })
+    }
+}
+
+/// Queues `message` on `conn`, so a request can write it in the same transaction as the
+/// rest of its changes.
+pub fn enqueue_on(conn: &mut PgConnection, message: &ContactMessage) -> Result<(), RepoError> {
+    let now = chrono::Utc::now();
+    diesel::insert_into(outbox::table)
+        .values((
+            outbox::payload.eq(serde_json::to_value(message)?),
+            outbox::status.eq(OutboxStatus::Pending.as_str()),
+            outbox::next_attempt_at.eq(now),
+            outbox::created_at.eq(now),
+        ))
+        .execute(conn)
+        .map_err(RepoError::from)?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl OutboxRepo for PostgresOutboxRepo {
+    async fn enqueue(&self, message: ContactMessage) -> Result<(), RepoError> {
+        let conn = &mut self.db_manager.write_conn()?;
+        enqueue_on(conn, &message)
+    }
+
+    async fn claim_due(&self, limit: u32, lease: Duration) -> Result<Vec<OutboxEntry>, RepoError> {
+        let now = chrono::Utc::now();
+        let lease = chrono::Duration::from_std(lease).map_err(RepoError::internal)?;
+
+        let rows = self.db_manager.transaction(|conn| {
+            // Rows another worker is claiming right now are skipped rather than waited for.
+            let ids = outbox::table
+                .filter(outbox::status.eq(OutboxStatus::Pending.as_str()))
+                .filter(outbox::next_attempt_at.le(now))
+                .order(outbox::next_attempt_at.asc())
+                .limit(limit.into())
+                .select(outbox::id)
+                .for_update()
+                .skip_locked()
+                .load::<i64>(conn)?;
+
+            diesel::update(outbox::table.filter(outbox::id.eq_any(ids)))
+                .set(outbox::next_attempt_at.eq(now + lease))
+                .returning((outbox::id, outbox::payload, outbox::attempts))
+                .load::<(i64, serde_json::Value, i32)>(conn)
+                .map_err(RepoError::from)
+        })?;
+
+        rows.into_iter()
+            .map(|(id, payload, attempts)| {
+                Ok(OutboxEntry {
+                    id,
+                    message: serde_json::from_value(payload)?,
+                    attempts: attempts.try_into().unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    async fn mark_sent(&self, id: i64) -> Result<(), RepoError> {
+        let updated = diesel::update(outbox::table.find(id))
+            .set((
+                outbox::status.eq(OutboxStatus::Sent.as_str()),
+                outbox::sent_at.eq(chrono::Utc::now()),
+            ))
+            .execute(&mut self.db_manager.write_conn()?)
+            .map_err(RepoError::from)?;
+
+        match updated {
+            0 => Err(RepoError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    async fn mark_failed(
+        &self,
+        id: i64,
+        error: &str,
+        retry_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(), RepoError> {
+        let status = match retry_at {
+            Some(_) => OutboxStatus::Pending,
+            None => OutboxStatus::Failed,
+        };
+        let updated = diesel::update(outbox::table.find(id))
+            .set((
+                outbox::status.eq(status.as_str()),
+                outbox::attempts.eq(outbox::attempts + 1),
+                outbox::last_error.eq(error),
+                outbox::next_attempt_at.eq(retry_at.unwrap_or_else(chrono::Utc::now)),
+            ))
+            .execute(&mut self.db_manager.write_conn()?)
+            .map_err(RepoError::from)?;
+
+        match updated {
+            0 => Err(RepoError::NotFound),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use diesel::prelude::*;
+
+    use crate::db::schema::outbox;
+    use crate::notify::ContactMessage;
+    use crate::repos::{
+        fixtures::test_db,
+        outbox_repo::{OutboxRepo, PostgresOutboxRepo},
+    };
+
+    fn message(subject: &str) -> ContactMessage {
+        ContactMessage {
+            to: "seller@test.com".to_string(),
+            reply_to: "buyer@test.com".to_string(),
+            subject: subject.to_string(),
+            body: "Is it still available?".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outbox_claim_and_mark() {
+        let db_manager = test_db();
+        let outbox_repo = PostgresOutboxRepo::new(db_manager.clone());
+        // Messages other tests left behind would be claimed too.
+        diesel::delete(outbox::table)
+            .execute(&mut db_manager.write_conn().unwrap())
+            .unwrap();
+
+        outbox_repo.enqueue(message("First")).await.unwrap();
+        outbox_repo.enqueue(message("Second")).await.unwrap();
+
+        let lease = Duration::from_secs(60);
+        let claimed = outbox_repo.claim_due(10, lease).await.unwrap();
+        assert_eq!(claimed.len(), 2);
+        assert_eq!(claimed[0].message, message("First"));
+        assert_eq!(claimed[0].attempts, 0);
+        // Leased, so not handed out again.
+        assert!(outbox_repo.claim_due(10, lease).await.unwrap().is_empty());
+
+        outbox_repo.mark_sent(claimed[0].id).await.unwrap();
+        outbox_repo
+            .mark_failed(
+                claimed[1].id,
+                "connection refused",
+                Some(chrono::Utc::now()),
+            )
+            .await
+            .unwrap();
+
+        let retried = outbox_repo.claim_due(10, lease).await.unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].id, claimed[1].id);
+        assert_eq!(retried[0].attempts, 1);
+
+        outbox_repo
+            .mark_failed(retried[0].id, "connection refused", None)
+            .await
+            .unwrap();
+        let statuses = outbox::table
+            .order(outbox::id)
+            .select((outbox::status, outbox::attempts, outbox::last_error))
+            .load::<(String, i32, Option<String>)>(&mut db_manager.write_conn().unwrap())
+            .unwrap();
+        assert_eq!(
+            statuses,
+            vec![
+                ("sent".to_string(), 0, None),
+                (
+                    "failed".to_string(),
+                    2,
+                    Some("connection refused".to_string())
+                ),
+            ]
+        );
+        assert!(outbox_repo.claim_due(10, lease).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use axum::async_trait;
+use diesel::dsl::count_star;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error};
+
+use crate::db::schema::{ads, reports};
+use crate::db::DbManager;
+use crate::models::ad::{Ad, AdId};
+use crate::models::report::ReportedAd;
+use crate::repos::error::RepoError;
+
+/// Reports an ad needs before it shows up in the moderation queue by default.
+pub const DEFAULT_REPORT_THRESHOLD: u32 = 3;
+
+#[async_trait]
+pub trait ReportRepo: Send + Sync {
+    /// Files a report against the ad; `NotFound` if there is no such ad or it was deleted.
+    async fn add(&self, ad_id: AdId, reason: &str) -> Result<(), RepoError>;
+    /// Live ads with at least `min_reports` reports, most reported first.
+    async fn list_reported(&self, min_reports: u32) -> Result<Vec<ReportedAd>, RepoError>;
+}
+
+#[derive(Clone)]
+pub struct PostgresReportRepo {
+    pub db_manager: DbManager,
+}
+
+impl PostgresReportRepo {
+    pub fn new(db_manager: DbManager) -> Arc<PostgresReportRepo> {
+        Arc::new(PostgresReportRepo { db_manager })
+    }
+}
+
+#[async_trait]
+impl ReportRepo for PostgresReportRepo {
+    async fn add(&self, ad_id: AdId, reason: &str) -> Result<(), RepoError> {
+        let conn = &mut self.db_manager.write_conn()?;
+
+        // Soft-deleted ads still satisfy the foreign key, so they are checked for here.
+        let deleted = ads::table
+            .find(ad_id)
+            .select(ads::deleted_at.is_not_null())
+            .first::<bool>(conn)
+            .optional()
+            .map_err(RepoError::from)?;
+        if deleted == Some(true) {
+            return Err(RepoError::NotFound);
+        }
+
+        diesel::insert_into(reports::table)
+            .values((
+                reports::ad_id.eq(ad_id),
+                reports::reason.eq(reason),
+                reports::created_at.eq(chrono::Utc::now()),
+            ))
+            .execute(conn)
+            .map_err(|e| match e {
+                Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                    RepoError::NotFound
+                }
+                e => RepoError::from(e),
+            })?;
+
+        Ok(())
+    }
+
+    async fn list_reported(&self, min_reports: u32) -> Result<Vec<ReportedAd>, RepoError> {
+        let rows = reports::table
+            .inner_join(ads::table)
+            .filter(ads::deleted_at.is_null())
+            .group_by(ads::id)
+            .having(count_star().ge(i64::from(min_reports)))
+            .order((count_star().desc(), ads::id.desc()))
+            .select((
+                Ad::as_select(),
+                count_star(),
+                diesel::dsl::max(reports::created_at).assume_not_null(),
+            ))
+            .load::<(Ad, i64, chrono::DateTime<chrono::Utc>)>(&mut self.db_manager.read_conn()?)
+            .map_err(RepoError::from)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(ad, report_count, last_reported_at)| ReportedAd {
+                ad,
+                report_count,
+                last_reported_at,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::models::ad::AdId;
+    use crate::repos::{
+        ad_repo::{AdRepo, PostgresAdRepo},
+        error::RepoError,
+        fixtures::{ad_content, seed_ad, test_db},
+        report_repo::{PostgresReportRepo, ReportRepo},
+    };
+
+    #[tokio::test]
+    async fn test_reports_above_threshold() {
+        let db_manager = test_db();
+        let ad_repo = PostgresAdRepo::new(db_manager.clone());
+        let report_repo = PostgresReportRepo::new(db_manager);
+
+        let spam = seed_ad(&*ad_repo, ad_content("Spam")).await;
+        let scam = seed_ad(&*ad_repo, ad_content("Scam")).await;
+        let fine = seed_ad(&*ad_repo, ad_content("Fine")).await;
+
+        for _ in 0..3 {
+            report_repo.add(spam.id, "spam").await.unwrap();
+        }
+        for _ in 0..2 {
+            report_repo.add(scam.id, "scam").await.unwrap();
+        }
+        report_repo.add(fine.id, "mistake").await.unwrap();
+
+        // Other tests' ads may be queued too, so only these three are looked at.
+        let queued = || async {
+            report_repo
+                .list_reported(2)
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|reported| [spam.id, scam.id, fine.id].contains(&reported.ad.id))
+                .map(|reported| (reported.ad.id, reported.report_count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(queued().await, vec![(spam.id, 3), (scam.id, 2)]);
+
+        // Deleted ads drop out of the queue and can't be reported any more.
+        ad_repo.delete(spam.id, &spam.user_email).await.unwrap();
+        assert_eq!(queued().await, vec![(scam.id, 2)]);
+
+        assert!(matches!(
+            report_repo.add(spam.id, "spam").await,
+            Err(RepoError::NotFound)
+        ));
+        assert!(matches!(
+            report_repo.add(AdId(i32::MAX), "spam").await,
+            Err(RepoError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::Duration;
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, Response},
+};
+use tracing::Span;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// One span per request, carrying the request id set or propagated by `SetRequestIdLayer`.
+/// Only the path is recorded; query strings and bodies stay out of the logs.
+pub fn make_span(req: &Request) -> Span {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-");
+
+    tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.uri().path(),
+    )
+}
+
+/// Logs the status and latency of every response: server errors at ERROR,
+/// everything else at INFO.
+pub fn on_response<B>(response: &Response<B>, latency: Duration, _span: &Span) {
+    let status = response.status().as_u16();
+    let latency_ms = latency.as_secs_f64() * 1000.0;
+
+    if response.status().is_server_error() {
+        tracing::error!(status, latency_ms, "request failed");
+    } else {
+        tracing::info!(status, latency_ms, "request finished");
+    }
+}
@@ -0,0 +1,72 @@
+use std::{sync::OnceLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::db::DbManager;
+
+const REQUEST_DURATION: &str = "http_request_duration_seconds";
+
+/// Latency buckets in seconds, from a cached page to a slow image upload.
+const REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Installs the global Prometheus recorder on first use and returns a handle to render it.
+/// Later calls, e.g. from tests building several apps, share the same recorder.
+pub fn prometheus_handle() -> PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+    HANDLE
+        .get_or_init(|| {
+            PrometheusBuilder::new()
+                .set_buckets_for_metric(
+                    Matcher::Full(REQUEST_DURATION.to_string()),
+                    REQUEST_DURATION_BUCKETS,
+                )
+                .expect("buckets are not empty")
+                .install_recorder()
+                .expect("Failed to install the Prometheus recorder")
+        })
+        .clone()
+}
+
+/// Counts requests and records their latency, labelled by route template rather than
+/// the concrete path so ids don't explode the label space. Added as a route layer,
+/// so only matched routes are measured.
+pub async fn track_requests(req: Request, next: Next) -> Response {
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let method = req.method().to_string();
+    let start = Instant::now();
+
+    let response = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("path", path),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!(REQUEST_DURATION, &labels).record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Pool gauges are sampled at scrape time rather than tracked continuously.
+pub fn record_pool_state(db_manager: &DbManager) {
+    for (pool, state) in [
+        ("write", db_manager.get_write_pool().state()),
+        ("read", db_manager.get_read_pool().state()),
+    ] {
+        metrics::gauge!("db_pool_connections", "pool" => pool).set(state.connections);
+        metrics::gauge!("db_pool_idle_connections", "pool" => pool).set(state.idle_connections);
+    }
+}